@@ -0,0 +1,522 @@
+use crate::decode_options::DecodeOptions;
+use crate::internet_protocol::{Ipv4Address, Ipv4Header};
+use crate::transmission_control_protocol::tcp_packet::TcpPacket;
+use crate::transmission_control_protocol::{TcpHeader, TCP_PROTOCOL_NUMBER};
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::fmt;
+
+pub mod bpf;
+mod parser;
+
+/*
+ * tcpdump 風のフィルター式。
+ *
+ * 対応している文法（tcpdump のサブセット）:
+ *
+ *   expr      := expr ("or" | "||") expr | expr ("and" | "&&") expr | ("not" | "!") expr | "(" expr ")"
+ *              | primitive | arith relop arith
+ *   primitive := ["src" | "dst"] "host" ADDRESS
+ *              | ["src" | "dst"] "net" ADDRESS "/" LEN
+ *              | ["tcp"] ["src" | "dst"] "port" NUMBER
+ *              | "ip" | "tcp" | "udp" | "icmp" | "ip" "proto" NUMBER
+ *   arith     := NUMBER | "len" | ("ip" | "tcp") "[" NUMBER [":" (1 | 2 | 4)] "]" | arith op arith | "(" arith ")"
+ *   op        := "+" | "-" | "*" | "/" | "&" | "|"
+ *   relop     := "=" | "==" | "!=" | "<" | "<=" | ">" | ">="
+ *
+ * `tcpflags`, `tcp-syn` などの名前付き定数も使える。
+ *
+ * NOTE: port は TCP のみ対応。UDP はまだ decode できないので。
+ */
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Filter {
+    expression: Expression,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) enum Expression {
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Not(Box<Expression>),
+    Primitive(Primitive),
+    Relation {
+        lhs: Arithmetic,
+        operator: RelationalOperator,
+        rhs: Arithmetic,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum AddressQualifier {
+    Source,
+    Destination,
+    Either,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum Primitive {
+    Protocol(u8),
+    Host {
+        qualifier: AddressQualifier,
+        address: Ipv4Address,
+    },
+    Net {
+        qualifier: AddressQualifier,
+        address: Ipv4Address,
+        prefix_length: u8,
+    },
+    Port {
+        qualifier: AddressQualifier,
+        port: u16,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum LoadBase {
+    Ip,
+    Tcp,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum ArithmeticOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    And,
+    Or,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum RelationalOperator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) enum Arithmetic {
+    Constant(u32),
+
+    /*
+     * ip[offset:size] / tcp[offset:size]. size は 1, 2, 4.
+     */
+    Load {
+        base: LoadBase,
+        offset: u32,
+        size: u8,
+    },
+
+    /*
+     * datagram 全体の長さ。
+     */
+    Length,
+
+    Binary {
+        operator: ArithmeticOperator,
+        lhs: Box<Arithmetic>,
+        rhs: Box<Arithmetic>,
+    },
+}
+
+/*
+ * position は入力文字列の先頭からの byte offset.
+ */
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum FilterParseError {
+    UnexpectedCharacter { position: usize, found: char },
+    UnexpectedToken { position: usize, found: String },
+    UnexpectedEnd,
+    InvalidNumber { position: usize, found: String },
+    InvalidAddress { position: usize, found: String },
+    InvalidLoadSize { position: usize, size: u32 },
+}
+
+impl fmt::Display for FilterParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterParseError::UnexpectedCharacter { position, found } => {
+                write!(f, "Unexpected character '{}' at {}.", found, position)
+            }
+            FilterParseError::UnexpectedToken { position, found } => {
+                write!(f, "Unexpected '{}' at {}.", found, position)
+            }
+            FilterParseError::UnexpectedEnd => write!(f, "Unexpected end of expression."),
+            FilterParseError::InvalidNumber { position, found } => {
+                write!(f, "Invalid number '{}' at {}.", found, position)
+            }
+            FilterParseError::InvalidAddress { position, found } => {
+                write!(f, "Invalid address '{}' at {}.", found, position)
+            }
+            FilterParseError::InvalidLoadSize { position, size } => write!(
+                f,
+                "Invalid load size {} at {}. It should be 1, 2 or 4.",
+                size, position
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for FilterParseError {}
+
+impl Filter {
+    pub fn parse(input: &str) -> Result<Self, FilterParseError> {
+        let expression = parser::parse(input)?;
+        Ok(Self { expression })
+    }
+
+    /*
+     * decode 済みのヘッダーに対して評価する。TCP 以外の datagram なら`tcp_header`は`None`.
+     *
+     * NOTE: BPF と同じく、TCP でない datagram に対する tcp[..] を含む比較は偽になり、
+     *       範囲外の読み込みや 0 除算があるとフィルター全体が偽になる。
+     */
+    pub fn matches(&self, ipv4_header: &Ipv4Header, tcp_header: Option<&TcpHeader>) -> bool {
+        let mut datagram = ipv4_header.encode();
+        if let Some(tcp_header) = tcp_header {
+            datagram.extend_from_slice(&tcp_header.encode());
+        }
+        /*
+         * payload は分からないので、len が total_length になるようにゼロで埋めておく。
+         */
+        let total_length = usize::from(ipv4_header.get_total_length());
+        if datagram.len() < total_length {
+            datagram.resize(total_length, 0);
+        }
+        self.matches_with(ipv4_header, tcp_header, &datagram)
+    }
+
+    pub fn matches_packet(&self, packet: &TcpPacket) -> bool {
+        let mut datagram = packet.get_ipv4_header().encode();
+        datagram.extend_from_slice(&packet.get_tcp_header().encode());
+        datagram.extend_from_slice(packet.get_payload());
+        self.matches_with(
+            packet.get_ipv4_header(),
+            Some(packet.get_tcp_header()),
+            &datagram,
+        )
+    }
+
+    /*
+     * datagram を decode して評価する。IPv4 として decode できなければ偽。
+     */
+    pub fn matches_datagram(&self, datagram: &[u8]) -> bool {
+        /*
+         * フィルターはヘッダーを分類するだけなので、チェックサムは確かめない。
+         * checksum offload が有効だと、送信したパケットのチェックサムは正しくないことがある。
+         */
+        let options = DecodeOptions::checksum_offloaded();
+        let Ok((ipv4_header, _)) = Ipv4Header::decode_with_options(datagram, &options) else {
+            return false;
+        };
+        let tcp_header = if ipv4_header.get_protocol() == TCP_PROTOCOL_NUMBER
+            && ipv4_header.get_fragment_offset() == 0
+        {
+            TcpHeader::decode(ipv4_header.get_payload(datagram)).ok()
+        } else {
+            None
+        };
+        self.matches_with(&ipv4_header, tcp_header.as_ref(), datagram)
+    }
+
+    fn matches_with(
+        &self,
+        ipv4_header: &Ipv4Header,
+        tcp_header: Option<&TcpHeader>,
+        datagram: &[u8],
+    ) -> bool {
+        let context = Context {
+            ipv4_header,
+            tcp_header,
+            datagram,
+        };
+        context.evaluate(&self.expression).unwrap_or(false)
+    }
+
+    pub(crate) fn get_expression(&self) -> &Expression {
+        &self.expression
+    }
+}
+
+struct Context<'a> {
+    ipv4_header: &'a Ipv4Header,
+    tcp_header: Option<&'a TcpHeader>,
+    datagram: &'a [u8],
+}
+
+impl Context<'_> {
+    /*
+     * None は評価の中断（BPF の`ret #0`に相当）。
+     */
+    fn evaluate(&self, expression: &Expression) -> Option<bool> {
+        match expression {
+            Expression::And(lhs, rhs) => Some(self.evaluate(lhs)? && self.evaluate(rhs)?),
+            Expression::Or(lhs, rhs) => Some(self.evaluate(lhs)? || self.evaluate(rhs)?),
+            Expression::Not(inner) => self.evaluate(inner).map(|matched| !matched),
+            Expression::Primitive(primitive) => Some(self.evaluate_primitive(primitive)),
+            Expression::Relation { lhs, operator, rhs } => {
+                if (lhs.loads_tcp() || rhs.loads_tcp()) && self.tcp_header().is_none() {
+                    return Some(false);
+                }
+                let rhs = self.calculate(rhs)?;
+                let lhs = self.calculate(lhs)?;
+                Some(match operator {
+                    RelationalOperator::Equal => lhs == rhs,
+                    RelationalOperator::NotEqual => lhs != rhs,
+                    RelationalOperator::Less => lhs < rhs,
+                    RelationalOperator::LessOrEqual => lhs <= rhs,
+                    RelationalOperator::Greater => lhs > rhs,
+                    RelationalOperator::GreaterOrEqual => lhs >= rhs,
+                })
+            }
+        }
+    }
+
+    fn evaluate_primitive(&self, primitive: &Primitive) -> bool {
+        let source = self.ipv4_header.get_source_address();
+        let destination = self.ipv4_header.get_destination_address();
+
+        match *primitive {
+            Primitive::Protocol(protocol) => self.ipv4_header.get_protocol() == protocol,
+            Primitive::Host { qualifier, address } => {
+                qualify(qualifier, source == address, destination == address)
+            }
+            Primitive::Net {
+                qualifier,
+                address,
+                prefix_length,
+            } => {
+                let mask = prefix_mask(prefix_length);
+                let network = u32::from_be_bytes(address) & mask;
+                qualify(
+                    qualifier,
+                    u32::from_be_bytes(source) & mask == network,
+                    u32::from_be_bytes(destination) & mask == network,
+                )
+            }
+            Primitive::Port { qualifier, port } => match self.tcp_header() {
+                Some(tcp_header) => qualify(
+                    qualifier,
+                    tcp_header.get_source_port() == port,
+                    tcp_header.get_destination_port() == port,
+                ),
+                None => false,
+            },
+        }
+    }
+
+    fn calculate(&self, arithmetic: &Arithmetic) -> Option<u32> {
+        match arithmetic {
+            Arithmetic::Constant(value) => Some(*value),
+            Arithmetic::Length => Some(self.datagram.len() as u32),
+            Arithmetic::Load { base, offset, size } => {
+                let base = match base {
+                    LoadBase::Ip => 0,
+                    LoadBase::Tcp => self.ipv4_header.get_header_length(),
+                };
+                let start = base + *offset as usize;
+                let loaded = self.datagram.get(start..start + usize::from(*size))?;
+                Some(
+                    loaded
+                        .iter()
+                        .fold(0u32, |acc, &b| (acc << 8) | u32::from(b)),
+                )
+            }
+            Arithmetic::Binary { operator, lhs, rhs } => {
+                let rhs = self.calculate(rhs)?;
+                let lhs = self.calculate(lhs)?;
+                match operator {
+                    ArithmeticOperator::Add => Some(lhs.wrapping_add(rhs)),
+                    ArithmeticOperator::Subtract => Some(lhs.wrapping_sub(rhs)),
+                    ArithmeticOperator::Multiply => Some(lhs.wrapping_mul(rhs)),
+                    ArithmeticOperator::Divide => lhs.checked_div(rhs),
+                    ArithmeticOperator::And => Some(lhs & rhs),
+                    ArithmeticOperator::Or => Some(lhs | rhs),
+                }
+            }
+        }
+    }
+
+    /*
+     * tcpdump と同じく、先頭以外のフラグメントには TCP ヘッダーがないものとして扱う。
+     */
+    fn tcp_header(&self) -> Option<&TcpHeader> {
+        if self.ipv4_header.get_protocol() != TCP_PROTOCOL_NUMBER
+            || self.ipv4_header.get_fragment_offset() != 0
+        {
+            return None;
+        }
+        self.tcp_header
+    }
+}
+
+impl Arithmetic {
+    pub(crate) fn loads_tcp(&self) -> bool {
+        match self {
+            Arithmetic::Load { base, .. } => *base == LoadBase::Tcp,
+            Arithmetic::Binary { lhs, rhs, .. } => lhs.loads_tcp() || rhs.loads_tcp(),
+            Arithmetic::Constant(_) | Arithmetic::Length => false,
+        }
+    }
+}
+
+fn qualify(qualifier: AddressQualifier, source: bool, destination: bool) -> bool {
+    match qualifier {
+        AddressQualifier::Source => source,
+        AddressQualifier::Destination => destination,
+        AddressQualifier::Either => source || destination,
+    }
+}
+
+pub(crate) fn prefix_mask(prefix_length: u8) -> u32 {
+    if prefix_length == 0 {
+        0
+    } else {
+        u32::MAX << (32 - u32::from(prefix_length.min(32)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transmission_control_protocol::ControlBits;
+
+    fn packet(source_port: u16, destination_port: u16, syn: bool) -> TcpPacket {
+        let mut control_bits = ControlBits::default();
+        control_bits.set_syn(syn);
+        control_bits.set_ack(true);
+        let mut tcp_header = TcpHeader::new(source_port, destination_port);
+        tcp_header.set_control_bits(control_bits);
+
+        TcpPacket::new(
+            Ipv4Header::new([10, 1, 2, 3], [192, 168, 0, 1], TCP_PROTOCOL_NUMBER),
+            tcp_header,
+            b"data".to_vec(),
+        )
+    }
+
+    fn matches(filter: &str, packet: &TcpPacket) -> bool {
+        Filter::parse(filter).unwrap().matches_packet(packet)
+    }
+
+    #[test]
+    fn test_host_and_net() {
+        let packet = packet(5432, 80, true);
+
+        assert!(matches("host 10.1.2.3", &packet));
+        assert!(matches("src host 10.1.2.3", &packet));
+        assert!(!matches("dst host 10.1.2.3", &packet));
+        assert!(matches("src net 10.0.0.0/8", &packet));
+        assert!(matches("dst net 192.168.0.0/16", &packet));
+        assert!(!matches("net 172.16.0.0/12", &packet));
+    }
+
+    #[test]
+    fn test_port_and_protocol() {
+        let packet = packet(5432, 80, true);
+
+        assert!(matches("tcp port 80", &packet));
+        assert!(matches("tcp dst port 80 and src port 5432", &packet));
+        assert!(!matches("tcp src port 80", &packet));
+        assert!(matches("tcp and not udp", &packet));
+        assert!(matches("ip proto 6", &packet));
+        assert!(!matches("icmp or udp", &packet));
+    }
+
+    #[test]
+    fn test_relations() {
+        let syn = packet(5432, 80, true);
+        let ack = packet(5432, 80, false);
+
+        assert!(matches("tcp[tcpflags] & tcp-syn != 0", &syn));
+        assert!(!matches("tcp[tcpflags] & tcp-syn != 0", &ack));
+        assert!(matches("(tcp[13] & 0x12) == 0x12", &syn));
+        assert!(matches("tcp[2:2] = 80", &syn));
+        assert!(matches("ip[9] = 6 and len = 44", &syn));
+        assert!(matches("len > 40 && len - 4 <= 40", &syn));
+        assert!(!matches("tcp[100:4] = 0", &syn));
+        assert!(!matches("not tcp[100:4] = 0", &syn));
+        assert!(!matches("len / 0 = 0 or tcp", &syn));
+        assert!(matches("tcp or len / 0 = 0", &syn));
+    }
+
+    #[test]
+    fn test_datagram_with_bad_checksum() {
+        let filter = Filter::parse("tcp port 80").unwrap();
+        let mut datagram = packet(5432, 80, true).encode().unwrap();
+        assert!(filter.matches_datagram(&datagram));
+
+        datagram[10] ^= 0xff;
+        datagram[11] ^= 0xff;
+        assert!(filter.matches_datagram(&datagram));
+    }
+
+    #[test]
+    fn test_non_tcp_datagram() {
+        let mut ipv4_header = Ipv4Header::new([10, 0, 0, 1], [10, 0, 0, 2], 17);
+        ipv4_header.set_total_length(28);
+        let filter = Filter::parse("not tcp port 53 and udp").unwrap();
+        assert!(filter.matches(&ipv4_header, None));
+        assert!(!Filter::parse("tcp[0] >= 0")
+            .unwrap()
+            .matches(&ipv4_header, None));
+        assert!(Filter::parse("not tcp[0] >= 0")
+            .unwrap()
+            .matches(&ipv4_header, None));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            Filter::parse("host 10.0.0.256"),
+            Err(FilterParseError::InvalidAddress {
+                position: 5,
+                found: "10.0.0.256".into()
+            })
+        );
+        assert_eq!(
+            Filter::parse("tcp and"),
+            Err(FilterParseError::UnexpectedEnd)
+        );
+        assert_eq!(
+            Filter::parse("tcp[13:3] = 0"),
+            Err(FilterParseError::InvalidLoadSize {
+                position: 7,
+                size: 3
+            })
+        );
+        assert_eq!(
+            Filter::parse("port 80 )"),
+            Err(FilterParseError::UnexpectedToken {
+                position: 8,
+                found: ")".into()
+            })
+        );
+        assert_eq!(
+            Filter::parse("port $"),
+            Err(FilterParseError::UnexpectedCharacter {
+                position: 5,
+                found: '$'
+            })
+        );
+        assert_eq!(
+            Filter::parse("port 70000"),
+            Err(FilterParseError::InvalidNumber {
+                position: 5,
+                found: "70000".into()
+            })
+        );
+        assert_eq!(
+            Filter::parse("ip proto 300"),
+            Err(FilterParseError::InvalidNumber {
+                position: 9,
+                found: "300".into()
+            })
+        );
+        assert!(Filter::parse("port 65535 and ip proto 255").is_ok());
+    }
+}
@@ -0,0 +1,754 @@
+use crate::capture_filter::{
+    prefix_mask, AddressQualifier, Arithmetic, ArithmeticOperator, Expression, Filter, LoadBase,
+    Primitive, RelationalOperator,
+};
+use crate::transmission_control_protocol::TCP_PROTOCOL_NUMBER;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/*
+ * classic BPF の命令コード。値は Linux の`linux/filter.h`と同じ。
+ */
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_ST: u16 = 0x02;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+
+const BPF_W: u16 = 0x00;
+const BPF_H: u16 = 0x08;
+const BPF_B: u16 = 0x10;
+
+const BPF_IMM: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_IND: u16 = 0x40;
+const BPF_MEM: u16 = 0x60;
+const BPF_LEN: u16 = 0x80;
+const BPF_MSH: u16 = 0xa0;
+
+const BPF_ADD: u16 = 0x00;
+const BPF_SUB: u16 = 0x10;
+const BPF_MUL: u16 = 0x20;
+const BPF_DIV: u16 = 0x30;
+const BPF_OR: u16 = 0x40;
+const BPF_AND: u16 = 0x50;
+
+const BPF_JA: u16 = 0x00;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JGT: u16 = 0x20;
+const BPF_JGE: u16 = 0x30;
+const BPF_JSET: u16 = 0x40;
+
+const BPF_K: u16 = 0x00;
+const BPF_X: u16 = 0x08;
+
+/*
+ * scratch memory M[] の数。
+ */
+const BPF_MEMWORDS: usize = 16;
+
+/*
+ * 受理した時に返す snap length. tcpdump と同じ値。
+ */
+const ACCEPT_SNAPLEN: u32 = 262144;
+
+/*
+ * Linux の`struct sock_filter`と同じ並び。
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct BpfInstruction {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+impl BpfInstruction {
+    pub fn new(code: u16, jt: u8, jf: u8, k: u32) -> Self {
+        Self { code, jt, jf, k }
+    }
+
+    pub fn get_code(&self) -> u16 {
+        self.code
+    }
+
+    pub fn get_jt(&self) -> u8 {
+        self.jt
+    }
+
+    pub fn get_jf(&self) -> u8 {
+        self.jf
+    }
+
+    pub fn get_k(&self) -> u32 {
+        self.k
+    }
+}
+
+/*
+ * datagram の先頭（IPv4 ヘッダー）を offset 0 とする BPF プログラム。
+ * Ethernet ヘッダーなどが付く socket には使えないので、`SOCK_RAW`や TUN (IFF_NO_PI) 向け。
+ */
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BpfProgram {
+    instructions: Vec<BpfInstruction>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BpfCompileError {
+    /*
+     * 条件ジャンプの飛び先が 255 命令より遠い。
+     */
+    JumpTooFar { from: usize, to: usize },
+
+    /*
+     * 算術式のネストが深すぎて scratch memory が足りない。
+     */
+    TooManyScratchRegisters,
+}
+
+impl fmt::Display for BpfCompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BpfCompileError::JumpTooFar { from, to } => {
+                write!(f, "Jump from {} to {} is too far.", from, to)
+            }
+            BpfCompileError::TooManyScratchRegisters => {
+                write!(
+                    f,
+                    "Expression needs more than {} scratch registers.",
+                    BPF_MEMWORDS
+                )
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BpfCompileError {}
+
+impl Filter {
+    pub fn compile_bpf(&self) -> Result<BpfProgram, BpfCompileError> {
+        let mut compiler = Compiler::default();
+        let accept = compiler.new_label();
+        let reject = compiler.new_label();
+
+        compiler.expression(self.get_expression(), accept, reject)?;
+        compiler.place(accept);
+        compiler.emit(BPF_RET | BPF_K, ACCEPT_SNAPLEN);
+        compiler.place(reject);
+        compiler.emit(BPF_RET | BPF_K, 0);
+
+        compiler.resolve()
+    }
+}
+
+impl BpfProgram {
+    pub fn get_instructions(&self) -> &[BpfInstruction] {
+        &self.instructions
+    }
+
+    /*
+     * `struct sock_filter`の配列としてエンコードする。SO_ATTACH_FILTER 用なのでネイティブエンディアン。
+     */
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(self.instructions.len() * 8);
+        for instruction in &self.instructions {
+            buffer.extend_from_slice(&instruction.code.to_ne_bytes());
+            buffer.push(instruction.jt);
+            buffer.push(instruction.jf);
+            buffer.extend_from_slice(&instruction.k.to_ne_bytes());
+        }
+        buffer
+    }
+
+    /*
+     * カーネルと同じ意味で実行して、受理する長さを返す（0 なら破棄）。
+     * 範囲外の読み込みや 0 除算、不正な命令は 0 を返す。
+     */
+    pub fn run(&self, packet: &[u8]) -> u32 {
+        let load = |offset: u32, size: usize| -> Option<u32> {
+            let start = offset as usize;
+            let bytes = packet.get(start..start.checked_add(size)?)?;
+            Some(bytes.iter().fold(0u32, |acc, &b| (acc << 8) | u32::from(b)))
+        };
+
+        let mut a: u32 = 0;
+        let mut x: u32 = 0;
+        let mut memory = [0u32; BPF_MEMWORDS];
+        let mut pc = 0;
+
+        while let Some(instruction) = self.instructions.get(pc) {
+            let BpfInstruction { code, jt, jf, k } = *instruction;
+            pc += 1;
+
+            match code & 0x07 {
+                BPF_LD | BPF_LDX => {
+                    let size = match code & 0x18 {
+                        BPF_W => 4,
+                        BPF_H => 2,
+                        BPF_B => 1,
+                        _ => return 0,
+                    };
+                    let value = match code & 0xe0 {
+                        BPF_IMM => Some(k),
+                        BPF_ABS => load(k, size),
+                        BPF_IND => load(x.wrapping_add(k), size),
+                        BPF_MEM => memory.get(k as usize).copied(),
+                        BPF_LEN => Some(packet.len() as u32),
+                        BPF_MSH if code & 0x07 == BPF_LDX => load(k, 1).map(|b| (b & 0x0f) * 4),
+                        _ => None,
+                    };
+                    let Some(value) = value else {
+                        return 0;
+                    };
+                    if code & 0x07 == BPF_LD {
+                        a = value;
+                    } else {
+                        x = value;
+                    }
+                }
+                BPF_ST => match memory.get_mut(k as usize) {
+                    Some(slot) => *slot = a,
+                    None => return 0,
+                },
+                BPF_ALU => {
+                    let operand = if code & BPF_X != 0 { x } else { k };
+                    a = match code & 0xf0 {
+                        BPF_ADD => a.wrapping_add(operand),
+                        BPF_SUB => a.wrapping_sub(operand),
+                        BPF_MUL => a.wrapping_mul(operand),
+                        BPF_DIV => match a.checked_div(operand) {
+                            Some(value) => value,
+                            None => return 0,
+                        },
+                        BPF_OR => a | operand,
+                        BPF_AND => a & operand,
+                        _ => return 0,
+                    };
+                }
+                BPF_JMP => {
+                    let operand = if code & BPF_X != 0 { x } else { k };
+                    let taken = match code & 0xf0 {
+                        BPF_JA => {
+                            pc += k as usize;
+                            continue;
+                        }
+                        BPF_JEQ => a == operand,
+                        BPF_JGT => a > operand,
+                        BPF_JGE => a >= operand,
+                        BPF_JSET => a & operand != 0,
+                        _ => return 0,
+                    };
+                    pc += usize::from(if taken { jt } else { jf });
+                }
+                BPF_RET => return if code & 0x18 == 0x10 { a } else { k },
+                _ => return 0,
+            }
+        }
+
+        0
+    }
+}
+
+/*
+ * `tcpdump -d`と同じ形式で出力する。
+ */
+impl fmt::Display for BpfProgram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (pc, instruction) in self.instructions.iter().enumerate() {
+            let BpfInstruction { code, jt, jf, k } = *instruction;
+            let size = match code & 0x18 {
+                BPF_H => "h",
+                BPF_B => "b",
+                _ => "",
+            };
+            let source = |k: u32| {
+                if code & BPF_X != 0 {
+                    String::from("x")
+                } else {
+                    format!("#0x{:x}", k)
+                }
+            };
+            let (mnemonic, operand): (String, String) = match (code & 0x07, code & 0xe0) {
+                (BPF_LD, BPF_IMM) => ("ld".into(), format!("#0x{:x}", k)),
+                (BPF_LD, BPF_ABS) => (format!("ld{}", size), format!("[{}]", k)),
+                (BPF_LD, BPF_IND) => (format!("ld{}", size), format!("[x + {}]", k)),
+                (BPF_LD, BPF_MEM) => ("ld".into(), format!("M[{}]", k)),
+                (BPF_LD, BPF_LEN) => ("ld".into(), "#pktlen".into()),
+                (BPF_LDX, BPF_IMM) => ("ldx".into(), format!("#0x{:x}", k)),
+                (BPF_LDX, BPF_MEM) => ("ldx".into(), format!("M[{}]", k)),
+                (BPF_LDX, BPF_MSH) => ("ldxb".into(), format!("4*([{}]&0xf)", k)),
+                (BPF_ST, _) => ("st".into(), format!("M[{}]", k)),
+                (BPF_ALU, _) => {
+                    let name = match code & 0xf0 {
+                        BPF_ADD => "add",
+                        BPF_SUB => "sub",
+                        BPF_MUL => "mul",
+                        BPF_DIV => "div",
+                        BPF_OR => "or",
+                        BPF_AND => "and",
+                        _ => "alu?",
+                    };
+                    (name.into(), source(k))
+                }
+                (BPF_JMP, _) if code & 0xf0 == BPF_JA => {
+                    ("ja".into(), format!("{}", pc + 1 + k as usize))
+                }
+                (BPF_JMP, _) => {
+                    let name = match code & 0xf0 {
+                        BPF_JEQ => "jeq",
+                        BPF_JGT => "jgt",
+                        BPF_JGE => "jge",
+                        BPF_JSET => "jset",
+                        _ => "jmp?",
+                    };
+                    (
+                        name.into(),
+                        format!(
+                            "{:<10}jt {}\tjf {}",
+                            source(k),
+                            pc + 1 + usize::from(jt),
+                            pc + 1 + usize::from(jf)
+                        ),
+                    )
+                }
+                (BPF_RET, _) if code & 0x18 == 0x10 => ("ret".into(), "a".into()),
+                (BPF_RET, _) => ("ret".into(), format!("#{}", k)),
+                _ => (format!("0x{:04x}", code), format!("{}", k)),
+            };
+            writeln!(f, "({:03}) {:<8}{}", pc, mnemonic, operand)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct Label(usize);
+
+/*
+ * 飛び先を Label のまま持つ命令列。最後に`resolve`で相対オフセットに直す。
+ */
+enum Item {
+    Instruction(BpfInstruction),
+    Jump {
+        code: u16,
+        k: u32,
+        jt: Label,
+        jf: Label,
+    },
+    Place(Label),
+}
+
+#[derive(Default)]
+struct Compiler {
+    items: Vec<Item>,
+    labels: usize,
+}
+
+impl Compiler {
+    fn new_label(&mut self) -> Label {
+        self.labels += 1;
+        Label(self.labels - 1)
+    }
+
+    fn place(&mut self, label: Label) {
+        self.items.push(Item::Place(label));
+    }
+
+    fn emit(&mut self, code: u16, k: u32) {
+        self.items
+            .push(Item::Instruction(BpfInstruction::new(code, 0, 0, k)));
+    }
+
+    fn jump(&mut self, code: u16, k: u32, jt: Label, jf: Label) {
+        self.items.push(Item::Jump { code, k, jt, jf });
+    }
+
+    fn expression(
+        &mut self,
+        expression: &Expression,
+        accept: Label,
+        reject: Label,
+    ) -> Result<(), BpfCompileError> {
+        match expression {
+            Expression::And(lhs, rhs) => {
+                let next = self.new_label();
+                self.expression(lhs, next, reject)?;
+                self.place(next);
+                self.expression(rhs, accept, reject)
+            }
+            Expression::Or(lhs, rhs) => {
+                let next = self.new_label();
+                self.expression(lhs, accept, next)?;
+                self.place(next);
+                self.expression(rhs, accept, reject)
+            }
+            Expression::Not(inner) => self.expression(inner, reject, accept),
+            Expression::Primitive(primitive) => {
+                self.primitive(primitive, accept, reject);
+                Ok(())
+            }
+            Expression::Relation { lhs, operator, rhs } => {
+                if lhs.loads_tcp() || rhs.loads_tcp() {
+                    let next = self.new_label();
+                    self.tcp_guard(next, reject);
+                    self.place(next);
+                }
+
+                /*
+                 * 右辺が定数なら K と比較、そうでなければ M[0] 経由で X と比較する。
+                 */
+                let (source, k) = match rhs {
+                    Arithmetic::Constant(k) => {
+                        self.arithmetic(lhs, 0)?;
+                        (BPF_K, *k)
+                    }
+                    _ => {
+                        self.arithmetic(rhs, 0)?;
+                        self.emit(BPF_ST, 0);
+                        self.arithmetic(lhs, 1)?;
+                        self.emit(BPF_LDX | BPF_MEM, 0);
+                        (BPF_X, 0)
+                    }
+                };
+
+                let (condition, swapped) = match operator {
+                    RelationalOperator::Equal => (BPF_JEQ, false),
+                    RelationalOperator::NotEqual => (BPF_JEQ, true),
+                    RelationalOperator::Greater => (BPF_JGT, false),
+                    RelationalOperator::GreaterOrEqual => (BPF_JGE, false),
+                    RelationalOperator::Less => (BPF_JGE, true),
+                    RelationalOperator::LessOrEqual => (BPF_JGT, true),
+                };
+                let (jt, jf) = if swapped {
+                    (reject, accept)
+                } else {
+                    (accept, reject)
+                };
+                self.jump(BPF_JMP | condition | source, k, jt, jf);
+                Ok(())
+            }
+        }
+    }
+
+    /*
+     * TCP で、かつ先頭のフラグメントなら`next`へ。
+     */
+    fn tcp_guard(&mut self, next: Label, reject: Label) {
+        let not_fragment = self.new_label();
+        self.emit(BPF_LD | BPF_B | BPF_ABS, 9);
+        self.jump(
+            BPF_JMP | BPF_JEQ | BPF_K,
+            u32::from(TCP_PROTOCOL_NUMBER),
+            not_fragment,
+            reject,
+        );
+        self.place(not_fragment);
+        self.emit(BPF_LD | BPF_H | BPF_ABS, 6);
+        self.jump(BPF_JMP | BPF_JSET | BPF_K, 0x1fff, reject, next);
+    }
+
+    fn primitive(&mut self, primitive: &Primitive, accept: Label, reject: Label) {
+        match *primitive {
+            Primitive::Protocol(protocol) => {
+                self.emit(BPF_LD | BPF_B | BPF_ABS, 9);
+                self.jump(
+                    BPF_JMP | BPF_JEQ | BPF_K,
+                    u32::from(protocol),
+                    accept,
+                    reject,
+                );
+            }
+            Primitive::Host { qualifier, address } => {
+                self.address(qualifier, u32::MAX, address, accept, reject);
+            }
+            Primitive::Net {
+                qualifier,
+                address,
+                prefix_length,
+            } => {
+                self.address(
+                    qualifier,
+                    prefix_mask(prefix_length),
+                    address,
+                    accept,
+                    reject,
+                );
+            }
+            Primitive::Port { qualifier, port } => {
+                let next = self.new_label();
+                self.tcp_guard(next, reject);
+                self.place(next);
+                self.emit(BPF_LDX | BPF_B | BPF_MSH, 0);
+
+                let offsets: &[u32] = match qualifier {
+                    AddressQualifier::Source => &[0],
+                    AddressQualifier::Destination => &[2],
+                    AddressQualifier::Either => &[0, 2],
+                };
+                self.compare_each(
+                    offsets,
+                    BPF_LD | BPF_H | BPF_IND,
+                    None,
+                    u32::from(port),
+                    accept,
+                    reject,
+                );
+            }
+        }
+    }
+
+    fn address(
+        &mut self,
+        qualifier: AddressQualifier,
+        mask: u32,
+        address: [u8; 4],
+        accept: Label,
+        reject: Label,
+    ) {
+        let offsets: &[u32] = match qualifier {
+            AddressQualifier::Source => &[12],
+            AddressQualifier::Destination => &[16],
+            AddressQualifier::Either => &[12, 16],
+        };
+        let mask = if mask == u32::MAX { None } else { Some(mask) };
+        self.compare_each(
+            offsets,
+            BPF_LD | BPF_W | BPF_ABS,
+            mask,
+            u32::from_be_bytes(address) & mask.unwrap_or(u32::MAX),
+            accept,
+            reject,
+        );
+    }
+
+    /*
+     * offsets のどれかから読んだ値が`value`と等しければ accept.
+     */
+    fn compare_each(
+        &mut self,
+        offsets: &[u32],
+        load: u16,
+        mask: Option<u32>,
+        value: u32,
+        accept: Label,
+        reject: Label,
+    ) {
+        for (i, offset) in offsets.iter().enumerate() {
+            let next = if i + 1 == offsets.len() {
+                reject
+            } else {
+                self.new_label()
+            };
+            self.emit(load, *offset);
+            if let Some(mask) = mask {
+                self.emit(BPF_ALU | BPF_AND | BPF_K, mask);
+            }
+            self.jump(BPF_JMP | BPF_JEQ | BPF_K, value, accept, next);
+            if next != reject {
+                self.place(next);
+            }
+        }
+    }
+
+    /*
+     * 結果を A に置く。`depth`以降の scratch memory を使ってよい。
+     */
+    fn arithmetic(&mut self, arithmetic: &Arithmetic, depth: usize) -> Result<(), BpfCompileError> {
+        match arithmetic {
+            Arithmetic::Constant(value) => self.emit(BPF_LD | BPF_IMM, *value),
+            Arithmetic::Length => self.emit(BPF_LD | BPF_W | BPF_LEN, 0),
+            Arithmetic::Load { base, offset, size } => {
+                let size = match size {
+                    1 => BPF_B,
+                    2 => BPF_H,
+                    _ => BPF_W,
+                };
+                match base {
+                    LoadBase::Ip => self.emit(BPF_LD | size | BPF_ABS, *offset),
+                    LoadBase::Tcp => {
+                        self.emit(BPF_LDX | BPF_B | BPF_MSH, 0);
+                        self.emit(BPF_LD | size | BPF_IND, *offset);
+                    }
+                }
+            }
+            Arithmetic::Binary { operator, lhs, rhs } => {
+                let operation = match operator {
+                    ArithmeticOperator::Add => BPF_ADD,
+                    ArithmeticOperator::Subtract => BPF_SUB,
+                    ArithmeticOperator::Multiply => BPF_MUL,
+                    ArithmeticOperator::Divide => BPF_DIV,
+                    ArithmeticOperator::And => BPF_AND,
+                    ArithmeticOperator::Or => BPF_OR,
+                };
+
+                /*
+                 * NOTE: カーネルは`div #0`を拒否するので、0 除算は X 経由にして実行時に破棄させる。
+                 */
+                match **rhs {
+                    Arithmetic::Constant(k) if !(operation == BPF_DIV && k == 0) => {
+                        self.arithmetic(lhs, depth)?;
+                        self.emit(BPF_ALU | operation | BPF_K, k);
+                    }
+                    _ => {
+                        if depth >= BPF_MEMWORDS {
+                            return Err(BpfCompileError::TooManyScratchRegisters);
+                        }
+                        self.arithmetic(rhs, depth)?;
+                        self.emit(BPF_ST, depth as u32);
+                        self.arithmetic(lhs, depth + 1)?;
+                        self.emit(BPF_LDX | BPF_MEM, depth as u32);
+                        self.emit(BPF_ALU | operation | BPF_X, 0);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve(self) -> Result<BpfProgram, BpfCompileError> {
+        let mut positions = alloc::vec![0; self.labels];
+        let mut pc = 0;
+        for item in &self.items {
+            match item {
+                Item::Place(Label(label)) => positions[*label] = pc,
+                _ => pc += 1,
+            }
+        }
+
+        let mut instructions = Vec::with_capacity(pc);
+        for item in self.items {
+            match item {
+                Item::Instruction(instruction) => instructions.push(instruction),
+                Item::Jump { code, k, jt, jf } => {
+                    let from = instructions.len();
+                    let offset = |Label(label): Label| {
+                        let to = positions[label];
+                        u8::try_from(to - from - 1)
+                            .map_err(|_| BpfCompileError::JumpTooFar { from, to })
+                    };
+                    instructions.push(BpfInstruction::new(code, offset(jt)?, offset(jf)?, k));
+                }
+                Item::Place(_) => {}
+            }
+        }
+
+        Ok(BpfProgram { instructions })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internet_protocol::Ipv4Header;
+    use crate::transmission_control_protocol::tcp_packet::TcpPacket;
+    use crate::transmission_control_protocol::{ControlBits, TcpHeader};
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    fn tcp_datagram(syn: bool) -> Vec<u8> {
+        let mut control_bits = ControlBits::default();
+        control_bits.set_syn(syn);
+        control_bits.set_ack(true);
+        let mut tcp_header = TcpHeader::new(5432, 80);
+        tcp_header.set_control_bits(control_bits);
+
+        TcpPacket::new(
+            Ipv4Header::new([10, 1, 2, 3], [192, 168, 0, 1], TCP_PROTOCOL_NUMBER),
+            tcp_header,
+            b"data".to_vec(),
+        )
+        .encode()
+        .unwrap()
+    }
+
+    fn udp_datagram() -> Vec<u8> {
+        let mut ipv4_header = Ipv4Header::new([10, 0, 0, 1], [10, 0, 0, 2], 17);
+        ipv4_header.set_total_length(28);
+        let mut datagram = ipv4_header.encode();
+        datagram.extend_from_slice(&[0; 8]);
+        datagram
+    }
+
+    #[test]
+    fn test_compiled_program_agrees_with_evaluator() {
+        let datagrams = [tcp_datagram(true), tcp_datagram(false), udp_datagram()];
+        let filters = [
+            "tcp",
+            "udp or icmp",
+            "host 10.1.2.3",
+            "dst host 10.1.2.3",
+            "src net 10.0.0.0/8 and not dst net 192.168.0.0/16",
+            "tcp port 80",
+            "src port 80",
+            "tcp dst port 80 and src port 5432",
+            "tcp[tcpflags] & tcp-syn != 0",
+            "not tcp[tcpflags] & tcp-syn != 0",
+            "(tcp[13] & 0x12) == 0x12",
+            "tcp[2:2] = 80",
+            "ip[9] = 6 and len = 44",
+            "len > 40 && len - 4 <= 40",
+            "len < ip[2:2] + 1",
+            "ip[2:2] / (len - len) = 0 or tcp",
+            "tcp or ip[2:2] / 0 = 0",
+            "ip[100:4] = 0",
+            "not ip[100:4] = 0",
+            "ip",
+        ];
+
+        for filter in filters {
+            let parsed = Filter::parse(filter).unwrap();
+            let program = parsed.compile_bpf().unwrap();
+            for datagram in &datagrams {
+                assert_eq!(
+                    program.run(datagram) != 0,
+                    parsed.matches_datagram(datagram),
+                    "{}\n{}",
+                    filter,
+                    program
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_compile_protocol() {
+        let program = Filter::parse("tcp").unwrap().compile_bpf().unwrap();
+        assert_eq!(
+            program.get_instructions(),
+            &[
+                BpfInstruction::new(0x30, 0, 0, 9),
+                BpfInstruction::new(0x15, 0, 1, 6),
+                BpfInstruction::new(0x06, 0, 0, 262144),
+                BpfInstruction::new(0x06, 0, 0, 0),
+            ]
+        );
+        assert_eq!(
+            program.to_string(),
+            "(000) ldb     [9]\n\
+             (001) jeq     #0x6      jt 2\tjf 3\n\
+             (002) ret     #262144\n\
+             (003) ret     #0\n"
+        );
+        assert_eq!(program.encode().len(), 4 * 8);
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let deep = vec!["len"; 20].join(" + ") + " = 0";
+        assert_eq!(
+            Filter::parse(&deep).unwrap().compile_bpf(),
+            Err(BpfCompileError::TooManyScratchRegisters)
+        );
+
+        let long = vec!["host 10.0.0.1"; 100].join(" or ") + " and tcp";
+        assert!(matches!(
+            Filter::parse(&long).unwrap().compile_bpf(),
+            Err(BpfCompileError::JumpTooFar { .. })
+        ));
+    }
+}
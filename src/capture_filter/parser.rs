@@ -0,0 +1,526 @@
+use crate::capture_filter::{
+    AddressQualifier, Arithmetic, ArithmeticOperator, Expression, FilterParseError, LoadBase,
+    Primitive, RelationalOperator,
+};
+use crate::internet_control_message_protocol::ICMP_PROTOCOL_NUMBER;
+use crate::internet_protocol::Ipv4Address;
+use crate::transmission_control_protocol::TCP_PROTOCOL_NUMBER;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+const UDP_PROTOCOL_NUMBER: u8 = 17;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Token {
+    /*
+     * キーワード、数値、アドレスなど。
+     */
+    Word(String),
+    Symbol(&'static str),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Spanned {
+    token: Token,
+    position: usize,
+}
+
+pub(crate) fn parse(input: &str) -> Result<Expression, FilterParseError> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+    };
+    let expression = parser.parse_or()?;
+    match parser.peek() {
+        None => Ok(expression),
+        Some(spanned) => Err(unexpected(spanned)),
+    }
+}
+
+/*
+ * 2 文字の記号を先に試す。
+ */
+const SYMBOLS: [&str; 19] = [
+    "&&", "||", "==", "!=", "<=", ">=", "!", "=", "<", ">", "(", ")", "[", "]", ":", "&", "|", "+",
+    "*",
+];
+
+fn tokenize(input: &str) -> Result<Vec<Spanned>, FilterParseError> {
+    let mut tokens = Vec::new();
+    let mut position = 0;
+
+    while position < input.len() {
+        let rest = &input[position..];
+        let c = rest.chars().next().unwrap();
+
+        if c.is_whitespace() {
+            position += c.len_utf8();
+            continue;
+        }
+
+        /*
+         * NOTE: `tcp-syn`や`10.0.0.0/8`を 1 語として扱うため、語の途中の`-`と`/`は語の一部。
+         *       演算子として使う時は前後に空白を入れる（tcpdump と同じ）。
+         */
+        if c.is_ascii_alphanumeric() || c == '_' {
+            let length = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || "_.-/".contains(c)))
+                .unwrap_or(rest.len());
+            tokens.push(Spanned {
+                token: Token::Word(rest[..length].to_string()),
+                position,
+            });
+            position += length;
+            continue;
+        }
+
+        if c == '-' || c == '/' {
+            tokens.push(Spanned {
+                token: Token::Symbol(if c == '-' { "-" } else { "/" }),
+                position,
+            });
+            position += 1;
+            continue;
+        }
+
+        match SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            Some(symbol) => {
+                tokens.push(Spanned {
+                    token: Token::Symbol(symbol),
+                    position,
+                });
+                position += symbol.len();
+            }
+            None => {
+                return Err(FilterParseError::UnexpectedCharacter { position, found: c });
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn unexpected(spanned: &Spanned) -> FilterParseError {
+    FilterParseError::UnexpectedToken {
+        position: spanned.position,
+        found: match &spanned.token {
+            Token::Word(word) => word.clone(),
+            Token::Symbol(symbol) => symbol.to_string(),
+        },
+    }
+}
+
+struct Parser {
+    tokens: Vec<Spanned>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Spanned> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<Spanned, FilterParseError> {
+        let spanned = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or(FilterParseError::UnexpectedEnd)?;
+        self.position += 1;
+        Ok(spanned)
+    }
+
+    fn peek_word(&self) -> Option<&str> {
+        match self.peek() {
+            Some(Spanned {
+                token: Token::Word(word),
+                ..
+            }) => Some(word),
+            _ => None,
+        }
+    }
+
+    fn peek_symbol(&self) -> Option<&'static str> {
+        match self.peek() {
+            Some(Spanned {
+                token: Token::Symbol(symbol),
+                ..
+            }) => Some(symbol),
+            _ => None,
+        }
+    }
+
+    /*
+     * 次が`words`か`symbols`のどれかなら読み進めて true.
+     */
+    fn accept(&mut self, words: &[&str], symbols: &[&str]) -> bool {
+        let matched = match self.peek() {
+            Some(Spanned {
+                token: Token::Word(word),
+                ..
+            }) => words.contains(&word.as_str()),
+            Some(Spanned {
+                token: Token::Symbol(symbol),
+                ..
+            }) => symbols.contains(symbol),
+            None => false,
+        };
+        if matched {
+            self.position += 1;
+        }
+        matched
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), FilterParseError> {
+        let spanned = self.next()?;
+        if matches!(spanned.token, Token::Symbol(found) if found == symbol) {
+            Ok(())
+        } else {
+            Err(unexpected(&spanned))
+        }
+    }
+
+    fn expect_word(&mut self, word: &str) -> Result<(), FilterParseError> {
+        let spanned = self.next()?;
+        if spanned.token == Token::Word(word.to_string()) {
+            Ok(())
+        } else {
+            Err(unexpected(&spanned))
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expression, FilterParseError> {
+        let mut expression = self.parse_and()?;
+        while self.accept(&["or"], &["||"]) {
+            let rhs = self.parse_and()?;
+            expression = Expression::Or(Box::new(expression), Box::new(rhs));
+        }
+        Ok(expression)
+    }
+
+    fn parse_and(&mut self) -> Result<Expression, FilterParseError> {
+        let mut expression = self.parse_not()?;
+        while self.accept(&["and"], &["&&"]) {
+            let rhs = self.parse_not()?;
+            expression = Expression::And(Box::new(expression), Box::new(rhs));
+        }
+        Ok(expression)
+    }
+
+    fn parse_not(&mut self) -> Result<Expression, FilterParseError> {
+        if self.accept(&["not"], &["!"]) {
+            let inner = self.parse_not()?;
+            return Ok(Expression::Not(Box::new(inner)));
+        }
+        self.parse_primary()
+    }
+
+    /*
+     * `(`で始まる場合、`(tcp[13] & 2) != 0`のような算術式か、`(tcp or udp)`のような
+     * 論理式のどちらか分からないので、先に比較式として読んでみて、だめなら戻る。
+     */
+    fn parse_primary(&mut self) -> Result<Expression, FilterParseError> {
+        if self.peek_symbol() == Some("(") {
+            let saved = self.position;
+            if let Ok(relation) = self.parse_relation() {
+                return Ok(relation);
+            }
+            self.position = saved + 1;
+            let expression = self.parse_or()?;
+            self.expect_symbol(")")?;
+            return Ok(expression);
+        }
+
+        if self.starts_arithmetic() {
+            return self.parse_relation();
+        }
+
+        self.parse_primitive()
+    }
+
+    fn starts_arithmetic(&self) -> bool {
+        match self.peek_word() {
+            Some("len") => true,
+            Some("ip" | "tcp") => matches!(
+                self.tokens.get(self.position + 1),
+                Some(Spanned {
+                    token: Token::Symbol("["),
+                    ..
+                })
+            ),
+            Some(word) => {
+                word.starts_with(|c: char| c.is_ascii_digit()) || named_constant(word).is_some()
+            }
+            None => false,
+        }
+    }
+
+    fn parse_relation(&mut self) -> Result<Expression, FilterParseError> {
+        let lhs = self.parse_arithmetic()?;
+        let spanned = self.next()?;
+        let operator = match spanned.token {
+            Token::Symbol("=") | Token::Symbol("==") => RelationalOperator::Equal,
+            Token::Symbol("!=") => RelationalOperator::NotEqual,
+            Token::Symbol("<") => RelationalOperator::Less,
+            Token::Symbol("<=") => RelationalOperator::LessOrEqual,
+            Token::Symbol(">") => RelationalOperator::Greater,
+            Token::Symbol(">=") => RelationalOperator::GreaterOrEqual,
+            _ => return Err(unexpected(&spanned)),
+        };
+        let rhs = self.parse_arithmetic()?;
+        Ok(Expression::Relation { lhs, operator, rhs })
+    }
+
+    fn parse_primitive(&mut self) -> Result<Expression, FilterParseError> {
+        let spanned = self.next()?;
+        let Token::Word(word) = &spanned.token else {
+            return Err(unexpected(&spanned));
+        };
+
+        match word.as_str() {
+            "ip" => {
+                if self.accept(&["proto"], &[]) {
+                    let protocol = self.parse_number()?;
+                    Ok(Expression::Primitive(Primitive::Protocol(protocol)))
+                } else {
+                    /*
+                     * このスタックは IPv4 しか扱わないので、常に真。
+                     */
+                    Ok(Expression::Relation {
+                        lhs: Arithmetic::Constant(0),
+                        operator: RelationalOperator::Equal,
+                        rhs: Arithmetic::Constant(0),
+                    })
+                }
+            }
+            "tcp" => {
+                let tcp = Expression::Primitive(Primitive::Protocol(TCP_PROTOCOL_NUMBER));
+                if matches!(self.peek_word(), Some("src" | "dst" | "port")) {
+                    let port = self.parse_qualified()?;
+                    Ok(Expression::And(Box::new(tcp), Box::new(port)))
+                } else {
+                    Ok(tcp)
+                }
+            }
+            "udp" => Ok(Expression::Primitive(Primitive::Protocol(
+                UDP_PROTOCOL_NUMBER,
+            ))),
+            "icmp" => Ok(Expression::Primitive(Primitive::Protocol(
+                ICMP_PROTOCOL_NUMBER,
+            ))),
+            "src" | "dst" | "host" | "net" | "port" => {
+                self.position -= 1;
+                self.parse_qualified()
+            }
+            _ => Err(unexpected(&spanned)),
+        }
+    }
+
+    /*
+     * ["src" | "dst"] ("host" | "net" | "port") VALUE
+     */
+    fn parse_qualified(&mut self) -> Result<Expression, FilterParseError> {
+        let qualifier = if self.accept(&["src"], &[]) {
+            AddressQualifier::Source
+        } else if self.accept(&["dst"], &[]) {
+            AddressQualifier::Destination
+        } else {
+            AddressQualifier::Either
+        };
+
+        let primitive = if self.accept(&["port"], &[]) {
+            let port = self.parse_number()?;
+            Primitive::Port { qualifier, port }
+        } else if self.accept(&["net"], &[]) {
+            let (address, prefix_length) = self.parse_network()?;
+            Primitive::Net {
+                qualifier,
+                address,
+                prefix_length,
+            }
+        } else {
+            /*
+             * `src 10.0.0.1`のように host を省略してもよい。
+             */
+            if qualifier == AddressQualifier::Either {
+                self.expect_word("host")?;
+            } else {
+                self.accept(&["host"], &[]);
+            }
+            let (address, _) = self.parse_address(false)?;
+            Primitive::Host { qualifier, address }
+        };
+
+        Ok(Expression::Primitive(primitive))
+    }
+
+    fn parse_network(&mut self) -> Result<(Ipv4Address, u8), FilterParseError> {
+        let (address, prefix_length) = self.parse_address(true)?;
+        Ok((address, prefix_length.unwrap_or(32)))
+    }
+
+    fn parse_address(
+        &mut self,
+        allow_prefix: bool,
+    ) -> Result<(Ipv4Address, Option<u8>), FilterParseError> {
+        let spanned = self.next()?;
+        let Token::Word(word) = &spanned.token else {
+            return Err(unexpected(&spanned));
+        };
+        let invalid = || FilterParseError::InvalidAddress {
+            position: spanned.position,
+            found: word.clone(),
+        };
+
+        let (address_part, prefix_part) = match word.split_once('/') {
+            Some((address, prefix)) if allow_prefix => (address, Some(prefix)),
+            Some(_) => return Err(invalid()),
+            None => (word.as_str(), None),
+        };
+
+        let octets: Vec<&str> = address_part.split('.').collect();
+        if octets.len() != 4 {
+            return Err(invalid());
+        }
+        let mut address = [0u8; 4];
+        for (i, octet) in octets.iter().enumerate() {
+            address[i] = octet.parse().map_err(|_| invalid())?;
+        }
+
+        let prefix_length = match prefix_part {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(length) if length <= 32 => Some(length),
+                _ => return Err(invalid()),
+            },
+            None => None,
+        };
+
+        Ok((address, prefix_length))
+    }
+
+    fn parse_arithmetic(&mut self) -> Result<Arithmetic, FilterParseError> {
+        self.parse_binary(0)
+    }
+
+    /*
+     * 優先順位の低い順に、`|`, `&`, `+ -`, `* /`.
+     */
+    fn parse_binary(&mut self, level: usize) -> Result<Arithmetic, FilterParseError> {
+        const LEVELS: [&[(&str, ArithmeticOperator)]; 4] = [
+            &[("|", ArithmeticOperator::Or)],
+            &[("&", ArithmeticOperator::And)],
+            &[
+                ("+", ArithmeticOperator::Add),
+                ("-", ArithmeticOperator::Subtract),
+            ],
+            &[
+                ("*", ArithmeticOperator::Multiply),
+                ("/", ArithmeticOperator::Divide),
+            ],
+        ];
+
+        if level == LEVELS.len() {
+            return self.parse_arithmetic_atom();
+        }
+
+        let mut lhs = self.parse_binary(level + 1)?;
+        while let Some(symbol) = self.peek_symbol() {
+            let Some((_, operator)) = LEVELS[level].iter().find(|(s, _)| *s == symbol) else {
+                break;
+            };
+            self.position += 1;
+            let rhs = self.parse_binary(level + 1)?;
+            lhs = Arithmetic::Binary {
+                operator: *operator,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn parse_arithmetic_atom(&mut self) -> Result<Arithmetic, FilterParseError> {
+        if self.accept(&[], &["("]) {
+            let arithmetic = self.parse_arithmetic()?;
+            self.expect_symbol(")")?;
+            return Ok(arithmetic);
+        }
+
+        let spanned = self.next()?;
+        let Token::Word(word) = &spanned.token else {
+            return Err(unexpected(&spanned));
+        };
+
+        match word.as_str() {
+            "len" => Ok(Arithmetic::Length),
+            "ip" | "tcp" if self.peek_symbol() == Some("[") => {
+                let base = if word == "ip" {
+                    LoadBase::Ip
+                } else {
+                    LoadBase::Tcp
+                };
+                self.expect_symbol("[")?;
+                let offset = self.parse_number()?;
+                let size = if self.accept(&[], &[":"]) {
+                    let position = self.peek().map(|s| s.position).unwrap_or_default();
+                    let size = self.parse_number()?;
+                    if ![1, 2, 4].contains(&size) {
+                        return Err(FilterParseError::InvalidLoadSize { position, size });
+                    }
+                    size as u8
+                } else {
+                    1
+                };
+                self.expect_symbol("]")?;
+                Ok(Arithmetic::Load { base, offset, size })
+            }
+            _ => {
+                self.position -= 1;
+                self.parse_number().map(Arithmetic::Constant)
+            }
+        }
+    }
+
+    /*
+     * 10 進数、0x で始まる 16 進数、名前付き定数。`T`に収まらない値は切り詰めずにエラーにする。
+     */
+    fn parse_number<T: TryFrom<u32>>(&mut self) -> Result<T, FilterParseError> {
+        let spanned = self.next()?;
+        let Token::Word(word) = &spanned.token else {
+            return Err(unexpected(&spanned));
+        };
+
+        let parsed = match named_constant(word) {
+            Some(value) => Ok(value),
+            None => match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => word.parse(),
+            },
+        };
+        parsed
+            .ok()
+            .and_then(|value| T::try_from(value).ok())
+            .ok_or_else(|| FilterParseError::InvalidNumber {
+                position: spanned.position,
+                found: word.clone(),
+            })
+    }
+}
+
+fn named_constant(word: &str) -> Option<u32> {
+    let value = match word {
+        "tcpflags" => 13,
+        "tcp-fin" => 0x01,
+        "tcp-syn" => 0x02,
+        "tcp-rst" => 0x04,
+        "tcp-push" => 0x08,
+        "tcp-ack" => 0x10,
+        "tcp-urg" => 0x20,
+        "tcp-ece" => 0x40,
+        "tcp-cwr" => 0x80,
+        _ => return None,
+    };
+    Some(value)
+}
@@ -0,0 +1,50 @@
+use byteorder::{BigEndian, ByteOrder};
+
+/*
+ * RFC1071 のインターネットチェックサム。IP ヘッダーと TCP で共通。
+ *
+ * See: https://www.rfc-editor.org/rfc/rfc1071
+ */
+pub fn calculate_internet_checksum(bytes: &[u8]) -> u16 {
+    !sum_words(bytes, 0)
+}
+
+/*
+ * 複数のバイト列を連結したものとしてチェックサムを計算する。
+ *
+ * NOTE: TCP の擬似ヘッダー + セグメントのように、一度バッファにコピーせずに計算したい時用。
+ *       各バイト列は（最後のものを除いて）偶数長であること。
+ */
+pub fn calculate_internet_checksum_of(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u16;
+    for part in parts {
+        sum = sum_words(part, sum);
+    }
+    !sum
+}
+
+fn sum_words(bytes: &[u8], initial: u16) -> u16 {
+    /*
+     * 16bits word に変換して全て足し算する。
+     * オーバーフローを考慮して、32bits で計算結果を保持する。
+     *
+     * もし長さが奇数（＝8bitだけ余ってる）の場合は、最後に 0u8 があるものとして扱う。
+     */
+    let mut sum = u32::from(initial);
+    let mut chunks = bytes.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum = sum.wrapping_add(u32::from(BigEndian::read_u16(chunk)));
+    }
+    if let [last] = chunks.remainder() {
+        sum = sum.wrapping_add(u32::from(*last) << 8);
+    }
+
+    /*
+     * オーバーフローした分を end-around carry して加え戻す
+     */
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    sum as u16
+}
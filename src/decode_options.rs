@@ -0,0 +1,71 @@
+use crate::internet_protocol::Ipv4HeaderDecodeError;
+use crate::transmission_control_protocol::TcpHeaderDecodeError;
+use core::fmt;
+
+/*
+ * 受信したパケットをどこまで厳密に検証するか。
+ *
+ * - strict: チェックサム不一致などは全てエラー。デフォルト。
+ * - lenient: キャプチャ解析用。致命的でない問題は警告として返し、デコードは続ける。
+ * - checksum_offloaded: NIC がチェックサムを検証済みの場合。チェックサムは見ない。
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DecodeOptions {
+    verify_checksums: bool,
+    lenient: bool,
+}
+
+impl DecodeOptions {
+    pub fn strict() -> Self {
+        Self {
+            verify_checksums: true,
+            lenient: false,
+        }
+    }
+
+    pub fn lenient() -> Self {
+        Self {
+            verify_checksums: true,
+            lenient: true,
+        }
+    }
+
+    pub fn checksum_offloaded() -> Self {
+        Self {
+            verify_checksums: false,
+            lenient: false,
+        }
+    }
+
+    pub fn verifies_checksums(&self) -> bool {
+        self.verify_checksums
+    }
+
+    pub fn is_lenient(&self) -> bool {
+        self.lenient
+    }
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        Self::strict()
+    }
+}
+
+/*
+ * lenient モードで許容された問題。
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DecodeWarning {
+    Ipv4(Ipv4HeaderDecodeError),
+    Tcp(TcpHeaderDecodeError),
+}
+
+impl fmt::Display for DecodeWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeWarning::Ipv4(error) => write!(f, "IPv4: {}", error),
+            DecodeWarning::Tcp(error) => write!(f, "TCP: {}", error),
+        }
+    }
+}
@@ -0,0 +1,617 @@
+use crate::decode_options::{DecodeOptions, DecodeWarning};
+use crate::internet_protocol::{Ipv4Address, Ipv4Header, DONT_FRAGMENT_FLAG, MORE_FRAGMENTS_FLAG};
+use crate::transmission_control_protocol::tcp_option::TcpOption;
+use crate::transmission_control_protocol::tcp_packet::TcpPacket;
+use crate::transmission_control_protocol::{ControlBits, TcpHeader, TCP_PROTOCOL_NUMBER};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+/*
+ * パケットを tcpdump 風に表示する。
+ *
+ * - `format_summary`: 1 行。`10.0.0.1.5432 > 10.0.0.2.3306: Flags [S.], seq 1, ack 2, ...`
+ * - `format_verbose`: ヘッダーのフィールドごとのツリー表示と hex dump.
+ *
+ * NOTE: 解析用なので、チェックサムが合わないパケットも lenient で decode して表示する。
+ */
+pub fn format_summary(datagram: &[u8]) -> String {
+    let mut output = String::new();
+    let _ = write_summary(&mut output, datagram);
+    output
+}
+
+pub fn format_verbose(datagram: &[u8]) -> String {
+    let mut output = String::new();
+    let _ = write_verbose(&mut output, datagram);
+    output
+}
+
+pub fn format_hex_dump(bytes: &[u8]) -> String {
+    let mut output = String::new();
+    let _ = write_hex_dump(&mut output, bytes);
+    output
+}
+
+fn write_summary<W: Write>(f: &mut W, datagram: &[u8]) -> fmt::Result {
+    let (header, warnings) =
+        match Ipv4Header::decode_with_options(datagram, &DecodeOptions::lenient()) {
+            Ok(decoded) => decoded,
+            Err(error) => return write!(f, "IP [invalid: {}] length {}", error, datagram.len()),
+        };
+
+    if header.get_protocol() == TCP_PROTOCOL_NUMBER && !header.is_fragment() {
+        match TcpPacket::decode_with_options(datagram, &DecodeOptions::lenient()) {
+            Ok((packet, warnings)) => {
+                write_tcp_summary(f, &packet)?;
+                return write_warnings(f, &warnings);
+            }
+            Err(error) => {
+                write_addresses(f, &header, None)?;
+                return write!(f, "tcp [invalid: {}]", error);
+            }
+        }
+    }
+
+    write_addresses(f, &header, None)?;
+    write!(
+        f,
+        "ip-proto-{} {}",
+        header.get_protocol(),
+        usize::from(header.get_total_length()).saturating_sub(header.get_header_length())
+    )?;
+    if header.is_fragment() {
+        write!(
+            f,
+            " (frag {}:{}@{}{})",
+            header.get_identification(),
+            usize::from(header.get_total_length()).saturating_sub(header.get_header_length()),
+            usize::from(header.get_fragment_offset()) * 8,
+            if header.get_flags() & MORE_FRAGMENTS_FLAG != 0 {
+                "+"
+            } else {
+                ""
+            }
+        )?;
+    }
+    write_warnings(f, &warnings)
+}
+
+fn write_tcp_summary<W: Write>(f: &mut W, packet: &TcpPacket) -> fmt::Result {
+    let tcp_header = packet.get_tcp_header();
+    let control_bits = tcp_header.get_control_bits();
+    let length = packet.get_payload().len() as u32;
+
+    write_addresses(
+        f,
+        packet.get_ipv4_header(),
+        Some((
+            tcp_header.get_source_port(),
+            tcp_header.get_destination_port(),
+        )),
+    )?;
+    write!(f, "Flags [{}]", FlagsSummary(control_bits))?;
+
+    /*
+     * tcpdump と同じく、データや SYN/FIN/RST がある時だけ seq を出す。
+     */
+    let sequence_number = tcp_header.get_sequence_number();
+    if length > 0 {
+        write!(
+            f,
+            ", seq {}:{}",
+            sequence_number,
+            sequence_number.wrapping_add(length)
+        )?;
+    } else if control_bits.get_syn() || control_bits.get_fin() || control_bits.get_rst() {
+        write!(f, ", seq {}", sequence_number)?;
+    }
+    if control_bits.get_ack() {
+        write!(f, ", ack {}", tcp_header.get_acknowledgment_number())?;
+    }
+    write!(f, ", win {}", tcp_header.get_window())?;
+    if control_bits.get_urg() {
+        write!(f, ", urg {}", tcp_header.get_urgent_pointer())?;
+    }
+
+    let options = tcp_header.get_options();
+    if !options.is_empty() {
+        write!(f, ", options [")?;
+        for (i, option) in options.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", option)?;
+        }
+        write!(f, "]")?;
+    }
+
+    write!(f, ", length {}", length)
+}
+
+fn write_addresses<W: Write>(
+    f: &mut W,
+    header: &Ipv4Header,
+    ports: Option<(u16, u16)>,
+) -> fmt::Result {
+    write!(f, "{}", AddressDisplay(header.get_source_address()))?;
+    if let Some((source_port, _)) = ports {
+        write!(f, ".{}", source_port)?;
+    }
+    write!(f, " > {}", AddressDisplay(header.get_destination_address()))?;
+    if let Some((_, destination_port)) = ports {
+        write!(f, ".{}", destination_port)?;
+    }
+    write!(f, ": ")
+}
+
+fn write_warnings<W: Write>(f: &mut W, warnings: &[DecodeWarning]) -> fmt::Result {
+    for warning in warnings {
+        write!(f, " [{}]", warning)?;
+    }
+    Ok(())
+}
+
+fn write_verbose<W: Write>(f: &mut W, datagram: &[u8]) -> fmt::Result {
+    let header = match Ipv4Header::decode_with_options(datagram, &DecodeOptions::lenient()) {
+        Ok((header, _)) => header,
+        Err(error) => {
+            writeln!(f, "Invalid IPv4 datagram: {}", error)?;
+            return write_hex_dump(f, datagram);
+        }
+    };
+    write_ipv4_tree(f, &header)?;
+
+    if header.get_protocol() == TCP_PROTOCOL_NUMBER && !header.is_fragment() {
+        match TcpPacket::decode_with_options(datagram, &DecodeOptions::lenient()) {
+            Ok((packet, _)) => {
+                write_tcp_tree(f, &packet)?;
+                if !packet.get_payload().is_empty() {
+                    writeln!(f, "Payload ({} bytes)", packet.get_payload().len())?;
+                }
+            }
+            Err(error) => writeln!(f, "Invalid TCP segment: {}", error)?,
+        }
+    }
+
+    write_hex_dump(f, datagram)
+}
+
+fn write_ipv4_tree<W: Write>(f: &mut W, header: &Ipv4Header) -> fmt::Result {
+    writeln!(
+        f,
+        "Internet Protocol Version 4, Src: {}, Dst: {}",
+        AddressDisplay(header.get_source_address()),
+        AddressDisplay(header.get_destination_address())
+    )?;
+    writeln!(f, "    Version: {}", header.get_version())?;
+    writeln!(
+        f,
+        "    Header Length: {} bytes ({})",
+        header.get_header_length(),
+        header.get_ihl()
+    )?;
+    writeln!(
+        f,
+        "    Differentiated Services: 0x{:02x} (DSCP: {}, ECN: {})",
+        header.get_dscp_ecn(),
+        header.get_dscp_ecn() >> 2,
+        header.get_dscp_ecn() & 0b11
+    )?;
+    writeln!(f, "    Total Length: {}", header.get_total_length())?;
+    writeln!(
+        f,
+        "    Identification: 0x{:04x} ({})",
+        header.get_identification(),
+        header.get_identification()
+    )?;
+
+    let mut flag_names = Vec::new();
+    if header.get_flags() & DONT_FRAGMENT_FLAG != 0 {
+        flag_names.push("Don't Fragment");
+    }
+    if header.get_flags() & MORE_FRAGMENTS_FLAG != 0 {
+        flag_names.push("More Fragments");
+    }
+    writeln!(
+        f,
+        "    Flags: 0x{:x} ({})",
+        header.get_flags(),
+        if flag_names.is_empty() {
+            String::from("none")
+        } else {
+            flag_names.join(", ")
+        }
+    )?;
+    writeln!(f, "    Fragment Offset: {}", header.get_fragment_offset())?;
+    writeln!(f, "    Time to Live: {}", header.get_ttl())?;
+    writeln!(
+        f,
+        "    Protocol: {} ({})",
+        protocol_name(header.get_protocol()),
+        header.get_protocol()
+    )?;
+    writeln!(
+        f,
+        "    Header Checksum: 0x{:04x} [{}]",
+        header.get_header_checksum(),
+        match header.validate_checksum() {
+            Ok(()) => String::from("correct"),
+            Err(error) => alloc::format!("{}", error),
+        }
+    )?;
+    if !header.get_options().is_empty() {
+        writeln!(f, "    Options: ({} bytes)", header.get_options().len())?;
+    }
+    Ok(())
+}
+
+fn write_tcp_tree<W: Write>(f: &mut W, packet: &TcpPacket) -> fmt::Result {
+    let tcp_header = packet.get_tcp_header();
+    let control_bits = tcp_header.get_control_bits();
+
+    writeln!(
+        f,
+        "Transmission Control Protocol, Src Port: {}, Dst Port: {}, Seq: {}, Ack: {}, Len: {}",
+        tcp_header.get_source_port(),
+        tcp_header.get_destination_port(),
+        tcp_header.get_sequence_number(),
+        tcp_header.get_acknowledgment_number(),
+        packet.get_payload().len()
+    )?;
+    writeln!(f, "    Source Port: {}", tcp_header.get_source_port())?;
+    writeln!(
+        f,
+        "    Destination Port: {}",
+        tcp_header.get_destination_port()
+    )?;
+    writeln!(
+        f,
+        "    Sequence Number: {}",
+        tcp_header.get_sequence_number()
+    )?;
+    writeln!(
+        f,
+        "    Acknowledgment Number: {}",
+        tcp_header.get_acknowledgment_number()
+    )?;
+    writeln!(
+        f,
+        "    Header Length: {} bytes ({})",
+        tcp_header.get_header_length(),
+        tcp_header.get_data_offset()
+    )?;
+    writeln!(
+        f,
+        "    Flags: 0x{:03x} ({})",
+        control_bits.encode(),
+        FlagNames(control_bits)
+    )?;
+    writeln!(f, "    Window: {}", tcp_header.get_window())?;
+
+    let checksum = packet.calculate_checksum();
+    if checksum == tcp_header.get_checksum() {
+        writeln!(f, "    Checksum: 0x{:04x} [correct]", checksum)?;
+    } else {
+        writeln!(
+            f,
+            "    Checksum: 0x{:04x} [incorrect, should be 0x{:04x}]",
+            tcp_header.get_checksum(),
+            checksum
+        )?;
+    }
+    writeln!(f, "    Urgent Pointer: {}", tcp_header.get_urgent_pointer())?;
+
+    let options = tcp_header.get_options();
+    if !options.is_empty() {
+        writeln!(
+            f,
+            "    Options: ({} bytes)",
+            tcp_header.get_header_length() - 20
+        )?;
+        for option in &options {
+            writeln!(f, "        {}", OptionName(option))?;
+        }
+    }
+    Ok(())
+}
+
+/*
+ * `0000  45 00 00 31 00 00 40 00  40 06 26 c1 0a 00 00 01  E..1..@.@.&.....`
+ */
+fn write_hex_dump<W: Write>(f: &mut W, bytes: &[u8]) -> fmt::Result {
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        write!(f, "{:04x}  ", line * 16)?;
+        for i in 0..16 {
+            match chunk.get(i) {
+                Some(byte) => write!(f, "{:02x} ", byte)?,
+                None => write!(f, "   ")?,
+            }
+            if i == 7 {
+                write!(f, " ")?;
+            }
+        }
+        write!(f, " ")?;
+        for &byte in chunk {
+            let c = if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            };
+            write!(f, "{}", c)?;
+        }
+        writeln!(f)?;
+    }
+    Ok(())
+}
+
+fn protocol_name(protocol: u8) -> &'static str {
+    match protocol {
+        1 => "ICMP",
+        TCP_PROTOCOL_NUMBER => "TCP",
+        17 => "UDP",
+        47 => "GRE",
+        _ => "Unknown",
+    }
+}
+
+struct AddressDisplay(Ipv4Address);
+
+impl fmt::Display for AddressDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+/*
+ * tcpdump の表記。ACK は`.`で、フラグがなければ`none`.
+ */
+struct FlagsSummary(ControlBits);
+
+impl fmt::Display for FlagsSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bits = self.0;
+        let flags = [
+            (bits.get_fin(), 'F'),
+            (bits.get_syn(), 'S'),
+            (bits.get_rst(), 'R'),
+            (bits.get_psh(), 'P'),
+            (bits.get_urg(), 'U'),
+            (bits.get_ece(), 'E'),
+            (bits.get_cwr(), 'W'),
+            (bits.get_ack(), '.'),
+        ];
+        if flags.iter().all(|(set, _)| !set) {
+            return write!(f, "none");
+        }
+        for (set, c) in flags {
+            if set {
+                write!(f, "{}", c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct FlagNames(ControlBits);
+
+impl fmt::Display for FlagNames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bits = self.0;
+        let flags = [
+            (bits.get_cwr(), "CWR"),
+            (bits.get_ece(), "ECE"),
+            (bits.get_urg(), "URG"),
+            (bits.get_ack(), "ACK"),
+            (bits.get_psh(), "PSH"),
+            (bits.get_rst(), "RST"),
+            (bits.get_syn(), "SYN"),
+            (bits.get_fin(), "FIN"),
+        ];
+        let names: Vec<&str> = flags
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+/*
+ * verbose 表示用の option 名。
+ */
+struct OptionName<'a>(&'a TcpOption);
+
+impl fmt::Display for OptionName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            TcpOption::EndOfOptionList => write!(f, "End of Option List"),
+            TcpOption::NoOperation => write!(f, "No-Operation"),
+            TcpOption::MaximumSegmentSize(mss) => write!(f, "Maximum Segment Size: {}", mss),
+            TcpOption::WindowScale(shift) => write!(
+                f,
+                "Window Scale: {} (multiply by {})",
+                shift,
+                1u32 << (*shift).min(14)
+            ),
+            TcpOption::SackPermitted => write!(f, "SACK Permitted"),
+            TcpOption::Sack(blocks) => {
+                write!(f, "SACK:")?;
+                for (left, right) in blocks {
+                    write!(f, " {}-{}", left, right)?;
+                }
+                Ok(())
+            }
+            TcpOption::Timestamps { tsval, tsecr } => {
+                write!(f, "Timestamps: TSval {}, TSecr {}", tsval, tsecr)
+            }
+            TcpOption::UserTimeout { minutes, timeout } => write!(
+                f,
+                "User Timeout: {} {}",
+                timeout,
+                if *minutes { "minutes" } else { "seconds" }
+            ),
+            TcpOption::Unknown { kind, data } => {
+                write!(f, "Unknown (kind {}, {} bytes)", kind, data.len())
+            }
+        }
+    }
+}
+
+/*
+ * tcpdump の options [...] の中の表記。
+ */
+impl fmt::Display for TcpOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpOption::EndOfOptionList => write!(f, "eol"),
+            TcpOption::NoOperation => write!(f, "nop"),
+            TcpOption::MaximumSegmentSize(mss) => write!(f, "mss {}", mss),
+            TcpOption::WindowScale(shift) => write!(f, "wscale {}", shift),
+            TcpOption::SackPermitted => write!(f, "sackOK"),
+            TcpOption::Sack(blocks) => {
+                write!(f, "sack {}", blocks.len())?;
+                for (left, right) in blocks {
+                    write!(f, " {{{}:{}}}", left, right)?;
+                }
+                Ok(())
+            }
+            TcpOption::Timestamps { tsval, tsecr } => write!(f, "TS val {} ecr {}", tsval, tsecr),
+            TcpOption::UserTimeout { minutes, timeout } => {
+                write!(f, "uto {}{}", timeout, if *minutes { 'm' } else { 's' })
+            }
+            TcpOption::Unknown { kind, data } => {
+                write!(f, "unknown-{}", kind)?;
+                if !data.is_empty() {
+                    write!(f, " 0x")?;
+                    for byte in data {
+                        write!(f, "{:02x}", byte)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Ipv4Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} > {}: ip-proto-{} ttl {} id {} length {}",
+            AddressDisplay(self.get_source_address()),
+            AddressDisplay(self.get_destination_address()),
+            self.get_protocol(),
+            self.get_ttl(),
+            self.get_identification(),
+            self.get_total_length()
+        )
+    }
+}
+
+impl fmt::Display for TcpHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} > {}: Flags [{}], seq {}, ack {}, win {}",
+            self.get_source_port(),
+            self.get_destination_port(),
+            FlagsSummary(self.get_control_bits()),
+            self.get_sequence_number(),
+            self.get_acknowledgment_number(),
+            self.get_window()
+        )
+    }
+}
+
+impl fmt::Display for TcpPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut output = String::new();
+        write_tcp_summary(&mut output, self)?;
+        f.write_str(&output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_datagram(payload: &[u8]) -> Vec<u8> {
+        let mut control_bits = ControlBits::default();
+        control_bits.set_syn(true);
+        control_bits.set_ack(true);
+
+        let mut tcp_header = TcpHeader::new(5432, 3306);
+        tcp_header.set_sequence_number(1);
+        tcp_header.set_acknowledgment_number(2);
+        tcp_header.set_control_bits(control_bits);
+        tcp_header.set_window(1000);
+        tcp_header
+            .set_options(&[
+                TcpOption::MaximumSegmentSize(1460),
+                TcpOption::SackPermitted,
+                TcpOption::Timestamps {
+                    tsval: 100,
+                    tsecr: 0,
+                },
+                TcpOption::NoOperation,
+                TcpOption::WindowScale(7),
+            ])
+            .unwrap();
+
+        let mut ipv4_header = Ipv4Header::new([10, 0, 0, 1], [10, 0, 0, 2], TCP_PROTOCOL_NUMBER);
+        ipv4_header.set_flags(DONT_FRAGMENT_FLAG);
+        TcpPacket::new(ipv4_header, tcp_header, payload.to_vec())
+            .encode()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_format_summary() {
+        assert_eq!(
+            format_summary(&sample_datagram(b"")),
+            "10.0.0.1.5432 > 10.0.0.2.3306: Flags [S.], seq 1, ack 2, win 1000, \
+             options [mss 1460,sackOK,TS val 100 ecr 0,nop,wscale 7], length 0"
+        );
+        assert!(format_summary(&sample_datagram(b"hello")).contains("seq 1:6, ack 2"));
+    }
+
+    #[test]
+    fn test_format_summary_bad_checksum() {
+        let mut datagram = sample_datagram(b"hello");
+        let last = datagram.len() - 1;
+        datagram[last] ^= 0xFF;
+
+        let summary = format_summary(&datagram);
+        assert!(summary.contains("length 5 [TCP: Checksum mismatch at byte 16: expected 0x"));
+    }
+
+    #[test]
+    fn test_format_verbose() {
+        let verbose = format_verbose(&sample_datagram(b"hi"));
+        assert!(verbose.contains("Internet Protocol Version 4, Src: 10.0.0.1, Dst: 10.0.0.2\n"));
+        assert!(verbose.contains("    Flags: 0x2 (Don't Fragment)\n"));
+        assert!(verbose.contains("    Flags: 0x012 (ACK, SYN)\n"));
+        assert!(verbose.contains("        Maximum Segment Size: 1460\n"));
+        assert!(verbose.contains("    Checksum: 0x"));
+        assert!(verbose.contains("[correct]"));
+        assert!(verbose.contains("Payload (2 bytes)\n"));
+        assert!(verbose.contains("0000  45 00 00 3e"));
+    }
+
+    #[test]
+    fn test_format_hex_dump() {
+        let bytes: Vec<u8> = (0x41..0x41 + 18).collect();
+        assert_eq!(
+            format_hex_dump(&bytes),
+            "0000  41 42 43 44 45 46 47 48  49 4a 4b 4c 4d 4e 4f 50  ABCDEFGHIJKLMNOP\n\
+             0010  51 52                                             QR\n"
+        );
+        assert_eq!(format_hex_dump(&[]), "");
+    }
+}
@@ -0,0 +1,236 @@
+use crate::checksum::calculate_internet_checksum;
+use alloc::vec::Vec;
+use core::fmt;
+
+pub const ICMP_PROTOCOL_NUMBER: u8 = 1;
+
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_DESTINATION_UNREACHABLE: u8 = 3;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+pub const ICMP_TIME_EXCEEDED: u8 = 11;
+
+/*
+ * Destination Unreachable の Code. DF が立っていて分片できなかった (RFC 1191)。
+ */
+pub const ICMP_FRAGMENTATION_NEEDED: u8 = 4;
+
+/*
+ * Type, Code, Checksum と、その後ろの 4bytes (Rest of Header).
+ */
+const ICMP_HEADER_LEN: usize = 8;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum IcmpMessageDecodeError {
+    InputTooShort,
+    ChecksumMismatch { expected: u16, found: u16 },
+}
+
+impl fmt::Display for IcmpMessageDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IcmpMessageDecodeError::InputTooShort => write!(f, "Input too short."),
+            IcmpMessageDecodeError::ChecksumMismatch { expected, found } => write!(
+                f,
+                "Checksum mismatch at byte 2: expected {:#06x}, found {:#06x}.",
+                expected, found
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for IcmpMessageDecodeError {}
+
+/*
+ * ICMP メッセージ（IP datagram の payload 部分）。
+ *
+ * Rest of Header の 4bytes の意味は Type ごとに違う。Echo / Echo Reply では
+ * Identifier と Sequence Number なので、そのアクセサーを用意している。
+ */
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct IcmpMessage {
+    message_type: u8,
+    code: u8,
+    checksum: u16,
+    rest_of_header: [u8; 4],
+    data: Vec<u8>,
+}
+
+impl IcmpMessage {
+    pub fn new(message_type: u8, code: u8, rest_of_header: [u8; 4], data: Vec<u8>) -> Self {
+        let mut message = Self {
+            message_type,
+            code,
+            checksum: 0,
+            rest_of_header,
+            data,
+        };
+        message.set_checksum();
+        message
+    }
+
+    pub fn new_echo_request(identifier: u16, sequence_number: u16, data: Vec<u8>) -> Self {
+        Self::new(
+            ICMP_ECHO_REQUEST,
+            0,
+            echo_rest_of_header(identifier, sequence_number),
+            data,
+        )
+    }
+
+    /*
+     * Echo Request に対する Echo Reply. Identifier, Sequence Number, Data はそのまま返す。
+     */
+    pub fn new_echo_reply(request: &IcmpMessage) -> Self {
+        Self::new(
+            ICMP_ECHO_REPLY,
+            0,
+            request.rest_of_header,
+            request.data.clone(),
+        )
+    }
+
+    pub fn get_type(&self) -> u8 {
+        self.message_type
+    }
+
+    pub fn get_code(&self) -> u8 {
+        self.code
+    }
+
+    pub fn get_checksum(&self) -> u16 {
+        self.checksum
+    }
+
+    pub fn get_rest_of_header(&self) -> [u8; 4] {
+        self.rest_of_header
+    }
+
+    pub fn get_identifier(&self) -> u16 {
+        u16::from_be_bytes([self.rest_of_header[0], self.rest_of_header[1]])
+    }
+
+    pub fn get_sequence_number(&self) -> u16 {
+        u16::from_be_bytes([self.rest_of_header[2], self.rest_of_header[3]])
+    }
+
+    /*
+     * Fragmentation Needed では、Rest of Header の後半 2bytes が Next-Hop MTU (RFC 1191 4)。
+     * 古いルーターは 0 を入れてくる。
+     */
+    pub fn get_next_hop_mtu(&self) -> u16 {
+        u16::from_be_bytes([self.rest_of_header[2], self.rest_of_header[3]])
+    }
+
+    pub fn get_data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn is_echo_request(&self) -> bool {
+        self.message_type == ICMP_ECHO_REQUEST && self.code == 0
+    }
+
+    pub fn is_echo_reply(&self) -> bool {
+        self.message_type == ICMP_ECHO_REPLY && self.code == 0
+    }
+
+    pub fn is_fragmentation_needed(&self) -> bool {
+        self.message_type == ICMP_DESTINATION_UNREACHABLE && self.code == ICMP_FRAGMENTATION_NEEDED
+    }
+
+    fn set_checksum(&mut self) {
+        self.checksum = 0;
+        self.checksum = calculate_internet_checksum(&self.encode());
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(ICMP_HEADER_LEN + self.data.len());
+        buffer.push(self.message_type);
+        buffer.push(self.code);
+        buffer.extend_from_slice(&self.checksum.to_be_bytes());
+        buffer.extend_from_slice(&self.rest_of_header);
+        buffer.extend_from_slice(&self.data);
+        buffer
+    }
+
+    /*
+     * IP datagram の payload 部分から decode する。チェックサムも検証する。
+     */
+    pub fn decode(buffer: &[u8]) -> Result<Self, IcmpMessageDecodeError> {
+        if buffer.len() < ICMP_HEADER_LEN {
+            return Err(IcmpMessageDecodeError::InputTooShort);
+        }
+
+        let message = Self {
+            message_type: buffer[0],
+            code: buffer[1],
+            checksum: u16::from_be_bytes([buffer[2], buffer[3]]),
+            rest_of_header: [buffer[4], buffer[5], buffer[6], buffer[7]],
+            data: buffer[ICMP_HEADER_LEN..].to_vec(),
+        };
+
+        /*
+         * チェックサム込みで計算して 0 になれば正しい。
+         */
+        if calculate_internet_checksum(buffer) != 0 {
+            let mut expected = message.clone();
+            expected.set_checksum();
+            return Err(IcmpMessageDecodeError::ChecksumMismatch {
+                expected: expected.checksum,
+                found: message.checksum,
+            });
+        }
+
+        Ok(message)
+    }
+}
+
+fn echo_rest_of_header(identifier: u16, sequence_number: u16) -> [u8; 4] {
+    let identifier = identifier.to_be_bytes();
+    let sequence_number = sequence_number.to_be_bytes();
+    [
+        identifier[0],
+        identifier[1],
+        sequence_number[0],
+        sequence_number[1],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_echo_round_trip() {
+        let request = IcmpMessage::new_echo_request(0x1234, 7, b"ping!".to_vec());
+        let bytes = request.encode();
+        assert_eq!(bytes.len(), 8 + 5);
+        assert_eq!(calculate_internet_checksum(&bytes), 0);
+
+        let decoded = IcmpMessage::decode(&bytes).unwrap();
+        assert!(decoded.is_echo_request());
+        assert_eq!(decoded.get_identifier(), 0x1234);
+        assert_eq!(decoded.get_sequence_number(), 7);
+        assert_eq!(decoded.get_data(), b"ping!");
+
+        let reply = IcmpMessage::new_echo_reply(&decoded);
+        assert!(reply.is_echo_reply());
+        assert_eq!(reply.get_identifier(), 0x1234);
+        assert_eq!(reply.get_data(), b"ping!");
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(
+            IcmpMessage::decode(&[8, 0, 0]),
+            Err(IcmpMessageDecodeError::InputTooShort)
+        );
+
+        let mut bytes = IcmpMessage::new_echo_request(1, 1, b"abc".to_vec()).encode();
+        bytes[8] ^= 0x01;
+        assert!(matches!(
+            IcmpMessage::decode(&bytes),
+            Err(IcmpMessageDecodeError::ChecksumMismatch { .. })
+        ));
+    }
+}
@@ -1,25 +1,114 @@
-use crate::transmission_control_protocol::TCP_PROTOCOL_NUMBER;
-use byteorder::{BigEndian, ByteOrder};
-use std::error::Error;
-use std::fmt;
-
-#[derive(Debug)]
+use crate::checksum::calculate_internet_checksum;
+use crate::decode_options::{DecodeOptions, DecodeWarning};
+use crate::layer::Layer;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Ipv4HeaderDecodeError {
     InputTooShort,
-    InvalidFieldValue(String),
-    // More...
+
+    /*
+     * Version が 4 ではない。
+     */
+    BadVersion {
+        found: u8,
+    },
+
+    /*
+     * IHL が 5 未満、もしくはバッファ長を超えている。
+     */
+    BadIhl {
+        ihl: u8,
+        buffer_len: usize,
+    },
+
+    UnsupportedProtocol(u8),
+
+    /*
+     * Total Length がヘッダー長より小さい、もしくはバッファ長を超えている。
+     */
+    BadTotalLength {
+        total_length: u16,
+        buffer_len: usize,
+    },
+
+    ChecksumMismatch {
+        expected: u16,
+        found: u16,
+    },
+}
+
+impl Ipv4HeaderDecodeError {
+    /*
+     * 問題のあったフィールド名。
+     */
+    pub fn field(&self) -> Option<&'static str> {
+        match self {
+            Ipv4HeaderDecodeError::InputTooShort => None,
+            Ipv4HeaderDecodeError::BadVersion { .. } => Some("version"),
+            Ipv4HeaderDecodeError::BadIhl { .. } => Some("ihl"),
+            Ipv4HeaderDecodeError::UnsupportedProtocol(_) => Some("protocol"),
+            Ipv4HeaderDecodeError::BadTotalLength { .. } => Some("total_length"),
+            Ipv4HeaderDecodeError::ChecksumMismatch { .. } => Some("header_checksum"),
+        }
+    }
+
+    /*
+     * 問題のあったフィールドの、IP ヘッダー先頭からの byte offset.
+     */
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            Ipv4HeaderDecodeError::InputTooShort => None,
+            Ipv4HeaderDecodeError::BadVersion { .. } => Some(0),
+            Ipv4HeaderDecodeError::BadIhl { .. } => Some(0),
+            Ipv4HeaderDecodeError::UnsupportedProtocol(_) => Some(9),
+            Ipv4HeaderDecodeError::BadTotalLength { .. } => Some(2),
+            Ipv4HeaderDecodeError::ChecksumMismatch { .. } => Some(10),
+        }
+    }
 }
 
 impl fmt::Display for Ipv4HeaderDecodeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Ipv4HeaderDecodeError::InputTooShort => write!(f, "Input too short."),
-            Ipv4HeaderDecodeError::InvalidFieldValue(_todo) => write!(f, "Invalid field value."),
+            Ipv4HeaderDecodeError::BadVersion { found } => write!(
+                f,
+                "Bad version at byte 0: expected {}, found {}.",
+                IPV4_VERSION, found
+            ),
+            Ipv4HeaderDecodeError::BadIhl { ihl, buffer_len } => write!(
+                f,
+                "Bad IHL at byte 0: ihl={} (header length {} bytes, minimum {}) but buffer length is {}.",
+                ihl,
+                usize::from(*ihl) * IPV4_HEADER_UNIT_BYTES,
+                IPV4_HEADER_MIN_LEN,
+                buffer_len
+            ),
+            Ipv4HeaderDecodeError::UnsupportedProtocol(protocol) => {
+                write!(f, "Unsupported protocol at byte 9: {}.", protocol)
+            }
+            Ipv4HeaderDecodeError::BadTotalLength {
+                total_length,
+                buffer_len,
+            } => write!(
+                f,
+                "Bad total length at byte 2: total_length={} but buffer length is {}.",
+                total_length, buffer_len
+            ),
+            Ipv4HeaderDecodeError::ChecksumMismatch { expected, found } => write!(
+                f,
+                "Header checksum mismatch at byte 10: expected {:#06x}, found {:#06x}.",
+                expected, found
+            ),
         }
     }
 }
 
-impl Error for Ipv4HeaderDecodeError {}
+#[cfg(feature = "std")]
+impl std::error::Error for Ipv4HeaderDecodeError {}
 
 /*
  * 注意：IHL (Internet Header Length) 自体は 4bits.
@@ -32,7 +121,13 @@ const IPV4_HEADER_UNIT_BITS: usize = 32;
 const IPV4_HEADER_UNIT_BYTES: usize = IPV4_HEADER_UNIT_BITS / 8;
 pub const IPV4_HEADER_MIN_LEN: usize = IHL_MIN_VALUE * IPV4_HEADER_UNIT_BYTES;
 const IPV4_VERSION: u8 = 4;
-const UDP_PROTOCOL_NUMBER: u8 = 17;
+const DEFAULT_TTL: u8 = 64;
+
+/*
+ * Flags (3bits) の各ビット。最上位ビットは予約されていて 0.
+ */
+pub const DONT_FRAGMENT_FLAG: u8 = 0b010;
+pub const MORE_FRAGMENTS_FLAG: u8 = 0b001;
 
 pub type Ipv4Address = [u8; 4];
 
@@ -78,9 +173,43 @@ pub struct Ipv4Header {
      */
     source_address: Ipv4Address,
     destination_address: Ipv4Address,
+
+    /*
+     * IHL が 5 より大きい時の Options（パディング込み）。
+     */
+    options: Vec<u8>,
 }
 
 impl Ipv4Header {
+    /*
+     * Options なし、TTL 64 のヘッダーを作る。
+     * total_length はヘッダー長だけなので、payload を載せる時は`set_total_length`で更新すること。
+     */
+    pub fn new(
+        source_address: Ipv4Address,
+        destination_address: Ipv4Address,
+        protocol: u8,
+    ) -> Self {
+        let mut header = Self {
+            version: IPV4_VERSION,
+            ihl: IHL_MIN_VALUE as u8,
+            dscp: 0,
+            ecn: 0,
+            total_length: IPV4_HEADER_MIN_LEN as u16,
+            identification: 0,
+            flags: 0,
+            fragment_offset: 0,
+            ttl: DEFAULT_TTL,
+            protocol,
+            header_checksum: 0,
+            source_address,
+            destination_address,
+            options: Vec::new(),
+        };
+        header.set_header_checksum();
+        header
+    }
+
     pub fn set_version(&mut self, version: u8) {
         assert!(
             version <= 0xF,
@@ -123,10 +252,27 @@ impl Ipv4Header {
         self.total_length
     }
 
+    pub fn set_total_length(&mut self, total_length: u16) {
+        self.total_length = total_length;
+        self.set_header_checksum();
+    }
+
+    /*
+     * IHL から求めたヘッダーの byte 長。
+     */
+    pub fn get_header_length(&self) -> usize {
+        usize::from(self.get_ihl()) * IPV4_HEADER_UNIT_BYTES
+    }
+
     pub fn get_identification(&self) -> u16 {
         self.identification
     }
 
+    pub fn set_identification(&mut self, identification: u16) {
+        self.identification = identification;
+        self.set_header_checksum();
+    }
+
     pub fn get_flags(&self) -> u8 {
         self.flags & 0b0000_0111
     }
@@ -175,6 +321,26 @@ impl Ipv4Header {
         self.destination_address
     }
 
+    pub fn get_options(&self) -> &[u8] {
+        &self.options
+    }
+
+    /*
+     * このヘッダーを先頭に持つ datagram から、payload 部分（IHL の後ろから total_length まで）を取り出す。
+     *
+     * NOTE: `validate_total_length` で検証済みの datagram を渡すこと。
+     */
+    pub fn get_payload<'a>(&self, datagram: &'a [u8]) -> &'a [u8] {
+        &datagram[self.get_header_length()..usize::from(self.total_length)]
+    }
+
+    /*
+     * フラグメントの一部（先頭以外、もしくは後続がある）かどうか。
+     */
+    pub fn is_fragment(&self) -> bool {
+        self.get_flags() & MORE_FRAGMENTS_FLAG != 0 || self.get_fragment_offset() != 0
+    }
+
     /*
      * 注意：Header checksum は、他のヘッダーフィールドが変わった時（例：TTL）に、再計算をする必要がある。
      *
@@ -204,41 +370,9 @@ impl Ipv4Header {
         let bytes = ipv4_header.encode();
 
         /*
-         * 2. ヘッダーを 16bits word に変換する。
-         *
-         *    Big Endian が通常は用いられるらしい？
+         * 2. 16bits word ごとの和の 1 の補数を取る。
          */
-        let mut words = Vec::new();
-        for i in (0..bytes.len()).step_by(2) {
-            let word = BigEndian::read_u16(&bytes[i..i + 2]);
-            words.push(word);
-        }
-
-        /*
-         * 3. 全てのワードを足し算する。この時、オーバーフローの可能性があるので注意。
-         *    なお、オーバーフローは無視します。
-         */
-        let mut sum = 0u32;
-        for &word in &words {
-            sum = sum.wrapping_add(u32::from(word));
-        }
-
-        /*
-         * オーバーフローした分を end-around carry して加え戻す
-         */
-        while sum > 0xFFFF {
-            sum = (sum & 0xFFFF) + (sum >> 16);
-        }
-
-        /*
-         * 4. 和の 1 の補数を取ります。
-         *
-         * 注意：オーバーフローを無視するので、まずは u16 に type cast する。
-         * 注意：1の補数は、単にビットを反転させるだけで求められるので、反転演算子`!`を実行している。
-         */
-        let checksum = !(sum as u16);
-
-        checksum
+        calculate_internet_checksum(&bytes)
     }
 
     pub fn encode(&self) -> Vec<u8> {
@@ -265,40 +399,65 @@ impl Ipv4Header {
 
         buffer[16..20].copy_from_slice(&self.destination_address);
 
+        buffer.extend_from_slice(&self.options);
+
         buffer
     }
 
     pub fn decode(buffer: &[u8]) -> Result<Self, Ipv4HeaderDecodeError> {
+        Self::decode_with_options(buffer, &DecodeOptions::default()).map(|(header, _)| header)
+    }
+
+    /*
+     * lenient モードでは、許容した問題を警告として一緒に返す。
+     */
+    pub fn decode_with_options(
+        buffer: &[u8],
+        options: &DecodeOptions,
+    ) -> Result<(Self, Vec<DecodeWarning>), Ipv4HeaderDecodeError> {
         Self::validate_buffer_length(buffer)?;
 
-        let version = buffer[0] & 0b1111_0000;
+        let version = buffer[0] >> 4;
         let ihl = buffer[0] & 0b0000_1111;
-        let dscp = buffer[1] & 0b1111_1100;
+        let dscp = buffer[1] >> 2;
         let ecn = buffer[1] & 0b0000_0011;
 
         let total_length = ((buffer[2] as u16) << 8) | (buffer[3] as u16);
         let identification = ((buffer[4] as u16) << 8) | (buffer[5] as u16);
 
-        let flags = buffer[6] & 0b1110_0000;
+        let flags = buffer[6] >> 5;
         let fragment_offset = (((buffer[6] & 0b0001_1111) as u16) << 8) | buffer[7] as u16;
 
         let ttl = buffer[8];
         let protocol = buffer[9];
 
-        /*
-         *
-         * NOTE: ここで誤りがないかチェックする必要があるとか？？？
-         *
-         */
         let header_checksum = ((buffer[10] as u16) << 8) | buffer[11] as u16;
         let source_address: Ipv4Address = [buffer[12], buffer[13], buffer[14], buffer[15]];
         let destination_address: Ipv4Address = [buffer[16], buffer[17], buffer[18], buffer[19]];
 
         Self::validate_version(version)?;
         Self::validate_header_length(ihl, buffer.len())?;
-        Self::validate_protocol(protocol)?;
 
-        Ok(Self {
+        let header_length = usize::from(ihl) * IPV4_HEADER_UNIT_BYTES;
+        let header_options = buffer[IPV4_HEADER_MIN_LEN..header_length].to_vec();
+
+        /*
+         * NOTE: protocol はここでは検証しない。どの protocol を受け付けるかは
+         *       `ProtocolRegistry` に登録された handler 次第。
+         */
+
+        /*
+         * NOTE: options を含めたヘッダー全体で検証するため、バイト列のまま計算する。
+         */
+        let mut warnings = Vec::new();
+        if options.verifies_checksums() {
+            match Self::validate_raw_checksum(&buffer[..header_length]) {
+                Err(error) if options.is_lenient() => warnings.push(DecodeWarning::Ipv4(error)),
+                result => result?,
+            }
+        }
+
+        let header = Self {
             version,
             ihl,
             dscp,
@@ -312,7 +471,10 @@ impl Ipv4Header {
             header_checksum,
             source_address,
             destination_address,
-        })
+            options: header_options,
+        };
+
+        Ok((header, warnings))
     }
 
     fn validate_buffer_length(buffer: &[u8]) -> Result<(), Ipv4HeaderDecodeError> {
@@ -325,54 +487,198 @@ impl Ipv4Header {
 
     fn validate_version(version: u8) -> Result<(), Ipv4HeaderDecodeError> {
         if version != IPV4_VERSION {
-            Err(Ipv4HeaderDecodeError::InvalidFieldValue(
-                "The `version` field should be 4.".to_string(),
-            ))
+            Err(Ipv4HeaderDecodeError::BadVersion { found: version })
         } else {
             Ok(())
         }
     }
 
     fn validate_header_length(ihl: u8, buffer_length: usize) -> Result<(), Ipv4HeaderDecodeError> {
-        if usize::from(ihl) < IHL_MIN_VALUE {
-            Err(Ipv4HeaderDecodeError::InvalidFieldValue(format!(
-                "The `ihl` field should not be less than {}.",
-                IHL_MIN_VALUE
-            )))
-        } else if usize::from(ihl * 4) >= buffer_length {
-            Err(Ipv4HeaderDecodeError::InvalidFieldValue(format!(
-                "The `ihl` field value is larget than the byte length. ihl={}. byte_length={}",
-                ihl, buffer_length
-            )))
+        let header_length = usize::from(ihl) * IPV4_HEADER_UNIT_BYTES;
+        if usize::from(ihl) < IHL_MIN_VALUE || header_length > buffer_length {
+            Err(Ipv4HeaderDecodeError::BadIhl {
+                ihl,
+                buffer_len: buffer_length,
+            })
         } else {
             Ok(())
         }
     }
 
-    fn validate_protocol(protocol: u8) -> Result<(), Ipv4HeaderDecodeError> {
-        if protocol != TCP_PROTOCOL_NUMBER && protocol != UDP_PROTOCOL_NUMBER {
-            Err(Ipv4HeaderDecodeError::InvalidFieldValue(format!(
-                "Unknown `protocol` field value. protocol={}",
-                protocol
-            )))
+    /*
+     * Total Length がヘッダー長以上で、かつ受信したバイト列に収まっているか。
+     *
+     * NOTE: Ethernet の最小フレーム長を満たすためのパディングが後ろに付くことがあるので、
+     *       バッファの方が長いのは問題ない。
+     */
+    pub fn validate_total_length(&self, buffer_length: usize) -> Result<(), Ipv4HeaderDecodeError> {
+        let total_length = usize::from(self.total_length);
+        if total_length < self.get_header_length() || total_length > buffer_length {
+            Err(Ipv4HeaderDecodeError::BadTotalLength {
+                total_length: self.total_length,
+                buffer_len: buffer_length,
+            })
         } else {
             Ok(())
         }
     }
 
-    /*
-     * NOTE: ついでだったので実装した。また実際にパケット受け取ったりするときに使います。
-     */
-    fn validate_checksum(&self) -> Result<(), Ipv4HeaderDecodeError> {
+    fn validate_raw_checksum(header_bytes: &[u8]) -> Result<(), Ipv4HeaderDecodeError> {
+        /*
+         * 正しいヘッダーであれば、チェックサムを含めた全体の和の 1 の補数は 0 になる。
+         */
+        if calculate_internet_checksum(header_bytes) == 0 {
+            return Ok(());
+        }
+
+        let found = u16::from_be_bytes([header_bytes[10], header_bytes[11]]);
+        let mut zeroed = header_bytes.to_vec();
+        zeroed[10..12].copy_from_slice(&[0, 0]);
+
+        Err(Ipv4HeaderDecodeError::ChecksumMismatch {
+            expected: calculate_internet_checksum(&zeroed),
+            found,
+        })
+    }
+
+    pub fn validate_checksum(&self) -> Result<(), Ipv4HeaderDecodeError> {
         let original_checksum = self.header_checksum;
         let checksum = self.calculate_header_checksum();
 
         if checksum != original_checksum {
-            Err(Ipv4HeaderDecodeError::InvalidFieldValue(format!(
-                "The header checksum doesn't match."
-            )))
+            Err(Ipv4HeaderDecodeError::ChecksumMismatch {
+                expected: checksum,
+                found: original_checksum,
+            })
         } else {
             Ok(())
         }
     }
 }
+
+impl Layer for Ipv4Header {
+    type Error = Ipv4HeaderDecodeError;
+
+    fn decode(buffer: &[u8]) -> Result<Self, Self::Error> {
+        Ipv4Header::decode(buffer)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        Ipv4Header::encode(self)
+    }
+
+    fn header_len(&self) -> usize {
+        self.get_header_length()
+    }
+
+    fn next_protocol(&self) -> Option<u8> {
+        Some(self.get_protocol())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transmission_control_protocol::TCP_PROTOCOL_NUMBER;
+
+    fn sample_header() -> Ipv4Header {
+        let mut header = Ipv4Header {
+            version: 4,
+            ihl: 5,
+            dscp: 0,
+            ecn: 0,
+            total_length: 40,
+            identification: 1234,
+            flags: 0b010,
+            fragment_offset: 0,
+            ttl: 64,
+            protocol: TCP_PROTOCOL_NUMBER,
+            header_checksum: 0,
+            source_address: [10, 0, 0, 1],
+            destination_address: [10, 0, 0, 2],
+            options: vec![],
+        };
+        header.set_header_checksum();
+        header
+    }
+
+    #[test]
+    fn test_decode_encoded_header() {
+        let bytes = sample_header().encode();
+        let header = Ipv4Header::decode(&bytes).unwrap();
+
+        assert_eq!(header.get_version(), 4);
+        assert_eq!(header.get_ihl(), 5);
+        assert_eq!(header.get_flags(), 0b010);
+        assert_eq!(header.get_ttl(), 64);
+        assert_eq!(header.get_source_address(), [10, 0, 0, 1]);
+        assert!(header.validate_checksum().is_ok());
+        assert_eq!(header.encode(), bytes);
+    }
+
+    #[test]
+    fn test_decode_bad_version() {
+        let mut bytes = sample_header().encode();
+        bytes[0] = (6 << 4) | 5;
+
+        let error = Ipv4Header::decode(&bytes).unwrap_err();
+        assert_eq!(error, Ipv4HeaderDecodeError::BadVersion { found: 6 });
+        assert_eq!(error.field(), Some("version"));
+        assert_eq!(error.offset(), Some(0));
+    }
+
+    #[test]
+    fn test_decode_bad_ihl() {
+        let mut bytes = sample_header().encode();
+        bytes[0] = (4 << 4) | 3;
+        assert_eq!(
+            Ipv4Header::decode(&bytes).unwrap_err(),
+            Ipv4HeaderDecodeError::BadIhl {
+                ihl: 3,
+                buffer_len: 20
+            }
+        );
+
+        bytes[0] = (4 << 4) | 6;
+        assert_eq!(
+            Ipv4Header::decode(&bytes).unwrap_err(),
+            Ipv4HeaderDecodeError::BadIhl {
+                ihl: 6,
+                buffer_len: 20
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_any_protocol() {
+        let mut header = sample_header();
+        header.set_protocol(47);
+
+        let header = Ipv4Header::decode(&header.encode()).unwrap();
+        assert_eq!(header.get_protocol(), 47);
+    }
+
+    #[test]
+    fn test_decode_checksum_mismatch() {
+        let mut bytes = sample_header().encode();
+        bytes[10] ^= 0xFF;
+
+        let error = Ipv4Header::decode(&bytes).unwrap_err();
+        assert_eq!(
+            error,
+            Ipv4HeaderDecodeError::ChecksumMismatch {
+                expected: sample_header().get_header_checksum(),
+                found: u16::from_be_bytes([bytes[10], bytes[11]]),
+            }
+        );
+
+        let (header, warnings) =
+            Ipv4Header::decode_with_options(&bytes, &DecodeOptions::lenient()).unwrap();
+        assert_eq!(warnings, vec![DecodeWarning::Ipv4(error)]);
+        assert!(header.validate_checksum().is_err());
+
+        let (_, warnings) =
+            Ipv4Header::decode_with_options(&bytes, &DecodeOptions::checksum_offloaded()).unwrap();
+        assert!(warnings.is_empty());
+    }
+}
@@ -0,0 +1,19 @@
+use alloc::vec::Vec;
+
+/*
+ * プロトコルの各層（IPv4, TCP, ...）のヘッダーに共通する操作。
+ *
+ * NOTE: `next_protocol` は、この層の payload をどのプロトコルとして解釈すべきかを表す。
+ *       IPv4 なら protocol フィールドの値。最上位の層（TCP など）は`None`.
+ */
+pub trait Layer: Sized {
+    type Error;
+
+    fn decode(buffer: &[u8]) -> Result<Self, Self::Error>;
+
+    fn encode(&self) -> Vec<u8>;
+
+    fn header_len(&self) -> usize;
+
+    fn next_protocol(&self) -> Option<u8>;
+}
@@ -0,0 +1,31 @@
+/*
+ * NOTE: `std` feature（デフォルト）を外すと `#![no_std]` + `alloc` でビルドできる。
+ *       組み込みターゲットでもパケットのエンコード・デコードを再利用するため。
+ */
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(any(feature = "std", feature = "alloc")))]
+compile_error!("Either the `std` or the `alloc` feature must be enabled.");
+
+extern crate alloc;
+
+pub mod capture_filter;
+pub mod checksum;
+pub mod decode_options;
+pub mod dissector;
+pub mod internet_control_message_protocol;
+pub mod internet_protocol;
+pub mod layer;
+pub mod network_stack;
+#[cfg(feature = "std")]
+pub mod packet_capture;
+pub mod packetdrill;
+pub mod protocol_registry;
+#[cfg(feature = "std")]
+pub mod replay;
+pub mod simulator;
+pub mod tcp_stack;
+pub mod timer;
+pub mod transmission_control_protocol;
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub mod tun;
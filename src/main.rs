@@ -1,6 +1,1137 @@
-pub mod internet_protocol;
-pub mod transmission_control_protocol;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, fmt};
 
-fn main() {
-    println!("Hello, world!");
+use tcp_ip_rust::capture_filter::Filter;
+use tcp_ip_rust::checksum::{calculate_internet_checksum, calculate_internet_checksum_of};
+use tcp_ip_rust::decode_options::DecodeOptions;
+use tcp_ip_rust::dissector::{format_summary, format_verbose};
+use tcp_ip_rust::internet_control_message_protocol::ICMP_PROTOCOL_NUMBER;
+use tcp_ip_rust::internet_protocol::{Ipv4Address, Ipv4Header, DONT_FRAGMENT_FLAG};
+use tcp_ip_rust::packet_capture::CaptureReader;
+use tcp_ip_rust::packetdrill;
+use tcp_ip_rust::protocol_registry::Direction;
+use tcp_ip_rust::transmission_control_protocol::tcp_option::TcpOption;
+use tcp_ip_rust::transmission_control_protocol::tcp_packet::TcpPacket;
+use tcp_ip_rust::transmission_control_protocol::tcp_pseudo_header::TcpPseudoHeader;
+use tcp_ip_rust::transmission_control_protocol::{ControlBits, TcpHeader, TCP_PROTOCOL_NUMBER};
+
+const USAGE: &str = "\
+Usage: tcp_ip_rust <command> [arguments]
+
+Commands:
+  decode <hex|file> [--summary]
+      Dissect an IPv4 datagram given as a hex string or a file of raw bytes.
+  encode --src ADDR --dst ADDR --sport PORT --dport PORT [options]
+      Build a TCP/IPv4 datagram and print it as hex.
+      options: --seq N --ack N --window N --ttl N --id N --mss N --wscale N
+               --payload TEXT --output FILE
+               --syn --fin --rst --psh --urg --ece --cwr --sackok --df
+      (--ack also sets the ACK flag)
+  checksum <hex|file> [--fix]
+      Verify the IPv4 header and TCP/ICMP checksums. --fix prints the corrected datagram.
+  pcap dump <file> [--verbose] [filter expression...]
+      Print the datagrams in a pcap/pcapng file, optionally filtered (tcpdump syntax).
+  packetdrill <script>...
+      Run packetdrill scripts against the built-in TCP stack in virtual time.
+  listen --tun NAME --addr ADDR --port PORT [--echo] [--capture FILE]
+      Accept TCP connections over a TUN device and print the received data.
+  connect --tun NAME --src ADDR --dst ADDR --dport PORT [--sport PORT] [--data TEXT] [--timeout SECS]
+          [--capture FILE]
+      Open a TCP connection over a TUN device, send data and close it.
+      --capture writes the datagrams sent and received by the TCP stack to a pcap file.
+  ping --tun NAME --src ADDR --dst ADDR [--count N] [--interval SECS]
+      Send ICMP echo requests over a TUN device.
+
+The TUN device must be configured separately, e.g.
+  ip tuntap add dev tun0 mode tun && ip addr add 10.0.0.1/24 dev tun0 && ip link set tun0 up
+and then use an address such as 10.0.0.2 for this program.
+";
+
+type CommandResult = Result<(), Box<dyn Error>>;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[String]) -> CommandResult {
+    let Some((command, rest)) = args.split_first() else {
+        print!("{}", USAGE);
+        return Ok(());
+    };
+
+    match command.as_str() {
+        "decode" => decode(rest),
+        "encode" => encode(rest),
+        "checksum" => checksum(rest),
+        "pcap" => match rest.split_first() {
+            Some((subcommand, rest)) if subcommand == "dump" => pcap_dump(rest),
+            _ => Err(UsageError("Usage: pcap dump <file> [filter expression...]".into()).into()),
+        },
+        "packetdrill" => packetdrill(rest),
+        "listen" => tun_commands::listen(rest),
+        "connect" => tun_commands::connect(rest),
+        "ping" => tun_commands::ping(rest),
+        "help" | "-h" | "--help" => {
+            print!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(UsageError(format!("Unknown command '{}'.\n\n{}", command, USAGE)).into()),
+    }
+}
+
+#[derive(Debug)]
+struct UsageError(String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for UsageError {}
+
+/*
+ * `--name value`, `--name=value`, `--flag` と位置引数。`--`以降は全て位置引数。
+ */
+#[derive(Debug, Default)]
+struct Arguments {
+    positional: Vec<String>,
+    options: BTreeMap<String, String>,
+    flags: BTreeSet<String>,
+}
+
+impl Arguments {
+    fn parse(args: &[String], options: &[&str], flags: &[&str]) -> Result<Self, UsageError> {
+        let mut arguments = Arguments::default();
+        let mut iter = args.iter();
+
+        while let Some(arg) = iter.next() {
+            if arg == "--" {
+                arguments.positional.extend(iter.by_ref().cloned());
+                break;
+            }
+            let Some(name) = arg.strip_prefix("--") else {
+                arguments.positional.push(arg.clone());
+                continue;
+            };
+
+            let (name, inline_value) = match name.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (name, None),
+            };
+
+            if flags.contains(&name) && inline_value.is_none() {
+                arguments.flags.insert(name.to_string());
+            } else if options.contains(&name) {
+                let value = match inline_value {
+                    Some(value) => value,
+                    None => iter
+                        .next()
+                        .cloned()
+                        .ok_or_else(|| UsageError(format!("--{} needs a value.", name)))?,
+                };
+                arguments.options.insert(name.to_string(), value);
+            } else {
+                return Err(UsageError(format!("Unknown option --{}.", name)));
+            }
+        }
+
+        Ok(arguments)
+    }
+
+    fn has_flag(&self, name: &str) -> bool {
+        self.flags.contains(name)
+    }
+
+    fn get<T: FromStr>(&self, name: &str) -> Result<Option<T>, UsageError> {
+        match self.options.get(name) {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| UsageError(format!("Invalid value for --{}: '{}'.", name, value))),
+            None => Ok(None),
+        }
+    }
+
+    fn require<T: FromStr>(&self, name: &str) -> Result<T, UsageError> {
+        self.get(name)?
+            .ok_or_else(|| UsageError(format!("--{} is required.", name)))
+    }
+
+    /*
+     * 秒数で指定する引数。負の値、NaN や無限大、`Duration`に収まらない値はエラー。
+     */
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    fn get_duration(&self, name: &str) -> Result<Option<Duration>, UsageError> {
+        let Some(seconds) = self.get::<f64>(name)? else {
+            return Ok(None);
+        };
+        Duration::try_from_secs_f64(seconds).map(Some).map_err(|_| {
+            UsageError(format!(
+                "--{} must be a non-negative number of seconds: '{}'.",
+                name, self.options[name]
+            ))
+        })
+    }
+
+    fn get_address(&self, name: &str) -> Result<Option<Ipv4Address>, UsageError> {
+        Ok(self.get::<Ipv4Addr>(name)?.map(|address| address.octets()))
+    }
+
+    fn require_address(&self, name: &str) -> Result<Ipv4Address, UsageError> {
+        self.get_address(name)?
+            .ok_or_else(|| UsageError(format!("--{} is required.", name)))
+    }
+
+    fn single_positional(&self, what: &str) -> Result<&str, UsageError> {
+        match self.positional.as_slice() {
+            [value] => Ok(value),
+            [] => Err(UsageError(format!("Missing {}.", what))),
+            _ => Err(UsageError(format!("Expected a single {}.", what))),
+        }
+    }
+}
+
+/*
+ * ファイルがあればその中身、なければ hex 文字列として読む。
+ */
+fn read_datagram(input: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    if Path::new(input).is_file() {
+        return Ok(fs::read(input)?);
+    }
+    parse_hex(input).ok_or_else(|| {
+        UsageError(format!("'{}' is neither a file nor a hex string.", input)).into()
+    })
+}
+
+/*
+ * 空白と`:`は無視する。`0x`プレフィックスも可。
+ */
+fn parse_hex(input: &str) -> Option<Vec<u8>> {
+    let input = input.trim();
+    let input = input
+        .strip_prefix("0x")
+        .or_else(|| input.strip_prefix("0X"))
+        .unwrap_or(input);
+    let digits: Vec<u8> = input
+        .bytes()
+        .filter(|b| !b.is_ascii_whitespace() && *b != b':')
+        .collect();
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return None;
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+fn format_hex(bytes: &[u8]) -> String {
+    let mut output = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(output, "{:02x}", byte);
+    }
+    output
+}
+
+fn decode(args: &[String]) -> CommandResult {
+    let arguments = Arguments::parse(args, &[], &["summary"])?;
+    let datagram = read_datagram(arguments.single_positional("hex string or file")?)?;
+
+    if arguments.has_flag("summary") {
+        println!("{}", format_summary(&datagram));
+    } else {
+        print!("{}", format_verbose(&datagram));
+    }
+    Ok(())
+}
+
+fn encode(args: &[String]) -> CommandResult {
+    let arguments = Arguments::parse(
+        args,
+        &[
+            "src", "dst", "sport", "dport", "seq", "ack", "window", "ttl", "id", "mss", "wscale",
+            "payload", "output",
+        ],
+        &[
+            "syn", "fin", "rst", "psh", "urg", "ece", "cwr", "sackok", "df",
+        ],
+    )?;
+
+    let mut ipv4_header = Ipv4Header::new(
+        arguments.require_address("src")?,
+        arguments.require_address("dst")?,
+        TCP_PROTOCOL_NUMBER,
+    );
+    if let Some(ttl) = arguments.get("ttl")? {
+        ipv4_header.set_ttl(ttl);
+    }
+    if let Some(identification) = arguments.get("id")? {
+        ipv4_header.set_identification(identification);
+    }
+    if arguments.has_flag("df") {
+        ipv4_header.set_flags(DONT_FRAGMENT_FLAG);
+    }
+
+    let mut tcp_header = TcpHeader::new(arguments.require("sport")?, arguments.require("dport")?);
+    tcp_header.set_sequence_number(arguments.get("seq")?.unwrap_or(0));
+    tcp_header.set_window(arguments.get("window")?.unwrap_or(65535));
+
+    let mut control_bits = ControlBits::default();
+    if let Some(acknowledgment_number) = arguments.get("ack")? {
+        tcp_header.set_acknowledgment_number(acknowledgment_number);
+        control_bits.set_ack(true);
+    }
+    control_bits.set_syn(arguments.has_flag("syn"));
+    control_bits.set_fin(arguments.has_flag("fin"));
+    control_bits.set_rst(arguments.has_flag("rst"));
+    control_bits.set_psh(arguments.has_flag("psh"));
+    control_bits.set_urg(arguments.has_flag("urg"));
+    control_bits.set_ece(arguments.has_flag("ece"));
+    control_bits.set_cwr(arguments.has_flag("cwr"));
+    tcp_header.set_control_bits(control_bits);
+
+    let mut options = Vec::new();
+    if let Some(mss) = arguments.get("mss")? {
+        options.push(TcpOption::MaximumSegmentSize(mss));
+    }
+    if arguments.has_flag("sackok") {
+        options.push(TcpOption::SackPermitted);
+    }
+    if let Some(shift) = arguments.get("wscale")? {
+        options.push(TcpOption::NoOperation);
+        options.push(TcpOption::WindowScale(shift));
+    }
+    tcp_header.set_options(&options)?;
+
+    let payload = arguments
+        .get::<String>("payload")?
+        .unwrap_or_default()
+        .into_bytes();
+    let datagram = TcpPacket::new(ipv4_header, tcp_header, payload).encode()?;
+
+    match arguments.get::<String>("output")? {
+        Some(path) => fs::write(path, &datagram)?,
+        None => println!("{}", format_hex(&datagram)),
+    }
+    Ok(())
+}
+
+/*
+ * 格納されているチェックサムと、計算し直したチェックサム。
+ */
+struct ChecksumCheck {
+    name: &'static str,
+    offset: usize,
+    stored: u16,
+    expected: u16,
+}
+
+fn checksum(args: &[String]) -> CommandResult {
+    let arguments = Arguments::parse(args, &[], &["fix"])?;
+    let mut datagram = read_datagram(arguments.single_positional("hex string or file")?)?;
+
+    let checks = collect_checksums(&datagram)?;
+    let mut all_ok = true;
+    for check in &checks {
+        if check.stored == check.expected {
+            println!("{:<4} checksum {:#06x} ok", check.name, check.stored);
+        } else {
+            all_ok = false;
+            println!(
+                "{:<4} checksum {:#06x} bad, expected {:#06x}",
+                check.name, check.stored, check.expected
+            );
+        }
+    }
+
+    if arguments.has_flag("fix") {
+        for check in &checks {
+            datagram[check.offset..check.offset + 2].copy_from_slice(&check.expected.to_be_bytes());
+        }
+        println!("{}", format_hex(&datagram));
+        return Ok(());
+    }
+
+    if all_ok {
+        Ok(())
+    } else {
+        Err("Checksum mismatch.".into())
+    }
+}
+
+fn collect_checksums(datagram: &[u8]) -> Result<Vec<ChecksumCheck>, Box<dyn Error>> {
+    let (ipv4_header, _) =
+        Ipv4Header::decode_with_options(datagram, &DecodeOptions::checksum_offloaded())?;
+    ipv4_header.validate_total_length(datagram.len())?;
+
+    let header_length = ipv4_header.get_header_length();
+    let mut checks = vec![checksum_at("ip", 0, &[], &datagram[..header_length], 10)];
+
+    let payload = ipv4_header.get_payload(datagram);
+    if ipv4_header.is_fragment() {
+        println!("(fragmented datagram, skipping the transport checksum)");
+        return Ok(checks);
+    }
+    match ipv4_header.get_protocol() {
+        TCP_PROTOCOL_NUMBER if payload.len() >= 18 => {
+            let pseudo_header = TcpPseudoHeader::from_addresses(
+                ipv4_header.get_source_address(),
+                ipv4_header.get_destination_address(),
+                payload.len(),
+            )
+            .encode();
+            checks.push(checksum_at(
+                "tcp",
+                header_length,
+                &pseudo_header,
+                payload,
+                16,
+            ));
+        }
+        ICMP_PROTOCOL_NUMBER if payload.len() >= 4 => {
+            checks.push(checksum_at("icmp", header_length, &[], payload, 2));
+        }
+        _ => {}
+    }
+    Ok(checks)
+}
+
+/*
+ * `bytes`の`field`にあるチェックサムを 0 にして計算し直す。
+ */
+fn checksum_at(
+    name: &'static str,
+    base: usize,
+    pseudo_header: &[u8],
+    bytes: &[u8],
+    field: usize,
+) -> ChecksumCheck {
+    let mut zeroed = bytes.to_vec();
+    zeroed[field..field + 2].copy_from_slice(&[0, 0]);
+    let expected = if pseudo_header.is_empty() {
+        calculate_internet_checksum(&zeroed)
+    } else {
+        calculate_internet_checksum_of(&[pseudo_header, &zeroed])
+    };
+
+    ChecksumCheck {
+        name,
+        offset: base + field,
+        stored: u16::from_be_bytes([bytes[field], bytes[field + 1]]),
+        expected,
+    }
+}
+
+fn pcap_dump(args: &[String]) -> CommandResult {
+    let arguments = Arguments::parse(args, &[], &["verbose"])?;
+    let Some((path, expression)) = arguments.positional.split_first() else {
+        return Err(UsageError("Missing capture file.".into()).into());
+    };
+    let filter = if expression.is_empty() {
+        None
+    } else {
+        Some(Filter::parse(&expression.join(" "))?)
+    };
+
+    let reader = CaptureReader::new(BufReader::new(File::open(path)?))?;
+    for frame in reader {
+        let frame = frame?;
+        let timestamp = format!(
+            "{}.{:06}",
+            frame.timestamp.as_secs(),
+            frame.timestamp.subsec_micros()
+        );
+        let direction = match frame.direction {
+            Some(Direction::Inbound) => "In  ",
+            Some(Direction::Outbound) => "Out ",
+            None => "",
+        };
+
+        let Some(datagram) = frame.get_datagram() else {
+            if filter.is_none() {
+                println!(
+                    "{} {}[non-IPv4 frame] length {}",
+                    timestamp,
+                    direction,
+                    frame.frame.len()
+                );
+            }
+            continue;
+        };
+        if let Some(filter) = &filter {
+            if !filter.matches_datagram(datagram) {
+                continue;
+            }
+        }
+
+        println!("{} {}{}", timestamp, direction, format_summary(datagram));
+        if arguments.has_flag("verbose") {
+            print!("{}", format_verbose(datagram));
+        }
+    }
+    Ok(())
+}
+
+fn packetdrill(args: &[String]) -> CommandResult {
+    let arguments = Arguments::parse(args, &[], &[])?;
+    if arguments.positional.is_empty() {
+        return Err(UsageError("Missing script file.".into()).into());
+    }
+
+    let mut failures = 0;
+    for path in &arguments.positional {
+        match packetdrill::run_file(Path::new(path)) {
+            Ok(()) => println!("ok   {}", path),
+            Err(error) => {
+                println!(
+                    "FAIL {}\n  {}",
+                    path,
+                    error.to_string().replace('\n', "\n  ")
+                );
+                failures += 1;
+            }
+        }
+    }
+    if failures > 0 {
+        return Err(format!(
+            "{} of {} scripts failed.",
+            failures,
+            arguments.positional.len()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/*
+ * TUN デバイスを使うコマンド。TCP は`TcpStack`が処理し、ここではデバイスとの間で datagram を
+ * 受け渡して、ICMP Echo Request に応答するだけ。
+ */
+#[cfg(target_os = "linux")]
+mod tun_commands {
+    use super::{Arguments, CommandResult, UsageError};
+    use std::error::Error;
+    use std::fs::File;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use tcp_ip_rust::internet_control_message_protocol::{IcmpMessage, ICMP_PROTOCOL_NUMBER};
+    use tcp_ip_rust::internet_protocol::{Ipv4Address, Ipv4Header, IPV4_HEADER_MIN_LEN};
+    use tcp_ip_rust::network_stack::NetworkStack;
+    use tcp_ip_rust::packet_capture::pcap::PcapWriter;
+    use tcp_ip_rust::packet_capture::{CaptureTap, LinkType, PacketCapture};
+    use tcp_ip_rust::tcp_stack::{Endpoint, SocketError, SocketHandle, TcpStack, TcpState};
+    use tcp_ip_rust::tun::TunDevice;
+
+    const MAX_DATAGRAM_LEN: usize = 65535;
+    const LISTEN_BACKLOG: usize = 16;
+    const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /*
+     * TUN デバイスと`TcpStack`をつなぐ。スタックの時刻はコマンドを始めてからの経過時間。
+     */
+    struct Host {
+        device: TunDevice,
+        stack: TcpStack,
+        address: Ipv4Address,
+        started: Instant,
+        buffer: Vec<u8>,
+        capture: Option<Arc<Mutex<PacketCapture>>>,
+    }
+
+    impl Host {
+        /*
+         * `capture_path`があれば、スタックが送受信する datagram をそのファイルに pcap で書く。
+         */
+        fn open(
+            name: &str,
+            address: Ipv4Address,
+            capture_path: Option<String>,
+        ) -> io::Result<Self> {
+            let mut stack = TcpStack::new(&[address]);
+            let capture = match capture_path {
+                Some(path) => {
+                    let writer = PcapWriter::new(File::create(path)?, LinkType::Raw)?;
+                    let mut capture = PacketCapture::new(Box::new(writer));
+                    capture.enable(0);
+                    let capture = Arc::new(Mutex::new(capture));
+                    stack.set_tap(Some(Box::new(CaptureTap::new(capture.clone(), 0))));
+                    Some(capture)
+                }
+                None => None,
+            };
+
+            Ok(Self {
+                device: TunDevice::open(name)?,
+                stack,
+                address,
+                started: Instant::now(),
+                buffer: vec![0u8; MAX_DATAGRAM_LEN],
+                capture,
+            })
+        }
+
+        fn now(&self) -> Duration {
+            self.started.elapsed()
+        }
+
+        /*
+         * スタックが送る datagram をデバイスに書く。キャプチャの書き込みに失敗していたら、そのエラーを返す。
+         */
+        fn transmit(&mut self) -> io::Result<()> {
+            while let Some(datagram) = self.stack.poll_transmit(self.now()) {
+                self.device.send(&datagram)?;
+            }
+            let error = self
+                .capture
+                .as_ref()
+                .and_then(|capture| capture.lock().ok()?.take_error());
+            match error {
+                Some(error) => Err(error),
+                None => Ok(()),
+            }
+        }
+
+        /*
+         * datagram が 1 つ届くか、スタックのタイマーか`deadline`の時刻になるまで待って処理する。
+         */
+        fn poll(&mut self, deadline: Option<Instant>) -> io::Result<()> {
+            self.transmit()?;
+            let now = self.now();
+            let timer = self
+                .stack
+                .poll_timeout()
+                .map(|wakeup| wakeup.saturating_sub(now));
+            let remaining =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            let timeout = match (timer, remaining) {
+                (Some(timer), Some(remaining)) => Some(timer.min(remaining)),
+                (timer, remaining) => timer.or(remaining),
+            };
+
+            if let Some(length) = self.device.receive(&mut self.buffer, timeout)? {
+                let datagram = &self.buffer[..length];
+                if !reply_to_echo_request(&mut self.device, self.address, datagram)? {
+                    let now = self.started.elapsed();
+                    self.stack.receive(now, datagram);
+                }
+            }
+
+            let now = self.now();
+            if self
+                .stack
+                .poll_timeout()
+                .is_some_and(|wakeup| wakeup <= now)
+            {
+                self.stack.handle_timeout(now);
+            }
+            self.transmit()
+        }
+
+        /*
+         * `deadline`を過ぎていれば`message`のエラー、そうでなければ`poll`する。
+         */
+        fn poll_until(
+            &mut self,
+            deadline: Option<Instant>,
+            message: &str,
+        ) -> Result<(), Box<dyn Error>> {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(message.into());
+            }
+            self.poll(deadline)?;
+            Ok(())
+        }
+
+        /*
+         * 届いているデータを読んで表示する（`echo`なら送り返す）。コネクションが終わったら`false`.
+         */
+        fn serve(&mut self, connection: SocketHandle, echo: bool) -> Result<bool, SocketError> {
+            let remote = self.stack.get_remote_endpoint(connection)?;
+            let mut buffer = [0u8; 4096];
+            loop {
+                let now = self.now();
+                match self.stack.recv(now, connection, &mut buffer) {
+                    Ok(0) => {
+                        println!("Connection closed by {}.", remote);
+                        self.stack.close(now, connection)?;
+                        return Ok(false);
+                    }
+                    Ok(length) => {
+                        print_data(remote, &buffer[..length]);
+
+                        /*
+                         * NOTE: 送信バッファに入りきらない分は捨てる。
+                         */
+                        if echo {
+                            let _ = self.stack.send(now, connection, &buffer[..length]);
+                        }
+                    }
+                    Err(SocketError::WouldBlock) => return Ok(true),
+                    Err(error) => {
+                        println!("Connection with {} failed: {}", remote, error);
+                        self.stack.close(now, connection)?;
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+    }
+
+    fn print_data(from: Endpoint, data: &[u8]) {
+        println!(
+            "{} > {} bytes: {}",
+            from,
+            data.len(),
+            String::from_utf8_lossy(data).trim_end()
+        );
+    }
+
+    fn format_address(address: Ipv4Address) -> String {
+        std::net::Ipv4Addr::from(address).to_string()
+    }
+
+    /*
+     * `local_address`宛ての ICMP Echo Request なら応答して`true`を返す。
+     */
+    fn reply_to_echo_request(
+        device: &mut TunDevice,
+        local_address: Ipv4Address,
+        datagram: &[u8],
+    ) -> io::Result<bool> {
+        let Ok(ipv4_header) = Ipv4Header::decode(datagram) else {
+            return Ok(false);
+        };
+        if ipv4_header.get_destination_address() != local_address
+            || ipv4_header.get_protocol() != ICMP_PROTOCOL_NUMBER
+        {
+            return Ok(false);
+        }
+        let Ok(message) = IcmpMessage::decode(ipv4_header.get_payload(datagram)) else {
+            return Ok(false);
+        };
+        if !message.is_echo_request() {
+            return Ok(false);
+        }
+
+        let reply = IcmpMessage::new_echo_reply(&message);
+        device.send(&icmp_datagram(
+            local_address,
+            ipv4_header.get_source_address(),
+            &reply,
+        ))?;
+        Ok(true)
+    }
+
+    /*
+     * `local_address`宛ての datagram を待つ。ICMP Echo Request には自動で応答する。
+     */
+    fn receive_for(
+        device: &mut TunDevice,
+        local_address: Ipv4Address,
+        deadline: Option<Instant>,
+    ) -> io::Result<Option<(Ipv4Header, Vec<u8>)>> {
+        let mut buffer = vec![0u8; MAX_DATAGRAM_LEN];
+        loop {
+            let timeout = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(timeout) => Some(timeout),
+                    None => return Ok(None),
+                },
+                None => None,
+            };
+            let Some(length) = device.receive(&mut buffer, timeout)? else {
+                continue;
+            };
+            let datagram = &buffer[..length];
+
+            let Ok(ipv4_header) = Ipv4Header::decode(datagram) else {
+                continue;
+            };
+            if ipv4_header.get_destination_address() != local_address
+                || reply_to_echo_request(device, local_address, datagram)?
+            {
+                continue;
+            }
+
+            return Ok(Some((ipv4_header, datagram.to_vec())));
+        }
+    }
+
+    fn icmp_datagram(
+        source_address: Ipv4Address,
+        destination_address: Ipv4Address,
+        message: &IcmpMessage,
+    ) -> Vec<u8> {
+        let payload = message.encode();
+        let mut ipv4_header =
+            Ipv4Header::new(source_address, destination_address, ICMP_PROTOCOL_NUMBER);
+        ipv4_header.set_total_length((IPV4_HEADER_MIN_LEN + payload.len()) as u16);
+        let mut datagram = ipv4_header.encode();
+        datagram.extend_from_slice(&payload);
+        datagram
+    }
+
+    pub(super) fn listen(args: &[String]) -> CommandResult {
+        let arguments = Arguments::parse(args, &["tun", "addr", "port", "capture"], &["echo"])?;
+        let address = arguments.require_address("addr")?;
+        let port: u16 = arguments.require("port")?;
+        let echo = arguments.has_flag("echo");
+
+        let mut host = Host::open(
+            &arguments.require::<String>("tun")?,
+            address,
+            arguments.get("capture")?,
+        )?;
+        let listener = host.stack.socket();
+        host.stack.bind(listener, address, port)?;
+        host.stack.listen(listener, LISTEN_BACKLOG)?;
+        println!(
+            "Listening on {} via {}",
+            Endpoint::new(address, port),
+            host.device.get_name()
+        );
+
+        let mut connections = Vec::new();
+        loop {
+            host.poll(None)?;
+            while let Ok(accepted) = host.stack.accept(listener) {
+                println!(
+                    "Connection established with {}",
+                    host.stack.get_remote_endpoint(accepted)?
+                );
+                connections.push(accepted);
+            }
+
+            let mut open = Vec::new();
+            for connection in connections {
+                if host.serve(connection, echo)? {
+                    open.push(connection);
+                }
+            }
+            connections = open;
+        }
+    }
+
+    pub(super) fn connect(args: &[String]) -> CommandResult {
+        let arguments = Arguments::parse(
+            args,
+            &[
+                "tun", "src", "dst", "sport", "dport", "data", "timeout", "capture",
+            ],
+            &[],
+        )?;
+        let timeout = arguments
+            .get_duration("timeout")?
+            .unwrap_or(DEFAULT_TIMEOUT);
+        let data = arguments
+            .get::<String>("data")?
+            .unwrap_or_default()
+            .into_bytes();
+        let source_address = arguments.require_address("src")?;
+        let remote = Endpoint::new(
+            arguments.require_address("dst")?,
+            arguments.require("dport")?,
+        );
+
+        let mut host = Host::open(
+            &arguments.require::<String>("tun")?,
+            source_address,
+            arguments.get("capture")?,
+        )?;
+        let socket = host.stack.socket();
+        if let Some(port) = arguments.get("sport")? {
+            host.stack.bind(socket, source_address, port)?;
+        }
+
+        /*
+         * 接続からお互いの FIN の交換までを`timeout`の間に終わらせる。
+         */
+        let deadline = Instant::now().checked_add(timeout);
+        host.stack
+            .connect(host.now(), socket, remote.address, remote.port)?;
+        while !host.stack.get_state(socket)?.is_synchronized() {
+            if let Some(error) = host.stack.take_error(socket)? {
+                return Err(error.into());
+            }
+            host.poll_until(deadline, "Connection timed out.")?;
+        }
+        println!(
+            "Connected to {} from port {}",
+            remote,
+            host.stack.get_local_endpoint(socket)?.port
+        );
+
+        let mut sent = 0;
+        while sent < data.len() {
+            match host.stack.send(host.now(), socket, &data[sent..]) {
+                Ok(length) => sent += length,
+                Err(SocketError::WouldBlock) => {
+                    host.poll_until(deadline, "Timed out while sending data.")?
+                }
+                Err(error) => return Err(error.into()),
+            }
+        }
+        host.stack.shutdown(host.now(), socket)?;
+
+        /*
+         * 相手の FIN を受け取り、こちらの FIN の ACK が届くまで待つ。
+         */
+        let mut buffer = [0u8; 4096];
+        loop {
+            match host.stack.recv(host.now(), socket, &mut buffer) {
+                Ok(0) => {
+                    if matches!(
+                        host.stack.get_state(socket)?,
+                        TcpState::TimeWait | TcpState::Closed
+                    ) {
+                        break;
+                    }
+                }
+                Ok(length) => {
+                    print_data(remote, &buffer[..length]);
+                    continue;
+                }
+                Err(SocketError::WouldBlock) => {}
+                Err(error) => return Err(error.into()),
+            }
+            host.poll_until(deadline, "Timed out while closing the connection.")?;
+        }
+
+        host.stack.close(host.now(), socket)?;
+        host.transmit()?;
+        println!("Connection closed.");
+        Ok(())
+    }
+
+    pub(super) fn ping(args: &[String]) -> CommandResult {
+        let arguments = Arguments::parse(args, &["tun", "src", "dst", "count", "interval"], &[])?;
+        let source_address = arguments.require_address("src")?;
+        let destination_address = arguments.require_address("dst")?;
+        let count: u16 = arguments.get("count")?.unwrap_or(4);
+        let interval = arguments
+            .get_duration("interval")?
+            .unwrap_or(Duration::from_secs(1));
+        if count == 0 {
+            return Err(UsageError("--count must be at least 1.".into()).into());
+        }
+
+        let mut device = TunDevice::open(&arguments.require::<String>("tun")?)?;
+        let identifier = std::process::id() as u16;
+        let data: Vec<u8> = (0..56u8).collect();
+        let mut received = 0;
+
+        println!(
+            "PING {} via {}: {} data bytes",
+            format_address(destination_address),
+            device.get_name(),
+            data.len()
+        );
+        for sequence_number in 0..count {
+            let started = Instant::now();
+            let request = IcmpMessage::new_echo_request(identifier, sequence_number, data.clone());
+            device.send(&icmp_datagram(
+                source_address,
+                destination_address,
+                &request,
+            ))?;
+
+            let deadline = started.checked_add(interval.max(Duration::from_secs(1)));
+            match wait_echo_reply(&mut device, source_address, deadline, &request)? {
+                Some((ipv4_header, length)) => {
+                    received += 1;
+                    println!(
+                        "{} bytes from {}: icmp_seq={} ttl={} time={:.3} ms",
+                        length,
+                        format_address(ipv4_header.get_source_address()),
+                        sequence_number,
+                        ipv4_header.get_ttl(),
+                        started.elapsed().as_secs_f64() * 1000.0
+                    );
+                }
+                None => println!("Request timeout for icmp_seq {}", sequence_number),
+            }
+
+            if sequence_number + 1 < count {
+                thread::sleep(interval.saturating_sub(started.elapsed()));
+            }
+        }
+
+        println!(
+            "--- {} ping statistics ---\n{} packets transmitted, {} received, {:.1}% packet loss",
+            format_address(destination_address),
+            count,
+            received,
+            f64::from(count - received) * 100.0 / f64::from(count)
+        );
+        if received == 0 {
+            return Err("No reply.".into());
+        }
+        Ok(())
+    }
+
+    fn wait_echo_reply(
+        device: &mut TunDevice,
+        local_address: Ipv4Address,
+        deadline: Option<Instant>,
+        request: &IcmpMessage,
+    ) -> io::Result<Option<(Ipv4Header, usize)>> {
+        while let Some((ipv4_header, datagram)) = receive_for(device, local_address, deadline)? {
+            if ipv4_header.get_protocol() != ICMP_PROTOCOL_NUMBER {
+                continue;
+            }
+            let Ok(reply) = IcmpMessage::decode(ipv4_header.get_payload(&datagram)) else {
+                continue;
+            };
+            if reply.is_echo_reply()
+                && reply.get_identifier() == request.get_identifier()
+                && reply.get_sequence_number() == request.get_sequence_number()
+            {
+                let length = datagram.len() - ipv4_header.get_header_length();
+                return Ok(Some((ipv4_header, length)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod tun_commands {
+    use super::CommandResult;
+
+    fn unsupported() -> CommandResult {
+        Err("TUN devices are only supported on Linux.".into())
+    }
+
+    pub(super) fn listen(_args: &[String]) -> CommandResult {
+        unsupported()
+    }
+
+    pub(super) fn connect(_args: &[String]) -> CommandResult {
+        unsupported()
+    }
+
+    pub(super) fn ping(_args: &[String]) -> CommandResult {
+        unsupported()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_arguments() {
+        let arguments = Arguments::parse(
+            &strings(&[
+                "--src",
+                "10.0.0.1",
+                "--syn",
+                "--dport=80",
+                "file",
+                "--",
+                "--x",
+            ]),
+            &["src", "dport"],
+            &["syn"],
+        )
+        .unwrap();
+        assert_eq!(arguments.require_address("src").unwrap(), [10, 0, 0, 1]);
+        assert_eq!(arguments.require::<u16>("dport").unwrap(), 80);
+        assert!(arguments.has_flag("syn"));
+        assert_eq!(arguments.positional, strings(&["file", "--x"]));
+
+        assert!(Arguments::parse(&strings(&["--bogus"]), &[], &[]).is_err());
+        assert!(Arguments::parse(&strings(&["--src"]), &["src"], &[]).is_err());
+        assert!(arguments.require::<u16>("sport").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        let parse = |value: &str| {
+            Arguments::parse(&strings(&["--timeout", value]), &["timeout"], &[])
+                .unwrap()
+                .get_duration("timeout")
+        };
+        assert_eq!(parse("1.5").unwrap(), Some(Duration::from_millis(1500)));
+        assert_eq!(parse("0").unwrap(), Some(Duration::ZERO));
+        for value in ["-1", "nan", "inf", "1e30", "x"] {
+            assert!(parse(value).is_err());
+        }
+        assert_eq!(Arguments::default().get_duration("timeout").unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_hex("0x45 00:1f"), Some(vec![0x45, 0x00, 0x1f]));
+        assert_eq!(parse_hex("abc"), None);
+        assert_eq!(parse_hex("zz"), None);
+        assert_eq!(format_hex(&[0x45, 0x0a]), "450a");
+    }
+
+    #[test]
+    fn test_encode_and_fix_checksums() {
+        let output = env::temp_dir().join(format!("tcp_ip_rust_cli_{}.bin", std::process::id()));
+        run(&strings(&[
+            "encode",
+            "--src",
+            "10.0.0.1",
+            "--dst",
+            "10.0.0.2",
+            "--sport",
+            "5432",
+            "--dport",
+            "80",
+            "--syn",
+            "--mss",
+            "1460",
+            "--payload",
+            "hi",
+            "--output",
+            output.to_str().unwrap(),
+        ]))
+        .unwrap();
+        let mut datagram = fs::read(&output).unwrap();
+        fs::remove_file(&output).unwrap();
+
+        let packet = TcpPacket::decode(&datagram).unwrap();
+        assert!(packet.get_tcp_header().get_control_bits().get_syn());
+        assert_eq!(packet.get_payload(), b"hi");
+        assert!(collect_checksums(&datagram)
+            .unwrap()
+            .iter()
+            .all(|check| check.stored == check.expected));
+
+        datagram[10] ^= 0xff;
+        let last = datagram.len() - 1;
+        datagram[last] ^= 0x01;
+        let checks = collect_checksums(&datagram).unwrap();
+        assert_eq!(checks.len(), 2);
+        assert!(checks.iter().all(|check| check.stored != check.expected));
+        assert!(run(&strings(&["checksum", &format_hex(&datagram)])).is_err());
+    }
 }
@@ -0,0 +1,42 @@
+use crate::protocol_registry::ProtocolRegistry;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+/*
+ * datagram を受け取って処理するもの（スタックのインスタンス）。
+ *
+ * `now` はスタックの時計での現在時刻。キャプチャのリプレイなどで、実時間とは別の時刻で
+ * 動かせるように、スタック自身に時計を読ませずに呼び出し側から渡す。
+ *
+ * 送信とタイマーも同じく呼び出し側が駆動する（smoltcp などと同じ poll 方式）。
+ * 呼び出し側は`poll_transmit`が`None`を返すまで送信を取り出し、`poll_timeout`の時刻に
+ * なったら`handle_timeout`を呼ぶ。
+ */
+pub trait NetworkStack {
+    fn receive(&mut self, now: Duration, datagram: &[u8]);
+
+    /*
+     * 送信待ちの datagram を 1 つ取り出す。
+     */
+    fn poll_transmit(&mut self, _now: Duration) -> Option<Vec<u8>> {
+        None
+    }
+
+    /*
+     * 次に`handle_timeout`を呼んでほしい時刻。タイマーがなければ`None`.
+     */
+    fn poll_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    fn handle_timeout(&mut self, _now: Duration) {}
+}
+
+/*
+ * NOTE: 処理できなかった datagram は、実際のスタックと同じように黙って捨てる。
+ */
+impl NetworkStack for ProtocolRegistry {
+    fn receive(&mut self, _now: Duration, datagram: &[u8]) {
+        let _ = self.dispatch(datagram);
+    }
+}
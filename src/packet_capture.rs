@@ -0,0 +1,532 @@
+use crate::decode_options::{DecodeOptions, DecodeWarning};
+use crate::internet_protocol::{Ipv4Header, Ipv4HeaderDecodeError};
+use crate::protocol_registry::{Direction, PacketTap};
+use crate::transmission_control_protocol::tcp_packet::{TcpPacket, TcpPacketDecodeError};
+use pcap::PcapReader;
+use pcapng::PcapngReader;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub mod pcap;
+pub mod pcapng;
+
+/*
+ * See: https://www.tcpdump.org/linktypes.html
+ */
+pub const LINKTYPE_ETHERNET: u32 = 1;
+pub const LINKTYPE_RAW: u32 = 101;
+
+/*
+ * Ethernet ヘッダーの長さと、IPv4 を表す EtherType.
+ */
+const ETHERNET_HEADER_LEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+
+pub type InterfaceId = u32;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LinkType {
+    /*
+     * IPv4 datagram をそのまま書く。
+     */
+    Raw,
+
+    /*
+     * Ethernet ヘッダーを付けて書く。
+     *
+     * NOTE: このスタックは IP 層から下を持っていないので、MAC アドレスが全て 0 の
+     *       ダミーのヘッダーを付ける。Ethernet 前提のツールに読ませたい時用。
+     */
+    Ethernet,
+}
+
+impl LinkType {
+    pub fn get_number(&self) -> u32 {
+        match self {
+            LinkType::Raw => LINKTYPE_RAW,
+            LinkType::Ethernet => LINKTYPE_ETHERNET,
+        }
+    }
+
+    pub fn from_number(number: u32) -> Option<Self> {
+        match number {
+            LINKTYPE_RAW => Some(LinkType::Raw),
+            LINKTYPE_ETHERNET => Some(LinkType::Ethernet),
+            _ => None,
+        }
+    }
+
+    /*
+     * IPv4 datagram をこのリンク層のフレームにする。
+     */
+    pub fn frame<'a>(&self, datagram: &'a [u8]) -> Cow<'a, [u8]> {
+        match self {
+            LinkType::Raw => Cow::Borrowed(datagram),
+            LinkType::Ethernet => {
+                let mut frame = vec![0u8; ETHERNET_HEADER_LEN];
+                frame[12..14].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+                frame.extend_from_slice(datagram);
+                Cow::Owned(frame)
+            }
+        }
+    }
+
+    /*
+     * `frame`の逆。IPv4 以外のフレームなら`None`.
+     */
+    pub fn unframe<'a>(&self, frame: &'a [u8]) -> Option<&'a [u8]> {
+        match self {
+            LinkType::Raw => Some(frame),
+            LinkType::Ethernet => {
+                if frame.len() < ETHERNET_HEADER_LEN
+                    || frame[12..14] != ETHERTYPE_IPV4.to_be_bytes()
+                {
+                    None
+                } else {
+                    Some(&frame[ETHERNET_HEADER_LEN..])
+                }
+            }
+        }
+    }
+}
+
+/*
+ * キャプチャした 1 パケット分。timestamp は UNIX epoch からの経過時間。
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct CaptureRecord<'a> {
+    pub interface_id: InterfaceId,
+    pub timestamp: Duration,
+    pub direction: Direction,
+    pub datagram: &'a [u8],
+}
+
+pub trait CaptureWriter {
+    fn write_record(&mut self, record: &CaptureRecord<'_>) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/*
+ * インターフェースごとにキャプチャの有効・無効を実行時に切り替える。
+ *
+ * 書き込みに失敗してもスタックの処理は止めたくないので、エラーは覚えておいて
+ * `take_error`で取り出せるようにする。
+ */
+pub struct PacketCapture {
+    writer: Box<dyn CaptureWriter + Send>,
+    enabled_interfaces: BTreeSet<InterfaceId>,
+    error: Option<io::Error>,
+}
+
+impl PacketCapture {
+    pub fn new(writer: Box<dyn CaptureWriter + Send>) -> Self {
+        Self {
+            writer,
+            enabled_interfaces: BTreeSet::new(),
+            error: None,
+        }
+    }
+
+    pub fn enable(&mut self, interface_id: InterfaceId) {
+        self.enabled_interfaces.insert(interface_id);
+    }
+
+    pub fn disable(&mut self, interface_id: InterfaceId) {
+        self.enabled_interfaces.remove(&interface_id);
+    }
+
+    pub fn is_enabled(&self, interface_id: InterfaceId) -> bool {
+        self.enabled_interfaces.contains(&interface_id)
+    }
+
+    pub fn record(&mut self, interface_id: InterfaceId, direction: Direction, datagram: &[u8]) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.record_at(interface_id, timestamp, direction, datagram);
+    }
+
+    pub fn record_at(
+        &mut self,
+        interface_id: InterfaceId,
+        timestamp: Duration,
+        direction: Direction,
+        datagram: &[u8],
+    ) {
+        if !self.is_enabled(interface_id) {
+            return;
+        }
+
+        let record = CaptureRecord {
+            interface_id,
+            timestamp,
+            direction,
+            datagram,
+        };
+        if let Err(error) = self.writer.write_record(&record) {
+            self.error.get_or_insert(error);
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+}
+
+/*
+ * `ProtocolRegistry`や`TcpStack`に設定して、そのインターフェースを通過する datagram を記録する。
+ *
+ * 複数のインターフェースで 1 つのファイルに書けるように、`PacketCapture`は共有する。
+ */
+pub struct CaptureTap {
+    capture: Arc<Mutex<PacketCapture>>,
+    interface_id: InterfaceId,
+}
+
+impl CaptureTap {
+    pub fn new(capture: Arc<Mutex<PacketCapture>>, interface_id: InterfaceId) -> Self {
+        Self {
+            capture,
+            interface_id,
+        }
+    }
+}
+
+impl PacketTap for CaptureTap {
+    fn tap(&mut self, direction: Direction, datagram: &[u8]) {
+        if let Ok(mut capture) = self.capture.lock() {
+            capture.record(self.interface_id, direction, datagram);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CaptureReadError {
+    Io(io::Error),
+
+    /*
+     * 先頭の magic number が pcap / pcapng のどれでもない。
+     */
+    UnknownFormat { magic: u32 },
+
+    UnsupportedLinkType(u32),
+
+    /*
+     * block / record の途中でファイルが終わっている。
+     */
+    Truncated,
+
+    /*
+     * block の長さが不正。offset はファイル先頭からの位置。
+     */
+    BadBlockLength { offset: u64, length: u32 },
+
+    /*
+     * classic pcap の record に書かれた長さが大きすぎる。
+     */
+    BadRecordLength(u32),
+
+    /*
+     * pcapng で、定義されていない interface 番号のパケットがある。
+     */
+    UnknownInterface(u32),
+}
+
+impl From<io::Error> for CaptureReadError {
+    fn from(error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::UnexpectedEof {
+            CaptureReadError::Truncated
+        } else {
+            CaptureReadError::Io(error)
+        }
+    }
+}
+
+impl fmt::Display for CaptureReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureReadError::Io(error) => write!(f, "I/O error: {}", error),
+            CaptureReadError::UnknownFormat { magic } => {
+                write!(f, "Unknown capture format. magic={:#010x}", magic)
+            }
+            CaptureReadError::UnsupportedLinkType(link_type) => {
+                write!(f, "Unsupported link type {}.", link_type)
+            }
+            CaptureReadError::Truncated => write!(f, "Capture file is truncated."),
+            CaptureReadError::BadBlockLength { offset, length } => {
+                write!(f, "Bad block length {} at byte {}.", length, offset)
+            }
+            CaptureReadError::BadRecordLength(length) => {
+                write!(f, "Bad record length {}.", length)
+            }
+            CaptureReadError::UnknownInterface(interface) => {
+                write!(f, "Packet refers to undefined interface {}.", interface)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CaptureReadError {}
+
+/*
+ * キャプチャファイルから読んだ 1 フレーム。
+ *
+ * NOTE: classic pcap は向きを記録できないので`direction`は`None`になる。
+ */
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CapturedFrame {
+    pub interface_id: InterfaceId,
+    pub timestamp: Duration,
+    pub direction: Option<Direction>,
+    pub link_type: LinkType,
+    pub frame: Vec<u8>,
+}
+
+impl CapturedFrame {
+    /*
+     * リンク層のヘッダーを外した IPv4 datagram. IPv4 でなければ`None`.
+     */
+    pub fn get_datagram(&self) -> Option<&[u8]> {
+        self.link_type.unframe(&self.frame)
+    }
+
+    pub fn decode_ipv4(&self) -> Option<Result<Ipv4Header, Ipv4HeaderDecodeError>> {
+        self.get_datagram().map(Ipv4Header::decode)
+    }
+
+    pub fn decode_tcp(
+        &self,
+        options: &DecodeOptions,
+    ) -> Option<Result<(TcpPacket, Vec<DecodeWarning>), TcpPacketDecodeError>> {
+        self.get_datagram()
+            .map(|datagram| TcpPacket::decode_with_options(datagram, options))
+    }
+}
+
+/*
+ * 先頭の magic number を見て、pcap か pcapng かを判別して読む。
+ */
+pub enum CaptureReader<R: Read> {
+    Pcap(PcapReader<R>),
+    Pcapng(PcapngReader<R>),
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, CaptureReadError> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+
+        if pcapng::is_section_header(magic) {
+            Ok(CaptureReader::Pcapng(PcapngReader::after_magic(
+                reader, magic,
+            )?))
+        } else if pcap::is_pcap_magic(magic) {
+            Ok(CaptureReader::Pcap(PcapReader::after_magic(reader, magic)?))
+        } else {
+            Err(CaptureReadError::UnknownFormat {
+                magic: u32::from_be_bytes(magic),
+            })
+        }
+    }
+
+    /*
+     * ファイルの終わりなら`None`.
+     */
+    pub fn next_frame(&mut self) -> Result<Option<CapturedFrame>, CaptureReadError> {
+        match self {
+            CaptureReader::Pcap(reader) => reader.next_frame(),
+            CaptureReader::Pcapng(reader) => reader.next_frame(),
+        }
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<CapturedFrame, CaptureReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+/*
+ * ファイルの終わりちょうどなら`Ok(false)`、途中で終わっていたら`Truncated`.
+ */
+fn read_exact_or_eof<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<bool, CaptureReadError> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => return Err(CaptureReadError::Truncated),
+            Ok(n) => read += n,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error.into()),
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pcap::PcapWriter;
+    use super::pcapng::PcapngWriter;
+    use super::*;
+    use crate::protocol_registry::ProtocolRegistry;
+    use crate::transmission_control_protocol::{TcpHeader, TCP_PROTOCOL_NUMBER};
+
+    /*
+     * テストからも書き込んだ中身を見られるように、共有バッファに書く。
+     */
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_ethernet_frame() {
+        let datagram = [0x45, 0, 0, 20];
+        let frame = LinkType::Ethernet.frame(&datagram);
+        assert_eq!(frame.len(), 18);
+        assert_eq!(&frame[12..14], &[0x08, 0x00]);
+        assert_eq!(LinkType::Ethernet.unframe(&frame), Some(&datagram[..]));
+        assert_eq!(LinkType::Raw.frame(&datagram), Cow::Borrowed(&datagram[..]));
+    }
+
+    #[test]
+    fn test_capture_only_enabled_interfaces() {
+        let buffer = SharedBuffer::default();
+        let writer = PcapWriter::new(buffer.clone(), LinkType::Raw).unwrap();
+        let capture = Arc::new(Mutex::new(PacketCapture::new(Box::new(writer))));
+
+        let mut registry = ProtocolRegistry::new();
+        registry.set_tap(Some(Box::new(CaptureTap::new(capture.clone(), 0))));
+
+        let datagram = [0x45u8; 20];
+        registry.tap_outbound(&datagram);
+        assert_eq!(buffer.0.lock().unwrap().len(), pcap::PCAP_GLOBAL_HEADER_LEN);
+
+        capture.lock().unwrap().enable(0);
+        registry.tap_outbound(&datagram);
+        assert_eq!(
+            buffer.0.lock().unwrap().len(),
+            pcap::PCAP_GLOBAL_HEADER_LEN + pcap::PCAP_RECORD_HEADER_LEN + 20
+        );
+
+        capture.lock().unwrap().disable(0);
+        registry.tap_outbound(&datagram);
+        assert_eq!(
+            buffer.0.lock().unwrap().len(),
+            pcap::PCAP_GLOBAL_HEADER_LEN + pcap::PCAP_RECORD_HEADER_LEN + 20
+        );
+        assert!(capture.lock().unwrap().take_error().is_none());
+    }
+
+    fn sample_datagram() -> Vec<u8> {
+        TcpPacket::new(
+            Ipv4Header::new([10, 0, 0, 1], [10, 0, 0, 2], TCP_PROTOCOL_NUMBER),
+            TcpHeader::new(5432, 3306),
+            b"hello".to_vec(),
+        )
+        .encode()
+        .unwrap()
+    }
+
+    fn write_records(writer: &mut dyn CaptureWriter, datagram: &[u8]) {
+        for (i, direction) in [Direction::Inbound, Direction::Outbound]
+            .into_iter()
+            .enumerate()
+        {
+            writer
+                .write_record(&CaptureRecord {
+                    interface_id: i as InterfaceId,
+                    timestamp: Duration::new(1_700_000_000 + i as u64, 5),
+                    direction,
+                    datagram,
+                })
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_pcap_round_trip() {
+        let datagram = sample_datagram();
+        let mut writer = PcapWriter::new(Vec::new(), LinkType::Ethernet).unwrap();
+        write_records(&mut writer, &datagram);
+
+        let bytes = writer.into_inner();
+        let frames: Vec<CapturedFrame> = CaptureReader::new(&bytes[..])
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].timestamp, Duration::new(1_700_000_000, 5));
+        assert_eq!(frames[0].direction, None);
+        assert_eq!(frames[1].get_datagram(), Some(&datagram[..]));
+
+        let (packet, _) = frames[1]
+            .decode_tcp(&DecodeOptions::strict())
+            .unwrap()
+            .unwrap();
+        assert_eq!(packet.get_payload(), b"hello");
+    }
+
+    #[test]
+    fn test_pcapng_round_trip() {
+        let datagram = sample_datagram();
+        let mut writer = PcapngWriter::new(Vec::new(), LinkType::Raw).unwrap();
+        write_records(&mut writer, &datagram);
+
+        let bytes = writer.into_inner();
+        let frames: Vec<CapturedFrame> = CaptureReader::new(&bytes[..])
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].interface_id, 0);
+        assert_eq!(frames[1].interface_id, 1);
+        assert_eq!(frames[1].timestamp, Duration::new(1_700_000_001, 5));
+        assert_eq!(frames[0].direction, Some(Direction::Inbound));
+        assert_eq!(frames[1].direction, Some(Direction::Outbound));
+        assert_eq!(
+            frames[0].decode_ipv4().unwrap().unwrap().get_protocol(),
+            TCP_PROTOCOL_NUMBER
+        );
+    }
+
+    #[test]
+    fn test_read_truncated_capture() {
+        let mut writer = PcapWriter::new(Vec::new(), LinkType::Raw).unwrap();
+        write_records(&mut writer, &sample_datagram());
+        let bytes = writer.into_inner();
+
+        let mut reader = CaptureReader::new(&bytes[..bytes.len() - 3]).unwrap();
+        assert!(reader.next_frame().unwrap().is_some());
+        assert!(matches!(
+            reader.next_frame(),
+            Err(CaptureReadError::Truncated)
+        ));
+
+        assert!(matches!(
+            CaptureReader::new(&[0u8; 24][..]),
+            Err(CaptureReadError::UnknownFormat { magic: 0 })
+        ));
+    }
+}
@@ -0,0 +1,257 @@
+use crate::packet_capture::{
+    read_exact_or_eof, CaptureReadError, CaptureRecord, CaptureWriter, CapturedFrame, LinkType,
+};
+use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+/*
+ * classic pcap 形式。
+ *
+ * See: https://www.ietf.org/archive/id/draft-ietf-opsawg-pcap-04.html
+ *
+ * NOTE: timestamp はナノ秒精度にしたいので、マイクロ秒版の 0xA1B2C3D4 ではなく
+ *       ナノ秒版の magic number を使う。
+ */
+pub const PCAP_MAGIC_MICROSECONDS: u32 = 0xA1B2_C3D4;
+pub const PCAP_MAGIC_NANOSECONDS: u32 = 0xA1B2_3C4D;
+pub const PCAP_GLOBAL_HEADER_LEN: usize = 24;
+pub const PCAP_RECORD_HEADER_LEN: usize = 16;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+pub const DEFAULT_SNAPLEN: u32 = 262_144;
+
+/*
+ * record の長さはファイルのヘッダーにある snaplen を信用せず、これより大きければ壊れているものとみなす。
+ */
+const MAX_RECORD_LENGTH: u32 = 16 * 1024 * 1024;
+
+/*
+ * NOTE: classic pcap はファイル全体で 1 つのリンク層しか持てず、インターフェースや
+ *       送受信の向きも記録できない。それらが必要なら pcapng を使うこと。
+ */
+pub struct PcapWriter<W: Write> {
+    writer: W,
+    link_type: LinkType,
+    snaplen: u32,
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(writer: W, link_type: LinkType) -> io::Result<Self> {
+        Self::with_snaplen(writer, link_type, DEFAULT_SNAPLEN)
+    }
+
+    /*
+     * `snaplen` より長いパケットは切り詰めて書く。
+     */
+    pub fn with_snaplen(mut writer: W, link_type: LinkType, snaplen: u32) -> io::Result<Self> {
+        writer.write_u32::<LittleEndian>(PCAP_MAGIC_NANOSECONDS)?;
+        writer.write_u16::<LittleEndian>(PCAP_VERSION_MAJOR)?;
+        writer.write_u16::<LittleEndian>(PCAP_VERSION_MINOR)?;
+        writer.write_i32::<LittleEndian>(0)?; // thiszone
+        writer.write_u32::<LittleEndian>(0)?; // sigfigs
+        writer.write_u32::<LittleEndian>(snaplen)?;
+        writer.write_u32::<LittleEndian>(link_type.get_number())?;
+
+        Ok(Self {
+            writer,
+            link_type,
+            snaplen,
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> CaptureWriter for PcapWriter<W> {
+    fn write_record(&mut self, record: &CaptureRecord<'_>) -> io::Result<()> {
+        let frame = self.link_type.frame(record.datagram);
+        let captured_length = frame.len().min(self.snaplen as usize);
+
+        self.writer
+            .write_u32::<LittleEndian>(record.timestamp.as_secs() as u32)?;
+        self.writer
+            .write_u32::<LittleEndian>(record.timestamp.subsec_nanos())?;
+        self.writer
+            .write_u32::<LittleEndian>(captured_length as u32)?;
+        self.writer.write_u32::<LittleEndian>(frame.len() as u32)?;
+        self.writer.write_all(&frame[..captured_length])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/*
+ * magic number はファイルを書いたマシンのバイトオーダーで書かれているので、
+ * リトルエンディアン・ビッグエンディアンどちらで読んで一致するかで判別する。
+ */
+pub(crate) fn is_pcap_magic(magic: [u8; 4]) -> bool {
+    let little = LittleEndian::read_u32(&magic);
+    let big = BigEndian::read_u32(&magic);
+    [little, big]
+        .iter()
+        .any(|&m| m == PCAP_MAGIC_MICROSECONDS || m == PCAP_MAGIC_NANOSECONDS)
+}
+
+pub struct PcapReader<R: Read> {
+    reader: R,
+    big_endian: bool,
+    nanoseconds: bool,
+    link_type: LinkType,
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, CaptureReadError> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if !is_pcap_magic(magic) {
+            return Err(CaptureReadError::UnknownFormat {
+                magic: BigEndian::read_u32(&magic),
+            });
+        }
+        Self::after_magic(reader, magic)
+    }
+
+    /*
+     * magic number を読み終わった後の reader から続きを読む。
+     */
+    pub(crate) fn after_magic(mut reader: R, magic: [u8; 4]) -> Result<Self, CaptureReadError> {
+        let little = LittleEndian::read_u32(&magic);
+        let big_endian = little != PCAP_MAGIC_MICROSECONDS && little != PCAP_MAGIC_NANOSECONDS;
+        let magic = if big_endian {
+            BigEndian::read_u32(&magic)
+        } else {
+            little
+        };
+
+        let mut rest = [0u8; PCAP_GLOBAL_HEADER_LEN - 4];
+        reader.read_exact(&mut rest)?;
+        let network = read_u32(&rest[16..20], big_endian);
+        let link_type =
+            LinkType::from_number(network).ok_or(CaptureReadError::UnsupportedLinkType(network))?;
+
+        Ok(Self {
+            reader,
+            big_endian,
+            nanoseconds: magic == PCAP_MAGIC_NANOSECONDS,
+            link_type,
+        })
+    }
+
+    pub fn get_link_type(&self) -> LinkType {
+        self.link_type
+    }
+
+    pub fn next_frame(&mut self) -> Result<Option<CapturedFrame>, CaptureReadError> {
+        let mut header = [0u8; PCAP_RECORD_HEADER_LEN];
+        if !read_exact_or_eof(&mut self.reader, &mut header)? {
+            return Ok(None);
+        }
+
+        let seconds = read_u32(&header[0..4], self.big_endian);
+        let fraction = read_u32(&header[4..8], self.big_endian);
+        let captured_length = read_u32(&header[8..12], self.big_endian);
+
+        let nanoseconds = if self.nanoseconds {
+            fraction
+        } else {
+            fraction.saturating_mul(1_000)
+        };
+
+        if captured_length > MAX_RECORD_LENGTH {
+            return Err(CaptureReadError::BadRecordLength(captured_length));
+        }
+
+        let mut frame = vec![0u8; captured_length as usize];
+        self.reader.read_exact(&mut frame)?;
+
+        Ok(Some(CapturedFrame {
+            interface_id: 0,
+            timestamp: Duration::new(u64::from(seconds), nanoseconds),
+            direction: None,
+            link_type: self.link_type,
+            frame,
+        }))
+    }
+}
+
+fn read_u32(bytes: &[u8], big_endian: bool) -> u32 {
+    if big_endian {
+        BigEndian::read_u32(bytes)
+    } else {
+        LittleEndian::read_u32(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol_registry::Direction;
+
+    #[test]
+    fn test_write_record() {
+        let mut writer = PcapWriter::with_snaplen(Vec::new(), LinkType::Raw, 4).unwrap();
+        writer
+            .write_record(&CaptureRecord {
+                interface_id: 0,
+                timestamp: Duration::new(1_700_000_000, 123_456_789),
+                direction: Direction::Inbound,
+                datagram: &[1, 2, 3, 4, 5, 6],
+            })
+            .unwrap();
+        let bytes = writer.into_inner();
+
+        assert_eq!(&bytes[0..4], &PCAP_MAGIC_NANOSECONDS.to_le_bytes());
+        assert_eq!(&bytes[20..24], &101u32.to_le_bytes());
+
+        let record = &bytes[PCAP_GLOBAL_HEADER_LEN..];
+        assert_eq!(&record[0..4], &1_700_000_000u32.to_le_bytes());
+        assert_eq!(&record[4..8], &123_456_789u32.to_le_bytes());
+        assert_eq!(&record[8..12], &4u32.to_le_bytes());
+        assert_eq!(&record[12..16], &6u32.to_le_bytes());
+        assert_eq!(&record[16..], &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_read_big_endian_microseconds() {
+        /*
+         * ビッグエンディアンのマシンで書かれた、マイクロ秒精度のファイル。
+         */
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&PCAP_MAGIC_MICROSECONDS.to_be_bytes());
+        bytes.extend_from_slice(&[0, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0]);
+        bytes.extend_from_slice(&65535u32.to_be_bytes());
+        bytes.extend_from_slice(&101u32.to_be_bytes());
+        bytes.extend_from_slice(&10u32.to_be_bytes());
+        bytes.extend_from_slice(&250u32.to_be_bytes());
+        bytes.extend_from_slice(&2u32.to_be_bytes());
+        bytes.extend_from_slice(&2u32.to_be_bytes());
+        bytes.extend_from_slice(&[0xAB, 0xCD]);
+
+        let mut reader = PcapReader::new(&bytes[..]).unwrap();
+        let frame = reader.next_frame().unwrap().unwrap();
+        assert_eq!(frame.timestamp, Duration::new(10, 250_000));
+        assert_eq!(frame.frame, vec![0xAB, 0xCD]);
+        assert!(reader.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_bad_record_length() {
+        let mut bytes = PcapWriter::new(Vec::new(), LinkType::Raw)
+            .unwrap()
+            .into_inner();
+        bytes.extend_from_slice(&[0; 8]);
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+
+        let mut reader = PcapReader::new(&bytes[..]).unwrap();
+        assert!(matches!(
+            reader.next_frame(),
+            Err(CaptureReadError::BadRecordLength(u32::MAX))
+        ));
+    }
+}
@@ -0,0 +1,446 @@
+use crate::packet_capture::{
+    read_exact_or_eof, CaptureReadError, CaptureRecord, CaptureWriter, CapturedFrame, InterfaceId,
+    LinkType,
+};
+use crate::protocol_registry::Direction;
+use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+/*
+ * pcapng 形式。
+ *
+ * See: https://www.ietf.org/archive/id/draft-ietf-opsawg-pcapng-01.html
+ */
+pub const SECTION_HEADER_BLOCK_TYPE: u32 = 0x0A0D_0D0A;
+pub const INTERFACE_DESCRIPTION_BLOCK_TYPE: u32 = 0x0000_0001;
+pub const SIMPLE_PACKET_BLOCK_TYPE: u32 = 0x0000_0003;
+pub const ENHANCED_PACKET_BLOCK_TYPE: u32 = 0x0000_0006;
+pub const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+pub const OPT_ENDOFOPT: u16 = 0;
+pub const IF_NAME: u16 = 2;
+pub const IF_TSRESOL: u16 = 9;
+pub const EPB_FLAGS: u16 = 2;
+
+/*
+ * if_tsresol = 9 は 10^-9 秒、つまりナノ秒単位の timestamp を意味する。
+ */
+const NANOSECOND_RESOLUTION: u8 = 9;
+
+/*
+ * if_tsresol がない場合のデフォルトはマイクロ秒 (10^-6).
+ */
+const DEFAULT_RESOLUTION: u8 = 6;
+
+/*
+ * これより大きい block は壊れているものとみなす。
+ */
+const MAX_BLOCK_LENGTH: u32 = 16 * 1024 * 1024;
+
+/*
+ * epb_flags の下位 2bits が向き。01 = inbound, 10 = outbound.
+ */
+pub const EPB_FLAGS_INBOUND: u32 = 0b01;
+pub const EPB_FLAGS_OUTBOUND: u32 = 0b10;
+
+const DEFAULT_SNAPLEN: u32 = 0; // 0 は無制限を意味する。
+
+/*
+ * pcapng 内での interface の定義。
+ */
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct InterfaceDescription {
+    pub name: String,
+    pub link_type: LinkType,
+}
+
+/*
+ * インターフェースごとに Interface Description Block を書く。
+ *
+ * NOTE: `add_interface` で事前に登録していないインターフェースのパケットが来た場合は、
+ *       `if<id>`という名前、デフォルトのリンク層で自動的に登録する。
+ */
+pub struct PcapngWriter<W: Write> {
+    writer: W,
+    default_link_type: LinkType,
+
+    /*
+     * InterfaceId -> (pcapng の中での interface 番号, 定義)
+     */
+    interfaces: BTreeMap<InterfaceId, (u32, InterfaceDescription)>,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    pub fn new(mut writer: W, default_link_type: LinkType) -> io::Result<Self> {
+        let mut body = Vec::new();
+        body.write_u32::<LittleEndian>(BYTE_ORDER_MAGIC)?;
+        body.write_u16::<LittleEndian>(1)?; // major version
+        body.write_u16::<LittleEndian>(0)?; // minor version
+        body.write_i64::<LittleEndian>(-1)?; // section length は不明
+        write_block(&mut writer, SECTION_HEADER_BLOCK_TYPE, &body)?;
+
+        Ok(Self {
+            writer,
+            default_link_type,
+            interfaces: BTreeMap::new(),
+        })
+    }
+
+    /*
+     * すでに登録済みなら何もしない。
+     */
+    pub fn add_interface(
+        &mut self,
+        interface_id: InterfaceId,
+        description: InterfaceDescription,
+    ) -> io::Result<()> {
+        if self.interfaces.contains_key(&interface_id) {
+            return Ok(());
+        }
+
+        let mut body = Vec::new();
+        body.write_u16::<LittleEndian>(description.link_type.get_number() as u16)?;
+        body.write_u16::<LittleEndian>(0)?; // reserved
+        body.write_u32::<LittleEndian>(DEFAULT_SNAPLEN)?;
+        write_option(&mut body, IF_NAME, description.name.as_bytes())?;
+        write_option(&mut body, IF_TSRESOL, &[NANOSECOND_RESOLUTION])?;
+        write_option(&mut body, OPT_ENDOFOPT, &[])?;
+        write_block(&mut self.writer, INTERFACE_DESCRIPTION_BLOCK_TYPE, &body)?;
+
+        let index = self.interfaces.len() as u32;
+        self.interfaces.insert(interface_id, (index, description));
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> CaptureWriter for PcapngWriter<W> {
+    fn write_record(&mut self, record: &CaptureRecord<'_>) -> io::Result<()> {
+        if !self.interfaces.contains_key(&record.interface_id) {
+            let description = InterfaceDescription {
+                name: format!("if{}", record.interface_id),
+                link_type: self.default_link_type,
+            };
+            self.add_interface(record.interface_id, description)?;
+        }
+        let (index, description) = &self.interfaces[&record.interface_id];
+
+        let frame = description.link_type.frame(record.datagram);
+        let timestamp = record.timestamp.as_nanos() as u64;
+        let flags = match record.direction {
+            Direction::Inbound => EPB_FLAGS_INBOUND,
+            Direction::Outbound => EPB_FLAGS_OUTBOUND,
+        };
+
+        let mut body = Vec::new();
+        body.write_u32::<LittleEndian>(*index)?;
+        body.write_u32::<LittleEndian>((timestamp >> 32) as u32)?;
+        body.write_u32::<LittleEndian>(timestamp as u32)?;
+        body.write_u32::<LittleEndian>(frame.len() as u32)?; // captured length
+        body.write_u32::<LittleEndian>(frame.len() as u32)?; // original length
+        body.extend_from_slice(&frame);
+        pad_to_32bits(&mut body);
+        write_option(&mut body, EPB_FLAGS, &flags.to_le_bytes())?;
+        write_option(&mut body, OPT_ENDOFOPT, &[])?;
+        write_block(&mut self.writer, ENHANCED_PACKET_BLOCK_TYPE, &body)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/*
+ * Section Header Block の block type は回文になっているので、バイトオーダーに関係なく判別できる。
+ */
+pub(crate) fn is_section_header(magic: [u8; 4]) -> bool {
+    LittleEndian::read_u32(&magic) == SECTION_HEADER_BLOCK_TYPE
+}
+
+/*
+ * 読み込み時の interface の情報。
+ */
+struct ReaderInterface {
+    link_type: LinkType,
+    resolution: u8,
+}
+
+pub struct PcapngReader<R: Read> {
+    reader: R,
+    big_endian: bool,
+    interfaces: Vec<ReaderInterface>,
+    offset: u64,
+}
+
+impl<R: Read> PcapngReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, CaptureReadError> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if !is_section_header(magic) {
+            return Err(CaptureReadError::UnknownFormat {
+                magic: BigEndian::read_u32(&magic),
+            });
+        }
+        Self::after_magic(reader, magic)
+    }
+
+    /*
+     * Section Header Block の block type を読み終わった後の reader から続きを読む。
+     */
+    pub(crate) fn after_magic(reader: R, _magic: [u8; 4]) -> Result<Self, CaptureReadError> {
+        let mut pcapng_reader = Self {
+            reader,
+            big_endian: false,
+            interfaces: Vec::new(),
+            offset: 4,
+        };
+        pcapng_reader.read_section_header()?;
+        Ok(pcapng_reader)
+    }
+
+    pub fn next_frame(&mut self) -> Result<Option<CapturedFrame>, CaptureReadError> {
+        loop {
+            let mut block_type = [0u8; 4];
+            if !read_exact_or_eof(&mut self.reader, &mut block_type)? {
+                return Ok(None);
+            }
+            self.offset += 4;
+
+            /*
+             * 新しい section が始まったら、interface の定義もリセットされる。
+             */
+            if is_section_header(block_type) {
+                self.read_section_header()?;
+                continue;
+            }
+
+            let block_type = self.read_u32(&block_type);
+            let body = self.read_block_body()?;
+
+            match block_type {
+                INTERFACE_DESCRIPTION_BLOCK_TYPE => self.read_interface_description(&body)?,
+                ENHANCED_PACKET_BLOCK_TYPE => return self.read_enhanced_packet(&body).map(Some),
+                SIMPLE_PACKET_BLOCK_TYPE => return self.read_simple_packet(&body).map(Some),
+                /*
+                 * 統計情報や名前解決などの block は使わないので読み飛ばす。
+                 */
+                _ => {}
+            }
+        }
+    }
+
+    fn read_section_header(&mut self) -> Result<(), CaptureReadError> {
+        let mut length = [0u8; 4];
+        self.reader.read_exact(&mut length)?;
+        let mut byte_order_magic = [0u8; 4];
+        self.reader.read_exact(&mut byte_order_magic)?;
+
+        self.big_endian = BigEndian::read_u32(&byte_order_magic) == BYTE_ORDER_MAGIC;
+        self.interfaces.clear();
+
+        let total_length = self.read_u32(&length);
+        if total_length < 28 || !total_length.is_multiple_of(4) || total_length > MAX_BLOCK_LENGTH {
+            return Err(CaptureReadError::BadBlockLength {
+                offset: self.offset - 4,
+                length: total_length,
+            });
+        }
+
+        /*
+         * 残り（バージョン、section length, options, 末尾の block total length）は使わない。
+         */
+        let mut rest = vec![0u8; total_length as usize - 12];
+        self.reader.read_exact(&mut rest)?;
+        self.offset += u64::from(total_length) - 4;
+        Ok(())
+    }
+
+    /*
+     * Block Total Length を読んで、body（末尾の Block Total Length を除く）を返す。
+     */
+    fn read_block_body(&mut self) -> Result<Vec<u8>, CaptureReadError> {
+        let mut length = [0u8; 4];
+        self.reader.read_exact(&mut length)?;
+        let total_length = self.read_u32(&length);
+        if total_length < 12 || !total_length.is_multiple_of(4) || total_length > MAX_BLOCK_LENGTH {
+            return Err(CaptureReadError::BadBlockLength {
+                offset: self.offset - 4,
+                length: total_length,
+            });
+        }
+
+        let mut body = vec![0u8; total_length as usize - 8];
+        self.reader.read_exact(&mut body)?;
+        body.truncate(body.len() - 4);
+        self.offset += u64::from(total_length) - 4;
+        Ok(body)
+    }
+
+    fn read_interface_description(&mut self, body: &[u8]) -> Result<(), CaptureReadError> {
+        if body.len() < 8 {
+            return Err(CaptureReadError::Truncated);
+        }
+        let number = u32::from(self.read_u16(&body[0..2]));
+        let link_type =
+            LinkType::from_number(number).ok_or(CaptureReadError::UnsupportedLinkType(number))?;
+
+        let mut resolution = DEFAULT_RESOLUTION;
+        for (code, value) in self.read_options(&body[8..]) {
+            if code == IF_TSRESOL && !value.is_empty() {
+                resolution = value[0];
+            }
+        }
+
+        self.interfaces.push(ReaderInterface {
+            link_type,
+            resolution,
+        });
+        Ok(())
+    }
+
+    fn read_enhanced_packet(&self, body: &[u8]) -> Result<CapturedFrame, CaptureReadError> {
+        if body.len() < 20 {
+            return Err(CaptureReadError::Truncated);
+        }
+        let interface_id = self.read_u32(&body[0..4]);
+        let interface = self
+            .interfaces
+            .get(interface_id as usize)
+            .ok_or(CaptureReadError::UnknownInterface(interface_id))?;
+
+        let timestamp =
+            (u64::from(self.read_u32(&body[4..8])) << 32) | u64::from(self.read_u32(&body[8..12]));
+        let captured_length = self.read_u32(&body[12..16]) as usize;
+        let padded_length = captured_length.next_multiple_of(4);
+        if body.len() < 20 + padded_length {
+            return Err(CaptureReadError::Truncated);
+        }
+        let frame = body[20..20 + captured_length].to_vec();
+
+        let mut direction = None;
+        for (code, value) in self.read_options(&body[20 + padded_length..]) {
+            if code == EPB_FLAGS && value.len() == 4 {
+                direction = match self.read_u32(value) & 0b11 {
+                    EPB_FLAGS_INBOUND => Some(Direction::Inbound),
+                    EPB_FLAGS_OUTBOUND => Some(Direction::Outbound),
+                    _ => None,
+                };
+            }
+        }
+
+        Ok(CapturedFrame {
+            interface_id,
+            timestamp: timestamp_to_duration(timestamp, interface.resolution),
+            direction,
+            link_type: interface.link_type,
+            frame,
+        })
+    }
+
+    /*
+     * Simple Packet Block は timestamp を持たず、常に最初の interface のもの。
+     */
+    fn read_simple_packet(&self, body: &[u8]) -> Result<CapturedFrame, CaptureReadError> {
+        if body.len() < 4 {
+            return Err(CaptureReadError::Truncated);
+        }
+        let interface = self
+            .interfaces
+            .first()
+            .ok_or(CaptureReadError::UnknownInterface(0))?;
+        let original_length = self.read_u32(&body[0..4]) as usize;
+        let captured_length = original_length.min(body.len() - 4);
+
+        Ok(CapturedFrame {
+            interface_id: 0,
+            timestamp: Duration::ZERO,
+            direction: None,
+            link_type: interface.link_type,
+            frame: body[4..4 + captured_length].to_vec(),
+        })
+    }
+
+    /*
+     * (option code, value) の組。壊れていたらそこで打ち切る。
+     */
+    fn read_options<'a>(&self, mut options: &'a [u8]) -> Vec<(u16, &'a [u8])> {
+        let mut result = Vec::new();
+        while options.len() >= 4 {
+            let code = self.read_u16(&options[0..2]);
+            let length = usize::from(self.read_u16(&options[2..4]));
+            if code == OPT_ENDOFOPT || options.len() < 4 + length {
+                break;
+            }
+            result.push((code, &options[4..4 + length]));
+            options = &options[(4 + length.next_multiple_of(4)).min(options.len())..];
+        }
+        result
+    }
+
+    fn read_u16(&self, bytes: &[u8]) -> u16 {
+        if self.big_endian {
+            BigEndian::read_u16(bytes)
+        } else {
+            LittleEndian::read_u16(bytes)
+        }
+    }
+
+    fn read_u32(&self, bytes: &[u8]) -> u32 {
+        if self.big_endian {
+            BigEndian::read_u32(bytes)
+        } else {
+            LittleEndian::read_u32(bytes)
+        }
+    }
+}
+
+/*
+ * if_tsresol の最上位ビットが 0 なら 10^-n 秒単位、1 なら 2^-n 秒単位。
+ */
+fn timestamp_to_duration(timestamp: u64, resolution: u8) -> Duration {
+    let exponent = u32::from(resolution & 0x7F);
+    let nanoseconds = if resolution & 0x80 == 0 {
+        if exponent <= 9 {
+            u128::from(timestamp) * 10u128.pow(9 - exponent)
+        } else {
+            u128::from(timestamp) / 10u128.pow(exponent.min(38) - 9)
+        }
+    } else {
+        (u128::from(timestamp) * 1_000_000_000) >> exponent.min(127)
+    };
+
+    Duration::new(
+        (nanoseconds / 1_000_000_000) as u64,
+        (nanoseconds % 1_000_000_000) as u32,
+    )
+}
+
+/*
+ * Block Type, Block Total Length, Body, Block Total Length の順に書く。
+ */
+fn write_block<W: Write>(writer: &mut W, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let total_length = (12 + body.len()) as u32;
+    writer.write_u32::<LittleEndian>(block_type)?;
+    writer.write_u32::<LittleEndian>(total_length)?;
+    writer.write_all(body)?;
+    writer.write_u32::<LittleEndian>(total_length)
+}
+
+fn write_option(body: &mut Vec<u8>, code: u16, value: &[u8]) -> io::Result<()> {
+    body.write_u16::<LittleEndian>(code)?;
+    body.write_u16::<LittleEndian>(value.len() as u16)?;
+    body.extend_from_slice(value);
+    pad_to_32bits(body);
+    Ok(())
+}
+
+fn pad_to_32bits(body: &mut Vec<u8>) {
+    while !body.len().is_multiple_of(4) {
+        body.push(0);
+    }
+}
@@ -0,0 +1,1409 @@
+use crate::internet_protocol::{Ipv4Address, Ipv4Header};
+use crate::network_stack::NetworkStack;
+use crate::tcp_stack::{SocketError, SocketHandle, SocketOption, TcpStack, UNSPECIFIED_ADDRESS};
+use crate::transmission_control_protocol::tcp_option::TcpOption;
+use crate::transmission_control_protocol::tcp_packet::TcpPacket;
+use crate::transmission_control_protocol::{ControlBits, TcpHeader, TCP_PROTOCOL_NUMBER};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+
+mod parser;
+
+/*
+ * packetdrill (https://github.com/google/packetdrill) 互換のスクリプトを`TcpStack`に対して実行する。
+ *
+ *   0   socket(..., SOCK_STREAM, IPPROTO_TCP) = 3
+ *   +0  bind(3, ..., ...) = 0
+ *   +0  listen(3, 1) = 0
+ *   +0  < S 0:0(0) win 65535 <mss 1460>
+ *   +0  > S. 0:0(0) ack 1 <mss 1460>
+ *
+ * `<`は相手から届くパケットで、スタックに注入する。`>`はスタックが送るはずのパケットで、
+ * 実際に送られたものと比べる。シーケンス番号は packetdrill と同じく ISN からの相対値で書く。
+ * スタック側の ISN は最初に送られた SYN から知る。
+ *
+ * 時刻は仮想時間で、`NetworkStack`のタイマーも仮想時間で進める。
+ *
+ * NOTE: 対応しているのは IPv4 の TCP と、よく使われるシステムコールだけ。
+ *       シェルコマンド（`...`）は読み飛ばす。
+ */
+#[derive(Debug)]
+pub struct Script {
+    events: Vec<Event>,
+
+    /*
+     * 送信パケットの時刻の許容誤差。`--tolerance_usecs`で変えられる。
+     */
+    tolerance: Duration,
+}
+
+/*
+ * スクリプト中のスタック側と相手側のアドレス。
+ */
+pub const LOCAL_ADDRESS: Ipv4Address = [192, 168, 0, 1];
+pub const REMOTE_ADDRESS: Ipv4Address = [192, 0, 2, 1];
+
+/*
+ * `bind(3, ..., ...)`で使うポート。`connect(3, ..., ...)`では相手のポートになる。
+ */
+pub const SCRIPT_PORT: u16 = 8080;
+
+/*
+ * listen しているスタックに相手が接続してくる時の、相手のポート。
+ */
+pub const REMOTE_EPHEMERAL_PORT: u16 = 48000;
+
+/*
+ * 相手側の ISN. スクリプト中の`<`のシーケンス番号はこれからの相対値。
+ */
+const REMOTE_INITIAL_SEQUENCE: u32 = 0x1000_0000;
+
+/*
+ * packetdrill のデフォルトと同じ 4 ms.
+ */
+const DEFAULT_TOLERANCE: Duration = Duration::from_micros(4000);
+
+/*
+ * `<`で win を省略した時のウィンドウ。
+ */
+const DEFAULT_INBOUND_WINDOW: u16 = 65535;
+
+#[derive(Debug)]
+pub(crate) struct Event {
+    line: usize,
+    timing: Timing,
+    action: Action,
+}
+
+/*
+ * `0.1`, `+0.1`, `*`, `0.1~0.2`（この範囲のどこか）, `0.1...0.2`（0.1 に呼んで 0.2 に戻る）.
+ */
+#[derive(Debug)]
+pub(crate) struct Timing {
+    start: TimeSpec,
+    end: Option<TimeSpec>,
+    blocking: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum TimeSpec {
+    Any,
+    Absolute(Duration),
+
+    /*
+     * 1 つ前のイベントの時刻からの相対値。
+     */
+    Relative(Duration),
+}
+
+#[derive(Debug)]
+pub(crate) enum Action {
+    Packet(PacketSpec),
+    Syscall(Syscall),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Debug)]
+pub(crate) struct PacketSpec {
+    direction: Direction,
+    control_bits: ControlBits,
+    sequence: u32,
+    length: u32,
+    acknowledgment: Option<u32>,
+
+    /*
+     * 送信パケットでは、省略されたら比べない。
+     */
+    window: Option<u16>,
+    options: Option<Vec<TcpOption>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Syscall {
+    name: String,
+    arguments: Vec<String>,
+    expected: ExpectedResult,
+}
+
+/*
+ * `= 3`, `= -1 EAGAIN`, `= O_RDWR|O_NONBLOCK`. 説明の`(...)`は捨てる。
+ */
+#[derive(Debug)]
+pub(crate) struct ExpectedResult {
+    value: String,
+    errno: Option<String>,
+}
+
+impl fmt::Display for ExpectedResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.errno {
+            Some(errno) => write!(f, "{} {}", self.value, errno),
+            None => write!(f, "{}", self.value),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PacketdrillError {
+    pub line: usize,
+    pub kind: PacketdrillErrorKind,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum PacketdrillErrorKind {
+    Parse(String),
+    Unsupported(String),
+    SyscallResult {
+        syscall: String,
+        expected: String,
+        actual: String,
+    },
+
+    /*
+     * スクリプトにないパケットが送られた。
+     */
+    UnexpectedPacket {
+        actual: String,
+        time: Duration,
+    },
+    MissingPacket {
+        expected: String,
+    },
+    PacketMismatch {
+        expected: String,
+        actual: String,
+        differences: Vec<String>,
+    },
+    TimingMismatch {
+        event: String,
+        earliest: Duration,
+        latest: Option<Duration>,
+        actual: Duration,
+    },
+
+    /*
+     * スクリプトの終わりまでブロックしたままだった。
+     */
+    StillBlocked {
+        syscall: String,
+    },
+}
+
+impl fmt::Display for PacketdrillError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            PacketdrillErrorKind::Parse(message) => write!(f, "{}", message),
+            PacketdrillErrorKind::Unsupported(message) => write!(f, "Unsupported: {}", message),
+            PacketdrillErrorKind::SyscallResult {
+                syscall,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{}() returned {}, but the script expected {}.",
+                syscall, actual, expected
+            ),
+            PacketdrillErrorKind::UnexpectedPacket { actual, time } => write!(
+                f,
+                "Unexpected outbound packet at {}.\n  actual:   {}",
+                format_time(*time),
+                actual
+            ),
+            PacketdrillErrorKind::MissingPacket { expected } => {
+                write!(
+                    f,
+                    "Expected outbound packet was not sent.\n  expected: {}",
+                    expected
+                )
+            }
+            PacketdrillErrorKind::PacketMismatch {
+                expected,
+                actual,
+                differences,
+            } => {
+                write!(
+                    f,
+                    "Outbound packet does not match.\n  expected: {}\n  actual:   {}",
+                    expected, actual
+                )?;
+                for difference in differences {
+                    write!(f, "\n  - {}", difference)?;
+                }
+                Ok(())
+            }
+            PacketdrillErrorKind::TimingMismatch {
+                event,
+                earliest,
+                latest,
+                actual,
+            } => {
+                write!(f, "{} happened at {}, ", event, format_time(*actual))?;
+                match latest {
+                    Some(latest) => write!(
+                        f,
+                        "but the script expected {} to {}.",
+                        format_time(*earliest),
+                        format_time(*latest)
+                    ),
+                    None => write!(
+                        f,
+                        "but the script expected {} or later.",
+                        format_time(*earliest)
+                    ),
+                }
+            }
+            PacketdrillErrorKind::StillBlocked { syscall } => {
+                write!(
+                    f,
+                    "{}() was still blocked at the end of the script.",
+                    syscall
+                )
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for PacketdrillError {}
+
+fn format_time(time: Duration) -> String {
+    format!("{}.{:06}", time.as_secs(), time.subsec_micros())
+}
+
+impl Script {
+    pub fn parse(input: &str) -> Result<Self, PacketdrillError> {
+        parser::parse(input)
+    }
+
+    pub fn get_tolerance(&self) -> Duration {
+        self.tolerance
+    }
+
+    pub fn set_tolerance(&mut self, tolerance: Duration) {
+        self.tolerance = tolerance;
+    }
+
+    /*
+     * `LOCAL_ADDRESS`を持つデフォルト設定のスタックで実行する。
+     */
+    pub fn run(&self) -> Result<(), PacketdrillError> {
+        self.run_with_stack(TcpStack::new(&[LOCAL_ADDRESS]))
+    }
+
+    /*
+     * 設定を変えたスタックで実行する。スタックは`LOCAL_ADDRESS`を持っていること。
+     */
+    pub fn run_with_stack(&self, stack: TcpStack) -> Result<(), PacketdrillError> {
+        Runner::new(self, stack).run()
+    }
+}
+
+/*
+ * ファイルを読んで実行する。
+ */
+#[cfg(feature = "std")]
+pub fn run_file(path: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
+    let input = std::fs::read_to_string(path)?;
+    Script::parse(&input)?.run()?;
+    Ok(())
+}
+
+struct FileDescriptor {
+    handle: SocketHandle,
+    nonblocking: bool,
+}
+
+/*
+ * ブロックしているシステムコール。イベントのたびにやり直す。
+ */
+struct BlockedCall<'a> {
+    line: usize,
+    syscall: &'a Syscall,
+
+    /*
+     * `...`で書かれていれば、戻るはずの時刻。
+     */
+    return_time: Option<Duration>,
+}
+
+/*
+ * システムコールの結果。`Err`は errno 名。
+ */
+type SyscallResult = Result<String, &'static str>;
+
+struct Runner<'a> {
+    script: &'a Script,
+    stack: TcpStack,
+    now: Duration,
+    last_event_time: Duration,
+    files: BTreeMap<i32, FileDescriptor>,
+    blocked: Option<BlockedCall<'a>>,
+
+    /*
+     * スタックが送ったが、まだスクリプトと照合していないパケットと送った時刻。
+     */
+    outbound: VecDeque<(Duration, Vec<u8>)>,
+
+    local_port: u16,
+    remote_port: u16,
+    local_initial_sequence: Option<u32>,
+}
+
+impl<'a> Runner<'a> {
+    fn new(script: &'a Script, stack: TcpStack) -> Self {
+        Self {
+            script,
+            stack,
+            now: Duration::ZERO,
+            last_event_time: Duration::ZERO,
+            files: BTreeMap::new(),
+            blocked: None,
+            outbound: VecDeque::new(),
+            local_port: SCRIPT_PORT,
+            remote_port: REMOTE_EPHEMERAL_PORT,
+            local_initial_sequence: None,
+        }
+    }
+
+    fn run(mut self) -> Result<(), PacketdrillError> {
+        for event in &self.script.events {
+            let start = self.resolve(event.timing.start);
+            let end = event.timing.end.and_then(|end| self.resolve(end));
+
+            match &event.action {
+                Action::Packet(spec) if spec.direction == Direction::Outbound => {
+                    let sent_at = self.expect_outbound(event.line, spec, start, end)?;
+                    self.last_event_time = start.unwrap_or(sent_at);
+                }
+                Action::Packet(spec) => {
+                    self.advance_to(event.line, start)?;
+                    self.inject(event.line, spec)?;
+                    self.last_event_time = start.unwrap_or(self.now);
+                }
+                Action::Syscall(syscall) => {
+                    self.advance_to(event.line, start)?;
+                    let return_time = if event.timing.blocking { end } else { None };
+                    self.call(event.line, syscall, return_time)?;
+                    self.last_event_time = start.unwrap_or(self.now);
+                }
+            }
+            self.collect_outbound();
+            self.retry_blocked()?;
+        }
+
+        match self.blocked {
+            Some(blocked) => Err(PacketdrillError {
+                line: blocked.line,
+                kind: PacketdrillErrorKind::StillBlocked {
+                    syscall: blocked.syscall.name.clone(),
+                },
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn resolve(&self, time: TimeSpec) -> Option<Duration> {
+        match time {
+            TimeSpec::Any => None,
+            TimeSpec::Absolute(time) => Some(time),
+            TimeSpec::Relative(offset) => Some(self.last_event_time + offset),
+        }
+    }
+
+    fn collect_outbound(&mut self) {
+        while let Some(datagram) = self.stack.poll_transmit(self.now) {
+            self.outbound.push_back((self.now, datagram));
+        }
+    }
+
+    /*
+     * タイマーが`deadline`までに切れるなら、時刻を進めて発火させる。発火させたら`true`.
+     */
+    fn fire_timer(&mut self, deadline: Option<Duration>) -> Result<bool, PacketdrillError> {
+        match self.stack.poll_timeout() {
+            Some(timeout) if deadline.is_none_or(|deadline| timeout <= deadline) => {
+                self.now = self.now.max(timeout);
+                self.stack.handle_timeout(self.now);
+                self.collect_outbound();
+                self.retry_blocked()?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /*
+     * `target`まで時刻を進める。その間にスクリプトにないパケットが送られたらエラー。
+     */
+    fn advance_to(
+        &mut self,
+        line: usize,
+        target: Option<Duration>,
+    ) -> Result<(), PacketdrillError> {
+        let target = target.unwrap_or(self.now);
+        loop {
+            self.collect_outbound();
+            if let Some((time, datagram)) = self.outbound.front() {
+                return Err(PacketdrillError {
+                    line,
+                    kind: PacketdrillErrorKind::UnexpectedPacket {
+                        actual: self.describe_outbound(datagram),
+                        time: *time,
+                    },
+                });
+            }
+            if !self.fire_timer(Some(target))? {
+                break;
+            }
+        }
+        self.now = self.now.max(target);
+        Ok(())
+    }
+
+    fn expect_outbound(
+        &mut self,
+        line: usize,
+        spec: &PacketSpec,
+        start: Option<Duration>,
+        end: Option<Duration>,
+    ) -> Result<Duration, PacketdrillError> {
+        let tolerance = self.script.tolerance;
+        let earliest = start.map_or(self.last_event_time, |start| {
+            start.saturating_sub(tolerance)
+        });
+        let latest = end.or(start).map(|end| end + tolerance);
+
+        loop {
+            self.collect_outbound();
+            if !self.outbound.is_empty() || !self.fire_timer(latest)? {
+                break;
+            }
+        }
+        let Some((sent_at, datagram)) = self.outbound.pop_front() else {
+            return Err(PacketdrillError {
+                line,
+                kind: PacketdrillErrorKind::MissingPacket {
+                    expected: format_spec(spec),
+                },
+            });
+        };
+
+        if sent_at < earliest || latest.is_some_and(|latest| sent_at > latest) {
+            return Err(PacketdrillError {
+                line,
+                kind: PacketdrillErrorKind::TimingMismatch {
+                    event: format!("Outbound packet '{}'", self.describe_outbound(&datagram)),
+                    earliest,
+                    latest,
+                    actual: sent_at,
+                },
+            });
+        }
+
+        let packet = match TcpPacket::decode(&datagram) {
+            Ok(packet) => packet,
+            Err(error) => {
+                return Err(PacketdrillError {
+                    line,
+                    kind: PacketdrillErrorKind::PacketMismatch {
+                        expected: format_spec(spec),
+                        actual: format!("undecodable datagram ({})", error),
+                        differences: Vec::new(),
+                    },
+                })
+            }
+        };
+        self.compare(line, spec, &packet)?;
+        Ok(sent_at)
+    }
+
+    fn compare(
+        &mut self,
+        line: usize,
+        spec: &PacketSpec,
+        packet: &TcpPacket,
+    ) -> Result<(), PacketdrillError> {
+        let tcp_header = packet.get_tcp_header();
+        let mut differences = Vec::new();
+
+        let actual_route = (
+            packet.get_source_address(),
+            tcp_header.get_source_port(),
+            packet.get_destination_address(),
+            tcp_header.get_destination_port(),
+        );
+        let expected_route = (
+            LOCAL_ADDRESS,
+            self.local_port,
+            REMOTE_ADDRESS,
+            self.remote_port,
+        );
+        if actual_route != expected_route {
+            differences.push(format!(
+                "addresses: expected {}, actual {}",
+                format_route(expected_route),
+                format_route(actual_route)
+            ));
+        }
+
+        let control_bits = tcp_header.get_control_bits();
+        if control_bits != spec.control_bits {
+            differences.push(format!(
+                "flags: expected {}, actual {}",
+                format_flags(spec.control_bits),
+                format_flags(control_bits)
+            ));
+        }
+
+        /*
+         * スタックの ISN は最初の SYN で決まる。
+         */
+        if control_bits.get_syn() && self.local_initial_sequence.is_none() {
+            self.local_initial_sequence =
+                Some(tcp_header.get_sequence_number().wrapping_sub(spec.sequence));
+        }
+        let local_initial_sequence = self.local_initial_sequence.unwrap_or(0);
+
+        let sequence = tcp_header
+            .get_sequence_number()
+            .wrapping_sub(local_initial_sequence);
+        let length = packet.get_payload().len() as u32;
+        if (sequence, length) != (spec.sequence, spec.length) {
+            differences.push(format!(
+                "sequence: expected {}, actual {}",
+                format_range(spec.sequence, spec.length),
+                format_range(sequence, length)
+            ));
+        }
+
+        if let Some(expected) = spec.acknowledgment {
+            let acknowledgment = tcp_header
+                .get_acknowledgment_number()
+                .wrapping_sub(REMOTE_INITIAL_SEQUENCE);
+            if control_bits.get_ack() && acknowledgment != expected {
+                differences.push(format!(
+                    "ack: expected {}, actual {}",
+                    expected, acknowledgment
+                ));
+            }
+        }
+
+        if let Some(expected) = spec.window {
+            if tcp_header.get_window() != expected {
+                differences.push(format!(
+                    "win: expected {}, actual {}",
+                    expected,
+                    tcp_header.get_window()
+                ));
+            }
+        }
+
+        if let Some(expected) = &spec.options {
+            let actual = relative_options(&tcp_header.get_options(), REMOTE_INITIAL_SEQUENCE);
+            if !options_match(expected, &actual) {
+                differences.push(format!(
+                    "options: expected {}, actual {}",
+                    format_options(expected),
+                    format_options(&actual)
+                ));
+            }
+        }
+
+        if differences.is_empty() {
+            return Ok(());
+        }
+        Err(PacketdrillError {
+            line,
+            kind: PacketdrillErrorKind::PacketMismatch {
+                expected: format_spec(spec),
+                actual: self.describe_packet(packet),
+                differences,
+            },
+        })
+    }
+
+    fn inject(&mut self, line: usize, spec: &PacketSpec) -> Result<(), PacketdrillError> {
+        let local_initial_sequence = self.local_initial_sequence.unwrap_or(0);
+
+        let mut tcp_header = TcpHeader::new(self.remote_port, self.local_port);
+        tcp_header.set_sequence_number(REMOTE_INITIAL_SEQUENCE.wrapping_add(spec.sequence));
+        if let Some(acknowledgment) = spec.acknowledgment {
+            tcp_header
+                .set_acknowledgment_number(local_initial_sequence.wrapping_add(acknowledgment));
+        }
+        tcp_header.set_control_bits(spec.control_bits);
+        tcp_header.set_window(spec.window.unwrap_or(DEFAULT_INBOUND_WINDOW));
+        if let Some(options) = &spec.options {
+            /*
+             * 長さはパースした時に確かめてある。
+             */
+            tcp_header
+                .set_options(&absolute_options(options, local_initial_sequence))
+                .unwrap();
+        }
+
+        let packet = TcpPacket::new(
+            Ipv4Header::new(REMOTE_ADDRESS, LOCAL_ADDRESS, TCP_PROTOCOL_NUMBER),
+            tcp_header,
+            vec![0; spec.length as usize],
+        );
+        let datagram = packet.encode().map_err(|error| PacketdrillError {
+            line,
+            kind: PacketdrillErrorKind::Parse(error.to_string()),
+        })?;
+        self.stack.receive(self.now, &datagram);
+        Ok(())
+    }
+
+    fn call(
+        &mut self,
+        line: usize,
+        syscall: &'a Syscall,
+        return_time: Option<Duration>,
+    ) -> Result<(), PacketdrillError> {
+        if let Some(blocked) = &self.blocked {
+            return Err(PacketdrillError {
+                line,
+                kind: PacketdrillErrorKind::Unsupported(format!(
+                    "{}() is called while {}() on line {} is still blocked.",
+                    syscall.name, blocked.syscall.name, blocked.line
+                )),
+            });
+        }
+
+        let blocked = BlockedCall {
+            line,
+            syscall,
+            return_time,
+        };
+        match self.execute(line, syscall, true)? {
+            Some(result) => self.finish(&blocked, result),
+            None => {
+                self.blocked = Some(blocked);
+                Ok(())
+            }
+        }
+    }
+
+    fn retry_blocked(&mut self) -> Result<(), PacketdrillError> {
+        let Some(blocked) = self.blocked.take() else {
+            return Ok(());
+        };
+        match self.execute(blocked.line, blocked.syscall, false)? {
+            Some(result) => self.finish(&blocked, result),
+            None => {
+                self.blocked = Some(blocked);
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(
+        &self,
+        call: &BlockedCall<'_>,
+        result: SyscallResult,
+    ) -> Result<(), PacketdrillError> {
+        if let Some(return_time) = call.return_time {
+            let tolerance = self.script.tolerance;
+            let earliest = return_time.saturating_sub(tolerance);
+            let latest = return_time + tolerance;
+            if self.now < earliest || self.now > latest {
+                return Err(PacketdrillError {
+                    line: call.line,
+                    kind: PacketdrillErrorKind::TimingMismatch {
+                        event: format!("Return from {}()", call.syscall.name),
+                        earliest,
+                        latest: Some(latest),
+                        actual: self.now,
+                    },
+                });
+            }
+        }
+
+        let expected = &call.syscall.expected;
+        let (matches, actual) = match result {
+            Ok(value) => (expected.errno.is_none() && expected.value == value, value),
+            Err(errno) => (
+                expected.value == "-1" && expected.errno.as_deref() == Some(errno),
+                format!("-1 {}", errno),
+            ),
+        };
+        if matches {
+            return Ok(());
+        }
+        Err(PacketdrillError {
+            line: call.line,
+            kind: PacketdrillErrorKind::SyscallResult {
+                syscall: call.syscall.name.clone(),
+                expected: expected.to_string(),
+                actual,
+            },
+        })
+    }
+
+    /*
+     * システムコールを実行する。ブロッキングの fd でまだ完了しないなら`None`.
+     *
+     * `first_attempt`が`false`なら、ブロックしていた呼び出しのやり直し。
+     */
+    fn execute(
+        &mut self,
+        line: usize,
+        syscall: &Syscall,
+        first_attempt: bool,
+    ) -> Result<Option<SyscallResult>, PacketdrillError> {
+        let arguments = &syscall.arguments;
+        let argument = |index: usize| -> Result<&str, PacketdrillError> {
+            arguments
+                .get(index)
+                .map(String::as_str)
+                .ok_or_else(|| PacketdrillError {
+                    line,
+                    kind: PacketdrillErrorKind::Parse(format!(
+                        "{}() needs at least {} arguments.",
+                        syscall.name,
+                        index + 1
+                    )),
+                })
+        };
+        let unsupported = |message: String| PacketdrillError {
+            line,
+            kind: PacketdrillErrorKind::Unsupported(message),
+        };
+
+        if syscall.name == "socket" {
+            if argument(1)? != "SOCK_STREAM" {
+                return Err(unsupported(format!("Socket type {}.", argument(1)?)));
+            }
+            let handle = self.stack.socket();
+            let fd = self.allocate_fd();
+            self.files.insert(
+                fd,
+                FileDescriptor {
+                    handle,
+                    nonblocking: false,
+                },
+            );
+            return Ok(Some(Ok(fd.to_string())));
+        }
+
+        let fd = argument(0)?;
+        let Some((fd, handle, nonblocking)) = fd.parse::<i32>().ok().and_then(|fd| {
+            self.files
+                .get(&fd)
+                .map(|file| (fd, file.handle, file.nonblocking))
+        }) else {
+            return Ok(Some(Err(SocketError::InvalidHandle.get_errno_name())));
+        };
+        let would_block = |error: SocketError| -> Option<SyscallResult> {
+            match error {
+                SocketError::WouldBlock if !nonblocking => None,
+                error => Some(Err(error.get_errno_name())),
+            }
+        };
+        let now = self.now;
+
+        let result = match syscall.name.as_str() {
+            "bind" => self
+                .stack
+                .bind(handle, UNSPECIFIED_ADDRESS, SCRIPT_PORT)
+                .map(|()| "0".to_string())
+                .map_err(|error| error.get_errno_name()),
+            "listen" => {
+                let backlog = parse_number(argument(1)?).ok_or_else(|| {
+                    unsupported(format!("Backlog {}.", argument(1).unwrap_or_default()))
+                })?;
+                self.stack
+                    .listen(handle, backlog as usize)
+                    .map(|()| "0".to_string())
+                    .map_err(|error| error.get_errno_name())
+            }
+            "accept" => match self.stack.accept(handle) {
+                Ok(child) => {
+                    let child_fd = self.allocate_fd();
+                    self.files.insert(
+                        child_fd,
+                        FileDescriptor {
+                            handle: child,
+                            nonblocking: false,
+                        },
+                    );
+                    Ok(child_fd.to_string())
+                }
+                Err(error) => return Ok(would_block(error)),
+            },
+            "connect" => return Ok(self.connect(handle, nonblocking, first_attempt)),
+            "write" | "send" => {
+                let length = parse_number(argument(2)?).ok_or_else(|| {
+                    unsupported(format!("Length {}.", argument(2).unwrap_or_default()))
+                })?;
+                match self.stack.send(now, handle, &vec![0; length as usize]) {
+                    Ok(sent) => Ok(sent.to_string()),
+                    Err(error) => return Ok(would_block(error)),
+                }
+            }
+            "read" | "recv" => {
+                let length = parse_number(argument(2)?).ok_or_else(|| {
+                    unsupported(format!("Length {}.", argument(2).unwrap_or_default()))
+                })?;
+                match self.stack.recv(now, handle, &mut vec![0; length as usize]) {
+                    Ok(received) => Ok(received.to_string()),
+                    Err(error) => return Ok(would_block(error)),
+                }
+            }
+            "shutdown" => match argument(1)? {
+                "SHUT_WR" | "SHUT_RDWR" => self
+                    .stack
+                    .shutdown(now, handle)
+                    .map(|()| "0".to_string())
+                    .map_err(|error| error.get_errno_name()),
+                "SHUT_RD" => Ok("0".to_string()),
+                how => return Err(unsupported(format!("shutdown({}).", how))),
+            },
+            "close" => {
+                self.files.remove(&fd);
+                self.stack
+                    .close(now, handle)
+                    .map(|()| "0".to_string())
+                    .map_err(|error| error.get_errno_name())
+            }
+            "fcntl" => match argument(1)? {
+                "F_GETFL" if nonblocking => Ok("O_RDWR|O_NONBLOCK".to_string()),
+                "F_GETFL" => Ok("O_RDWR".to_string()),
+                "F_SETFL" => {
+                    let nonblocking = argument(2)?
+                        .split('|')
+                        .any(|flag| flag.trim() == "O_NONBLOCK");
+                    if let Some(file) = self.files.get_mut(&fd) {
+                        file.nonblocking = nonblocking;
+                    }
+                    Ok("0".to_string())
+                }
+                command => return Err(unsupported(format!("fcntl({}).", command))),
+            },
+            "setsockopt" => {
+                let value = parse_number(strip_brackets(argument(3)?));
+                let option = match (argument(1)?, argument(2)?, value) {
+                    ("SOL_SOCKET", "SO_REUSEADDR", Some(value)) => {
+                        SocketOption::ReuseAddress(value != 0)
+                    }
+                    ("SOL_SOCKET", "SO_REUSEPORT", Some(value)) => {
+                        SocketOption::ReusePort(value != 0)
+                    }
+                    ("SOL_SOCKET", "SO_SNDBUF", Some(value)) => {
+                        SocketOption::SendBufferSize(value as usize)
+                    }
+                    ("SOL_SOCKET", "SO_RCVBUF", Some(value)) => {
+                        SocketOption::ReceiveBufferSize(value as usize)
+                    }
+                    ("IPPROTO_TCP" | "SOL_TCP", "TCP_NODELAY", Some(value)) => {
+                        SocketOption::NoDelay(value != 0)
+                    }
+                    ("IPPROTO_TCP" | "SOL_TCP", "TCP_CORK", Some(value)) => {
+                        SocketOption::Cork(value != 0)
+                    }
+                    ("IPPROTO_TCP" | "SOL_TCP", "TCP_QUICKACK", Some(value)) => {
+                        SocketOption::QuickAck(value != 0)
+                    }
+                    ("SOL_SOCKET", "SO_KEEPALIVE", Some(value)) => {
+                        SocketOption::KeepAlive(value != 0)
+                    }
+                    ("IPPROTO_TCP" | "SOL_TCP", "TCP_KEEPIDLE", Some(value)) => {
+                        SocketOption::KeepAliveIdle(Duration::from_secs(value))
+                    }
+                    ("IPPROTO_TCP" | "SOL_TCP", "TCP_KEEPINTVL", Some(value)) => {
+                        SocketOption::KeepAliveInterval(Duration::from_secs(value))
+                    }
+                    ("IPPROTO_TCP" | "SOL_TCP", "TCP_KEEPCNT", Some(value)) => {
+                        SocketOption::KeepAliveCount(value as u32)
+                    }
+                    ("IPPROTO_TCP" | "SOL_TCP", "TCP_USER_TIMEOUT", Some(value)) => {
+                        SocketOption::UserTimeout(Duration::from_millis(value))
+                    }
+                    (level, name, _) => {
+                        return Err(unsupported(format!("setsockopt({}, {}).", level, name)))
+                    }
+                };
+                self.stack
+                    .set_option(handle, option)
+                    .map(|()| "0".to_string())
+                    .map_err(|error| error.get_errno_name())
+            }
+            "getsockopt" => match (argument(1)?, argument(2)?) {
+                ("SOL_SOCKET", "SO_ERROR") => {
+                    let error = self.stack.take_error(handle).ok().flatten();
+                    let expected = strip_brackets(argument(3)?);
+                    let expected_errno = errno_name(expected);
+                    let actual = error.map(|error| error.get_errno_name());
+                    if expected_errno != actual {
+                        return Err(PacketdrillError {
+                            line,
+                            kind: PacketdrillErrorKind::SyscallResult {
+                                syscall: "getsockopt(SO_ERROR)".to_string(),
+                                expected: expected.to_string(),
+                                actual: actual.unwrap_or("0").to_string(),
+                            },
+                        });
+                    }
+                    Ok("0".to_string())
+                }
+                (level, name) => {
+                    return Err(unsupported(format!("getsockopt({}, {}).", level, name)))
+                }
+            },
+            name => return Err(unsupported(format!("System call {}().", name))),
+        };
+        self.collect_outbound();
+        Ok(Some(result))
+    }
+
+    fn connect(
+        &mut self,
+        handle: SocketHandle,
+        nonblocking: bool,
+        first_attempt: bool,
+    ) -> Option<SyscallResult> {
+        if first_attempt {
+            if let Err(error) = self
+                .stack
+                .connect(self.now, handle, REMOTE_ADDRESS, SCRIPT_PORT)
+            {
+                return Some(Err(error.get_errno_name()));
+            }
+            self.remote_port = SCRIPT_PORT;
+            if let Ok(local) = self.stack.get_local_endpoint(handle) {
+                self.local_port = local.port;
+            }
+            self.collect_outbound();
+        }
+
+        if let Ok(Some(error)) = self.stack.take_error(handle) {
+            return Some(Err(error.get_errno_name()));
+        }
+        match self.stack.get_state(handle) {
+            Ok(state) if state.is_synchronized() => Some(Ok("0".to_string())),
+            Ok(_) if nonblocking => Some(Err("EINPROGRESS")),
+            Ok(_) => None,
+            Err(error) => Some(Err(error.get_errno_name())),
+        }
+    }
+
+    /*
+     * Linux と同じく、空いている最小の番号。0 から 2 は標準入出力が使っている。
+     */
+    fn allocate_fd(&self) -> i32 {
+        (3..).find(|fd| !self.files.contains_key(fd)).unwrap()
+    }
+
+    fn describe_outbound(&self, datagram: &[u8]) -> String {
+        match TcpPacket::decode(datagram) {
+            Ok(packet) => self.describe_packet(&packet),
+            Err(error) => format!("undecodable datagram ({})", error),
+        }
+    }
+
+    /*
+     * 送られたパケットをスクリプトと同じ書式にする。
+     */
+    fn describe_packet(&self, packet: &TcpPacket) -> String {
+        let tcp_header = packet.get_tcp_header();
+        let control_bits = tcp_header.get_control_bits();
+
+        /*
+         * まだ ISN を知らなければ、SYN のシーケンス番号を ISN とみなす。
+         */
+        let local_initial_sequence = self
+            .local_initial_sequence
+            .or(control_bits
+                .get_syn()
+                .then(|| tcp_header.get_sequence_number()))
+            .unwrap_or(0);
+
+        let mut description = format!(
+            "> {} {}",
+            format_flags(control_bits),
+            format_range(
+                tcp_header
+                    .get_sequence_number()
+                    .wrapping_sub(local_initial_sequence),
+                packet.get_payload().len() as u32
+            )
+        );
+        if control_bits.get_ack() {
+            description.push_str(&format!(
+                " ack {}",
+                tcp_header
+                    .get_acknowledgment_number()
+                    .wrapping_sub(REMOTE_INITIAL_SEQUENCE)
+            ));
+        }
+        description.push_str(&format!(" win {}", tcp_header.get_window()));
+        let options = relative_options(&tcp_header.get_options(), REMOTE_INITIAL_SEQUENCE);
+        if !options.is_empty() {
+            description.push(' ');
+            description.push_str(&format_options(&options));
+        }
+        description
+    }
+}
+
+fn parse_number(input: &str) -> Option<u64> {
+    input.trim().parse().ok()
+}
+
+fn strip_brackets(input: &str) -> &str {
+    input
+        .trim()
+        .strip_prefix('[')
+        .and_then(|input| input.strip_suffix(']'))
+        .unwrap_or(input)
+        .trim()
+}
+
+/*
+ * SO_ERROR の値。0 ならエラーなし。数値でも errno 名でも書ける。
+ */
+fn errno_name(value: &str) -> Option<&'static str> {
+    const ERRNOS: [(&str, u32); 11] = [
+        ("EPIPE", 32),
+        ("EBADF", 9),
+        ("EAGAIN", 11),
+        ("EINVAL", 22),
+        ("EADDRINUSE", 98),
+        ("EADDRNOTAVAIL", 99),
+        ("ECONNRESET", 104),
+        ("EISCONN", 106),
+        ("ENOTCONN", 107),
+        ("ETIMEDOUT", 110),
+        ("ECONNREFUSED", 111),
+    ];
+    ERRNOS
+        .iter()
+        .find(|(name, number)| *name == value || parse_number(value) == Some(u64::from(*number)))
+        .map(|(name, _)| *name)
+}
+
+/*
+ * SACK のブロックを ISN からの相対値にする。
+ */
+fn relative_options(options: &[TcpOption], initial_sequence: u32) -> Vec<TcpOption> {
+    options
+        .iter()
+        .map(|option| match option {
+            TcpOption::Sack(blocks) => TcpOption::Sack(
+                blocks
+                    .iter()
+                    .map(|(left, right)| {
+                        (
+                            left.wrapping_sub(initial_sequence),
+                            right.wrapping_sub(initial_sequence),
+                        )
+                    })
+                    .collect(),
+            ),
+            option => option.clone(),
+        })
+        .collect()
+}
+
+fn absolute_options(options: &[TcpOption], initial_sequence: u32) -> Vec<TcpOption> {
+    relative_options(options, initial_sequence.wrapping_neg())
+}
+
+/*
+ * TS val はスタックの時計で決まるので比べない。
+ */
+fn options_match(expected: &[TcpOption], actual: &[TcpOption]) -> bool {
+    expected.len() == actual.len()
+        && expected
+            .iter()
+            .zip(actual)
+            .all(|(expected, actual)| match (expected, actual) {
+                (
+                    TcpOption::Timestamps {
+                        tsecr: expected, ..
+                    },
+                    TcpOption::Timestamps { tsecr: actual, .. },
+                ) => expected == actual,
+                (expected, actual) => expected == actual,
+            })
+}
+
+fn format_route(route: (Ipv4Address, u16, Ipv4Address, u16)) -> String {
+    let ([a, b, c, d], source_port, [e, f, g, h], destination_port) = route;
+    format!(
+        "{}.{}.{}.{}:{} > {}.{}.{}.{}:{}",
+        a, b, c, d, source_port, e, f, g, h, destination_port
+    )
+}
+
+fn format_flags(control_bits: ControlBits) -> String {
+    let flags = [
+        (control_bits.get_fin(), 'F'),
+        (control_bits.get_syn(), 'S'),
+        (control_bits.get_rst(), 'R'),
+        (control_bits.get_psh(), 'P'),
+        (control_bits.get_urg(), 'U'),
+        (control_bits.get_ack(), '.'),
+        (control_bits.get_ece(), 'E'),
+        (control_bits.get_cwr(), 'W'),
+    ];
+    flags
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, flag)| *flag)
+        .collect()
+}
+
+fn format_range(sequence: u32, length: u32) -> String {
+    format!("{}:{}({})", sequence, sequence.wrapping_add(length), length)
+}
+
+fn format_options(options: &[TcpOption]) -> String {
+    let options: Vec<String> = options
+        .iter()
+        .map(|option| match option {
+            TcpOption::EndOfOptionList => "eol".to_string(),
+            TcpOption::NoOperation => "nop".to_string(),
+            TcpOption::MaximumSegmentSize(mss) => format!("mss {}", mss),
+            TcpOption::WindowScale(shift) => format!("wscale {}", shift),
+            TcpOption::SackPermitted => "sackOK".to_string(),
+            TcpOption::Sack(blocks) => {
+                let blocks: Vec<String> = blocks
+                    .iter()
+                    .map(|(left, right)| format!("{}:{}", left, right))
+                    .collect();
+                format!("sack {}", blocks.join(" "))
+            }
+            TcpOption::Timestamps { tsval, tsecr } => format!("TS val {} ecr {}", tsval, tsecr),
+            TcpOption::UserTimeout { minutes, timeout } => {
+                format!("uto {}{}", timeout, if *minutes { 'm' } else { 's' })
+            }
+            TcpOption::Unknown { kind, .. } => format!("kind {}", kind),
+        })
+        .collect();
+    format!("<{}>", options.join(","))
+}
+
+fn format_spec(spec: &PacketSpec) -> String {
+    let mut description = format!(
+        "{} {} {}",
+        match spec.direction {
+            Direction::Inbound => '<',
+            Direction::Outbound => '>',
+        },
+        format_flags(spec.control_bits),
+        format_range(spec.sequence, spec.length)
+    );
+    if let Some(acknowledgment) = spec.acknowledgment {
+        description.push_str(&format!(" ack {}", acknowledgment));
+    }
+    if let Some(window) = spec.window {
+        description.push_str(&format!(" win {}", window));
+    }
+    if let Some(options) = &spec.options {
+        description.push(' ');
+        description.push_str(&format_options(options));
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSIVE_OPEN: &str = "\
+0   socket(..., SOCK_STREAM, IPPROTO_TCP) = 3
++0  setsockopt(3, SOL_SOCKET, SO_REUSEADDR, [1], 4) = 0
++0  setsockopt(3, SOL_SOCKET, SO_RCVBUF, [65535], 4) = 0
++0  bind(3, ..., ...) = 0
++0  listen(3, 1) = 0
+
++0  < S 0:0(0) win 32792 <mss 1000,sackOK>
++0  > S. 0:0(0) ack 1 <mss 1460>
++.1 < . 1:1(0) ack 1 win 32792
++0  accept(3, ..., ...) = 4
+
+// 相手の MSS は 1000.
++0  write(4, ..., 1500) = 1500
++0  > . 1:1001(1000) ack 1
++0  > P. 1001:1501(500) ack 1
++.1 < . 1:1(0) ack 1501 win 32792
+
++0  < P. 1:101(100) ack 1501 win 32792
++0  > . 1501:1501(0) ack 101 win 65435
++0  read(4, ..., 1000) = 100
++0  fcntl(4, F_SETFL, O_RDWR|O_NONBLOCK) = 0
++0  read(4, ..., 1000) = -1 EAGAIN (Resource temporarily unavailable)
+
++0  close(4) = 0
++0  > F. 1501:1501(0) ack 101
++.1 < F. 101:101(0) ack 1502 win 32792
++0  > . 1502:1502(0) ack 102
+";
+
+    #[test]
+    fn test_passive_open() {
+        let mut script = Script::parse(PASSIVE_OPEN).unwrap();
+        assert_eq!(script.run(), Ok(()));
+
+        script.set_tolerance(Duration::ZERO);
+        assert_eq!(script.run(), Ok(()));
+    }
+
+    #[test]
+    fn test_active_open() {
+        let script = Script::parse(
+            "0   socket(..., SOCK_STREAM, IPPROTO_TCP) = 3
+             +0  fcntl(3, F_SETFL, O_RDWR|O_NONBLOCK) = 0
+             +0  fcntl(3, F_GETFL) = O_RDWR|O_NONBLOCK
+             +0  connect(3, ..., ...) = -1 EINPROGRESS (Operation now in progress)
+             +0  > S 0:0(0) win 65535 <mss 1460,nop,nop,TS val 0 ecr 0,nop,wscale 3>
+             +.1 < S. 0:0(0) ack 1 win 5792 <mss 1200>
+             +0  > . 1:1(0) ack 1
+             +0  getsockopt(3, SOL_SOCKET, SO_ERROR, [0], [4]) = 0
+             +0  read(3, ..., 100) = -1 EAGAIN (Resource temporarily unavailable)
+             +0  < P. 1:6(5) ack 1 win 5792
+             +0  > . 1:1(0) ack 6
+             +0  read(3, ..., 100) = 5
+             +0  < R. 6:6(0) ack 1 win 0
+             +0  write(3, ..., 10) = -1 ECONNRESET (Connection reset by peer)
+             +0  close(3) = 0",
+        )
+        .unwrap();
+        assert_eq!(script.run(), Ok(()));
+    }
+
+    #[test]
+    fn test_blocking_calls() {
+        let script = Script::parse(
+            "0   socket(..., SOCK_STREAM, IPPROTO_TCP) = 3
+             0...0.1 connect(3, ..., ...) = 0
+             0   > S 0:0(0) <mss 1460,nop,nop,TS val 0 ecr 0,nop,wscale 3>
+             0.1 < S. 0:0(0) ack 1 win 5792 <mss 1460>
+             +0  > . 1:1(0) ack 1
+             +0...0.3 read(3, ..., 100) = 10
+             0.3 < P. 1:11(10) ack 1 win 5792
+             +0  > . 1:1(0) ack 11",
+        )
+        .unwrap();
+        assert_eq!(script.run(), Ok(()));
+
+        let script = Script::parse(
+            "0   socket(..., SOCK_STREAM, IPPROTO_TCP) = 3
+             0   connect(3, ..., ...) = -1 ECONNREFUSED (Connection refused)
+             0   > S 0:0(0) <mss 1460,nop,nop,TS val 0 ecr 0,nop,wscale 3>
+             0.1 < R. 0:0(0) ack 1 win 0",
+        )
+        .unwrap();
+        assert_eq!(script.run(), Ok(()));
+
+        let script = Script::parse(
+            "0 socket(..., SOCK_STREAM, IPPROTO_TCP) = 3
+             0 bind(3, ..., ...) = 0
+             0 listen(3, 1) = 0
+             0 accept(3, ..., ...) = 4",
+        )
+        .unwrap();
+        assert_eq!(
+            script.run().unwrap_err().kind,
+            PacketdrillErrorKind::StillBlocked {
+                syscall: "accept".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_packet_mismatch_reports_differences() {
+        let script = Script::parse(
+            "0  socket(..., SOCK_STREAM, IPPROTO_TCP) = 3
+             +0 bind(3, ..., ...) = 0
+             +0 listen(3, 1) = 0
+             +0 < S 0:0(0) win 32792 <mss 1000>
+             +0 > S. 0:0(0) ack 2 win 1000 <mss 536>",
+        )
+        .unwrap();
+        let error = script.run().unwrap_err();
+        assert_eq!(error.line, 5);
+        let PacketdrillErrorKind::PacketMismatch {
+            expected,
+            actual,
+            differences,
+        } = &error.kind
+        else {
+            panic!("Unexpected error: {}", error);
+        };
+        assert_eq!(expected, "> S. 0:0(0) ack 2 win 1000 <mss 536>");
+        assert_eq!(actual, "> S. 0:0(0) ack 1 win 65535 <mss 1460>");
+        assert_eq!(
+            differences,
+            &vec![
+                "ack: expected 2, actual 1".to_string(),
+                "win: expected 1000, actual 65535".to_string(),
+                "options: expected <mss 536>, actual <mss 1460>".to_string(),
+            ]
+        );
+        assert_eq!(
+            error.to_string(),
+            "line 5: Outbound packet does not match.\n\
+             \x20 expected: > S. 0:0(0) ack 2 win 1000 <mss 536>\n\
+             \x20 actual:   > S. 0:0(0) ack 1 win 65535 <mss 1460>\n\
+             \x20 - ack: expected 2, actual 1\n\
+             \x20 - win: expected 1000, actual 65535\n\
+             \x20 - options: expected <mss 536>, actual <mss 1460>"
+        );
+    }
+
+    #[test]
+    fn test_unexpected_missing_and_late_packets() {
+        let listen = "0  socket(..., SOCK_STREAM, IPPROTO_TCP) = 3
+                      +0 bind(3, ..., ...) = 0
+                      +0 listen(3, 1) = 0
+                      +0 < S 0:0(0) win 32792 <mss 1000>\n";
+
+        let script = Script::parse(&format!("{}+0 < . 1:1(0) ack 1 win 257", listen)).unwrap();
+        let error = script.run().unwrap_err();
+        assert_eq!(error.line, 5);
+        assert!(matches!(
+            error.kind,
+            PacketdrillErrorKind::UnexpectedPacket { .. }
+        ));
+
+        let script = Script::parse(&format!(
+            "{}+0 > S. 0:0(0) ack 1\n+0 > . 1:1(0) ack 1",
+            listen
+        ))
+        .unwrap();
+        let error = script.run().unwrap_err();
+        assert_eq!(error.line, 6);
+        assert_eq!(
+            error.kind,
+            PacketdrillErrorKind::MissingPacket {
+                expected: "> . 1:1(0) ack 1".to_string()
+            }
+        );
+
+        let script = Script::parse(&format!("{}+0.5 > S. 0:0(0) ack 1", listen)).unwrap();
+        let error = script.run().unwrap_err();
+        assert_eq!(
+            error.kind,
+            PacketdrillErrorKind::TimingMismatch {
+                event: "Outbound packet '> S. 0:0(0) ack 1 win 65535 <mss 1460>'".to_string(),
+                earliest: Duration::from_micros(496_000),
+                latest: Some(Duration::from_micros(504_000)),
+                actual: Duration::ZERO,
+            }
+        );
+    }
+}
@@ -0,0 +1,549 @@
+use crate::packetdrill::{
+    Action, Direction, Event, ExpectedResult, PacketSpec, PacketdrillError, PacketdrillErrorKind,
+    Script, Syscall, TimeSpec, Timing, DEFAULT_TOLERANCE,
+};
+use crate::transmission_control_protocol::tcp_option::TcpOption;
+use crate::transmission_control_protocol::ControlBits;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::time::Duration;
+
+pub(crate) fn parse(input: &str) -> Result<Script, PacketdrillError> {
+    let mut script = Script {
+        events: Vec::new(),
+        tolerance: DEFAULT_TOLERANCE,
+    };
+
+    let mut in_block_comment = false;
+    for (index, raw_line) in input.lines().enumerate() {
+        let line_number = index + 1;
+        let line = strip_comments(raw_line, &mut in_block_comment);
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        /*
+         * 時刻のないシェルコマンド。
+         */
+        if line.starts_with('`') {
+            continue;
+        }
+
+        if let Some(option) = line.strip_prefix("--") {
+            parse_command_line_option(option, &mut script)
+                .map_err(|message| parse_error(line_number, message))?;
+            continue;
+        }
+
+        let event = parse_event(line).map_err(|message| parse_error(line_number, message))?;
+        if let Some((timing, action)) = event {
+            script.events.push(Event {
+                line: line_number,
+                timing,
+                action,
+            });
+        }
+    }
+    Ok(script)
+}
+
+fn parse_error(line: usize, message: String) -> PacketdrillError {
+    PacketdrillError {
+        line,
+        kind: PacketdrillErrorKind::Parse(message),
+    }
+}
+
+/*
+ * `//`から行末と`/* */`を取り除く。
+ */
+fn strip_comments(line: &str, in_block_comment: &mut bool) -> String {
+    let mut result = String::new();
+    let mut rest = line;
+    loop {
+        if *in_block_comment {
+            match rest.find("*/") {
+                Some(end) => {
+                    rest = &rest[end + 2..];
+                    *in_block_comment = false;
+                }
+                None => return result,
+            }
+        }
+        let line_comment = rest.find("//");
+        let block_comment = rest.find("/*");
+        match (line_comment, block_comment) {
+            (Some(line_start), Some(block_start)) if line_start < block_start => {
+                result.push_str(&rest[..line_start]);
+                return result;
+            }
+            (Some(line_start), None) => {
+                result.push_str(&rest[..line_start]);
+                return result;
+            }
+            (_, Some(block_start)) => {
+                result.push_str(&rest[..block_start]);
+                result.push(' ');
+                rest = &rest[block_start + 2..];
+                *in_block_comment = true;
+            }
+            (None, None) => {
+                result.push_str(rest);
+                return result;
+            }
+        }
+    }
+}
+
+fn parse_command_line_option(option: &str, script: &mut Script) -> Result<(), String> {
+    let (name, value) = option.split_once('=').unwrap_or((option, ""));
+    match name.trim() {
+        "tolerance_usecs" => {
+            let micros = parse_integer(value.trim())?;
+            script.tolerance = Duration::from_micros(micros);
+            Ok(())
+        }
+        name => Err(format!("Unsupported option --{}.", name)),
+    }
+}
+
+/*
+ * 1 行を時刻と動作に分ける。シェルコマンド（`...`）は実行できないので`None`を返して読み飛ばす。
+ */
+fn parse_event(line: &str) -> Result<Option<(Timing, Action)>, String> {
+    let (time, rest) = line
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| format!("Expected an event after the time: '{}'.", line))?;
+    let timing = parse_timing(time)?;
+    let rest = rest.trim_start();
+
+    let action = if let Some(packet) = rest.strip_prefix('<') {
+        Action::Packet(parse_packet(Direction::Inbound, packet)?)
+    } else if let Some(packet) = rest.strip_prefix('>') {
+        Action::Packet(parse_packet(Direction::Outbound, packet)?)
+    } else if rest.starts_with('`') {
+        return Ok(None);
+    } else {
+        Action::Syscall(parse_syscall(rest)?)
+    };
+
+    if timing.blocking && !matches!(action, Action::Syscall(_)) {
+        return Err("Only system calls can have a '...' duration.".to_string());
+    }
+    Ok(Some((timing, action)))
+}
+
+fn parse_timing(input: &str) -> Result<Timing, String> {
+    if let Some((start, end)) = input.split_once("...") {
+        return Ok(Timing {
+            start: parse_time_spec(start)?,
+            end: Some(parse_time_spec(end)?),
+            blocking: true,
+        });
+    }
+    if let Some((start, end)) = input.split_once('~') {
+        return Ok(Timing {
+            start: parse_time_spec(start)?,
+            end: Some(parse_time_spec(end)?),
+            blocking: false,
+        });
+    }
+    Ok(Timing {
+        start: parse_time_spec(input)?,
+        end: None,
+        blocking: false,
+    })
+}
+
+fn parse_time_spec(input: &str) -> Result<TimeSpec, String> {
+    if input == "*" {
+        return Ok(TimeSpec::Any);
+    }
+    match input.strip_prefix('+') {
+        Some(relative) => Ok(TimeSpec::Relative(parse_seconds(relative)?)),
+        None => Ok(TimeSpec::Absolute(parse_seconds(input)?)),
+    }
+}
+
+/*
+ * "1.25" のような秒数。浮動小数点数を経由せず、マイクロ秒単位で正確に読む。
+ */
+fn parse_seconds(input: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid time '{}'.", input);
+    let (seconds, fraction) = input.split_once('.').unwrap_or((input, ""));
+    if fraction.len() > 6 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let seconds = if seconds.is_empty() && !fraction.is_empty() {
+        0
+    } else {
+        seconds.parse::<u64>().map_err(|_| invalid())?
+    };
+    let mut micros = 0u64;
+    for i in 0..6 {
+        let digit = fraction
+            .as_bytes()
+            .get(i)
+            .map_or(0, |b| u64::from(b - b'0'));
+        micros = micros * 10 + digit;
+    }
+    Ok(Duration::from_secs(seconds) + Duration::from_micros(micros))
+}
+
+fn parse_integer(input: &str) -> Result<u64, String> {
+    let parsed = match input.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => input.parse::<u64>(),
+    };
+    parsed.map_err(|_| format!("Invalid number '{}'.", input))
+}
+
+fn parse_u32(input: &str) -> Result<u32, String> {
+    let value = parse_integer(input)?;
+    u32::try_from(value).map_err(|_| format!("Number {} is out of range.", value))
+}
+
+fn parse_u16(input: &str) -> Result<u16, String> {
+    let value = parse_integer(input)?;
+    u16::try_from(value).map_err(|_| format!("Number {} is out of range.", value))
+}
+
+fn parse_u8(input: &str) -> Result<u8, String> {
+    let value = parse_integer(input)?;
+    u8::try_from(value).map_err(|_| format!("Number {} is out of range.", value))
+}
+
+/*
+ * `S. 0:0(0) ack 1 win 65535 <mss 1460,nop,wscale 7>`
+ */
+fn parse_packet(direction: Direction, input: &str) -> Result<PacketSpec, String> {
+    let (header, options) = match input.find('<') {
+        Some(start) => {
+            let options = input[start + 1..]
+                .trim_end()
+                .strip_suffix('>')
+                .ok_or_else(|| "Unterminated TCP option list.".to_string())?;
+            (&input[..start], Some(parse_options(options)?))
+        }
+        None => (input, None),
+    };
+
+    let mut tokens = header.split_whitespace();
+    let flags = tokens
+        .next()
+        .ok_or_else(|| "Expected TCP flags.".to_string())?;
+    let control_bits = parse_flags(flags)?;
+
+    let range = tokens
+        .next()
+        .ok_or_else(|| "Expected 'start:end(length)'.".to_string())?;
+    let (sequence, end, length) = parse_sequence_range(range)?;
+    if end.wrapping_sub(sequence) != length {
+        return Err(format!(
+            "Sequence range {} does not match the payload length {}.",
+            range, length
+        ));
+    }
+
+    let mut spec = PacketSpec {
+        direction,
+        control_bits,
+        sequence,
+        length,
+        acknowledgment: None,
+        window: None,
+        options,
+    };
+    while let Some(keyword) = tokens.next() {
+        let value = tokens
+            .next()
+            .ok_or_else(|| format!("Expected a value after '{}'.", keyword))?;
+        match keyword {
+            "ack" => spec.acknowledgment = Some(parse_u32(value)?),
+            "win" => spec.window = Some(parse_u16(value)?),
+            keyword => return Err(format!("Unsupported packet field '{}'.", keyword)),
+        }
+    }
+    if spec.acknowledgment.is_some() != spec.control_bits.get_ack() {
+        return Err("'ack' must be given if and only if the '.' flag is set.".to_string());
+    }
+    Ok(spec)
+}
+
+fn parse_flags(input: &str) -> Result<ControlBits, String> {
+    let mut control_bits = ControlBits::default();
+    for flag in input.chars() {
+        match flag {
+            'S' => control_bits.set_syn(true),
+            'F' => control_bits.set_fin(true),
+            'R' => control_bits.set_rst(true),
+            'P' => control_bits.set_psh(true),
+            '.' => control_bits.set_ack(true),
+            'U' => control_bits.set_urg(true),
+            'E' => control_bits.set_ece(true),
+            'W' => control_bits.set_cwr(true),
+            flag => return Err(format!("Unknown TCP flag '{}'.", flag)),
+        }
+    }
+    Ok(control_bits)
+}
+
+fn parse_sequence_range(input: &str) -> Result<(u32, u32, u32), String> {
+    let invalid = || format!("Invalid sequence range '{}'.", input);
+    let (start, rest) = input.split_once(':').ok_or_else(invalid)?;
+    let (end, length) = rest.split_once('(').ok_or_else(invalid)?;
+    let length = length.strip_suffix(')').ok_or_else(invalid)?;
+    Ok((parse_u32(start)?, parse_u32(end)?, parse_u32(length)?))
+}
+
+/*
+ * TCP ヘッダーに収まらない option 列（5 つ以上の SACK ブロックなど）もここで弾く。
+ */
+fn parse_options(input: &str) -> Result<Vec<TcpOption>, String> {
+    let options = input
+        .split(',')
+        .map(str::trim)
+        .filter(|option| !option.is_empty())
+        .map(parse_option)
+        .collect::<Result<Vec<_>, String>>()?;
+    TcpOption::encode_all(&options).map_err(|error| error.to_string())?;
+    Ok(options)
+}
+
+fn parse_option(input: &str) -> Result<TcpOption, String> {
+    let words: Vec<&str> = input.split_whitespace().collect();
+    match words.as_slice() {
+        ["nop"] => Ok(TcpOption::NoOperation),
+        ["eol"] => Ok(TcpOption::EndOfOptionList),
+        ["sackOK"] => Ok(TcpOption::SackPermitted),
+        ["mss", value] => Ok(TcpOption::MaximumSegmentSize(parse_u16(value)?)),
+        ["wscale", value] => Ok(TcpOption::WindowScale(parse_u8(value)?)),
+        ["TS", "val", tsval, "ecr", tsecr] => Ok(TcpOption::Timestamps {
+            tsval: parse_u32(tsval)?,
+            tsecr: parse_u32(tsecr)?,
+        }),
+        ["uto", value] => {
+            let (timeout, minutes) = match value.strip_suffix('m') {
+                Some(timeout) => (timeout, true),
+                None => (value.strip_suffix('s').unwrap_or(value), false),
+            };
+            Ok(TcpOption::UserTimeout {
+                minutes,
+                timeout: parse_u16(timeout)?,
+            })
+        }
+        ["sack", blocks @ ..] if !blocks.is_empty() => {
+            let blocks = blocks
+                .iter()
+                .map(|block| {
+                    let (left, right) = block
+                        .split_once(':')
+                        .ok_or_else(|| format!("Invalid SACK block '{}'.", block))?;
+                    Ok((parse_u32(left)?, parse_u32(right)?))
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            Ok(TcpOption::Sack(blocks))
+        }
+        _ => Err(format!("Unsupported TCP option '{}'.", input)),
+    }
+}
+
+/*
+ * `write(4, ..., 1000) = 1000`, `connect(3, ..., ...) = -1 EINPROGRESS (Operation now in progress)`
+ */
+fn parse_syscall(input: &str) -> Result<Syscall, String> {
+    let open = input
+        .find('(')
+        .ok_or_else(|| format!("Expected a system call: '{}'.", input))?;
+    let name = input[..open].trim();
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!("Invalid system call name '{}'.", name));
+    }
+
+    let close = find_closing_parenthesis(input, open)
+        .ok_or_else(|| "Unterminated argument list.".to_string())?;
+    let arguments = split_arguments(&input[open + 1..close]);
+
+    let result = input[close + 1..]
+        .trim()
+        .strip_prefix('=')
+        .ok_or_else(|| "Expected '= result' after the system call.".to_string())?
+        .trim();
+    let result = match result.find('(') {
+        Some(description) => result[..description].trim_end(),
+        None => result,
+    };
+    let mut words = result.split_whitespace();
+    let value = words
+        .next()
+        .ok_or_else(|| "Expected a return value.".to_string())?
+        .to_string();
+    let errno = words.next().map(ToString::to_string);
+    if words.next().is_some() {
+        return Err(format!(
+            "Unexpected text after the return value: '{}'.",
+            result
+        ));
+    }
+
+    Ok(Syscall {
+        name: name.to_string(),
+        arguments,
+        expected: ExpectedResult { value, errno },
+    })
+}
+
+fn find_closing_parenthesis(input: &str, open: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    for (index, c) in input.char_indices().skip_while(|(index, _)| *index < open) {
+        match c {
+            '"' => in_string = !in_string,
+            '(' | '[' | '{' if !in_string => depth += 1,
+            ')' | ']' | '}' if !in_string => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(index);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/*
+ * トップレベルのカンマで区切る。`[1]`や`{...}`の中のカンマでは区切らない。
+ */
+fn split_arguments(input: &str) -> Vec<String> {
+    let mut arguments = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0usize;
+    let mut in_string = false;
+    for c in input.chars() {
+        match c {
+            '"' => in_string = !in_string,
+            '(' | '[' | '{' if !in_string => depth += 1,
+            ')' | ']' | '}' if !in_string => depth = depth.saturating_sub(1),
+            ',' if !in_string && depth == 0 => {
+                arguments.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    if !current.trim().is_empty() || !arguments.is_empty() {
+        arguments.push(current.trim().to_string());
+    }
+    arguments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_parse_packet_and_syscall_lines() {
+        let script = parse(
+            "--tolerance_usecs=10000\n\
+             0   socket(..., SOCK_STREAM, IPPROTO_TCP) = 3 // comment\n\
+             /* block\n\
+                comment */ +0.1 setsockopt(3, SOL_SOCKET, SO_REUSEADDR, [1], 4) = 0\n\
+             0.2~0.3 > S. 0:0(0) ack 1 <mss 1460,sackOK,TS val 100 ecr 0,nop,wscale 7>\n\
+             +0 connect(3, ..., ...) = -1 EINPROGRESS (Operation now in progress)\n\
+             `sysctl -q net.ipv4.tcp_timestamps=0`\n\
+             * < P. 1:1001(1000) ack 1 win 257 <sack 1:2 3:4>\n",
+        )
+        .unwrap();
+
+        assert_eq!(script.tolerance, Duration::from_millis(10));
+        assert_eq!(script.events.len(), 5);
+
+        let event = &script.events[1];
+        assert_eq!(event.line, 4);
+        assert_eq!(
+            event.timing.start,
+            TimeSpec::Relative(Duration::from_millis(100))
+        );
+        let Action::Syscall(syscall) = &event.action else {
+            panic!("Expected a system call.");
+        };
+        assert_eq!(syscall.name, "setsockopt");
+        assert_eq!(
+            syscall.arguments,
+            vec!["3", "SOL_SOCKET", "SO_REUSEADDR", "[1]", "4"]
+        );
+
+        let event = &script.events[2];
+        assert_eq!(
+            event.timing.end,
+            Some(TimeSpec::Absolute(Duration::from_millis(300)))
+        );
+        let Action::Packet(packet) = &event.action else {
+            panic!("Expected a packet.");
+        };
+        assert_eq!(packet.direction, Direction::Outbound);
+        assert!(packet.control_bits.get_syn() && packet.control_bits.get_ack());
+        assert_eq!(packet.acknowledgment, Some(1));
+        assert_eq!(packet.window, None);
+        assert_eq!(
+            packet.options.as_deref(),
+            Some(
+                &[
+                    TcpOption::MaximumSegmentSize(1460),
+                    TcpOption::SackPermitted,
+                    TcpOption::Timestamps {
+                        tsval: 100,
+                        tsecr: 0
+                    },
+                    TcpOption::NoOperation,
+                    TcpOption::WindowScale(7),
+                ][..]
+            )
+        );
+
+        let Action::Syscall(syscall) = &script.events[3].action else {
+            panic!("Expected a system call.");
+        };
+        assert_eq!(syscall.expected.value, "-1");
+        assert_eq!(syscall.expected.errno.as_deref(), Some("EINPROGRESS"));
+
+        let event = &script.events[4];
+        assert_eq!(event.timing.start, TimeSpec::Any);
+        let Action::Packet(packet) = &event.action else {
+            panic!("Expected a packet.");
+        };
+        assert_eq!((packet.sequence, packet.length), (1, 1000));
+        assert_eq!(packet.window, Some(257));
+    }
+
+    #[test]
+    fn test_parse_errors_report_line() {
+        let error = parse("0 socket(..., SOCK_STREAM, IPPROTO_TCP) = 3\n+0 < X 0:0(0) win 1\n")
+            .unwrap_err();
+        assert_eq!(error.line, 2);
+        assert_eq!(
+            error.kind,
+            PacketdrillErrorKind::Parse("Unknown TCP flag 'X'.".to_string())
+        );
+
+        let error = parse("0 < S 0:10(0) win 1\n").unwrap_err();
+        assert!(matches!(error.kind, PacketdrillErrorKind::Parse(_)));
+        let error = parse("0 < S. 0:0(0) win 1\n").unwrap_err();
+        assert!(matches!(error.kind, PacketdrillErrorKind::Parse(_)));
+
+        /*
+         * TCP ヘッダーに収まらない SACK.
+         */
+        let error = parse("0 < . 1:1(0) ack 1 win 1 <sack 1:2 3:4 5:6 7:8 9:10>\n").unwrap_err();
+        assert_eq!(
+            error.kind,
+            PacketdrillErrorKind::Parse("Too many SACK blocks: 5 (maximum 4).".to_string())
+        );
+    }
+}
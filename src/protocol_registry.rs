@@ -0,0 +1,298 @@
+use crate::decode_options::{DecodeOptions, DecodeWarning};
+use crate::internet_protocol::{Ipv4Header, Ipv4HeaderDecodeError};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+
+/*
+ * handler が返すエラー。プロトコルごとにエラーの型が違うので trait object にする。
+ */
+pub trait HandlerError: fmt::Debug + fmt::Display {}
+
+impl<T: fmt::Debug + fmt::Display> HandlerError for T {}
+
+/*
+ * IPv4 の payload を受け取る handler.
+ *
+ * `datagram` は IPv4 ヘッダーを含む datagram 全体。payload だけが欲しい場合は
+ * `Ipv4Header::get_payload` を使う。
+ */
+pub trait ProtocolHandler {
+    fn handle(&mut self, header: &Ipv4Header, datagram: &[u8])
+        -> Result<(), Box<dyn HandlerError>>;
+}
+
+#[derive(Debug)]
+pub enum DispatchError {
+    Ipv4(Ipv4HeaderDecodeError),
+
+    Handler {
+        protocol: u8,
+        error: Box<dyn HandlerError>,
+    },
+}
+
+impl From<Ipv4HeaderDecodeError> for DispatchError {
+    fn from(error: Ipv4HeaderDecodeError) -> Self {
+        DispatchError::Ipv4(error)
+    }
+}
+
+impl fmt::Display for DispatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DispatchError::Ipv4(error) => write!(f, "IPv4: {}", error),
+            DispatchError::Handler { protocol, error } => {
+                write!(f, "Protocol {}: {}", protocol, error)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DispatchError {}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/*
+ * IPv4 層を通過する datagram を覗き見る。パケットキャプチャ用。
+ *
+ * NOTE: 受信した datagram は decode する前に渡すので、壊れているものも含まれる。
+ */
+pub trait PacketTap {
+    fn tap(&mut self, direction: Direction, datagram: &[u8]);
+}
+
+/*
+ * IPv4 の protocol 番号ごとに handler を登録して、受信した datagram を振り分ける。
+ *
+ * ICMP, UDP, GRE や実験的なプロトコルも、ここに登録するだけで扱えるようにする。
+ */
+pub struct ProtocolRegistry {
+    handlers: BTreeMap<u8, Box<dyn ProtocolHandler>>,
+    decode_options: DecodeOptions,
+    tap: Option<Box<dyn PacketTap>>,
+}
+
+impl ProtocolRegistry {
+    pub fn new() -> Self {
+        Self::with_decode_options(DecodeOptions::default())
+    }
+
+    pub fn with_decode_options(decode_options: DecodeOptions) -> Self {
+        Self {
+            handlers: BTreeMap::new(),
+            decode_options,
+            tap: None,
+        }
+    }
+
+    /*
+     * 以前に設定されていた tap があれば、置き換えて返す。`None`で外す。
+     */
+    pub fn set_tap(&mut self, tap: Option<Box<dyn PacketTap>>) -> Option<Box<dyn PacketTap>> {
+        core::mem::replace(&mut self.tap, tap)
+    }
+
+    /*
+     * 送信する datagram を tap に渡す。送信側は datagram を組み立てた後に呼ぶこと。
+     */
+    pub fn tap_outbound(&mut self, datagram: &[u8]) {
+        if let Some(tap) = self.tap.as_mut() {
+            tap.tap(Direction::Outbound, datagram);
+        }
+    }
+
+    /*
+     * すでに登録されていた handler があれば、置き換えて返す。
+     */
+    pub fn register(
+        &mut self,
+        protocol: u8,
+        handler: Box<dyn ProtocolHandler>,
+    ) -> Option<Box<dyn ProtocolHandler>> {
+        self.handlers.insert(protocol, handler)
+    }
+
+    pub fn unregister(&mut self, protocol: u8) -> Option<Box<dyn ProtocolHandler>> {
+        self.handlers.remove(&protocol)
+    }
+
+    pub fn is_registered(&self, protocol: u8) -> bool {
+        self.handlers.contains_key(&protocol)
+    }
+
+    /*
+     * datagram を decode して、protocol に対応する handler に渡す。
+     * lenient モードで許容した問題は警告として返す。
+     */
+    pub fn dispatch(&mut self, datagram: &[u8]) -> Result<Vec<DecodeWarning>, DispatchError> {
+        if let Some(tap) = self.tap.as_mut() {
+            tap.tap(Direction::Inbound, datagram);
+        }
+
+        let (header, warnings) = Ipv4Header::decode_with_options(datagram, &self.decode_options)?;
+        header.validate_total_length(datagram.len())?;
+
+        let protocol = header.get_protocol();
+        let handler = self
+            .handlers
+            .get_mut(&protocol)
+            .ok_or(Ipv4HeaderDecodeError::UnsupportedProtocol(protocol))?;
+
+        handler
+            .handle(&header, datagram)
+            .map_err(|error| DispatchError::Handler { protocol, error })?;
+
+        Ok(warnings)
+    }
+}
+
+impl Default for ProtocolRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transmission_control_protocol::tcp_packet::TcpPacket;
+    use crate::transmission_control_protocol::{TcpHeader, TCP_PROTOCOL_NUMBER};
+    use alloc::rc::Rc;
+    use alloc::vec;
+    use core::cell::RefCell;
+
+    const ICMP_PROTOCOL_NUMBER: u8 = 1;
+
+    struct RecordingHandler {
+        received: Rc<RefCell<Vec<Vec<u8>>>>,
+    }
+
+    impl ProtocolHandler for RecordingHandler {
+        fn handle(
+            &mut self,
+            header: &Ipv4Header,
+            datagram: &[u8],
+        ) -> Result<(), Box<dyn HandlerError>> {
+            self.received
+                .borrow_mut()
+                .push(header.get_payload(datagram).to_vec());
+            Ok(())
+        }
+    }
+
+    struct TcpHandler;
+
+    impl ProtocolHandler for TcpHandler {
+        fn handle(&mut self, _: &Ipv4Header, datagram: &[u8]) -> Result<(), Box<dyn HandlerError>> {
+            TcpPacket::decode(datagram)
+                .map_err(|error| Box::new(error) as Box<dyn HandlerError>)?;
+            Ok(())
+        }
+    }
+
+    fn datagram(protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = Ipv4Header::new([10, 0, 0, 1], [10, 0, 0, 2], protocol);
+        header.set_total_length((header.get_header_length() + payload.len()) as u16);
+
+        let mut bytes = header.encode();
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[test]
+    fn test_dispatch_to_registered_handler() {
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let mut registry = ProtocolRegistry::new();
+        registry.register(
+            ICMP_PROTOCOL_NUMBER,
+            Box::new(RecordingHandler {
+                received: received.clone(),
+            }),
+        );
+
+        registry
+            .dispatch(&datagram(ICMP_PROTOCOL_NUMBER, &[8, 0, 0, 0]))
+            .unwrap();
+        assert_eq!(*received.borrow(), vec![vec![8, 0, 0, 0]]);
+    }
+
+    #[test]
+    fn test_dispatch_unregistered_protocol() {
+        let mut registry = ProtocolRegistry::new();
+        registry.register(TCP_PROTOCOL_NUMBER, Box::new(TcpHandler));
+
+        let result = registry.dispatch(&datagram(47, &[0; 4]));
+        assert!(matches!(
+            result,
+            Err(DispatchError::Ipv4(
+                Ipv4HeaderDecodeError::UnsupportedProtocol(47)
+            ))
+        ));
+
+        registry.unregister(TCP_PROTOCOL_NUMBER);
+        assert!(!registry.is_registered(TCP_PROTOCOL_NUMBER));
+    }
+
+    struct RecordingTap {
+        tapped: Rc<RefCell<Vec<(Direction, usize)>>>,
+    }
+
+    impl PacketTap for RecordingTap {
+        fn tap(&mut self, direction: Direction, datagram: &[u8]) {
+            self.tapped.borrow_mut().push((direction, datagram.len()));
+        }
+    }
+
+    #[test]
+    fn test_tap_sees_every_datagram() {
+        let tapped = Rc::new(RefCell::new(Vec::new()));
+        let mut registry = ProtocolRegistry::new();
+        registry.set_tap(Some(Box::new(RecordingTap {
+            tapped: tapped.clone(),
+        })));
+
+        /*
+         * handler が登録されていなくても、受信したものは全て tap に渡る。
+         */
+        assert!(registry.dispatch(&datagram(47, &[0; 4])).is_err());
+        registry.tap_outbound(&datagram(47, &[0; 8]));
+
+        assert_eq!(
+            *tapped.borrow(),
+            vec![(Direction::Inbound, 24), (Direction::Outbound, 28)]
+        );
+    }
+
+    #[test]
+    fn test_dispatch_handler_error() {
+        let mut registry = ProtocolRegistry::new();
+        registry.register(TCP_PROTOCOL_NUMBER, Box::new(TcpHandler));
+
+        /*
+         * TCP ヘッダーとして短すぎる payload.
+         */
+        let result = registry.dispatch(&datagram(TCP_PROTOCOL_NUMBER, &[0; 4]));
+        assert!(matches!(
+            result,
+            Err(DispatchError::Handler {
+                protocol: TCP_PROTOCOL_NUMBER,
+                ..
+            })
+        ));
+
+        let header = TcpHeader::new(1, 2);
+        let packet = TcpPacket::new(
+            Ipv4Header::new([10, 0, 0, 1], [10, 0, 0, 2], TCP_PROTOCOL_NUMBER),
+            header,
+            vec![],
+        );
+        assert!(registry.dispatch(&packet.encode().unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,347 @@
+use crate::network_stack::NetworkStack;
+use crate::packet_capture::{CaptureReadError, CaptureReader};
+use crate::protocol_registry::Direction;
+use std::fmt;
+use std::io::Read;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/*
+ * キャプチャをどの速さでスタックに流すか。
+ */
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ReplayTiming {
+    /*
+     * キャプチャされた時と同じ間隔で流す。
+     */
+    Original,
+
+    /*
+     * 間隔を 1/n にして流す。`Accelerated(2.0)`なら 2 倍速。n は正の有限な値であること。
+     */
+    Accelerated(f64),
+
+    /*
+     * 待たずに全て流す。スタックに渡す時刻はキャプチャ上の時刻のままなので、
+     * 仮想時計で動かすスタックのテストに使う。
+     */
+    Immediate,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ReplayTimingError {
+    /*
+     * `Accelerated`の倍率が 0 以下、もしくは NaN や無限大。
+     */
+    InvalidFactor(f64),
+}
+
+impl fmt::Display for ReplayTimingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayTimingError::InvalidFactor(factor) => write!(
+                f,
+                "Invalid replay speed factor {}. It must be positive and finite.",
+                factor
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReplayTimingError {}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct ReplayReport {
+    pub frames: usize,
+    pub replayed: usize,
+    pub skipped_outbound: usize,
+    pub skipped_non_ipv4: usize,
+
+    /*
+     * `handle_timeout`を呼んだ回数と、スタックが送った datagram の数。
+     */
+    pub timeouts: usize,
+    pub transmitted: usize,
+}
+
+/*
+ * キャプチャをスタックのインスタンスに流し込む。
+ *
+ * NOTE: デフォルトでは、pcapng で outbound と記録されているフレームは流さない。
+ *       それはキャプチャした時のスタック自身が送ったものなので。
+ */
+pub struct Replay {
+    timing: ReplayTiming,
+    include_outbound: bool,
+}
+
+impl Replay {
+    pub fn new(timing: ReplayTiming) -> Result<Self, ReplayTimingError> {
+        if let ReplayTiming::Accelerated(factor) = timing {
+            if !(factor.is_finite() && factor > 0.0) {
+                return Err(ReplayTimingError::InvalidFactor(factor));
+            }
+        }
+        Ok(Self {
+            timing,
+            include_outbound: false,
+        })
+    }
+
+    pub fn set_include_outbound(&mut self, include_outbound: bool) {
+        self.include_outbound = include_outbound;
+    }
+
+    /*
+     * フレームを流す前に、その時刻までに期限が来るスタックのタイマーを処理する。スタックが送る
+     * datagram は、処理のたびに`poll_transmit`で取り出して、送った時刻と一緒に`on_transmit`に渡す。
+     *
+     * NOTE: 最後のフレームより後のタイマーは処理しない。続きは呼び出し側で動かすこと。
+     */
+    pub fn run<R: Read, S: NetworkStack>(
+        &self,
+        reader: &mut CaptureReader<R>,
+        stack: &mut S,
+        mut on_transmit: impl FnMut(Duration, Vec<u8>),
+    ) -> Result<ReplayReport, CaptureReadError> {
+        let mut report = ReplayReport::default();
+        let started_at = Instant::now();
+        let mut first_timestamp = None;
+        let mut clock = Duration::ZERO;
+
+        while let Some(frame) = reader.next_frame()? {
+            report.frames += 1;
+
+            if frame.direction == Some(Direction::Outbound) && !self.include_outbound {
+                report.skipped_outbound += 1;
+                continue;
+            }
+            let Some(datagram) = frame.get_datagram() else {
+                report.skipped_non_ipv4 += 1;
+                continue;
+            };
+
+            /*
+             * キャプチャの最初のフレームからの経過時間。時刻が戻っている場合は 0 扱い。
+             */
+            let first_timestamp = *first_timestamp.get_or_insert(frame.timestamp);
+            let offset = frame.timestamp.saturating_sub(first_timestamp);
+
+            let now = match self.timing {
+                ReplayTiming::Original => offset,
+                /*
+                 * 倍率がとても小さいと`Duration`に収まらないので、その場合は最大値にしておく。
+                 */
+                ReplayTiming::Accelerated(factor) => {
+                    Duration::try_from_secs_f64(offset.as_secs_f64() / factor)
+                        .unwrap_or(Duration::MAX)
+                }
+                ReplayTiming::Immediate => offset,
+            };
+
+            while let Some(timeout) = stack.poll_timeout().filter(|timeout| *timeout <= now) {
+                clock = clock.max(timeout);
+                self.wait_until(started_at, clock);
+                stack.handle_timeout(clock);
+                report.timeouts += 1;
+                report.transmitted += transmit_all(stack, clock, &mut on_transmit);
+            }
+
+            self.wait_until(started_at, now);
+            clock = clock.max(now);
+            stack.receive(now, datagram);
+            report.replayed += 1;
+            report.transmitted += transmit_all(stack, now, &mut on_transmit);
+        }
+
+        Ok(report)
+    }
+
+    /*
+     * 再生を始めてから`now`だけ経つまで待つ。`Immediate`なら待たない。
+     */
+    fn wait_until(&self, started_at: Instant, now: Duration) {
+        if self.timing == ReplayTiming::Immediate {
+            return;
+        }
+        let elapsed = started_at.elapsed();
+        if now > elapsed {
+            thread::sleep(now - elapsed);
+        }
+    }
+}
+
+fn transmit_all<S: NetworkStack>(
+    stack: &mut S,
+    now: Duration,
+    on_transmit: &mut impl FnMut(Duration, Vec<u8>),
+) -> usize {
+    let mut count = 0;
+    while let Some(datagram) = stack.poll_transmit(now) {
+        on_transmit(now, datagram);
+        count += 1;
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internet_protocol::{Ipv4Address, Ipv4Header};
+    use crate::packet_capture::pcapng::PcapngWriter;
+    use crate::packet_capture::{CaptureRecord, CaptureWriter, LinkType};
+    use crate::tcp_stack::TcpStack;
+    use crate::transmission_control_protocol::tcp_packet::TcpPacket;
+    use crate::transmission_control_protocol::{ControlBits, TcpHeader, TCP_PROTOCOL_NUMBER};
+
+    #[derive(Default)]
+    struct RecordingStack {
+        received: Vec<(Duration, Vec<u8>)>,
+    }
+
+    impl NetworkStack for RecordingStack {
+        fn receive(&mut self, now: Duration, datagram: &[u8]) {
+            self.received.push((now, datagram.to_vec()));
+        }
+    }
+
+    fn capture(records: &[(u64, Direction, &[u8])]) -> Vec<u8> {
+        let mut writer = PcapngWriter::new(Vec::new(), LinkType::Raw).unwrap();
+        for (millis, direction, datagram) in records {
+            writer
+                .write_record(&CaptureRecord {
+                    interface_id: 0,
+                    timestamp: Duration::from_secs(1_700_000_000) + Duration::from_millis(*millis),
+                    direction: *direction,
+                    datagram,
+                })
+                .unwrap();
+        }
+        writer.into_inner()
+    }
+
+    #[test]
+    fn test_replay_immediate() {
+        let bytes = capture(&[
+            (0, Direction::Inbound, &[1]),
+            (500, Direction::Outbound, &[2]),
+            (1500, Direction::Inbound, &[3]),
+        ]);
+        let mut reader = CaptureReader::new(&bytes[..]).unwrap();
+        let mut stack = RecordingStack::default();
+
+        let report = Replay::new(ReplayTiming::Immediate)
+            .unwrap()
+            .run(&mut reader, &mut stack, |_, _| {})
+            .unwrap();
+
+        assert_eq!(
+            report,
+            ReplayReport {
+                frames: 3,
+                replayed: 2,
+                skipped_outbound: 1,
+                skipped_non_ipv4: 0,
+                timeouts: 0,
+                transmitted: 0,
+            }
+        );
+        assert_eq!(
+            stack.received,
+            vec![
+                (Duration::ZERO, vec![1]),
+                (Duration::from_millis(1500), vec![3])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replay_accelerated() {
+        let bytes = capture(&[
+            (0, Direction::Inbound, &[1]),
+            (100, Direction::Outbound, &[2]),
+            (200, Direction::Inbound, &[3]),
+        ]);
+        let mut reader = CaptureReader::new(&bytes[..]).unwrap();
+        let mut stack = RecordingStack::default();
+
+        let mut replay = Replay::new(ReplayTiming::Accelerated(10.0)).unwrap();
+        replay.set_include_outbound(true);
+        let started_at = Instant::now();
+        let report = replay.run(&mut reader, &mut stack, |_, _| {}).unwrap();
+
+        assert_eq!(report.replayed, 3);
+        assert!(started_at.elapsed() >= Duration::from_millis(20));
+        assert_eq!(stack.received[2].0, Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_timers_fire_between_frames() {
+        let server: Ipv4Address = [10, 0, 0, 1];
+        let client: Ipv4Address = [10, 0, 0, 2];
+        let syn = |port| {
+            let mut control_bits = ControlBits::default();
+            control_bits.set_syn(true);
+            let mut tcp_header = TcpHeader::new(5000, port);
+            tcp_header.set_control_bits(control_bits);
+            tcp_header.set_window(1000);
+            TcpPacket::new(
+                Ipv4Header::new(client, server, TCP_PROTOCOL_NUMBER),
+                tcp_header,
+                Vec::new(),
+            )
+            .encode()
+            .unwrap()
+        };
+
+        /*
+         * SYN に応答した後、ACK が来ないので SYN-ACK が再送される。2 つ目のフレームは
+         * 閉じたポートへの SYN で、RST が返る。
+         */
+        let bytes = capture(&[
+            (0, Direction::Inbound, &syn(80)),
+            (2500, Direction::Inbound, &syn(81)),
+        ]);
+        let mut reader = CaptureReader::new(&bytes[..]).unwrap();
+
+        let mut stack = TcpStack::new(&[server]);
+        let listener = stack.socket();
+        stack.bind(listener, server, 80).unwrap();
+        stack.listen(listener, 1).unwrap();
+
+        let mut transmitted = Vec::new();
+        let report = Replay::new(ReplayTiming::Immediate)
+            .unwrap()
+            .run(&mut reader, &mut stack, |now, datagram| {
+                let packet = TcpPacket::decode(&datagram).unwrap();
+                let control_bits = packet.get_tcp_header().get_control_bits();
+                transmitted.push((now, control_bits.get_syn(), control_bits.get_rst()));
+            })
+            .unwrap();
+
+        assert_eq!(report.replayed, 2);
+        assert!(report.timeouts > 0);
+        assert_eq!(report.transmitted, transmitted.len());
+        assert_eq!(transmitted.first(), Some(&(Duration::ZERO, true, false)));
+        assert_eq!(
+            transmitted.last(),
+            Some(&(Duration::from_millis(2500), false, true))
+        );
+        let retransmissions = &transmitted[1..transmitted.len() - 1];
+        assert!(!retransmissions.is_empty());
+        assert!(retransmissions.iter().all(|(now, syn, _)| *syn
+            && *now >= Duration::from_secs(1)
+            && *now < Duration::from_millis(2500)));
+    }
+
+    #[test]
+    fn test_invalid_factor() {
+        for factor in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(matches!(
+                Replay::new(ReplayTiming::Accelerated(factor)),
+                Err(ReplayTimingError::InvalidFactor(_))
+            ));
+        }
+        assert!(Replay::new(ReplayTiming::Accelerated(f64::MIN_POSITIVE)).is_ok());
+    }
+}
@@ -0,0 +1,794 @@
+use crate::internet_protocol::Ipv4Address;
+use crate::network_stack::NetworkStack;
+use alloc::collections::{BTreeMap, BinaryHeap};
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use core::time::Duration;
+
+/*
+ * 複数のスタックのインスタンスを 1 プロセス内でつなぐ、決定的なネットワークシミュレーター。
+ *
+ * - 時刻は仮想時計で、イベントがある時刻まで一気に進める。実時間では待たない。
+ * - 遅延、ジッター、帯域、損失、重複、順序入れ替え、ビット化けを、シード付きの乱数で再現する。
+ *   同じシードと同じ操作なら、毎回同じ結果になる。
+ *
+ * datagram の宛先アドレスで届け先のノードを決める。リンクがなければ捨てる。
+ */
+pub type NodeId = usize;
+
+/*
+ * 片方向のリンクの性質。確率は 0.0 から 1.0.
+ */
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct LinkConditions {
+    delay: Duration,
+    jitter: Duration,
+    bandwidth: Option<u64>,
+    loss: f64,
+    duplication: f64,
+    reordering: f64,
+    reordering_delay: Duration,
+    corruption: f64,
+}
+
+impl LinkConditions {
+    /*
+     * 遅延なし、帯域無制限、損失なし。
+     */
+    pub fn ideal() -> Self {
+        Self {
+            delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+            bandwidth: None,
+            loss: 0.0,
+            duplication: 0.0,
+            reordering: 0.0,
+            reordering_delay: Duration::ZERO,
+            corruption: 0.0,
+        }
+    }
+
+    pub fn get_delay(&self) -> Duration {
+        self.delay
+    }
+
+    pub fn set_delay(&mut self, delay: Duration) {
+        self.delay = delay;
+    }
+
+    /*
+     * 各 datagram の遅延に、0 から jitter までの一様乱数を足す。
+     */
+    pub fn get_jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    pub fn set_jitter(&mut self, jitter: Duration) {
+        self.jitter = jitter;
+    }
+
+    /*
+     * bits per second. `None`なら無制限。
+     */
+    pub fn get_bandwidth(&self) -> Option<u64> {
+        self.bandwidth
+    }
+
+    pub fn set_bandwidth(&mut self, bits_per_second: Option<u64>) {
+        assert_ne!(bits_per_second, Some(0), "Bandwidth must not be zero.");
+        self.bandwidth = bits_per_second;
+    }
+
+    pub fn get_loss(&self) -> f64 {
+        self.loss
+    }
+
+    pub fn set_loss(&mut self, probability: f64) {
+        self.loss = validate_probability(probability);
+    }
+
+    pub fn get_duplication(&self) -> f64 {
+        self.duplication
+    }
+
+    pub fn set_duplication(&mut self, probability: f64) {
+        self.duplication = validate_probability(probability);
+    }
+
+    /*
+     * `probability`の確率で、datagram を`delay`だけ余計に遅らせて後続に追い越させる。
+     */
+    pub fn get_reordering(&self) -> (f64, Duration) {
+        (self.reordering, self.reordering_delay)
+    }
+
+    pub fn set_reordering(&mut self, probability: f64, delay: Duration) {
+        self.reordering = validate_probability(probability);
+        self.reordering_delay = delay;
+    }
+
+    /*
+     * `probability`の確率で、datagram のどこか 1 ビットを反転させる。
+     */
+    pub fn get_corruption(&self) -> f64 {
+        self.corruption
+    }
+
+    pub fn set_corruption(&mut self, probability: f64) {
+        self.corruption = validate_probability(probability);
+    }
+}
+
+impl Default for LinkConditions {
+    fn default() -> Self {
+        Self::ideal()
+    }
+}
+
+fn validate_probability(probability: f64) -> f64 {
+    assert!(
+        (0.0..=1.0).contains(&probability),
+        "Invalid probability {}. It should be between 0.0 and 1.0.",
+        probability
+    );
+    probability
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct SimulatorStatistics {
+    pub sent: usize,
+    pub delivered: usize,
+    pub lost: usize,
+    pub duplicated: usize,
+    pub reordered: usize,
+    pub corrupted: usize,
+
+    /*
+     * 宛先のノードがない、もしくはリンクがないので捨てた。
+     */
+    pub unroutable: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SimulatorEventKind {
+    Sent,
+    Delivered,
+    Lost,
+    Duplicated,
+    Reordered,
+    Corrupted,
+    Unroutable,
+}
+
+/*
+ * `set_recording(true)`の時に記録するイベント。テストの失敗時の調査用。
+ */
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SimulatorEvent {
+    pub time: Duration,
+    pub kind: SimulatorEventKind,
+    pub from: NodeId,
+    pub to: Option<NodeId>,
+    pub length: usize,
+}
+
+struct Node<S> {
+    stack: S,
+    addresses: Vec<Ipv4Address>,
+}
+
+struct Link {
+    conditions: LinkConditions,
+
+    /*
+     * 帯域制限がある時、直前の datagram を送り終わる時刻。
+     */
+    busy_until: Duration,
+}
+
+/*
+ * 同じ時刻に届くものは送った順に処理するため、連番を持たせる。
+ */
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Delivery {
+    at: Duration,
+    sequence: u64,
+    from: NodeId,
+    to: NodeId,
+    datagram: Vec<u8>,
+}
+
+pub struct Simulator<S: NetworkStack> {
+    now: Duration,
+    nodes: Vec<Node<S>>,
+    links: BTreeMap<(NodeId, NodeId), Link>,
+    in_flight: BinaryHeap<Reverse<Delivery>>,
+    next_sequence: u64,
+    random: SplitMix64,
+    statistics: SimulatorStatistics,
+    recording: bool,
+    events: Vec<SimulatorEvent>,
+}
+
+impl<S: NetworkStack> Simulator<S> {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            now: Duration::ZERO,
+            nodes: Vec::new(),
+            links: BTreeMap::new(),
+            in_flight: BinaryHeap::new(),
+            next_sequence: 0,
+            random: SplitMix64::new(seed),
+            statistics: SimulatorStatistics::default(),
+            recording: false,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn add_node(&mut self, stack: S, addresses: &[Ipv4Address]) -> NodeId {
+        self.nodes.push(Node {
+            stack,
+            addresses: addresses.to_vec(),
+        });
+        self.nodes.len() - 1
+    }
+
+    /*
+     * 両方向に同じ性質のリンクを張る。
+     */
+    pub fn connect(&mut self, a: NodeId, b: NodeId, conditions: LinkConditions) {
+        self.set_link_conditions(a, b, conditions);
+        self.set_link_conditions(b, a, conditions);
+    }
+
+    pub fn set_link_conditions(&mut self, from: NodeId, to: NodeId, conditions: LinkConditions) {
+        assert!(
+            from < self.nodes.len() && to < self.nodes.len(),
+            "Unknown node."
+        );
+        self.links
+            .entry((from, to))
+            .and_modify(|link| link.conditions = conditions)
+            .or_insert(Link {
+                conditions,
+                busy_until: Duration::ZERO,
+            });
+    }
+
+    pub fn get_now(&self) -> Duration {
+        self.now
+    }
+
+    pub fn get_stack(&self, node: NodeId) -> &S {
+        &self.nodes[node].stack
+    }
+
+    /*
+     * NOTE: スタックを直接操作して送信させた場合も、次の`step`で取り出して送る。
+     */
+    pub fn get_stack_mut(&mut self, node: NodeId) -> &mut S {
+        &mut self.nodes[node].stack
+    }
+
+    pub fn get_statistics(&self) -> SimulatorStatistics {
+        self.statistics
+    }
+
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording = recording;
+    }
+
+    pub fn get_events(&self) -> &[SimulatorEvent] {
+        &self.events
+    }
+
+    /*
+     * 次のイベントを 1 つ処理する。処理するものがなければ false.
+     */
+    pub fn step(&mut self) -> bool {
+        self.transmit_all();
+
+        let next_delivery = self.in_flight.peek().map(|Reverse(delivery)| delivery.at);
+        let next_timeout = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(id, node)| node.stack.poll_timeout().map(|at| (at, id)))
+            .min();
+
+        match (next_delivery, next_timeout) {
+            (None, None) => false,
+            (Some(at), Some((timeout, _))) if at <= timeout => {
+                self.deliver_next();
+                true
+            }
+            (Some(_), None) => {
+                self.deliver_next();
+                true
+            }
+            (_, Some((timeout, id))) => {
+                self.now = self.now.max(timeout);
+                let now = self.now;
+                self.nodes[id].stack.handle_timeout(now);
+                true
+            }
+        }
+    }
+
+    /*
+     * `deadline`までのイベントを全て処理して、時刻を`deadline`に進める。
+     */
+    pub fn run_until(&mut self, deadline: Duration) {
+        while self.next_event_time().is_some_and(|at| at <= deadline) {
+            self.step();
+        }
+        self.now = self.now.max(deadline);
+        self.transmit_all();
+    }
+
+    pub fn run_for(&mut self, duration: Duration) {
+        self.run_until(self.now + duration);
+    }
+
+    /*
+     * 処理するものがなくなるまで進める。`max_steps`回で止まらなければ false.
+     */
+    pub fn run_until_idle(&mut self, max_steps: usize) -> bool {
+        for _ in 0..max_steps {
+            if !self.step() {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn next_event_time(&mut self) -> Option<Duration> {
+        self.transmit_all();
+        let next_delivery = self.in_flight.peek().map(|Reverse(delivery)| delivery.at);
+        let next_timeout = self
+            .nodes
+            .iter()
+            .filter_map(|node| node.stack.poll_timeout())
+            .min();
+        match (next_delivery, next_timeout) {
+            (Some(a), Some(b)) => Some(a.min(b).max(self.now)),
+            (a, b) => a.or(b).map(|at| at.max(self.now)),
+        }
+    }
+
+    fn deliver_next(&mut self) {
+        let Some(Reverse(delivery)) = self.in_flight.pop() else {
+            return;
+        };
+        self.now = self.now.max(delivery.at);
+        self.statistics.delivered += 1;
+        self.record(
+            SimulatorEventKind::Delivered,
+            delivery.from,
+            Some(delivery.to),
+            delivery.datagram.len(),
+        );
+
+        let now = self.now;
+        self.nodes[delivery.to]
+            .stack
+            .receive(now, &delivery.datagram);
+    }
+
+    fn transmit_all(&mut self) {
+        let now = self.now;
+        for from in 0..self.nodes.len() {
+            while let Some(datagram) = self.nodes[from].stack.poll_transmit(now) {
+                self.transmit(from, datagram);
+            }
+        }
+    }
+
+    fn transmit(&mut self, from: NodeId, datagram: Vec<u8>) {
+        self.statistics.sent += 1;
+        self.record(SimulatorEventKind::Sent, from, None, datagram.len());
+
+        let destination = datagram
+            .get(16..20)
+            .map(|bytes| [bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let to = destination.and_then(|destination| {
+            self.nodes
+                .iter()
+                .position(|node| node.addresses.contains(&destination))
+        });
+        let Some(link) = to.and_then(|to| self.links.get_mut(&(from, to))) else {
+            self.statistics.unroutable += 1;
+            self.record(SimulatorEventKind::Unroutable, from, to, datagram.len());
+            return;
+        };
+        let to = to.unwrap();
+        let conditions = link.conditions;
+
+        /*
+         * 帯域制限：前の datagram を送り終わってから、長さ分の時間をかけて送り出す。
+         * 失われる datagram も帯域は消費する。
+         */
+        let mut departure = self.now;
+        if let Some(bandwidth) = conditions.bandwidth {
+            let bits = datagram.len() as u64 * 8;
+            let serialization = Duration::from_nanos(bits * 1_000_000_000 / bandwidth);
+            departure = link.busy_until.max(self.now) + serialization;
+            link.busy_until = departure;
+        }
+
+        if self.random.chance(conditions.loss) {
+            self.statistics.lost += 1;
+            self.record(SimulatorEventKind::Lost, from, Some(to), datagram.len());
+            return;
+        }
+
+        let copies = if self.random.chance(conditions.duplication) {
+            self.statistics.duplicated += 1;
+            self.record(
+                SimulatorEventKind::Duplicated,
+                from,
+                Some(to),
+                datagram.len(),
+            );
+            2
+        } else {
+            1
+        };
+
+        for _ in 0..copies {
+            let mut datagram = datagram.clone();
+            let mut at = departure + conditions.delay + self.random.duration(conditions.jitter);
+
+            if self.random.chance(conditions.reordering) {
+                self.statistics.reordered += 1;
+                self.record(
+                    SimulatorEventKind::Reordered,
+                    from,
+                    Some(to),
+                    datagram.len(),
+                );
+                at += conditions.reordering_delay;
+            }
+
+            if !datagram.is_empty() && self.random.chance(conditions.corruption) {
+                let bit = self.random.below(datagram.len() as u64 * 8) as usize;
+                datagram[bit / 8] ^= 1 << (bit % 8);
+                self.statistics.corrupted += 1;
+                self.record(
+                    SimulatorEventKind::Corrupted,
+                    from,
+                    Some(to),
+                    datagram.len(),
+                );
+            }
+
+            self.in_flight.push(Reverse(Delivery {
+                at,
+                sequence: self.next_sequence,
+                from,
+                to,
+                datagram,
+            }));
+            self.next_sequence += 1;
+        }
+    }
+
+    fn record(
+        &mut self,
+        kind: SimulatorEventKind,
+        from: NodeId,
+        to: Option<NodeId>,
+        length: usize,
+    ) {
+        if self.recording {
+            self.events.push(SimulatorEvent {
+                time: self.now,
+                kind,
+                from,
+                to,
+                length,
+            });
+        }
+    }
+}
+
+/*
+ * 再現性のための小さな擬似乱数生成器。暗号用途には使わないこと。
+ */
+struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /*
+     * [0.0, 1.0) の一様乱数。
+     */
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /*
+     * NOTE: 確率 0 の時は乱数を消費しない。条件を追加しても他の乱数列が変わらないように。
+     */
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+
+    fn duration(&mut self, max: Duration) -> Duration {
+        if max.is_zero() {
+            return Duration::ZERO;
+        }
+        let nanos = max.as_nanos().min(u128::from(u64::MAX)) as u64;
+        Duration::from_nanos(self.below(nanos.saturating_add(1)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internet_protocol::Ipv4Header;
+    use crate::protocol_registry::{Direction, PacketTap};
+    use crate::tcp_stack::{SocketError, TcpConfig, TcpStack};
+    use crate::transmission_control_protocol::tcp_packet::TcpPacket;
+    use crate::transmission_control_protocol::TCP_PROTOCOL_NUMBER;
+    use alloc::boxed::Box;
+    use alloc::rc::Rc;
+    use alloc::vec;
+    use core::cell::Cell;
+
+    const A: Ipv4Address = [10, 0, 0, 1];
+    const B: Ipv4Address = [10, 0, 0, 2];
+
+    /*
+     * 受け取った datagram の時刻と中身を記録し、`outbox`に入れたものを送るだけのスタック。
+     * `resend_after`を設定すると、その時刻に 1 回だけ送信するタイマーを持つ。
+     */
+    #[derive(Default)]
+    struct TestStack {
+        received: Vec<(Duration, Vec<u8>)>,
+        outbox: Vec<Vec<u8>>,
+        timer: Option<(Duration, Vec<u8>)>,
+    }
+
+    impl NetworkStack for TestStack {
+        fn receive(&mut self, now: Duration, datagram: &[u8]) {
+            self.received.push((now, datagram.to_vec()));
+        }
+
+        fn poll_transmit(&mut self, _now: Duration) -> Option<Vec<u8>> {
+            if self.outbox.is_empty() {
+                None
+            } else {
+                Some(self.outbox.remove(0))
+            }
+        }
+
+        fn poll_timeout(&self) -> Option<Duration> {
+            self.timer.as_ref().map(|(at, _)| *at)
+        }
+
+        fn handle_timeout(&mut self, _now: Duration) {
+            if let Some((_, datagram)) = self.timer.take() {
+                self.outbox.push(datagram);
+            }
+        }
+    }
+
+    fn datagram(source: Ipv4Address, destination: Ipv4Address, payload_len: usize) -> Vec<u8> {
+        let mut header = Ipv4Header::new(source, destination, TCP_PROTOCOL_NUMBER);
+        header.set_total_length((20 + payload_len) as u16);
+        let mut datagram = header.encode();
+        datagram.resize(20 + payload_len, 0);
+        datagram
+    }
+
+    fn two_nodes(seed: u64, conditions: LinkConditions) -> (Simulator<TestStack>, NodeId, NodeId) {
+        let mut simulator = Simulator::new(seed);
+        let a = simulator.add_node(TestStack::default(), &[A]);
+        let b = simulator.add_node(TestStack::default(), &[B]);
+        simulator.connect(a, b, conditions);
+        (simulator, a, b)
+    }
+
+    #[test]
+    fn test_delay_and_bandwidth() {
+        let mut conditions = LinkConditions::ideal();
+        conditions.set_delay(Duration::from_millis(10));
+        conditions.set_bandwidth(Some(8_000_000));
+        let (mut simulator, a, b) = two_nodes(1, conditions);
+
+        /*
+         * 1000bytes は 8Mbps で 1ms かかるので、2 つ目は 1ms 遅れて届く。
+         */
+        simulator.get_stack_mut(a).outbox = vec![datagram(A, B, 980), datagram(A, B, 980)];
+        assert!(simulator.run_until_idle(100));
+
+        let arrivals: Vec<Duration> = simulator
+            .get_stack(b)
+            .received
+            .iter()
+            .map(|(at, _)| *at)
+            .collect();
+        assert_eq!(
+            arrivals,
+            vec![Duration::from_millis(11), Duration::from_millis(12)]
+        );
+        assert_eq!(simulator.get_now(), Duration::from_millis(12));
+    }
+
+    #[test]
+    fn test_timers_drive_the_clock() {
+        let (mut simulator, a, b) = two_nodes(1, LinkConditions::ideal());
+        simulator.get_stack_mut(a).timer = Some((Duration::from_secs(3), datagram(A, B, 0)));
+
+        simulator.run_until(Duration::from_secs(2));
+        assert!(simulator.get_stack(b).received.is_empty());
+        assert_eq!(simulator.get_now(), Duration::from_secs(2));
+
+        simulator.run_for(Duration::from_secs(2));
+        assert_eq!(simulator.get_stack(b).received.len(), 1);
+        assert_eq!(simulator.get_stack(b).received[0].0, Duration::from_secs(3));
+        assert_eq!(simulator.get_now(), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_impairments_are_deterministic() {
+        let mut conditions = LinkConditions::ideal();
+        conditions.set_delay(Duration::from_millis(5));
+        conditions.set_jitter(Duration::from_millis(5));
+        conditions.set_loss(0.1);
+        conditions.set_duplication(0.1);
+        conditions.set_reordering(0.1, Duration::from_millis(20));
+        conditions.set_corruption(0.1);
+
+        let run = |seed| {
+            let (mut simulator, a, b) = two_nodes(seed, conditions);
+            simulator.set_recording(true);
+            simulator.get_stack_mut(a).outbox = (0..1000).map(|_| datagram(A, B, 20)).collect();
+            assert!(simulator.run_until_idle(10_000));
+            (
+                simulator.get_statistics(),
+                simulator.get_events().to_vec(),
+                simulator.get_stack(b).received.clone(),
+            )
+        };
+
+        let (statistics, events, received) = run(42);
+        assert_eq!(run(42), (statistics, events.clone(), received.clone()));
+        assert_ne!(run(43).1, events);
+
+        assert_eq!(statistics.sent, 1000);
+        assert_eq!(
+            statistics.delivered,
+            1000 - statistics.lost + statistics.duplicated
+        );
+        assert_eq!(received.len(), statistics.delivered);
+        for count in [
+            statistics.lost,
+            statistics.duplicated,
+            statistics.reordered,
+            statistics.corrupted,
+        ] {
+            assert!((50..200).contains(&count), "{:?}", statistics);
+        }
+
+        let corrupted = received
+            .iter()
+            .filter(|(_, datagram)| *datagram != self::datagram(A, B, 20))
+            .count();
+        assert_eq!(corrupted, statistics.corrupted);
+    }
+
+    /*
+     * 送った TCP の payload の合計を数える。送るべきデータより多ければ再送している。
+     */
+    #[derive(Clone, Default)]
+    struct PayloadCounter(Rc<Cell<usize>>);
+
+    impl PacketTap for PayloadCounter {
+        fn tap(&mut self, direction: Direction, datagram: &[u8]) {
+            if direction == Direction::Outbound {
+                let packet = TcpPacket::decode(datagram).unwrap();
+                self.0.set(self.0.get() + packet.get_payload().len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_tcp_bulk_transfer_over_lossy_link() {
+        let mut conditions = LinkConditions::ideal();
+        conditions.set_delay(Duration::from_millis(10));
+        conditions.set_jitter(Duration::from_millis(2));
+        conditions.set_loss(0.05);
+        conditions.set_reordering(0.05, Duration::from_millis(15));
+
+        let mut simulator = Simulator::new(7);
+        let server = simulator.add_node(
+            TcpStack::with_secret(&[A], TcpConfig::default(), [1; 16]),
+            &[A],
+        );
+        let client = simulator.add_node(
+            TcpStack::with_secret(&[B], TcpConfig::default(), [2; 16]),
+            &[B],
+        );
+        simulator.connect(server, client, conditions);
+
+        let listener = simulator.get_stack_mut(server).socket();
+        simulator
+            .get_stack_mut(server)
+            .bind(listener, A, 80)
+            .unwrap();
+        simulator.get_stack_mut(server).listen(listener, 1).unwrap();
+
+        let sent_payload = PayloadCounter::default();
+        simulator
+            .get_stack_mut(client)
+            .set_tap(Some(Box::new(sent_payload.clone())));
+        let socket = simulator.get_stack_mut(client).socket();
+        simulator
+            .get_stack_mut(client)
+            .connect(Duration::ZERO, socket, A, 80)
+            .unwrap();
+
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut sent = 0;
+        let mut accepted = None;
+        let mut received = Vec::new();
+        let mut buffer = [0u8; 4096];
+        while received.len() < data.len() {
+            assert!(simulator.get_now() < Duration::from_secs(600));
+            simulator.run_for(Duration::from_millis(10));
+            let now = simulator.get_now();
+
+            let stack = simulator.get_stack_mut(client);
+            if sent < data.len() && stack.get_state(socket).unwrap().is_synchronized() {
+                match stack.send(now, socket, &data[sent..]) {
+                    Ok(length) => sent += length,
+                    Err(SocketError::WouldBlock) => {}
+                    Err(error) => panic!("{:?}", error),
+                }
+            }
+
+            let stack = simulator.get_stack_mut(server);
+            accepted = accepted.or_else(|| stack.accept(listener).ok());
+            if let Some(accepted) = accepted {
+                while let Ok(length) = stack.recv(now, accepted, &mut buffer) {
+                    received.extend_from_slice(&buffer[..length]);
+                }
+            }
+        }
+
+        assert!(received == data);
+        let statistics = simulator.get_statistics();
+        assert!(statistics.lost > 0 && statistics.reordered > 0);
+        assert!(sent_payload.0.get() > data.len());
+    }
+
+    #[test]
+    fn test_unroutable() {
+        let mut simulator = Simulator::new(0);
+        let a = simulator.add_node(TestStack::default(), &[A]);
+        let b = simulator.add_node(TestStack::default(), &[B]);
+        simulator.get_stack_mut(a).outbox = vec![datagram(A, B, 0), datagram(A, [10, 0, 0, 9], 0)];
+
+        assert!(simulator.run_until_idle(10));
+        assert_eq!(simulator.get_statistics().unroutable, 2);
+        assert!(simulator.get_stack(b).received.is_empty());
+    }
+}
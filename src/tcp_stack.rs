@@ -0,0 +1,3577 @@
+use crate::decode_options::DecodeOptions;
+use crate::internet_control_message_protocol::{IcmpMessage, ICMP_PROTOCOL_NUMBER};
+use crate::internet_protocol::{Ipv4Address, Ipv4Header, DONT_FRAGMENT_FLAG, IPV4_HEADER_MIN_LEN};
+use crate::network_stack::NetworkStack;
+use crate::protocol_registry::{Direction, PacketTap};
+use crate::timer::{TimerId, TimerWheel};
+use crate::transmission_control_protocol::tcp_option::TcpOption;
+use crate::transmission_control_protocol::tcp_packet::TcpPacket;
+use crate::transmission_control_protocol::{TCP_HEADER_MIN_LEN, TCP_PROTOCOL_NUMBER};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::vec::Vec;
+use core::fmt;
+use core::hash::BuildHasher;
+use core::time::Duration;
+
+use challenge_ack::ChallengeAckLimiter;
+pub use connection::TcpState;
+use connection::{Connection, DEFAULT_SEND_MSS};
+use demux::{ConnectionTable, Destination, KeyedHasher};
+use ephemeral_port::EphemeralPortAllocator;
+use initial_sequence::InitialSequenceNumberGenerator;
+use syn_cookie::{SynCookieOptions, SynCookies};
+
+mod challenge_ack;
+mod connection;
+mod demux;
+mod ephemeral_port;
+mod initial_sequence;
+mod path_mtu;
+mod retransmission;
+mod syn_cookie;
+
+/*
+ * ソケット API を持つ TCP スタック。
+ *
+ * BSD ソケットに似た操作（socket, bind, listen, accept, connect, send, recv, close）を、
+ * ノンブロッキングで提供する。パケットの送受信とタイマーは`NetworkStack`を通して
+ * 呼び出し側が駆動する。
+ *
+ * コネクションのタイマー（再送、遅延 ACK など）は、一番早いものを 1 つだけスタック全体の`TimerWheel`に登録する。
+ *
+ * NOTE: まだ輻輳制御、順序の入れ替わったセグメントの再構築はしない。
+ */
+pub struct TcpStack {
+    addresses: Vec<Ipv4Address>,
+    config: TcpConfig,
+    sockets: BTreeMap<SocketHandle, Socket>,
+    table: ConnectionTable,
+    timers: TimerWheel<SocketHandle>,
+    next_handle: usize,
+    ephemeral_ports: EphemeralPortAllocator,
+    initial_sequence_numbers: InitialSequenceNumberGenerator,
+
+    /*
+     * RFC 7323 7.1: TSval の時計に足すずれを 4 タプルごとに変える。起動してからの時間を見せず、
+     * 他のコネクションの TSval からも推測できないように。
+     */
+    timestamp_offsets: KeyedHasher,
+    syn_cookies: SynCookies,
+
+    /*
+     * TIME-WAIT のコネクション。`TcpConfig::maximum_time_wait_connections`を超えないようにする。
+     */
+    time_waits: BTreeSet<SocketHandle>,
+    challenge_acks: ChallengeAckLimiter,
+    outbox: VecDeque<Vec<u8>>,
+    now: Duration,
+
+    /*
+     * `ProtocolRegistry`を通さずに IPv4 を処理するので、キャプチャ用の tap もスタックが持つ。
+     */
+    tap: Option<Box<dyn PacketTap>>,
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+pub struct SocketHandle(usize);
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+pub struct Endpoint {
+    pub address: Ipv4Address,
+    pub port: u16,
+}
+
+impl Endpoint {
+    pub fn new(address: Ipv4Address, port: u16) -> Self {
+        Self { address, port }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.address;
+        write!(f, "{}.{}.{}.{}:{}", a, b, c, d, self.port)
+    }
+}
+
+/*
+ * オプションなしの IPv4 ヘッダーと TCP ヘッダー。MTU からこれを引いたものが MSS になる。
+ */
+pub(crate) const HEADERS_LENGTH: u16 = (IPV4_HEADER_MIN_LEN + TCP_HEADER_MIN_LEN) as u16;
+
+/*
+ * TCP のセグメントは全て DF を立てて送る。途中で分片させずに ICMP Fragmentation Needed を
+ * 返してもらい、Path MTU Discovery (RFC 1191) に使う。
+ */
+pub(crate) fn segment_ip_header(source: Ipv4Address, destination: Ipv4Address) -> Ipv4Header {
+    let mut header = Ipv4Header::new(source, destination, TCP_PROTOCOL_NUMBER);
+    header.set_flags(DONT_FRAGMENT_FLAG);
+    header
+}
+
+/*
+ * INADDR_ANY. listen でこのアドレスに bind すると、全てのローカルアドレス宛てを受け付ける。
+ */
+pub const UNSPECIFIED_ADDRESS: Ipv4Address = [0, 0, 0, 0];
+
+/*
+ * 最後に SYN cookie を送ってから、cookie の ACK を受け付ける時間。
+ */
+const SYN_COOKIE_LIFETIME: Duration = Duration::from_secs(128);
+
+/*
+ * タイマーの分解能。RFC 6298 の G.
+ */
+pub(crate) const TIMER_RESOLUTION: Duration = Duration::from_millis(1);
+
+/*
+ * errno に相当するエラー。
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SocketError {
+    InvalidHandle,
+    InvalidState,
+    AddressInUse,
+    AddressNotAvailable,
+    WouldBlock,
+    NotConnected,
+    AlreadyConnected,
+    ConnectionRefused,
+    ConnectionReset,
+    BrokenPipe,
+    TimedOut,
+}
+
+impl SocketError {
+    /*
+     * Linux の errno 名。
+     */
+    pub fn get_errno_name(&self) -> &'static str {
+        match self {
+            SocketError::InvalidHandle => "EBADF",
+            SocketError::InvalidState => "EINVAL",
+            SocketError::AddressInUse => "EADDRINUSE",
+            SocketError::AddressNotAvailable => "EADDRNOTAVAIL",
+            SocketError::WouldBlock => "EAGAIN",
+            SocketError::NotConnected => "ENOTCONN",
+            SocketError::AlreadyConnected => "EISCONN",
+            SocketError::ConnectionRefused => "ECONNREFUSED",
+            SocketError::ConnectionReset => "ECONNRESET",
+            SocketError::BrokenPipe => "EPIPE",
+            SocketError::TimedOut => "ETIMEDOUT",
+        }
+    }
+}
+
+impl fmt::Display for SocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            SocketError::InvalidHandle => "Bad socket handle.",
+            SocketError::InvalidState => "Invalid argument for the socket state.",
+            SocketError::AddressInUse => "Address already in use.",
+            SocketError::AddressNotAvailable => "Cannot assign requested address.",
+            SocketError::WouldBlock => "Resource temporarily unavailable.",
+            SocketError::NotConnected => "Socket is not connected.",
+            SocketError::AlreadyConnected => "Socket is already connected.",
+            SocketError::ConnectionRefused => "Connection refused.",
+            SocketError::ConnectionReset => "Connection reset by peer.",
+            SocketError::BrokenPipe => "Broken pipe.",
+            SocketError::TimedOut => "Connection timed out.",
+        };
+        write!(f, "{} ({})", message, self.get_errno_name())
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SocketError {}
+
+/*
+ * `TcpConfig`の setter に渡された値が範囲外。設定は変わらない。
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TcpConfigError {
+    InvalidMtu(u16),
+    InvalidEphemeralPortRange {
+        minimum: u16,
+        maximum: u16,
+    },
+    InvalidDelayedAckTimeout(Duration),
+    InvalidUserTimeoutLimits {
+        minimum: Duration,
+        maximum: Duration,
+    },
+}
+
+impl fmt::Display for TcpConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpConfigError::InvalidMtu(mtu) => write!(
+                f,
+                "Invalid MTU {}. It must be at least {}.",
+                mtu,
+                path_mtu::MINIMUM_PATH_MTU
+            ),
+            TcpConfigError::InvalidEphemeralPortRange { minimum, maximum } => {
+                write!(f, "Invalid ephemeral port range {}-{}.", minimum, maximum)
+            }
+            TcpConfigError::InvalidDelayedAckTimeout(timeout) => write!(
+                f,
+                "Invalid delayed ACK timeout {:?}. It must be at most 500ms.",
+                timeout
+            ),
+            TcpConfigError::InvalidUserTimeoutLimits { minimum, maximum } => write!(
+                f,
+                "Invalid user timeout limits {:?}-{:?}.",
+                minimum, maximum
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for TcpConfigError {}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TcpConfig {
+    maximum_segment_size: u16,
+    send_buffer_size: usize,
+    receive_buffer_size: usize,
+    initial_retransmission_timeout: Duration,
+    minimum_retransmission_timeout: Duration,
+    maximum_retransmission_timeout: Duration,
+    maximum_retransmissions: u32,
+    maximum_syn_retransmissions: u32,
+    ephemeral_port_range: (u16, u16),
+    syn_cookies: bool,
+    window_scaling: bool,
+    timestamps: bool,
+    mtu: u16,
+    mtu_probing: bool,
+    delayed_ack_timeout: Duration,
+    keep_alive_idle: Duration,
+    keep_alive_interval: Duration,
+    keep_alive_count: u32,
+    user_timeout_limits: (Duration, Duration),
+    maximum_segment_lifetime: Duration,
+    maximum_time_wait_connections: usize,
+    challenge_ack_limit: u32,
+}
+
+impl TcpConfig {
+    /*
+     * SYN で広告する MSS の上限（TCP_MAXSEG）。インターフェースの MTU から決まる MSS の方が
+     * 小さければ、そちらを広告する。送るセグメントも、これと相手の MSS と経路の MTU で切り詰める。
+     */
+    pub fn get_maximum_segment_size(&self) -> u16 {
+        self.maximum_segment_size
+    }
+
+    pub fn set_maximum_segment_size(&mut self, maximum_segment_size: u16) {
+        self.maximum_segment_size = maximum_segment_size;
+    }
+
+    pub(crate) fn get_advertised_mss(&self) -> u16 {
+        self.maximum_segment_size.min(self.mtu - HEADERS_LENGTH)
+    }
+
+    /*
+     * インターフェースの MTU. 経路の MTU もここから探し始める。
+     */
+    pub fn get_mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    pub fn set_mtu(&mut self, mtu: u16) -> Result<(), TcpConfigError> {
+        if mtu < path_mtu::MINIMUM_PATH_MTU {
+            return Err(TcpConfigError::InvalidMtu(mtu));
+        }
+        self.mtu = mtu;
+        Ok(())
+    }
+
+    /*
+     * RFC 4821 の Packetization Layer Path MTU Discovery. 再送が続いたら MTU を下げ、
+     * 大きいセグメントで探り直す。ICMP が届かない経路（ブラックホール）でも通信を続けられる。
+     */
+    pub fn get_mtu_probing(&self) -> bool {
+        self.mtu_probing
+    }
+
+    pub fn set_mtu_probing(&mut self, mtu_probing: bool) {
+        self.mtu_probing = mtu_probing;
+    }
+
+    pub fn get_send_buffer_size(&self) -> usize {
+        self.send_buffer_size
+    }
+
+    pub fn set_send_buffer_size(&mut self, send_buffer_size: usize) {
+        self.send_buffer_size = send_buffer_size;
+    }
+
+    pub fn get_receive_buffer_size(&self) -> usize {
+        self.receive_buffer_size
+    }
+
+    pub fn set_receive_buffer_size(&mut self, receive_buffer_size: usize) {
+        self.receive_buffer_size = receive_buffer_size;
+    }
+
+    /*
+     * RTT を測る前の RTO. RFC 6298 2.1 では 1 秒。
+     */
+    pub fn get_initial_retransmission_timeout(&self) -> Duration {
+        self.initial_retransmission_timeout
+    }
+
+    pub fn set_initial_retransmission_timeout(&mut self, timeout: Duration) {
+        self.initial_retransmission_timeout = timeout;
+    }
+
+    /*
+     * RFC 6298 2.4 では 1 秒。Linux は 200 ミリ秒。
+     */
+    pub fn get_minimum_retransmission_timeout(&self) -> Duration {
+        self.minimum_retransmission_timeout
+    }
+
+    pub fn set_minimum_retransmission_timeout(&mut self, timeout: Duration) {
+        self.minimum_retransmission_timeout = timeout;
+    }
+
+    pub fn get_maximum_retransmission_timeout(&self) -> Duration {
+        self.maximum_retransmission_timeout
+    }
+
+    pub fn set_maximum_retransmission_timeout(&mut self, timeout: Duration) {
+        self.maximum_retransmission_timeout = timeout;
+    }
+
+    /*
+     * 再送をこの回数続けても ACK が来なければ ETIMEDOUT で諦める（Linux の tcp_retries2）。
+     */
+    pub fn get_maximum_retransmissions(&self) -> u32 {
+        self.maximum_retransmissions
+    }
+
+    pub fn set_maximum_retransmissions(&mut self, count: u32) {
+        self.maximum_retransmissions = count;
+    }
+
+    /*
+     * SYN, SYN-ACK の再送の上限（Linux の tcp_syn_retries）。
+     */
+    pub fn get_maximum_syn_retransmissions(&self) -> u32 {
+        self.maximum_syn_retransmissions
+    }
+
+    pub fn set_maximum_syn_retransmissions(&mut self, count: u32) {
+        self.maximum_syn_retransmissions = count;
+    }
+
+    /*
+     * connect() や bind(port 0) で選ぶポートの範囲（両端を含む）。既定は IANA の 49152 - 65535.
+     */
+    pub fn get_ephemeral_port_range(&self) -> (u16, u16) {
+        self.ephemeral_port_range
+    }
+
+    pub fn set_ephemeral_port_range(
+        &mut self,
+        minimum: u16,
+        maximum: u16,
+    ) -> Result<(), TcpConfigError> {
+        if !(0 < minimum && minimum <= maximum) {
+            return Err(TcpConfigError::InvalidEphemeralPortRange { minimum, maximum });
+        }
+        self.ephemeral_port_range = (minimum, maximum);
+        Ok(())
+    }
+
+    /*
+     * SYN-RECEIVED の子コネクションが backlog に達したら、SYN cookie で応える。
+     */
+    pub fn get_syn_cookies(&self) -> bool {
+        self.syn_cookies
+    }
+
+    pub fn set_syn_cookies(&mut self, syn_cookies: bool) {
+        self.syn_cookies = syn_cookies;
+    }
+
+    /*
+     * SYN で Window Scale オプション (RFC 7323) を送る。シフトは受信バッファの大きさで決める。
+     */
+    pub fn get_window_scaling(&self) -> bool {
+        self.window_scaling
+    }
+
+    pub fn set_window_scaling(&mut self, window_scaling: bool) {
+        self.window_scaling = window_scaling;
+    }
+
+    /*
+     * SYN で Timestamps オプション (RFC 7323) を送る。相手も使えば、RTT の測定と PAWS に使う。
+     */
+    pub fn get_timestamps(&self) -> bool {
+        self.timestamps
+    }
+
+    pub fn set_timestamps(&mut self, timestamps: bool) {
+        self.timestamps = timestamps;
+    }
+
+    /*
+     * 遅延 ACK を待つ時間。RFC 1122 4.2.3.2 で 0.5 秒以下と決められている。0 なら遅延させない。
+     */
+    pub fn get_delayed_ack_timeout(&self) -> Duration {
+        self.delayed_ack_timeout
+    }
+
+    pub fn set_delayed_ack_timeout(&mut self, timeout: Duration) -> Result<(), TcpConfigError> {
+        if timeout > Duration::from_millis(500) {
+            return Err(TcpConfigError::InvalidDelayedAckTimeout(timeout));
+        }
+        self.delayed_ack_timeout = timeout;
+        Ok(())
+    }
+
+    /*
+     * SO_KEEPALIVE を有効にしたソケットの TCP_KEEPIDLE, TCP_KEEPINTVL, TCP_KEEPCNT の既定値。
+     * Linux と同じく 2 時間、75 秒、9 回。
+     */
+    pub fn get_keep_alive_idle(&self) -> Duration {
+        self.keep_alive_idle
+    }
+
+    pub fn set_keep_alive_idle(&mut self, idle: Duration) {
+        self.keep_alive_idle = idle;
+    }
+
+    pub fn get_keep_alive_interval(&self) -> Duration {
+        self.keep_alive_interval
+    }
+
+    pub fn set_keep_alive_interval(&mut self, interval: Duration) {
+        self.keep_alive_interval = interval;
+    }
+
+    pub fn get_keep_alive_count(&self) -> u32 {
+        self.keep_alive_count
+    }
+
+    pub fn set_keep_alive_count(&mut self, count: u32) {
+        self.keep_alive_count = count;
+    }
+
+    /*
+     * 相手の User Timeout オプションから決める USER_TIMEOUT の下限と上限 (RFC 5482 の L_LIMIT, U_LIMIT)。
+     * 下限の既定値は RFC 1122 4.2.3.5 の R2 と同じ 100 秒、上限は 1 時間。
+     */
+    pub fn get_user_timeout_limits(&self) -> (Duration, Duration) {
+        self.user_timeout_limits
+    }
+
+    pub fn set_user_timeout_limits(
+        &mut self,
+        minimum: Duration,
+        maximum: Duration,
+    ) -> Result<(), TcpConfigError> {
+        if minimum > maximum {
+            return Err(TcpConfigError::InvalidUserTimeoutLimits { minimum, maximum });
+        }
+        self.user_timeout_limits = (minimum, maximum);
+        Ok(())
+    }
+
+    /*
+     * MSL. TIME-WAIT ではこの 2 倍待つ。既定値は Linux の TIME-WAIT (TCP_TIMEWAIT_LEN) が 60 秒になるように 30 秒。
+     */
+    pub fn get_maximum_segment_lifetime(&self) -> Duration {
+        self.maximum_segment_lifetime
+    }
+
+    pub fn set_maximum_segment_lifetime(&mut self, lifetime: Duration) {
+        self.maximum_segment_lifetime = lifetime;
+    }
+
+    /*
+     * TIME-WAIT のコネクションの上限（Linux の tcp_max_tw_buckets）。超えたら TIME-WAIT を飛ばして閉じる。
+     */
+    pub fn get_maximum_time_wait_connections(&self) -> usize {
+        self.maximum_time_wait_connections
+    }
+
+    pub fn set_maximum_time_wait_connections(&mut self, count: usize) {
+        self.maximum_time_wait_connections = count;
+    }
+
+    /*
+     * スタック全体で 1 秒あたりに送る RFC 5961 の challenge ACK の数の目安（Linux の tcp_challenge_ack_limit）。
+     * 実際の上限は、この半分から 1.5 倍の間で秒ごとに変わる。0 なら challenge ACK を送らない。
+     */
+    pub fn get_challenge_ack_limit(&self) -> u32 {
+        self.challenge_ack_limit
+    }
+
+    pub fn set_challenge_ack_limit(&mut self, limit: u32) {
+        self.challenge_ack_limit = limit;
+    }
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            maximum_segment_size: 1460,
+            send_buffer_size: 262144,
+            receive_buffer_size: 262144,
+            initial_retransmission_timeout: Duration::from_secs(1),
+            minimum_retransmission_timeout: Duration::from_secs(1),
+            maximum_retransmission_timeout: Duration::from_secs(60),
+            maximum_retransmissions: 15,
+            maximum_syn_retransmissions: 6,
+            ephemeral_port_range: (49152, 65535),
+            syn_cookies: true,
+            window_scaling: true,
+            timestamps: true,
+            mtu: 1500,
+            mtu_probing: true,
+            delayed_ack_timeout: Duration::from_millis(200),
+            keep_alive_idle: Duration::from_secs(7200),
+            keep_alive_interval: Duration::from_secs(75),
+            keep_alive_count: 9,
+            user_timeout_limits: (Duration::from_secs(100), Duration::from_secs(3600)),
+            maximum_segment_lifetime: Duration::from_secs(30),
+            maximum_time_wait_connections: 262144,
+            challenge_ack_limit: 1000,
+        }
+    }
+}
+
+/*
+ * setsockopt で設定できるもの。
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SocketOption {
+    ReuseAddress(bool),
+    ReusePort(bool),
+    SendBufferSize(usize),
+    ReceiveBufferSize(usize),
+    NoDelay(bool),
+    Cork(bool),
+    QuickAck(bool),
+    KeepAlive(bool),
+    KeepAliveIdle(Duration),
+    KeepAliveInterval(Duration),
+    KeepAliveCount(u32),
+    UserTimeout(Duration),
+    AdvertisedUserTimeout(Duration),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SocketOptions {
+    /*
+     * SO_REUSEADDR: TIME-WAIT のコネクションや、同じく SO_REUSEADDR の listen していない
+     * ソケットがあっても bind できる。
+     */
+    reuse_address: bool,
+
+    /*
+     * SO_REUSEPORT: 全員が SO_REUSEPORT なら、listen も含めて同じアドレスとポートを共有できる。
+     */
+    reuse_port: bool,
+    send_buffer_size: usize,
+    receive_buffer_size: usize,
+
+    /*
+     * TCP_NODELAY: Nagle のアルゴリズムを使わず、小さいセグメントもすぐに送る。
+     */
+    nodelay: bool,
+
+    /*
+     * TCP_CORK: MSS に満たないデータは、cork を外すか 200 ミリ秒経つまで送らない。
+     */
+    cork: bool,
+
+    /*
+     * TCP_QUICKACK: 遅延 ACK を使わない。Linux と違い、false にするまで続く。
+     */
+    quick_ack: bool,
+
+    /*
+     * SO_KEEPALIVE: 何も受信しないまま`keep_alive_idle`経ったら、`keep_alive_interval`ごとに
+     * keepalive probe を送り、`keep_alive_count`回応答がなければ閉じる。
+     */
+    keep_alive: bool,
+    keep_alive_idle: Duration,
+    keep_alive_interval: Duration,
+    keep_alive_count: u32,
+
+    /*
+     * TCP_USER_TIMEOUT: 送ったものがこれより長く ACK されなければ閉じる。0 なら再送の回数で決める。
+     */
+    user_timeout: Duration,
+
+    /*
+     * RFC 5482 の ADV_UTO. 0 でなければ User Timeout オプションで相手に知らせ、
+     * 相手から受け取ったものと合わせて USER_TIMEOUT を決める。
+     */
+    advertised_user_timeout: Duration,
+}
+
+enum Socket {
+    /*
+     * socket() の直後、もしくは bind() の後。
+     */
+    Unconnected {
+        local: Option<Endpoint>,
+        options: SocketOptions,
+    },
+    Listener {
+        local: Endpoint,
+        backlog: usize,
+        options: SocketOptions,
+
+        /*
+         * まだ accept されていない子コネクション（SYN-RECEIVED も含む）。
+         */
+        queue: VecDeque<SocketHandle>,
+
+        /*
+         * 最後に SYN cookie を送った時刻。最近あふれていなければ cookie の ACK は受け付けない。
+         */
+        last_overflow: Option<Duration>,
+    },
+    Connection {
+        connection: Box<Connection>,
+        options: SocketOptions,
+
+        /*
+         * listen ソケットから生まれたもので、まだ accept されていなければ親。
+         */
+        parent: Option<SocketHandle>,
+
+        /*
+         * close() された。コネクションが CLOSED になったら消す。
+         */
+        closed: bool,
+
+        /*
+         * `TcpStack::timers`に登録しているタイマー。
+         */
+        timer: Option<TimerId>,
+    },
+}
+
+impl Socket {
+    fn get_local(&self) -> Option<Endpoint> {
+        match self {
+            Socket::Unconnected { local, .. } => *local,
+            Socket::Listener { local, .. } => Some(*local),
+            Socket::Connection { connection, .. } => Some(connection.get_local()),
+        }
+    }
+
+    fn get_options(&self) -> SocketOptions {
+        match self {
+            Socket::Unconnected { options, .. }
+            | Socket::Listener { options, .. }
+            | Socket::Connection { options, .. } => *options,
+        }
+    }
+}
+
+/*
+ * std の`RandomState`は、OS の乱数で初期化した SipHash の鍵を持っているので、それを借りる。
+ */
+#[cfg(feature = "std")]
+fn random_secret() -> [u8; 16] {
+    use std::hash::BuildHasher;
+
+    let state = std::collections::hash_map::RandomState::new();
+    let mut secret = [0; 16];
+    secret[..8].copy_from_slice(&state.hash_one(0u8).to_le_bytes());
+    secret[8..].copy_from_slice(&state.hash_one(1u8).to_le_bytes());
+    secret
+}
+
+/*
+ * NOTE: no_std では乱数源がないので、固定の鍵になる。`TcpStack::with_secret`で渡すこと。
+ */
+#[cfg(not(feature = "std"))]
+fn random_secret() -> [u8; 16] {
+    [0; 16]
+}
+
+fn overlaps(bound: Endpoint, address: Ipv4Address, port: u16) -> bool {
+    bound.port == port
+        && (bound.address == address
+            || bound.address == UNSPECIFIED_ADDRESS
+            || address == UNSPECIFIED_ADDRESS)
+}
+
+/*
+ * `handle`を`address`:`port`に bind すると、他のソケットとぶつかるか。
+ */
+fn has_bind_conflict(
+    sockets: &BTreeMap<SocketHandle, Socket>,
+    handle: SocketHandle,
+    options: SocketOptions,
+    address: Ipv4Address,
+    port: u16,
+) -> bool {
+    sockets.iter().any(|(other_handle, other)| {
+        let Some(other_local) = other.get_local() else {
+            return false;
+        };
+        if *other_handle == handle || !overlaps(other_local, address, port) {
+            return false;
+        }
+        let other_options = other.get_options();
+        let reusable = match other {
+            Socket::Connection { connection, .. }
+                if connection.get_state() == TcpState::TimeWait =>
+            {
+                options.reuse_address
+            }
+            Socket::Listener { .. } => false,
+            _ => options.reuse_address && other_options.reuse_address,
+        };
+        !(reusable || (options.reuse_port && other_options.reuse_port))
+    })
+}
+
+impl TcpStack {
+    pub fn new(addresses: &[Ipv4Address]) -> Self {
+        Self::with_config(addresses, TcpConfig::default())
+    }
+
+    pub fn with_config(addresses: &[Ipv4Address], config: TcpConfig) -> Self {
+        Self::with_secret(addresses, config, random_secret())
+    }
+
+    /*
+     * 4 タプルのハッシュ、ephemeral port、ISN に使う秘密の鍵を決めて作る。再現性の欲しいテストや、乱数源のない環境のため。
+     * 用途ごとに`secret`から別の副鍵を作って使う。
+     */
+    pub fn with_secret(addresses: &[Ipv4Address], config: TcpConfig, secret: [u8; 16]) -> Self {
+        let hasher = KeyedHasher::new(secret);
+        Self {
+            addresses: addresses.to_vec(),
+            config,
+            sockets: BTreeMap::new(),
+            table: ConnectionTable::new(hasher.derive("connection table")),
+            timers: TimerWheel::new(TIMER_RESOLUTION),
+            next_handle: 0,
+            ephemeral_ports: EphemeralPortAllocator::new(hasher.derive("ephemeral port")),
+            initial_sequence_numbers: InitialSequenceNumberGenerator::new(
+                hasher.derive("initial sequence"),
+            ),
+            timestamp_offsets: hasher.derive("timestamp offset"),
+            syn_cookies: SynCookies::new(hasher.derive("syn cookie")),
+            time_waits: BTreeSet::new(),
+            challenge_acks: ChallengeAckLimiter::new(
+                hasher.derive("challenge ack"),
+                config.challenge_ack_limit,
+            ),
+            outbox: VecDeque::new(),
+            now: Duration::ZERO,
+            tap: None,
+        }
+    }
+
+    /*
+     * 受信した datagram は decode する前に、送信する datagram は`poll_transmit`で取り出す時に渡す。
+     * 以前に設定されていた tap があれば、置き換えて返す。`None`で外す。
+     */
+    pub fn set_tap(&mut self, tap: Option<Box<dyn PacketTap>>) -> Option<Box<dyn PacketTap>> {
+        core::mem::replace(&mut self.tap, tap)
+    }
+
+    pub fn get_config(&self) -> &TcpConfig {
+        &self.config
+    }
+
+    pub fn socket(&mut self) -> SocketHandle {
+        let handle = self.allocate_handle();
+        self.sockets.insert(
+            handle,
+            Socket::Unconnected {
+                local: None,
+                options: SocketOptions {
+                    reuse_address: false,
+                    reuse_port: false,
+                    send_buffer_size: self.config.send_buffer_size,
+                    receive_buffer_size: self.config.receive_buffer_size,
+                    nodelay: false,
+                    cork: false,
+                    quick_ack: false,
+                    keep_alive: false,
+                    keep_alive_idle: self.config.keep_alive_idle,
+                    keep_alive_interval: self.config.keep_alive_interval,
+                    keep_alive_count: self.config.keep_alive_count,
+                    user_timeout: Duration::ZERO,
+                    advertised_user_timeout: Duration::ZERO,
+                },
+            },
+        );
+        handle
+    }
+
+    fn allocate_handle(&mut self) -> SocketHandle {
+        self.next_handle += 1;
+        SocketHandle(self.next_handle - 1)
+    }
+
+    pub fn set_option(
+        &mut self,
+        handle: SocketHandle,
+        option: SocketOption,
+    ) -> Result<(), SocketError> {
+        let socket = self.get_socket_mut(handle)?;
+        let options = match socket {
+            Socket::Unconnected { options, .. }
+            | Socket::Listener { options, .. }
+            | Socket::Connection { options, .. } => options,
+        };
+        match option {
+            SocketOption::ReuseAddress(reuse_address) => options.reuse_address = reuse_address,
+            SocketOption::ReusePort(reuse_port) => options.reuse_port = reuse_port,
+            SocketOption::SendBufferSize(size) => options.send_buffer_size = size,
+            SocketOption::ReceiveBufferSize(size) => options.receive_buffer_size = size,
+            SocketOption::NoDelay(nodelay) => options.nodelay = nodelay,
+            SocketOption::Cork(cork) => options.cork = cork,
+            SocketOption::QuickAck(quick_ack) => options.quick_ack = quick_ack,
+            SocketOption::KeepAlive(keep_alive) => options.keep_alive = keep_alive,
+            SocketOption::KeepAliveIdle(idle) => options.keep_alive_idle = idle,
+            SocketOption::KeepAliveInterval(interval) => options.keep_alive_interval = interval,
+            SocketOption::KeepAliveCount(count) => options.keep_alive_count = count,
+            SocketOption::UserTimeout(timeout) => options.user_timeout = timeout,
+            SocketOption::AdvertisedUserTimeout(timeout) => {
+                options.advertised_user_timeout = timeout
+            }
+        }
+
+        /*
+         * 接続済みなら、cork を外した時などに待たせていたものを送る。
+         */
+        if let Socket::Connection { connection, .. } = socket {
+            connection.set_option(option);
+            self.flush(handle);
+        }
+        Ok(())
+    }
+
+    /*
+     * ポート 0 なら、他と衝突しないポートを ephemeral port の範囲から選ぶ。
+     */
+    pub fn bind(
+        &mut self,
+        handle: SocketHandle,
+        address: Ipv4Address,
+        port: u16,
+    ) -> Result<(), SocketError> {
+        if address != UNSPECIFIED_ADDRESS && !self.addresses.contains(&address) {
+            return Err(SocketError::AddressNotAvailable);
+        }
+
+        let options = self.get_socket(handle)?.get_options();
+        let port = match port {
+            0 => self
+                .ephemeral_ports
+                .allocate(self.config.ephemeral_port_range, address, None, |port| {
+                    !has_bind_conflict(&self.sockets, handle, options, address, port)
+                })
+                .ok_or(SocketError::AddressInUse)?,
+            port if has_bind_conflict(&self.sockets, handle, options, address, port) => {
+                return Err(SocketError::AddressInUse);
+            }
+            port => port,
+        };
+
+        match self.get_socket_mut(handle)? {
+            Socket::Unconnected {
+                local: local @ None,
+                ..
+            } => {
+                *local = Some(Endpoint::new(address, port));
+                Ok(())
+            }
+            _ => Err(SocketError::InvalidState),
+        }
+    }
+
+    pub fn listen(&mut self, handle: SocketHandle, backlog: usize) -> Result<(), SocketError> {
+        let socket = self.get_socket_mut(handle)?;
+        match socket {
+            Socket::Unconnected {
+                local: Some(local),
+                options,
+            } => {
+                /*
+                 * SO_REUSEADDR で同じアドレスに bind していても、listen できるのは 1 つだけ。
+                 */
+                let (local, options) = (*local, *options);
+                if !self
+                    .table
+                    .insert_listener(local, handle, options.reuse_port)
+                {
+                    return Err(SocketError::AddressInUse);
+                }
+                self.sockets.insert(
+                    handle,
+                    Socket::Listener {
+                        local,
+                        backlog: backlog.max(1),
+                        options,
+                        queue: VecDeque::new(),
+                        last_overflow: None,
+                    },
+                );
+                Ok(())
+            }
+            Socket::Listener {
+                backlog: current, ..
+            } => {
+                *current = backlog.max(1);
+                Ok(())
+            }
+            _ => Err(SocketError::InvalidState),
+        }
+    }
+
+    /*
+     * ESTABLISHED になった子コネクションを 1 つ取り出す。なければ`WouldBlock`.
+     */
+    pub fn accept(&mut self, handle: SocketHandle) -> Result<SocketHandle, SocketError> {
+        let Socket::Listener { queue, .. } = self.get_socket(handle)? else {
+            return Err(SocketError::InvalidState);
+        };
+
+        let ready = queue.iter().position(|child| {
+            matches!(
+                self.sockets.get(child),
+                Some(Socket::Connection { connection, .. })
+                    if connection.get_state().is_synchronized()
+            )
+        });
+        let Some(index) = ready else {
+            return Err(SocketError::WouldBlock);
+        };
+
+        let Some(Socket::Listener { queue, .. }) = self.sockets.get_mut(&handle) else {
+            unreachable!();
+        };
+        let child = queue.remove(index).unwrap();
+        if let Some(Socket::Connection { parent, .. }) = self.sockets.get_mut(&child) {
+            *parent = None;
+        }
+        Ok(child)
+    }
+
+    /*
+     * SYN を送って SYN-SENT になる。接続の完了は`get_state`で確認する。
+     */
+    pub fn connect(
+        &mut self,
+        now: Duration,
+        handle: SocketHandle,
+        address: Ipv4Address,
+        port: u16,
+    ) -> Result<(), SocketError> {
+        self.now = now;
+        let remote = Endpoint::new(address, port);
+        let (local, options) = match self.get_socket(handle)? {
+            Socket::Unconnected { local, options } => (*local, *options),
+            Socket::Connection { .. } => return Err(SocketError::AlreadyConnected),
+            Socket::Listener { .. } => return Err(SocketError::InvalidState),
+        };
+
+        let local = match local {
+            Some(local) if local.address != UNSPECIFIED_ADDRESS => local,
+            Some(local) => Endpoint::new(self.source_address_for(address)?, local.port),
+            None => {
+                let local_address = self.source_address_for(address)?;
+                let port = self.allocate_ephemeral_port(local_address, remote)?;
+                Endpoint::new(local_address, port)
+            }
+        };
+        if !self.table.insert_connection(local, remote, handle) {
+            return Err(SocketError::AddressInUse);
+        }
+
+        let connection = Connection::connect(
+            local,
+            remote,
+            self.initial_sequence_number(local, remote),
+            self.timestamp_offset(local, remote),
+            &self.config,
+            &options,
+        );
+        self.sockets.insert(
+            handle,
+            Socket::Connection {
+                connection: Box::new(connection),
+                options,
+                parent: None,
+                closed: false,
+                timer: None,
+            },
+        );
+        self.flush(handle);
+        Ok(())
+    }
+
+    /*
+     * NOTE: ルーティングテーブルはないので、最初のローカルアドレスを使う。
+     */
+    fn source_address_for(&self, _destination: Ipv4Address) -> Result<Ipv4Address, SocketError> {
+        self.addresses
+            .first()
+            .copied()
+            .ok_or(SocketError::AddressNotAvailable)
+    }
+
+    /*
+     * connect() で使うポート。同じ 4 タプルのコネクション（TIME-WAIT も含む）がなく、
+     * bind や listen しているソケットもないもの。他の宛先へのコネクションとは共有する。
+     */
+    fn allocate_ephemeral_port(
+        &mut self,
+        local_address: Ipv4Address,
+        remote: Endpoint,
+    ) -> Result<u16, SocketError> {
+        let table = &self.table;
+        let sockets = &self.sockets;
+        self.ephemeral_ports
+            .allocate(
+                self.config.ephemeral_port_range,
+                local_address,
+                Some(remote),
+                |port| {
+                    let local = Endpoint::new(local_address, port);
+                    table.get_connection(local, remote).is_none()
+                        && !sockets.values().any(|socket| {
+                            !matches!(socket, Socket::Connection { .. })
+                                && socket
+                                    .get_local()
+                                    .is_some_and(|bound| overlaps(bound, local_address, port))
+                        })
+                },
+            )
+            .ok_or(SocketError::AddressNotAvailable)
+    }
+
+    fn initial_sequence_number(&self, local: Endpoint, remote: Endpoint) -> u32 {
+        self.initial_sequence_numbers
+            .generate(self.now, local, remote)
+    }
+
+    fn timestamp_offset(&self, local: Endpoint, remote: Endpoint) -> u32 {
+        self.timestamp_offsets.hash_one((local, remote)) as u32
+    }
+
+    pub fn send(
+        &mut self,
+        now: Duration,
+        handle: SocketHandle,
+        data: &[u8],
+    ) -> Result<usize, SocketError> {
+        self.now = now;
+        let sent = self.get_connection_mut(handle)?.send(data)?;
+        self.flush(handle);
+        Ok(sent)
+    }
+
+    pub fn recv(
+        &mut self,
+        now: Duration,
+        handle: SocketHandle,
+        buffer: &mut [u8],
+    ) -> Result<usize, SocketError> {
+        self.now = now;
+        let received = self.get_connection_mut(handle)?.receive(buffer)?;
+        self.flush(handle);
+        Ok(received)
+    }
+
+    /*
+     * 送信側を閉じる（shutdown(SHUT_WR)）。
+     */
+    pub fn shutdown(&mut self, now: Duration, handle: SocketHandle) -> Result<(), SocketError> {
+        self.now = now;
+        self.get_connection_mut(handle)?.shutdown()?;
+        self.flush(handle);
+        Ok(())
+    }
+
+    /*
+     * ハンドルを手放す。コネクションは FIN を送って閉じる処理を続ける。
+     *
+     * NOTE: 読まれていないデータが残っていれば、Linux と同じく RST を送る（RFC 2525 2.17）。
+     */
+    pub fn close(&mut self, now: Duration, handle: SocketHandle) -> Result<(), SocketError> {
+        self.now = now;
+        let socket = self
+            .sockets
+            .get_mut(&handle)
+            .ok_or(SocketError::InvalidHandle)?;
+
+        match socket {
+            Socket::Unconnected { .. } => {
+                self.sockets.remove(&handle);
+            }
+            Socket::Listener { local, queue, .. } => {
+                let children: Vec<SocketHandle> = queue.drain(..).collect();
+                self.table.remove_listener(*local, handle);
+                self.sockets.remove(&handle);
+                for child in children {
+                    if let Some(Socket::Connection { connection, .. }) =
+                        self.sockets.get_mut(&child)
+                    {
+                        connection.abort();
+                    }
+                    self.mark_closed(child);
+                    self.flush(child);
+                }
+            }
+            Socket::Connection { connection, .. } => {
+                if connection.has_unread_data() {
+                    connection.abort();
+                } else {
+                    connection.shutdown()?;
+                }
+                self.mark_closed(handle);
+                self.flush(handle);
+            }
+        }
+        Ok(())
+    }
+
+    fn mark_closed(&mut self, handle: SocketHandle) {
+        if let Some(Socket::Connection { closed, .. }) = self.sockets.get_mut(&handle) {
+            *closed = true;
+        }
+    }
+
+    pub fn get_state(&self, handle: SocketHandle) -> Result<TcpState, SocketError> {
+        match self.get_socket(handle)? {
+            Socket::Unconnected { .. } => Ok(TcpState::Closed),
+            Socket::Listener { .. } => Ok(TcpState::Listen),
+            Socket::Connection { connection, .. } => Ok(connection.get_state()),
+        }
+    }
+
+    /*
+     * RST の受信などで記録されたエラーを取り出す（SO_ERROR）。
+     */
+    pub fn take_error(&mut self, handle: SocketHandle) -> Result<Option<SocketError>, SocketError> {
+        match self.get_socket_mut(handle)? {
+            Socket::Connection { connection, .. } => Ok(connection.take_error()),
+            _ => Ok(None),
+        }
+    }
+
+    /*
+     * 現在の RTO と、平滑化した RTT（まだ測れていなければ`None`）。
+     */
+    pub fn get_retransmission_timeout(
+        &self,
+        handle: SocketHandle,
+    ) -> Result<Duration, SocketError> {
+        match self.get_socket(handle)? {
+            Socket::Connection { connection, .. } => Ok(connection.get_retransmission_timeout()),
+            _ => Err(SocketError::NotConnected),
+        }
+    }
+
+    pub fn get_smoothed_rtt(&self, handle: SocketHandle) -> Result<Option<Duration>, SocketError> {
+        match self.get_socket(handle)? {
+            Socket::Connection { connection, .. } => Ok(connection.get_smoothed_rtt()),
+            _ => Err(SocketError::NotConnected),
+        }
+    }
+
+    /*
+     * 今使っている経路の MTU.
+     */
+    pub fn get_path_mtu(&self, handle: SocketHandle) -> Result<u16, SocketError> {
+        match self.get_socket(handle)? {
+            Socket::Connection { connection, .. } => Ok(connection.get_path_mtu()),
+            _ => Err(SocketError::NotConnected),
+        }
+    }
+
+    /*
+     * 今使っている USER_TIMEOUT. TCP_USER_TIMEOUT か、相手と交換した User Timeout オプションで決まる。
+     */
+    pub fn get_user_timeout(&self, handle: SocketHandle) -> Result<Option<Duration>, SocketError> {
+        match self.get_socket(handle)? {
+            Socket::Connection { connection, .. } => Ok(connection.get_user_timeout()),
+            _ => Err(SocketError::NotConnected),
+        }
+    }
+
+    pub fn get_local_endpoint(&self, handle: SocketHandle) -> Result<Endpoint, SocketError> {
+        self.get_socket(handle)?
+            .get_local()
+            .ok_or(SocketError::InvalidState)
+    }
+
+    pub fn get_remote_endpoint(&self, handle: SocketHandle) -> Result<Endpoint, SocketError> {
+        match self.get_socket(handle)? {
+            Socket::Connection { connection, .. } => Ok(connection.get_remote()),
+            _ => Err(SocketError::NotConnected),
+        }
+    }
+
+    fn get_socket(&self, handle: SocketHandle) -> Result<&Socket, SocketError> {
+        match self.sockets.get(&handle) {
+            Some(Socket::Connection { closed: true, .. }) | None => Err(SocketError::InvalidHandle),
+            Some(socket) => Ok(socket),
+        }
+    }
+
+    fn get_socket_mut(&mut self, handle: SocketHandle) -> Result<&mut Socket, SocketError> {
+        match self.sockets.get_mut(&handle) {
+            Some(Socket::Connection { closed: true, .. }) | None => Err(SocketError::InvalidHandle),
+            Some(socket) => Ok(socket),
+        }
+    }
+
+    fn get_connection_mut(&mut self, handle: SocketHandle) -> Result<&mut Connection, SocketError> {
+        match self.get_socket_mut(handle)? {
+            Socket::Connection { connection, .. } => Ok(connection),
+            _ => Err(SocketError::NotConnected),
+        }
+    }
+
+    /*
+     * LISTEN で SYN を受け取った。backlog に空きがあれば子コネクションを作る。
+     * `initial_send_sequence`は、TIME-WAIT のコネクションを置き換える時の ISS.
+     */
+    fn on_syn_to_listener(
+        &mut self,
+        listener: SocketHandle,
+        packet: &TcpPacket,
+        initial_send_sequence: Option<u32>,
+    ) {
+        let Some(Socket::Listener {
+            backlog, options, ..
+        }) = self.sockets.get(&listener)
+        else {
+            return;
+        };
+        let (backlog, options) = (*backlog, *options);
+        let (pending, established) = self.count_children(listener);
+        if established >= backlog {
+            return;
+        }
+        if pending >= backlog {
+            if self.config.syn_cookies {
+                self.send_syn_cookie(listener, packet, options);
+            }
+            return;
+        }
+
+        let tcp_header = packet.get_tcp_header();
+        let local = Endpoint::new(
+            packet.get_destination_address(),
+            tcp_header.get_destination_port(),
+        );
+        let remote = Endpoint::new(packet.get_source_address(), tcp_header.get_source_port());
+        let connection = Connection::accept(
+            self.now,
+            packet,
+            initial_send_sequence.unwrap_or_else(|| self.initial_sequence_number(local, remote)),
+            self.timestamp_offset(local, remote),
+            &self.config,
+            &options,
+        );
+
+        let child = self.allocate_handle();
+        if !self.table.insert_connection(local, remote, child) {
+            return;
+        }
+        self.sockets.insert(
+            child,
+            Socket::Connection {
+                connection: Box::new(connection),
+                options,
+                parent: Some(listener),
+                closed: false,
+                timer: None,
+            },
+        );
+        if let Some(Socket::Listener { queue, .. }) = self.sockets.get_mut(&listener) {
+            queue.push_back(child);
+        }
+        self.flush(child);
+    }
+
+    /*
+     * accept されていない子コネクションのうち、SYN-RECEIVED のものとそれ以外の数。
+     */
+    fn count_children(&self, listener: SocketHandle) -> (usize, usize) {
+        let Some(Socket::Listener { queue, .. }) = self.sockets.get(&listener) else {
+            return (0, 0);
+        };
+        let pending = queue
+            .iter()
+            .filter(|child| {
+                matches!(
+                    self.sockets.get(child),
+                    Some(Socket::Connection { connection, .. })
+                        if connection.get_state() == TcpState::SynReceived
+                )
+            })
+            .count();
+        (pending, queue.len() - pending)
+    }
+
+    fn send_syn_cookie(&mut self, listener: SocketHandle, syn: &TcpPacket, options: SocketOptions) {
+        let tcp_header = syn.get_tcp_header();
+        let local = Endpoint::new(
+            syn.get_destination_address(),
+            tcp_header.get_destination_port(),
+        );
+        let remote = Endpoint::new(syn.get_source_address(), tcp_header.get_source_port());
+        let peer_mss = tcp_header
+            .get_options()
+            .iter()
+            .find_map(|option| match option {
+                TcpOption::MaximumSegmentSize(mss) => Some(*mss),
+                _ => None,
+            })
+            .unwrap_or(DEFAULT_SEND_MSS);
+
+        let cookie = self.syn_cookies.generate(
+            self.now,
+            local,
+            remote,
+            tcp_header.get_sequence_number(),
+            peer_mss.min(self.config.get_advertised_mss()),
+        );
+        let syn_ack = syn_cookie::syn_ack_for(
+            syn,
+            cookie,
+            self.config.get_advertised_mss(),
+            options.receive_buffer_size.min(usize::from(u16::MAX)) as u16,
+            self.config
+                .window_scaling
+                .then(|| connection::window_shift_for(options.receive_buffer_size)),
+            self.config
+                .timestamps
+                .then(|| SynCookieOptions::from_syn(syn))
+                .flatten(),
+            connection::timestamp_clock(self.now, self.timestamp_offset(local, remote)),
+        );
+        /*
+         * SYN-ACK は payload を持たないので、total_length に必ず収まる。
+         */
+        self.outbox.push_back(syn_ack.encode().unwrap());
+        if let Some(Socket::Listener { last_overflow, .. }) = self.sockets.get_mut(&listener) {
+            *last_overflow = Some(self.now);
+        }
+    }
+
+    /*
+     * listen ソケットに来た ACK が SYN cookie への応答なら、子コネクションを作る。
+     * RST を返すべきなら false.
+     */
+    fn on_ack_to_listener(&mut self, listener: SocketHandle, packet: &TcpPacket) -> bool {
+        let Some(Socket::Listener {
+            backlog,
+            options,
+            last_overflow: Some(last_overflow),
+            ..
+        }) = self.sockets.get(&listener)
+        else {
+            return false;
+        };
+        if !self.config.syn_cookies || self.now.saturating_sub(*last_overflow) > SYN_COOKIE_LIFETIME
+        {
+            return false;
+        }
+        let (backlog, options) = (*backlog, *options);
+
+        let tcp_header = packet.get_tcp_header();
+        let local = Endpoint::new(
+            packet.get_destination_address(),
+            tcp_header.get_destination_port(),
+        );
+        let remote = Endpoint::new(packet.get_source_address(), tcp_header.get_source_port());
+        let Some(mss) = self.syn_cookies.validate(
+            self.now,
+            local,
+            remote,
+            tcp_header.get_sequence_number().wrapping_sub(1),
+            tcp_header.get_acknowledgment_number().wrapping_sub(1),
+        ) else {
+            return false;
+        };
+
+        /*
+         * accept キューがいっぱいなら、RST は返さずに捨てる。相手は再送してくる。
+         */
+        if self.count_children(listener).1 >= backlog {
+            return true;
+        }
+
+        let cookie_options = connection::timestamps_of(tcp_header)
+            .map(|(_, tsecr)| SynCookieOptions::decode_timestamp(tsecr))
+            .unwrap_or_default();
+        let mut connection = Connection::accept_cookie(
+            self.now,
+            packet,
+            mss,
+            cookie_options,
+            self.timestamp_offset(local, remote),
+            &self.config,
+            &options,
+        );
+        connection.on_segment(self.now, packet);
+
+        let child = self.allocate_handle();
+        if !self.table.insert_connection(local, remote, child) {
+            return true;
+        }
+        self.sockets.insert(
+            child,
+            Socket::Connection {
+                connection: Box::new(connection),
+                options,
+                parent: Some(listener),
+                closed: false,
+                timer: None,
+            },
+        );
+        if let Some(Socket::Listener { queue, .. }) = self.sockets.get_mut(&listener) {
+            queue.push_back(child);
+        }
+        self.flush(child);
+        true
+    }
+
+    /*
+     * TIME-WAIT のコネクションに、新しいコネクションを始められる SYN が来たら、
+     * 古い方を閉じて listen ソケットに渡す。渡したら true.
+     */
+    fn recycle_time_wait(&mut self, handle: SocketHandle, syn: &TcpPacket) -> bool {
+        let Some(Socket::Connection { connection, .. }) = self.sockets.get_mut(&handle) else {
+            return false;
+        };
+        let Some(initial_send_sequence) =
+            connection.get_recycled_sequence(self.now, syn.get_tcp_header())
+        else {
+            return false;
+        };
+        let Some(listener) = self
+            .table
+            .get_listener(connection.get_local(), connection.get_remote())
+        else {
+            return false;
+        };
+        connection.close_time_wait();
+        self.flush(handle);
+        self.on_syn_to_listener(listener, syn, Some(initial_send_sequence));
+        true
+    }
+
+    fn send_reset(&mut self, packet: &TcpPacket) {
+        if let Some(reset) = demux::reset_for(packet) {
+            /*
+             * RST は payload を持たないので、total_length に必ず収まる。
+             */
+            self.outbox.push_back(reset.encode().unwrap());
+        }
+    }
+
+    /*
+     * ICMP Fragmentation Needed を、元のセグメントを送ったコネクションに渡す。
+     * ICMP には、送った datagram の IP ヘッダーと TCP ヘッダーの先頭 8bytes（ポートとシーケンス番号）が入っている。
+     */
+    fn on_icmp(&mut self, datagram: &[u8]) {
+        let Ok(header) = Ipv4Header::decode(datagram) else {
+            return;
+        };
+        if header.validate_total_length(datagram.len()).is_err()
+            || !self.addresses.contains(&header.get_destination_address())
+        {
+            return;
+        }
+        let Ok(message) = IcmpMessage::decode(header.get_payload(datagram)) else {
+            return;
+        };
+        if !message.is_fragmentation_needed() {
+            return;
+        }
+
+        let original = message.get_data();
+        let Ok(original_header) = Ipv4Header::decode(original) else {
+            return;
+        };
+        let Some(tcp_header) = original.get(original_header.get_header_length()..) else {
+            return;
+        };
+        if original_header.get_protocol() != TCP_PROTOCOL_NUMBER || tcp_header.len() < 8 {
+            return;
+        }
+        let local = Endpoint::new(
+            original_header.get_source_address(),
+            u16::from_be_bytes([tcp_header[0], tcp_header[1]]),
+        );
+        let remote = Endpoint::new(
+            original_header.get_destination_address(),
+            u16::from_be_bytes([tcp_header[2], tcp_header[3]]),
+        );
+        let sequence =
+            u32::from_be_bytes([tcp_header[4], tcp_header[5], tcp_header[6], tcp_header[7]]);
+
+        let Destination::Connection(handle) = self.table.lookup(local, remote) else {
+            return;
+        };
+        if let Some(Socket::Connection { connection, .. }) = self.sockets.get_mut(&handle) {
+            connection.on_packet_too_big(
+                sequence,
+                message.get_next_hop_mtu(),
+                original_header.get_total_length(),
+            );
+        }
+        self.flush(handle);
+    }
+
+    /*
+     * コネクションが送りたいセグメントを全て outbox に移し、タイマーを登録し直す。
+     * CLOSED になったものは片付ける。
+     */
+    fn flush(&mut self, handle: SocketHandle) {
+        let Some(Socket::Connection {
+            connection,
+            parent,
+            closed,
+            timer,
+            ..
+        }) = self.sockets.get_mut(&handle)
+        else {
+            return;
+        };
+
+        if connection.take_challenge_ack() && self.challenge_acks.allow(self.now) {
+            connection.send_challenge_ack();
+        }
+        while let Some(segment) = connection.poll_segment(self.now) {
+            /*
+             * セグメントは MTU に収まる大きさで作っているので、total_length を超えることはない。
+             */
+            self.outbox.push_back(segment.encode().unwrap());
+        }
+
+        if connection.get_state() == TcpState::TimeWait && !self.time_waits.contains(&handle) {
+            if self.time_waits.len() < self.config.maximum_time_wait_connections {
+                self.time_waits.insert(handle);
+            } else {
+                connection.close_time_wait();
+            }
+        }
+
+        let deadline = connection.get_deadline();
+        let registered = timer.and_then(|id| self.timers.get_deadline(id));
+        if deadline != registered {
+            if let Some(id) = timer.take() {
+                self.timers.cancel(id);
+            }
+            *timer = deadline.map(|deadline| self.timers.insert(deadline, handle));
+        }
+
+        if connection.get_state() != TcpState::Closed {
+            return;
+        }
+        self.time_waits.remove(&handle);
+        self.table
+            .remove_connection(connection.get_local(), connection.get_remote(), handle);
+        match (*parent, *closed) {
+            (Some(parent), _) => {
+                self.sockets.remove(&handle);
+                if let Some(Socket::Listener { queue, .. }) = self.sockets.get_mut(&parent) {
+                    queue.retain(|child| *child != handle);
+                }
+            }
+            (None, true) => {
+                self.sockets.remove(&handle);
+            }
+            (None, false) => {}
+        }
+    }
+}
+
+impl NetworkStack for TcpStack {
+    fn receive(&mut self, now: Duration, datagram: &[u8]) {
+        self.now = now;
+        if let Some(tap) = self.tap.as_mut() {
+            tap.tap(Direction::Inbound, datagram);
+        }
+        if Ipv4Header::decode(datagram)
+            .is_ok_and(|header| header.get_protocol() == ICMP_PROTOCOL_NUMBER)
+        {
+            self.on_icmp(datagram);
+            return;
+        }
+        let Ok(packet) = TcpPacket::decode_with_options(datagram, &DecodeOptions::strict())
+            .map(|(packet, _)| packet)
+        else {
+            return;
+        };
+        if !self.addresses.contains(&packet.get_destination_address()) {
+            return;
+        }
+
+        let tcp_header = packet.get_tcp_header();
+        let local = Endpoint::new(
+            packet.get_destination_address(),
+            tcp_header.get_destination_port(),
+        );
+        let remote = Endpoint::new(packet.get_source_address(), tcp_header.get_source_port());
+
+        let control_bits = tcp_header.get_control_bits();
+        match self.table.lookup(local, remote) {
+            Destination::Connection(handle) => {
+                if control_bits.get_syn()
+                    && !control_bits.get_ack()
+                    && !control_bits.get_rst()
+                    && self.recycle_time_wait(handle, &packet)
+                {
+                    return;
+                }
+                if let Some(Socket::Connection { connection, .. }) = self.sockets.get_mut(&handle) {
+                    connection.on_segment(now, &packet);
+                }
+                self.flush(handle);
+            }
+
+            /*
+             * LISTEN では SYN だけを受け付け、ACK には RST を返す。RFC 9293 3.10.7.2.
+             */
+            Destination::Listener(listener) => {
+                if control_bits.get_rst() {
+                    return;
+                }
+                if control_bits.get_ack() {
+                    if control_bits.get_syn() || !self.on_ack_to_listener(listener, &packet) {
+                        self.send_reset(&packet);
+                    }
+                } else if control_bits.get_syn() {
+                    self.on_syn_to_listener(listener, &packet, None);
+                }
+            }
+            Destination::None => self.send_reset(&packet),
+        }
+    }
+
+    fn poll_transmit(&mut self, now: Duration) -> Option<Vec<u8>> {
+        self.now = now;
+        let datagram = self.outbox.pop_front()?;
+        if let Some(tap) = self.tap.as_mut() {
+            tap.tap(Direction::Outbound, &datagram);
+        }
+        Some(datagram)
+    }
+
+    fn poll_timeout(&self) -> Option<Duration> {
+        self.timers.next_wakeup()
+    }
+
+    fn handle_timeout(&mut self, now: Duration) {
+        self.now = now;
+        for handle in self.timers.advance(now) {
+            if let Some(Socket::Connection {
+                connection, timer, ..
+            }) = self.sockets.get_mut(&handle)
+            {
+                *timer = None;
+                connection.handle_timeout(now);
+            }
+            self.flush(handle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timer::{Clock, VirtualClock};
+    use crate::transmission_control_protocol::{ControlBits, TcpHeader};
+    use alloc::rc::Rc;
+    use alloc::vec;
+    use core::cell::RefCell;
+
+    const SERVER: Ipv4Address = [10, 0, 0, 1];
+    const CLIENT: Ipv4Address = [10, 0, 0, 2];
+
+    /*
+     * テストで組み立てたセグメントの datagram.
+     */
+    fn datagram(
+        source: Ipv4Address,
+        destination: Ipv4Address,
+        tcp_header: TcpHeader,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        TcpPacket::new(
+            Ipv4Header::new(source, destination, TCP_PROTOCOL_NUMBER),
+            tcp_header,
+            payload.to_vec(),
+        )
+        .encode()
+        .unwrap()
+    }
+
+    /*
+     * `packet`の TCP ヘッダーを`edit`で書き換えたもの。
+     */
+    fn rewrite(packet: &TcpPacket, edit: impl FnOnce(&mut TcpHeader)) -> Vec<u8> {
+        let mut tcp_header = packet.get_tcp_header().clone();
+        edit(&mut tcp_header);
+        datagram(
+            packet.get_source_address(),
+            packet.get_destination_address(),
+            tcp_header,
+            packet.get_payload(),
+        )
+    }
+
+    fn exchange(from: &mut TcpStack, to: &mut TcpStack) -> usize {
+        let mut count = 0;
+        while let Some(datagram) = from.poll_transmit(Duration::ZERO) {
+            to.receive(Duration::ZERO, &datagram);
+            count += 1;
+        }
+        count
+    }
+
+    fn exchange_at(from: &mut TcpStack, to: &mut TcpStack, now: Duration) -> usize {
+        let mut count = 0;
+        while let Some(datagram) = from.poll_transmit(now) {
+            to.receive(now, &datagram);
+            count += 1;
+        }
+        count
+    }
+
+    fn settle(a: &mut TcpStack, b: &mut TcpStack) {
+        while exchange(a, b) + exchange(b, a) > 0 {}
+    }
+
+    fn settle_at(a: &mut TcpStack, b: &mut TcpStack, now: Duration) {
+        while exchange_at(a, b, now) + exchange_at(b, a, now) > 0 {}
+    }
+
+    fn listening_server() -> (TcpStack, SocketHandle) {
+        let mut server = TcpStack::new(&[SERVER]);
+        let listener = server.socket();
+        server.bind(listener, UNSPECIFIED_ADDRESS, 80).unwrap();
+        server.listen(listener, 1).unwrap();
+        (server, listener)
+    }
+
+    #[test]
+    fn test_handshake_data_and_close() {
+        let (mut server, listener) = listening_server();
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        assert_eq!(client.get_state(socket), Ok(TcpState::SynSent));
+        assert_eq!(server.accept(listener), Err(SocketError::WouldBlock));
+
+        settle(&mut client, &mut server);
+        assert_eq!(client.get_state(socket), Ok(TcpState::Established));
+        let accepted = server.accept(listener).unwrap();
+        assert_eq!(server.get_state(accepted), Ok(TcpState::Established));
+        assert_eq!(
+            server.get_remote_endpoint(accepted).unwrap(),
+            client.get_local_endpoint(socket).unwrap()
+        );
+
+        let data: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        assert_eq!(client.send(Duration::ZERO, socket, &data), Ok(5000));
+        settle(&mut client, &mut server);
+
+        let mut buffer = [0u8; 8192];
+        assert_eq!(server.recv(Duration::ZERO, accepted, &mut buffer), Ok(5000));
+        assert_eq!(&buffer[..5000], &data[..]);
+        assert_eq!(
+            server.recv(Duration::ZERO, accepted, &mut buffer),
+            Err(SocketError::WouldBlock)
+        );
+
+        client.close(Duration::ZERO, socket).unwrap();
+        settle(&mut client, &mut server);
+        assert_eq!(server.get_state(accepted), Ok(TcpState::CloseWait));
+        assert_eq!(server.recv(Duration::ZERO, accepted, &mut buffer), Ok(0));
+
+        server.close(Duration::ZERO, accepted).unwrap();
+        settle(&mut client, &mut server);
+        assert_eq!(server.get_state(accepted), Err(SocketError::InvalidHandle));
+        assert_eq!(client.get_state(socket), Err(SocketError::InvalidHandle));
+        assert_eq!(server.sockets.len(), 1);
+    }
+
+    /*
+     * tap に渡された datagram を、テストからも見られるように共有して記録する。
+     */
+    type Tapped = Vec<(Direction, Vec<u8>)>;
+
+    #[derive(Clone, Default)]
+    struct RecordingTap(Rc<RefCell<Tapped>>);
+
+    impl PacketTap for RecordingTap {
+        fn tap(&mut self, direction: Direction, datagram: &[u8]) {
+            self.0.borrow_mut().push((direction, datagram.to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_tap_sees_inbound_and_outbound_datagrams() {
+        let (mut server, listener) = listening_server();
+        let tap = RecordingTap::default();
+        assert!(server.set_tap(Some(Box::new(tap.clone()))).is_none());
+
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        let syn = client.poll_transmit(Duration::ZERO).unwrap();
+        server.receive(Duration::ZERO, &syn);
+        let syn_ack = server.poll_transmit(Duration::ZERO).unwrap();
+        client.receive(Duration::ZERO, &syn_ack);
+        settle(&mut client, &mut server);
+        server.accept(listener).unwrap();
+
+        /*
+         * 壊れた datagram も decode する前に渡す。
+         */
+        server.receive(Duration::ZERO, &[0x45]);
+
+        let recorded = tap.0.borrow();
+        assert_eq!(recorded.len(), 4);
+        assert_eq!(recorded[0], (Direction::Inbound, syn));
+        assert_eq!(recorded[1], (Direction::Outbound, syn_ack));
+        assert_eq!(recorded[2].0, Direction::Inbound);
+        assert_eq!(recorded[3], (Direction::Inbound, vec![0x45]));
+        drop(recorded);
+
+        assert!(server.set_tap(None).is_some());
+        server.receive(Duration::ZERO, &[0x45]);
+        assert_eq!(tap.0.borrow().len(), 4);
+    }
+
+    #[test]
+    fn test_config_rejects_invalid_values() {
+        let mut config = TcpConfig::default();
+        let minimum_mtu = path_mtu::MINIMUM_PATH_MTU;
+        assert_eq!(
+            config.set_mtu(minimum_mtu - 1),
+            Err(TcpConfigError::InvalidMtu(minimum_mtu - 1))
+        );
+        assert_eq!(
+            config.set_ephemeral_port_range(0, 100),
+            Err(TcpConfigError::InvalidEphemeralPortRange {
+                minimum: 0,
+                maximum: 100
+            })
+        );
+        assert!(config.set_ephemeral_port_range(200, 100).is_err());
+        assert!(config
+            .set_delayed_ack_timeout(Duration::from_millis(501))
+            .is_err());
+        assert!(config
+            .set_user_timeout_limits(Duration::from_secs(2), Duration::from_secs(1))
+            .is_err());
+
+        /*
+         * エラーの時は設定を変えない。
+         */
+        assert_eq!(config, TcpConfig::default());
+
+        assert_eq!(config.set_mtu(minimum_mtu), Ok(()));
+        assert_eq!(config.get_mtu(), minimum_mtu);
+    }
+
+    #[test]
+    fn test_send_respects_peer_window_and_mss() {
+        let mut config = TcpConfig::default();
+        config.set_receive_buffer_size(1000);
+        config.set_maximum_segment_size(400);
+        let mut server = TcpStack::with_config(&[SERVER], config);
+        let listener = server.socket();
+        server.bind(listener, SERVER, 80).unwrap();
+        server.listen(listener, 1).unwrap();
+
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        settle(&mut client, &mut server);
+        let accepted = server.accept(listener).unwrap();
+
+        client.send(Duration::ZERO, socket, &[7; 3000]).unwrap();
+        let mut lengths = Vec::new();
+        while let Some(datagram) = client.poll_transmit(Duration::ZERO) {
+            let packet = TcpPacket::decode(&datagram).unwrap();
+            lengths.push(packet.get_payload().len());
+            server.receive(Duration::ZERO, &datagram);
+        }
+
+        /*
+         * タイムスタンプのオプションの 12 バイトだけ MSS より小さく送る。
+         */
+        assert_eq!(lengths, vec![388, 388, 224]);
+
+        /*
+         * 読んでウィンドウが開くと、ウィンドウ更新の ACK を受けて続きが送られる。
+         */
+        let mut buffer = [0u8; 1000];
+        assert_eq!(server.recv(Duration::ZERO, accepted, &mut buffer), Ok(1000));
+        settle(&mut client, &mut server);
+        assert_eq!(server.recv(Duration::ZERO, accepted, &mut buffer), Ok(1000));
+    }
+
+    #[test]
+    fn test_reset_and_refused() {
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        let syn = client.poll_transmit(Duration::ZERO).unwrap();
+        let syn = TcpPacket::decode(&syn).unwrap();
+
+        let mut tcp_header = syn.get_tcp_header().clone();
+        tcp_header.set_source_port(80);
+        tcp_header.set_destination_port(syn.get_tcp_header().get_source_port());
+        tcp_header.set_acknowledgment_number(tcp_header.get_sequence_number().wrapping_add(1));
+        tcp_header.set_sequence_number(0);
+        let mut control_bits = ControlBits::default();
+        control_bits.set_rst(true);
+        control_bits.set_ack(true);
+        tcp_header.set_control_bits(control_bits);
+        tcp_header.set_options(&[]).unwrap();
+        let reset = datagram(SERVER, CLIENT, tcp_header, &[]);
+
+        client.receive(Duration::ZERO, &reset);
+        assert_eq!(client.get_state(socket), Ok(TcpState::Closed));
+        assert_eq!(
+            client.take_error(socket),
+            Ok(Some(SocketError::ConnectionRefused))
+        );
+    }
+
+    #[test]
+    fn test_reset_for_unacceptable_ack_in_syn_states() {
+        let (mut server, listener) = listening_server();
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        let (_, syn) = in_flight(&mut client);
+        server.receive(Duration::ZERO, &syn[0]);
+        let (_, syn_ack) = in_flight(&mut server);
+        let syn = TcpPacket::decode(&syn[0]).unwrap();
+        let syn_sequence = syn.get_tcp_header().get_sequence_number();
+
+        /*
+         * `packet`の ACK 番号と制御ビットを書き換えたもの。
+         */
+        let with_acknowledgment =
+            |packet: &TcpPacket, acknowledgment: u32, control_bits: ControlBits| {
+                rewrite(packet, |tcp_header| {
+                    tcp_header.set_acknowledgment_number(acknowledgment);
+                    tcp_header.set_control_bits(control_bits);
+                })
+            };
+
+        let is_reset_at = |datagrams: &[Vec<u8>], sequence: u32| {
+            let [reset] = datagrams else {
+                return false;
+            };
+            let reset = TcpPacket::decode(reset).unwrap();
+            let tcp_header = reset.get_tcp_header();
+            tcp_header.get_control_bits() == control_bits(false, true, false)
+                && tcp_header.get_sequence_number() == sequence
+        };
+
+        /*
+         * SYN-SENT で、送っていないものへの ACK には <SEQ=SEG.ACK><CTL=RST>. こちらは SYN-SENT のまま。
+         * RST には RST を返さない。
+         */
+        let syn_ack = TcpPacket::decode(&syn_ack[0]).unwrap();
+        let syn_ack_bits = control_bits(true, false, true);
+        for acknowledgment in [syn_sequence, syn_sequence + 1000] {
+            client.receive(
+                Duration::ZERO,
+                &with_acknowledgment(&syn_ack, acknowledgment, syn_ack_bits),
+            );
+            assert!(is_reset_at(&in_flight(&mut client).1, acknowledgment));
+        }
+        client.receive(
+            Duration::ZERO,
+            &with_acknowledgment(
+                &syn_ack,
+                syn_sequence + 1000,
+                control_bits(true, true, false),
+            ),
+        );
+        assert!(in_flight(&mut client).1.is_empty());
+        assert_eq!(client.get_state(socket), Ok(TcpState::SynSent));
+
+        /*
+         * SYN-RECEIVED でも同じ。
+         */
+        let ack = rewrite(&syn, |tcp_header| {
+            tcp_header.set_sequence_number(syn_sequence + 1);
+            tcp_header.set_acknowledgment_number(12345);
+            tcp_header.set_control_bits(control_bits(true, false, false));
+        });
+        server.receive(Duration::ZERO, &ack);
+        assert!(is_reset_at(&in_flight(&mut server).1, 12345));
+        assert_eq!(server.accept(listener), Err(SocketError::WouldBlock));
+
+        /*
+         * 正しい SYN-ACK と ACK ならつながる。
+         */
+        client.receive(Duration::ZERO, &syn_ack.encode().unwrap());
+        settle(&mut client, &mut server);
+        assert_eq!(client.get_state(socket), Ok(TcpState::Established));
+        assert!(server.accept(listener).is_ok());
+    }
+
+    #[test]
+    fn test_syn_retransmission_backoff() {
+        let clock = VirtualClock::new();
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        client.connect(clock.now(), socket, SERVER, 80).unwrap();
+
+        /*
+         * SYN は全て落とす。
+         */
+        let mut sent = Vec::new();
+        loop {
+            while client.poll_transmit(clock.now()).is_some() {
+                sent.push(clock.now().as_secs());
+            }
+            let Some(wakeup) = client.poll_timeout() else {
+                break;
+            };
+            clock.set(wakeup);
+            client.handle_timeout(clock.now());
+        }
+        assert_eq!(sent, vec![0, 1, 3, 7, 15, 31, 63]);
+
+        /*
+         * RTO は 60 秒で頭打ちになる。
+         */
+        assert_eq!(clock.now(), Duration::from_secs(123));
+        assert_eq!(client.get_state(socket), Ok(TcpState::Closed));
+        assert_eq!(client.take_error(socket), Ok(Some(SocketError::TimedOut)));
+    }
+
+    #[test]
+    fn test_data_retransmission_and_rtt() {
+        let clock = VirtualClock::new();
+        let (mut server, listener) = listening_server();
+        let mut config = TcpConfig::default();
+        config.set_timestamps(false);
+        let mut client = TcpStack::with_config(&[CLIENT], config);
+        let socket = client.socket();
+        client.connect(clock.now(), socket, SERVER, 80).unwrap();
+        exchange_at(&mut client, &mut server, clock.now());
+        clock.advance(Duration::from_millis(50));
+        exchange_at(&mut server, &mut client, clock.now());
+        exchange_at(&mut client, &mut server, clock.now());
+        let accepted = server.accept(listener).unwrap();
+        assert_eq!(
+            client.get_smoothed_rtt(socket),
+            Ok(Some(Duration::from_millis(50)))
+        );
+        assert_eq!(
+            client.get_retransmission_timeout(socket),
+            Ok(Duration::from_secs(1))
+        );
+
+        /*
+         * データを落とすと、RTO の後に送り直す。再送の ACK では RTT を測らない（Karn）。
+         */
+        client.send(clock.now(), socket, b"hello").unwrap();
+        assert!(client.poll_transmit(clock.now()).is_some());
+        assert!(client.poll_timeout() <= Some(Duration::from_millis(1050)));
+        clock.set(Duration::from_millis(1050));
+        client.handle_timeout(clock.now());
+        assert_eq!(
+            client.get_retransmission_timeout(socket),
+            Ok(Duration::from_secs(2))
+        );
+        exchange_at(&mut client, &mut server, clock.now());
+        clock.advance(Duration::from_millis(50));
+        exchange_at(&mut server, &mut client, clock.now());
+        assert_eq!(client.poll_timeout(), None);
+        assert_eq!(
+            client.get_smoothed_rtt(socket),
+            Ok(Some(Duration::from_millis(50)))
+        );
+
+        let mut buffer = [0u8; 16];
+        assert_eq!(server.recv(clock.now(), accepted, &mut buffer), Ok(5));
+        assert_eq!(&buffer[..5], b"hello");
+
+        /*
+         * 新しいデータで測り直すと、RTO は元に戻る。
+         */
+        client.send(clock.now(), socket, b"world").unwrap();
+        exchange_at(&mut client, &mut server, clock.now());
+        clock.advance(Duration::from_millis(30));
+        exchange_at(&mut server, &mut client, clock.now());
+        assert_eq!(
+            client.get_retransmission_timeout(socket),
+            Ok(Duration::from_secs(1))
+        );
+        assert!(client.get_smoothed_rtt(socket).unwrap() < Some(Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn test_reset_for_unknown_port_and_listener_ack() {
+        let (mut server, listener) = listening_server();
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        client.connect(Duration::ZERO, socket, SERVER, 81).unwrap();
+        settle(&mut client, &mut server);
+        assert_eq!(client.get_state(socket), Ok(TcpState::Closed));
+        assert_eq!(
+            client.take_error(socket),
+            Ok(Some(SocketError::ConnectionRefused))
+        );
+
+        /*
+         * listen ソケットに ACK が来たら、SEQ=SEG.ACK の RST.
+         */
+        let mut tcp_header = TcpHeader::new(50000, 80);
+        tcp_header.set_sequence_number(100);
+        tcp_header.set_acknowledgment_number(12345);
+        let mut control_bits = ControlBits::default();
+        control_bits.set_ack(true);
+        tcp_header.set_control_bits(control_bits);
+        server.receive(Duration::ZERO, &datagram(CLIENT, SERVER, tcp_header, &[]));
+        let reset = TcpPacket::decode(&server.poll_transmit(Duration::ZERO).unwrap()).unwrap();
+        assert!(reset.get_tcp_header().get_control_bits().get_rst());
+        assert_eq!(reset.get_tcp_header().get_sequence_number(), 12345);
+        assert_eq!(server.accept(listener), Err(SocketError::WouldBlock));
+
+        /*
+         * listen できるのは 1 つだけ。
+         */
+        let other = server.socket();
+        server
+            .set_option(other, SocketOption::ReuseAddress(true))
+            .unwrap();
+        server.bind(other, SERVER, 81).unwrap();
+        let another = server.socket();
+        server
+            .set_option(another, SocketOption::ReuseAddress(true))
+            .unwrap();
+        server.bind(another, SERVER, 81).unwrap();
+        server.listen(other, 1).unwrap();
+        assert_eq!(server.listen(another, 1), Err(SocketError::AddressInUse));
+    }
+
+    #[test]
+    fn test_ephemeral_ports() {
+        let mut config = TcpConfig::default();
+        config.set_ephemeral_port_range(40000, 40001).unwrap();
+        let mut client = TcpStack::with_config(&[CLIENT], config);
+
+        let bound = client.socket();
+        client.bind(bound, UNSPECIFIED_ADDRESS, 0).unwrap();
+        let bound_port = client.get_local_endpoint(bound).unwrap().port;
+        assert!((40000..=40001).contains(&bound_port));
+
+        /*
+         * bind されているポートは避け、宛先が違えば同じポートを使う。
+         */
+        let mut ports = Vec::new();
+        for port in [80, 81] {
+            let socket = client.socket();
+            client
+                .connect(Duration::ZERO, socket, SERVER, port)
+                .unwrap();
+            ports.push(client.get_local_endpoint(socket).unwrap().port);
+        }
+        assert_eq!(ports, vec![40001 - (bound_port - 40000); 2]);
+
+        let socket = client.socket();
+        assert_eq!(
+            client.connect(Duration::ZERO, socket, SERVER, 80),
+            Err(SocketError::AddressNotAvailable)
+        );
+        let other = client.socket();
+        assert_eq!(
+            client.bind(other, UNSPECIFIED_ADDRESS, 0),
+            Err(SocketError::AddressInUse)
+        );
+    }
+
+    #[test]
+    fn test_reuse_address_over_time_wait_and_reuse_port() {
+        let (mut server, listener) = listening_server();
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        client.bind(socket, CLIENT, 5000).unwrap();
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        settle(&mut client, &mut server);
+        let accepted = server.accept(listener).unwrap();
+        client.shutdown(Duration::ZERO, socket).unwrap();
+        server.close(Duration::ZERO, accepted).unwrap();
+        settle(&mut client, &mut server);
+        assert_eq!(client.get_state(socket), Ok(TcpState::TimeWait));
+
+        let other = client.socket();
+        assert_eq!(
+            client.bind(other, CLIENT, 5000),
+            Err(SocketError::AddressInUse)
+        );
+        client
+            .set_option(other, SocketOption::ReuseAddress(true))
+            .unwrap();
+        assert_eq!(client.bind(other, CLIENT, 5000), Ok(()));
+
+        /*
+         * SO_REUSEPORT なら、複数のソケットが同じポートで listen できる。
+         */
+        let mut handles = Vec::new();
+        for _ in 0..2 {
+            let handle = server.socket();
+            server
+                .set_option(handle, SocketOption::ReusePort(true))
+                .unwrap();
+            server.bind(handle, SERVER, 8080).unwrap();
+            server.listen(handle, 16).unwrap();
+            handles.push(handle);
+        }
+        let plain = server.socket();
+        assert_eq!(
+            server.bind(plain, SERVER, 8080),
+            Err(SocketError::AddressInUse)
+        );
+
+        for _ in 0..16 {
+            let socket = client.socket();
+            client
+                .connect(Duration::ZERO, socket, SERVER, 8080)
+                .unwrap();
+        }
+        settle(&mut client, &mut server);
+        for handle in handles {
+            assert!(server.accept(handle).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_syn_cookies_when_syn_queue_overflows() {
+        let mut server = TcpStack::new(&[SERVER]);
+        let listener = server.socket();
+        server.bind(listener, SERVER, 80).unwrap();
+        server.listen(listener, 2).unwrap();
+        let mut client = TcpStack::new(&[CLIENT]);
+        let sockets: Vec<SocketHandle> = (0..3)
+            .map(|_| {
+                let socket = client.socket();
+                client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+                socket
+            })
+            .collect();
+        exchange(&mut client, &mut server);
+        assert_eq!(server.count_children(listener), (2, 0));
+        exchange(&mut server, &mut client);
+
+        /*
+         * 2 つ目の ACK は落とす。3 つ目は SYN cookie の ACK.
+         */
+        let port = |socket| client.get_local_endpoint(socket).unwrap().port;
+        let ports: Vec<u16> = sockets.iter().map(|socket| port(*socket)).collect();
+        while let Some(datagram) = client.poll_transmit(Duration::ZERO) {
+            let packet = TcpPacket::decode(&datagram).unwrap();
+            if packet.get_tcp_header().get_source_port() != ports[1] {
+                server.receive(Duration::ZERO, &datagram);
+            }
+        }
+        let first = server.accept(listener).unwrap();
+        assert_eq!(server.get_remote_endpoint(first).unwrap().port, ports[0]);
+        let third = server.accept(listener).unwrap();
+        assert_eq!(server.get_remote_endpoint(third).unwrap().port, ports[2]);
+        assert_eq!(server.get_state(third), Ok(TcpState::Established));
+
+        /*
+         * cookie から作ったコネクションでもデータを送れる。
+         * タイムスタンプに入れておいたので、ウィンドウスケールも使える。
+         */
+        client.send(Duration::ZERO, sockets[2], b"cookie").unwrap();
+        exchange(&mut client, &mut server);
+        let ack = TcpPacket::decode(&server.poll_transmit(Duration::ZERO).unwrap()).unwrap();
+        assert!(connection::timestamps_of(ack.get_tcp_header()).is_some());
+        assert_eq!(
+            ack.get_tcp_header().get_window(),
+            ((262144 - 6) >> 3) as u16
+        );
+        let mut buffer = [0u8; 16];
+        assert_eq!(server.recv(Duration::ZERO, third, &mut buffer), Ok(6));
+        assert_eq!(&buffer[..6], b"cookie");
+
+        /*
+         * cookie が合わない ACK には RST.
+         */
+        let mut tcp_header = TcpHeader::new(40000, 80);
+        tcp_header.set_sequence_number(1);
+        tcp_header.set_acknowledgment_number(1);
+        let mut control_bits = ControlBits::default();
+        control_bits.set_ack(true);
+        tcp_header.set_control_bits(control_bits);
+        while server.poll_transmit(Duration::ZERO).is_some() {}
+        server.receive(Duration::ZERO, &datagram(CLIENT, SERVER, tcp_header, &[]));
+        let reset = TcpPacket::decode(&server.poll_transmit(Duration::ZERO).unwrap()).unwrap();
+        assert!(reset.get_tcp_header().get_control_bits().get_rst());
+    }
+
+    #[test]
+    fn test_syn_cookie_timestamp_does_not_go_backwards() {
+        /*
+         * TSval の下位 5 ビットがどの値でも、cookie の SYN-ACK の TSval は、
+         * コネクションを作った後に送るセグメントの TSval を超えない。
+         */
+        for millis in 1000..1032 {
+            let now = Duration::from_millis(millis);
+            let mut server = TcpStack::new(&[SERVER]);
+            let listener = server.socket();
+            server.bind(listener, SERVER, 80).unwrap();
+            server.listen(listener, 1).unwrap();
+            let mut client = TcpStack::new(&[CLIENT]);
+            let sockets: Vec<SocketHandle> = (0..2)
+                .map(|_| {
+                    let socket = client.socket();
+                    client.connect(now, socket, SERVER, 80).unwrap();
+                    socket
+                })
+                .collect();
+            exchange_at(&mut client, &mut server, now);
+
+            /*
+             * 2 つ目の SYN には cookie で応える。accept キューを空けてから cookie の ACK を届ける。
+             */
+            let cookie_port = client.get_local_endpoint(sockets[1]).unwrap().port;
+            let mut syn_ack_tsval = None;
+            while let Some(datagram) = server.poll_transmit(now) {
+                let packet = TcpPacket::decode(&datagram).unwrap();
+                if packet.get_tcp_header().get_destination_port() == cookie_port {
+                    syn_ack_tsval = connection::timestamps_of(packet.get_tcp_header());
+                }
+                client.receive(now, &datagram);
+            }
+            let mut accepted = Vec::new();
+            while let Some(datagram) = client.poll_transmit(now) {
+                server.receive(now, &datagram);
+                accepted.extend(server.accept(listener));
+            }
+            let cookie = accepted[1];
+            assert_eq!(
+                server.get_remote_endpoint(cookie).unwrap().port,
+                cookie_port
+            );
+
+            server.send(now, cookie, b"x").unwrap();
+            let data = TcpPacket::decode(&server.poll_transmit(now).unwrap()).unwrap();
+            let (syn_ack_tsval, _) = syn_ack_tsval.unwrap();
+            let (data_tsval, _) = connection::timestamps_of(data.get_tcp_header()).unwrap();
+            assert!(
+                connection::sequence_le(syn_ack_tsval, data_tsval),
+                "{} > {} at {:?}",
+                syn_ack_tsval,
+                data_tsval,
+                now
+            );
+        }
+    }
+
+    #[test]
+    fn test_bind_conflicts() {
+        let (mut server, _listener) = listening_server();
+        let other = server.socket();
+        assert_eq!(
+            server.bind(other, SERVER, 80),
+            Err(SocketError::AddressInUse)
+        );
+        assert_eq!(
+            server.bind(other, [192, 168, 0, 1], 81),
+            Err(SocketError::AddressNotAvailable)
+        );
+        assert_eq!(server.bind(other, SERVER, 81), Ok(()));
+        assert_eq!(
+            server.bind(other, SERVER, 82),
+            Err(SocketError::InvalidState)
+        );
+    }
+
+    fn window_scale_of(datagram: &[u8]) -> Option<u8> {
+        let packet = TcpPacket::decode(datagram).unwrap();
+        packet
+            .get_tcp_header()
+            .get_options()
+            .iter()
+            .find_map(|option| match option {
+                TcpOption::WindowScale(shift) => Some(*shift),
+                _ => None,
+            })
+    }
+
+    /*
+     * 受け取らずに送れるだけ送ったバイト数。
+     */
+    fn in_flight(from: &mut TcpStack) -> (usize, Vec<Vec<u8>>) {
+        let mut datagrams = Vec::new();
+        let mut bytes = 0;
+        while let Some(datagram) = from.poll_transmit(Duration::ZERO) {
+            bytes += TcpPacket::decode(&datagram).unwrap().get_payload().len();
+            datagrams.push(datagram);
+        }
+        (bytes, datagrams)
+    }
+
+    #[test]
+    fn test_window_scaling() {
+        let (mut server, listener) = listening_server();
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        let syn = client.poll_transmit(Duration::ZERO).unwrap();
+        assert_eq!(window_scale_of(&syn), Some(3));
+        server.receive(Duration::ZERO, &syn);
+        let syn_ack = server.poll_transmit(Duration::ZERO).unwrap();
+        assert_eq!(window_scale_of(&syn_ack), Some(3));
+        client.receive(Duration::ZERO, &syn_ack);
+        settle(&mut client, &mut server);
+        let accepted = server.accept(listener).unwrap();
+
+        /*
+         * SYN-ACK のウィンドウはスケールされないので、最初は 64 KiB まで。
+         * その後の ACK では 64 KiB を超えるウィンドウを広告できるので、残りを一度に送れる。
+         */
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        assert_eq!(client.send(Duration::ZERO, socket, &data), Ok(200_000));
+        let (bytes, datagrams) = in_flight(&mut client);
+        assert_eq!(bytes, 65535);
+        for datagram in datagrams {
+            server.receive(Duration::ZERO, &datagram);
+        }
+        exchange(&mut server, &mut client);
+        let (bytes, datagrams) = in_flight(&mut client);
+        assert_eq!(bytes, 200_000 - 65535);
+        for datagram in datagrams {
+            server.receive(Duration::ZERO, &datagram);
+        }
+        let mut buffer = vec![0u8; 262144];
+        assert_eq!(
+            server.recv(Duration::ZERO, accepted, &mut buffer),
+            Ok(200_000)
+        );
+        assert_eq!(&buffer[..200_000], &data[..]);
+
+        /*
+         * 相手がオプションを送ってこなければ、どちらもスケールしない。
+         */
+        let mut config = TcpConfig::default();
+        config.set_window_scaling(false);
+        let mut client = TcpStack::with_config(&[CLIENT], config);
+        let socket = client.socket();
+
+        /*
+         * no_std では鍵が固定なので、前のクライアントと同じ ephemeral port にならないようにする。
+         */
+        client.bind(socket, CLIENT, 50000).unwrap();
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        let syn = client.poll_transmit(Duration::ZERO).unwrap();
+        assert_eq!(window_scale_of(&syn), None);
+        server.receive(Duration::ZERO, &syn);
+        let syn_ack = server.poll_transmit(Duration::ZERO).unwrap();
+        assert_eq!(window_scale_of(&syn_ack), None);
+        client.receive(Duration::ZERO, &syn_ack);
+        settle(&mut client, &mut server);
+
+        assert_eq!(client.send(Duration::ZERO, socket, &data), Ok(200_000));
+        let (bytes, datagrams) = in_flight(&mut client);
+        assert_eq!(bytes, 65535);
+        for datagram in datagrams {
+            server.receive(Duration::ZERO, &datagram);
+        }
+        exchange(&mut server, &mut client);
+        let (bytes, _) = in_flight(&mut client);
+        assert_eq!(bytes, 65535);
+    }
+
+    #[test]
+    fn test_timestamps_rtt_and_paws() {
+        let clock = VirtualClock::new();
+        let (mut server, listener) = listening_server();
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        clock.set(Duration::from_secs(1));
+        client.connect(clock.now(), socket, SERVER, 80).unwrap();
+        exchange_at(&mut client, &mut server, clock.now());
+        let syn_ack = server.poll_transmit(clock.now()).unwrap();
+
+        /*
+         * TSval は起動してからの時間そのままではなく、コネクションごと、向きごとにずらしてある。
+         */
+        let (tsval, tsecr) =
+            connection::timestamps_of(TcpPacket::decode(&syn_ack).unwrap().get_tcp_header())
+                .unwrap();
+        assert_ne!(tsval, 1000);
+        assert_ne!(tsecr, 1000);
+        assert_ne!(tsval, tsecr);
+        clock.advance(Duration::from_millis(100));
+        client.receive(clock.now(), &syn_ack);
+        exchange_at(&mut client, &mut server, clock.now());
+        let accepted = server.accept(listener).unwrap();
+        assert_eq!(
+            client.get_smoothed_rtt(socket),
+            Ok(Some(Duration::from_millis(100)))
+        );
+
+        /*
+         * TSecr でどの送信への ACK か分かるので、再送したセグメントの ACK でも RTT を測れる。
+         */
+        clock.set(Duration::from_secs(2));
+        client.send(clock.now(), socket, b"hello").unwrap();
+        let original = TcpPacket::decode(&client.poll_transmit(clock.now()).unwrap()).unwrap();
+        assert!(client.poll_timeout() <= Some(Duration::from_secs(3)));
+        clock.set(Duration::from_secs(3));
+        client.handle_timeout(clock.now());
+        exchange_at(&mut client, &mut server, clock.now());
+        clock.advance(Duration::from_millis(300));
+        exchange_at(&mut server, &mut client, clock.now());
+        assert_eq!(
+            client.get_smoothed_rtt(socket),
+            Ok(Some(Duration::from_millis(125)))
+        );
+        let mut buffer = [0u8; 16];
+        assert_eq!(server.recv(clock.now(), accepted, &mut buffer), Ok(5));
+
+        /*
+         * シーケンス番号はウィンドウ内でも、TSval が古いセグメントは PAWS で捨てて ACK を返す。
+         */
+        let receive_next = original
+            .get_tcp_header()
+            .get_sequence_number()
+            .wrapping_add(5);
+        let (tsval, tsecr) = connection::timestamps_of(original.get_tcp_header()).unwrap();
+        let stale = [
+            TcpOption::NoOperation,
+            TcpOption::NoOperation,
+            TcpOption::Timestamps { tsval, tsecr },
+        ];
+        let resend = |options: &[TcpOption]| {
+            rewrite(&original, |tcp_header| {
+                tcp_header.set_sequence_number(receive_next);
+                tcp_header.set_options(options).unwrap();
+            })
+        };
+        server.receive(clock.now(), &resend(&stale));
+        assert_eq!(
+            server.recv(clock.now(), accepted, &mut buffer),
+            Err(SocketError::WouldBlock)
+        );
+        assert!(server.poll_transmit(clock.now()).is_some());
+
+        /*
+         * タイムスタンプのないセグメントは黙って捨てる。
+         */
+        server.receive(clock.now(), &resend(&[]));
+        assert_eq!(
+            server.recv(clock.now(), accepted, &mut buffer),
+            Err(SocketError::WouldBlock)
+        );
+        assert!(server.poll_transmit(clock.now()).is_none());
+
+        /*
+         * 24 日より長く TS.Recent が更新されていなければ、PAWS では捨てない。
+         */
+        clock.advance(Duration::from_secs(25 * 24 * 60 * 60));
+        server.receive(clock.now(), &resend(&stale));
+        assert_eq!(server.recv(clock.now(), accepted, &mut buffer), Ok(5));
+    }
+
+    const ROUTER: Ipv4Address = [10, 0, 0, 254];
+
+    /*
+     * `datagram`を転送できなかったルーターが返す ICMP Fragmentation Needed.
+     */
+    fn fragmentation_needed(datagram: &[u8], next_hop_mtu: u16) -> Vec<u8> {
+        use crate::internet_control_message_protocol::{
+            ICMP_DESTINATION_UNREACHABLE, ICMP_FRAGMENTATION_NEEDED,
+        };
+
+        let header = Ipv4Header::decode(datagram).unwrap();
+        let [high, low] = next_hop_mtu.to_be_bytes();
+        let message = IcmpMessage::new(
+            ICMP_DESTINATION_UNREACHABLE,
+            ICMP_FRAGMENTATION_NEEDED,
+            [0, 0, high, low],
+            datagram[..header.get_header_length() + 8].to_vec(),
+        )
+        .encode();
+        let mut icmp_header =
+            Ipv4Header::new(ROUTER, header.get_source_address(), ICMP_PROTOCOL_NUMBER);
+        icmp_header.set_total_length((IPV4_HEADER_MIN_LEN + message.len()) as u16);
+        let mut icmp = icmp_header.encode();
+        icmp.extend(message);
+        icmp
+    }
+
+    #[test]
+    fn test_mss_from_mtu_and_path_mtu_discovery() {
+        let (mut server, listener) = listening_server();
+        let mut config = TcpConfig::default();
+        config.set_mtu(1400).unwrap();
+        let mut client = TcpStack::with_config(&[CLIENT], config);
+        let socket = client.socket();
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        let syn = TcpPacket::decode(&client.poll_transmit(Duration::ZERO).unwrap()).unwrap();
+        assert_eq!(
+            syn.get_tcp_header().get_options()[0],
+            TcpOption::MaximumSegmentSize(1360)
+        );
+        server.receive(Duration::ZERO, &syn.encode().unwrap());
+        settle(&mut client, &mut server);
+        let accepted = server.accept(listener).unwrap();
+
+        /*
+         * 全てのセグメントに DF を立てて、MTU に収まるように送る。
+         */
+        let data: Vec<u8> = (0..4000u32).map(|i| i as u8).collect();
+        server.send(Duration::ZERO, accepted, &data).unwrap();
+        let mut flight = Vec::new();
+        let mut lengths = Vec::new();
+        while let Some(datagram) = server.poll_transmit(Duration::ZERO) {
+            assert_eq!(
+                Ipv4Header::decode(&datagram).unwrap().get_flags(),
+                DONT_FRAGMENT_FLAG
+            );
+            lengths.push(TcpPacket::decode(&datagram).unwrap().get_payload().len());
+            flight.push(datagram);
+        }
+        assert_eq!(lengths, vec![1348, 1348, 1304]);
+
+        /*
+         * シーケンス番号が送ったものでなければ、ICMP は無視する。
+         */
+        let mut forged = TcpPacket::decode(&flight[0]).unwrap();
+        let mut tcp_header = forged.get_tcp_header().clone();
+        tcp_header.set_sequence_number(tcp_header.get_sequence_number().wrapping_sub(10000));
+        forged = TcpPacket::new(segment_ip_header(SERVER, CLIENT), tcp_header, Vec::new());
+        server.receive(
+            Duration::ZERO,
+            &fragmentation_needed(&forged.encode().unwrap(), 1000),
+        );
+        assert_eq!(server.get_path_mtu(accepted), Ok(1500));
+        assert!(server.poll_transmit(Duration::ZERO).is_none());
+
+        /*
+         * 途中の MTU が 1200 だと、RTO を待たずに小さくして送り直す。
+         */
+        server.receive(Duration::ZERO, &fragmentation_needed(&flight[0], 1200));
+        assert_eq!(server.get_path_mtu(accepted), Ok(1200));
+        let mut lengths = Vec::new();
+        loop {
+            let mut count = 0;
+            while let Some(datagram) = server.poll_transmit(Duration::ZERO) {
+                assert!(datagram.len() <= 1200);
+                lengths.push(TcpPacket::decode(&datagram).unwrap().get_payload().len());
+                client.receive(Duration::ZERO, &datagram);
+                count += 1;
+            }
+            if count + exchange(&mut client, &mut server) == 0 {
+                break;
+            }
+        }
+        assert_eq!(lengths, vec![1148, 1148, 1148, 556]);
+        let mut buffer = [0u8; 8192];
+        assert_eq!(client.recv(Duration::ZERO, socket, &mut buffer), Ok(4000));
+        assert_eq!(&buffer[..4000], &data[..]);
+    }
+
+    #[test]
+    fn test_black_hole_detection_and_probing() {
+        let clock = VirtualClock::new();
+        let mut config = TcpConfig::default();
+        config.set_receive_buffer_size(16384);
+        let mut server = TcpStack::with_config(&[SERVER], config);
+        let listener = server.socket();
+        server.bind(listener, SERVER, 80).unwrap();
+        server.listen(listener, 1).unwrap();
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        client.connect(clock.now(), socket, SERVER, 80).unwrap();
+        settle(&mut client, &mut server);
+        let accepted = server.accept(listener).unwrap();
+
+        /*
+         * 1100 バイトより大きい datagram は、ICMP を返さずに捨てられる経路。
+         * 少しずつ送り続けて、probe を送る機会を作る。
+         */
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut sent = 0;
+        let mut received = Vec::new();
+        let mut buffer = vec![0u8; 65536];
+        for _ in 0..1000 {
+            if sent < data.len() {
+                let chunk = &data[sent..(sent + 10_000).min(data.len())];
+                sent += client.send(clock.now(), socket, chunk).unwrap_or(0);
+            }
+            loop {
+                let mut delivered = 0;
+                while let Some(datagram) = client.poll_transmit(clock.now()) {
+                    if datagram.len() <= 1100 {
+                        server.receive(clock.now(), &datagram);
+                        delivered += 1;
+                    }
+                }
+                while let Ok(length) = server.recv(clock.now(), accepted, &mut buffer) {
+                    received.extend_from_slice(&buffer[..length]);
+                }
+                delivered += exchange_at(&mut server, &mut client, clock.now());
+                if delivered == 0 {
+                    break;
+                }
+            }
+            if received.len() == data.len() {
+                break;
+            }
+            clock.advance(Duration::from_millis(100));
+            client.handle_timeout(clock.now());
+        }
+        assert_eq!(received, data);
+
+        /*
+         * 一度 1024 まで下げて、probe で 1100 の近くまで探り直している。
+         */
+        let path_mtu = client.get_path_mtu(socket).unwrap();
+        assert!((1092..=1100).contains(&path_mtu), "{}", path_mtu);
+    }
+
+    fn payload_lengths(datagrams: &[Vec<u8>]) -> Vec<usize> {
+        datagrams
+            .iter()
+            .map(|datagram| TcpPacket::decode(datagram).unwrap().get_payload().len())
+            .collect()
+    }
+
+    #[test]
+    fn test_nagle_nodelay_and_cork() {
+        let (mut server, listener) = listening_server();
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        settle(&mut client, &mut server);
+        let accepted = server.accept(listener).unwrap();
+
+        /*
+         * 小さいセグメントが ACK されるまで、次の小さいデータはまとめて待たせる。
+         */
+        for _ in 0..3 {
+            assert_eq!(client.send(Duration::ZERO, socket, b"a"), Ok(1));
+        }
+        let (_, datagrams) = in_flight(&mut client);
+        assert_eq!(payload_lengths(&datagrams), [1]);
+        server.receive(Duration::ZERO, &datagrams[0]);
+        exchange(&mut server, &mut client);
+        let (_, datagrams) = in_flight(&mut client);
+        assert_eq!(payload_lengths(&datagrams), [2]);
+        server.receive(Duration::ZERO, &datagrams[0]);
+        settle(&mut client, &mut server);
+
+        client
+            .set_option(socket, SocketOption::NoDelay(true))
+            .unwrap();
+        for _ in 0..3 {
+            assert_eq!(client.send(Duration::ZERO, socket, b"b"), Ok(1));
+        }
+        let (_, datagrams) = in_flight(&mut client);
+        assert_eq!(payload_lengths(&datagrams), [1, 1, 1]);
+        for datagram in datagrams {
+            server.receive(Duration::ZERO, &datagram);
+        }
+        settle(&mut client, &mut server);
+
+        /*
+         * cork 中は MSS に満たない分を待たせ、cork を外したら送る。
+         */
+        client.set_option(socket, SocketOption::Cork(true)).unwrap();
+        assert_eq!(client.send(Duration::ZERO, socket, &[0; 1000]), Ok(1000));
+        assert_eq!(client.poll_transmit(Duration::ZERO), None);
+        assert_eq!(client.send(Duration::ZERO, socket, &[0; 1000]), Ok(1000));
+        let (_, datagrams) = in_flight(&mut client);
+        assert_eq!(payload_lengths(&datagrams), [1448]);
+        server.receive(Duration::ZERO, &datagrams[0]);
+        client
+            .set_option(socket, SocketOption::Cork(false))
+            .unwrap();
+        let (_, datagrams) = in_flight(&mut client);
+        assert_eq!(payload_lengths(&datagrams), [552]);
+        server.receive(Duration::ZERO, &datagrams[0]);
+        settle(&mut client, &mut server);
+
+        /*
+         * cork を外さなくても、200 ミリ秒経ったら送る。
+         */
+        let now = Duration::from_secs(1);
+        client.set_option(socket, SocketOption::Cork(true)).unwrap();
+        assert_eq!(client.send(now, socket, &[0; 10]), Ok(10));
+        client.handle_timeout(now + Duration::from_millis(199));
+        assert_eq!(client.poll_transmit(now), None);
+        client.handle_timeout(now + Duration::from_millis(200));
+        let (_, datagrams) = in_flight(&mut client);
+        assert_eq!(payload_lengths(&datagrams), [10]);
+        server.receive(now, &datagrams[0]);
+
+        let mut buffer = [0; 4096];
+        assert_eq!(server.recv(now, accepted, &mut buffer), Ok(2016));
+    }
+
+    fn acknowledgment_of(datagram: &[u8]) -> u32 {
+        TcpPacket::decode(datagram)
+            .unwrap()
+            .get_tcp_header()
+            .get_acknowledgment_number()
+    }
+
+    #[test]
+    fn test_delayed_ack_and_quick_ack() {
+        let mut config = TcpConfig::default();
+        config
+            .set_delayed_ack_timeout(Duration::from_millis(100))
+            .unwrap();
+        let mut server = TcpStack::with_config(&[SERVER], config);
+        let listener = server.socket();
+        server.bind(listener, UNSPECIFIED_ADDRESS, 80).unwrap();
+        server.listen(listener, 1).unwrap();
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        settle(&mut client, &mut server);
+        let accepted = server.accept(listener).unwrap();
+        client
+            .set_option(socket, SocketOption::NoDelay(true))
+            .unwrap();
+
+        /*
+         * 最初の 16 セグメントは quick ACK モードで、すぐに ACK する。
+         */
+        let mut now = Duration::ZERO;
+        for _ in 0..16 {
+            client.send(now, socket, b"a").unwrap();
+            assert_eq!(exchange_at(&mut client, &mut server, now), 1);
+            assert_eq!(exchange_at(&mut server, &mut client, now), 1);
+        }
+
+        /*
+         * その後は 2 セグメントごとに ACK し、1 つだけなら遅延させる。
+         */
+        now += Duration::from_millis(10);
+        client.send(now, socket, b"a").unwrap();
+        exchange_at(&mut client, &mut server, now);
+        assert_eq!(server.poll_transmit(now), None);
+        client.send(now, socket, b"a").unwrap();
+        exchange_at(&mut client, &mut server, now);
+        let ack = server.poll_transmit(now).unwrap();
+        assert_eq!(server.poll_transmit(now), None);
+        client.receive(now, &ack);
+
+        client.send(now, socket, b"a").unwrap();
+        exchange_at(&mut client, &mut server, now);
+        server.handle_timeout(now + Duration::from_millis(99));
+        assert_eq!(server.poll_transmit(now), None);
+        now += Duration::from_millis(100);
+        server.handle_timeout(now);
+        let ack = server.poll_transmit(now).unwrap();
+        client.receive(now, &ack);
+
+        /*
+         * 遅延している間に送るデータがあれば、ACK はそれに載せる。
+         */
+        client.send(now, socket, b"a").unwrap();
+        exchange_at(&mut client, &mut server, now);
+        assert_eq!(server.send(now, accepted, b"reply"), Ok(5));
+        let (_, datagrams) = in_flight(&mut server);
+        assert_eq!(payload_lengths(&datagrams), [5]);
+        let expected = acknowledgment_of(&datagrams[0]);
+        client.receive(now, &datagrams[0]);
+        server.handle_timeout(now + Duration::from_millis(100));
+        assert_eq!(server.poll_transmit(now), None);
+        exchange_at(&mut client, &mut server, now);
+
+        /*
+         * TCP_QUICKACK にしたら、遅延させていた ACK をすぐに送り、その後も遅延させない。
+         */
+        client.send(now, socket, b"a").unwrap();
+        exchange_at(&mut client, &mut server, now);
+        assert_eq!(server.poll_transmit(now), None);
+        server
+            .set_option(accepted, SocketOption::QuickAck(true))
+            .unwrap();
+        let ack = server.poll_transmit(now).unwrap();
+        assert_eq!(acknowledgment_of(&ack), expected.wrapping_add(1));
+        client.receive(now, &ack);
+        client.send(now, socket, b"a").unwrap();
+        exchange_at(&mut client, &mut server, now);
+        assert_eq!(exchange_at(&mut server, &mut client, now), 1);
+        server
+            .set_option(accepted, SocketOption::QuickAck(false))
+            .unwrap();
+
+        /*
+         * 順番が飛んだら、すぐに ACK して quick ACK モードに入る。
+         */
+        client.send(now, socket, b"a").unwrap();
+        client.send(now, socket, b"a").unwrap();
+        let (_, datagrams) = in_flight(&mut client);
+        server.receive(now, &datagrams[1]);
+        assert_eq!(exchange_at(&mut server, &mut client, now), 1);
+        server.receive(now, &datagrams[0]);
+        assert_eq!(exchange_at(&mut server, &mut client, now), 1);
+        client.send(now, socket, b"a").unwrap();
+        exchange_at(&mut client, &mut server, now);
+        assert_eq!(exchange_at(&mut server, &mut client, now), 1);
+    }
+
+    #[test]
+    fn test_zero_window_probes_and_receiver_sws_avoidance() {
+        let mut server = TcpStack::new(&[SERVER]);
+        let listener = server.socket();
+        server
+            .set_option(listener, SocketOption::ReceiveBufferSize(4096))
+            .unwrap();
+        server.bind(listener, UNSPECIFIED_ADDRESS, 80).unwrap();
+        server.listen(listener, 1).unwrap();
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        settle(&mut client, &mut server);
+        let accepted = server.accept(listener).unwrap();
+
+        let data: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+        assert_eq!(client.send(Duration::ZERO, socket, &data), Ok(10000));
+        settle(&mut client, &mut server);
+
+        /*
+         * ウィンドウが 0 になったら、RTO の後から間隔を倍にしながら probe を送る。
+         */
+        let mut now = Duration::ZERO;
+        for interval in [1, 2, 4] {
+            now += Duration::from_secs(interval);
+            client.handle_timeout(now - Duration::from_millis(1));
+            assert_eq!(client.poll_transmit(now), None);
+            client.handle_timeout(now);
+            let (bytes, datagrams) = in_flight(&mut client);
+            assert_eq!((bytes, datagrams.len()), (0, 1));
+            if interval == 4 {
+                server.receive(now, &datagrams[0]);
+                let ack = server.poll_transmit(now).unwrap();
+                assert_eq!(
+                    TcpPacket::decode(&ack)
+                        .unwrap()
+                        .get_tcp_header()
+                        .get_window(),
+                    0
+                );
+                client.receive(now, &ack);
+            }
+        }
+
+        /*
+         * 少し読んだだけでは、ウィンドウを広告しない。
+         */
+        let mut buffer = vec![0; 10000];
+        assert_eq!(server.recv(now, accepted, &mut buffer[..100]), Ok(100));
+        assert_eq!(server.poll_transmit(now), None);
+        assert_eq!(server.recv(now, accepted, &mut buffer[100..1500]), Ok(1400));
+        let update = server.poll_transmit(now).unwrap();
+        assert_eq!(
+            TcpPacket::decode(&update)
+                .unwrap()
+                .get_tcp_header()
+                .get_window(),
+            1500
+        );
+
+        /*
+         * ウィンドウの更新が失われても、次の probe で分かる。
+         */
+        now += Duration::from_secs(8);
+        client.handle_timeout(now);
+        exchange_at(&mut client, &mut server, now);
+        exchange_at(&mut server, &mut client, now);
+        let (bytes, datagrams) = in_flight(&mut client);
+        assert_eq!(bytes, 1500);
+        for datagram in datagrams {
+            server.receive(now, &datagram);
+        }
+
+        let mut received = 1500;
+        while received < 10000 {
+            settle_at(&mut client, &mut server, now);
+            received += server.recv(now, accepted, &mut buffer[received..]).unwrap();
+        }
+        assert_eq!(buffer, data);
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let (mut server, _listener) = listening_server();
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        for option in [
+            SocketOption::KeepAlive(true),
+            SocketOption::KeepAliveIdle(Duration::from_secs(60)),
+            SocketOption::KeepAliveInterval(Duration::from_secs(10)),
+            SocketOption::KeepAliveCount(3),
+        ] {
+            client.set_option(socket, option).unwrap();
+        }
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        settle(&mut client, &mut server);
+
+        /*
+         * 何も受信しないまま 60 秒経ったら probe を送る。応答があれば、また 60 秒待つ。
+         */
+        let second = Duration::from_secs(1);
+        let mut now = Duration::from_secs(60);
+        client.handle_timeout(now - Duration::from_millis(1));
+        assert_eq!(client.poll_transmit(now), None);
+        client.handle_timeout(now);
+        let probe = client.poll_transmit(now).unwrap();
+        assert!(TcpPacket::decode(&probe).unwrap().get_payload().is_empty());
+        server.receive(now, &probe);
+        assert_eq!(exchange_at(&mut server, &mut client, now), 1);
+
+        now += Duration::from_secs(60);
+        client.handle_timeout(now - second);
+        assert_eq!(client.poll_transmit(now), None);
+
+        /*
+         * 応答がなければ 10 秒ごとに送り、3 回で RST を送って閉じる。
+         */
+        for _ in 0..3 {
+            client.handle_timeout(now);
+            assert_eq!(in_flight(&mut client).1.len(), 1);
+            now += Duration::from_secs(10);
+        }
+        client.handle_timeout(now - second);
+        assert_eq!(client.get_state(socket), Ok(TcpState::Established));
+        client.handle_timeout(now);
+        let reset = client.poll_transmit(now).unwrap();
+        let reset = TcpPacket::decode(&reset).unwrap();
+        assert!(reset.get_tcp_header().get_control_bits().get_rst());
+        assert_eq!(client.get_state(socket), Ok(TcpState::Closed));
+        assert_eq!(client.take_error(socket), Ok(Some(SocketError::TimedOut)));
+    }
+
+    fn user_timeout_in(datagram: &[u8]) -> Option<TcpOption> {
+        TcpPacket::decode(datagram)
+            .unwrap()
+            .get_tcp_header()
+            .get_options()
+            .into_iter()
+            .find(|option| matches!(option, TcpOption::UserTimeout { .. }))
+    }
+
+    #[test]
+    fn test_user_timeout() {
+        /*
+         * TCP_USER_TIMEOUT があれば、再送の回数ではなく時間で諦める。
+         */
+        let (mut server, _listener) = listening_server();
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        client
+            .set_option(socket, SocketOption::UserTimeout(Duration::from_secs(5)))
+            .unwrap();
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        settle(&mut client, &mut server);
+        assert_eq!(
+            client.get_user_timeout(socket),
+            Ok(Some(Duration::from_secs(5)))
+        );
+
+        let now = Duration::from_secs(1);
+        client.send(now, socket, b"lost").unwrap();
+        in_flight(&mut client);
+        for retransmission in [2, 4] {
+            client.handle_timeout(Duration::from_secs(retransmission));
+            assert_eq!(in_flight(&mut client).1.len(), 1);
+        }
+        client.handle_timeout(Duration::from_millis(5999));
+        assert_eq!(client.get_state(socket), Ok(TcpState::Established));
+        client.handle_timeout(Duration::from_secs(6));
+        assert_eq!(client.get_state(socket), Ok(TcpState::Closed));
+        assert_eq!(client.take_error(socket), Ok(Some(SocketError::TimedOut)));
+
+        /*
+         * User Timeout オプションを SYN で交換し、両方の大きい方を使う。
+         */
+        let mut server = TcpStack::new(&[SERVER]);
+        let listener = server.socket();
+        server
+            .set_option(
+                listener,
+                SocketOption::AdvertisedUserTimeout(Duration::from_secs(200)),
+            )
+            .unwrap();
+        server.bind(listener, UNSPECIFIED_ADDRESS, 80).unwrap();
+        server.listen(listener, 1).unwrap();
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        client
+            .set_option(
+                socket,
+                SocketOption::AdvertisedUserTimeout(Duration::from_secs(600)),
+            )
+            .unwrap();
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        let syn = client.poll_transmit(Duration::ZERO).unwrap();
+        assert_eq!(
+            user_timeout_in(&syn),
+            Some(TcpOption::UserTimeout {
+                minutes: false,
+                timeout: 600
+            })
+        );
+        server.receive(Duration::ZERO, &syn);
+        let syn_ack = server.poll_transmit(Duration::ZERO).unwrap();
+        assert_eq!(
+            user_timeout_in(&syn_ack),
+            Some(TcpOption::UserTimeout {
+                minutes: false,
+                timeout: 200
+            })
+        );
+        client.receive(Duration::ZERO, &syn_ack);
+        settle(&mut client, &mut server);
+        let accepted = server.accept(listener).unwrap();
+        let ten_minutes = Some(Duration::from_secs(600));
+        assert_eq!(client.get_user_timeout(socket), Ok(ten_minutes));
+        assert_eq!(server.get_user_timeout(accepted), Ok(ten_minutes));
+
+        /*
+         * 後から変えたら、次のデータのないセグメントで知らせる。15 ビットの秒に収まらなければ分で送る。上限は 1 時間。
+         */
+        client
+            .set_option(
+                socket,
+                SocketOption::AdvertisedUserTimeout(Duration::from_secs(40000)),
+            )
+            .unwrap();
+        let ack = client.poll_transmit(Duration::ZERO).unwrap();
+        assert_eq!(
+            user_timeout_in(&ack),
+            Some(TcpOption::UserTimeout {
+                minutes: true,
+                timeout: 666
+            })
+        );
+        server.receive(Duration::ZERO, &ack);
+        assert_eq!(
+            server.get_user_timeout(accepted),
+            Ok(Some(Duration::from_secs(3600)))
+        );
+
+        /*
+         * ADV_UTO を設定していない側は、相手のオプションを使わない。
+         */
+        let (mut server, listener) = listening_server();
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        client
+            .set_option(
+                socket,
+                SocketOption::AdvertisedUserTimeout(Duration::from_secs(600)),
+            )
+            .unwrap();
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        settle(&mut client, &mut server);
+        let accepted = server.accept(listener).unwrap();
+        assert_eq!(server.get_user_timeout(accepted), Ok(None));
+        assert_eq!(client.get_user_timeout(socket), Ok(None));
+    }
+
+    #[test]
+    fn test_time_wait() {
+        let (mut server, listener) = listening_server();
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        client.bind(socket, CLIENT, 5000).unwrap();
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        settle(&mut client, &mut server);
+        let accepted = server.accept(listener).unwrap();
+
+        client.shutdown(Duration::ZERO, socket).unwrap();
+        settle(&mut client, &mut server);
+        assert_eq!(client.get_state(socket), Ok(TcpState::FinWait2));
+        server.close(Duration::ZERO, accepted).unwrap();
+        let (_, fin) = in_flight(&mut server);
+        client.receive(Duration::ZERO, &fin[0]);
+        exchange(&mut client, &mut server);
+        assert_eq!(client.get_state(socket), Ok(TcpState::TimeWait));
+
+        /*
+         * RFC 1337: RCV.NXT ちょうどの RST でも TIME-WAIT は終わらない。
+         */
+        let fin = TcpPacket::decode(&fin[0]).unwrap();
+        let reset = rewrite(&fin, |tcp_header| {
+            tcp_header.set_sequence_number(tcp_header.get_sequence_number() + 1);
+            tcp_header.set_control_bits(control_bits(false, true, false));
+            tcp_header.set_options(&[]).unwrap();
+        });
+        client.receive(Duration::from_secs(10), &reset);
+        assert_eq!(client.poll_transmit(Duration::from_secs(10)), None);
+        assert_eq!(client.get_state(socket), Ok(TcpState::TimeWait));
+
+        /*
+         * 再送された FIN には ACK を返し、そこから 2MSL 待ち直す。
+         */
+        let now = Duration::from_secs(30);
+        client.receive(now, &fin.encode().unwrap());
+        let (_, ack) = in_flight(&mut client);
+        assert_eq!(ack.len(), 1);
+        assert_eq!(
+            acknowledgment_of(&ack[0]),
+            fin.get_tcp_header().get_sequence_number() + 1
+        );
+        client.handle_timeout(Duration::from_secs(60));
+        assert_eq!(client.get_state(socket), Ok(TcpState::TimeWait));
+        client.handle_timeout(now + Duration::from_secs(60));
+        assert_eq!(client.get_state(socket), Ok(TcpState::Closed));
+        assert_eq!(client.poll_timeout(), None);
+
+        client.close(now, socket).unwrap();
+        let other = client.socket();
+        assert_eq!(client.bind(other, CLIENT, 5000), Ok(()));
+    }
+
+    #[test]
+    fn test_time_wait_recycling_and_limit() {
+        let (mut server, listener) = listening_server();
+        let mut client = TcpStack::new(&[CLIENT]);
+        let socket = client.socket();
+        client.bind(socket, CLIENT, 5000).unwrap();
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        settle(&mut client, &mut server);
+        let accepted = server.accept(listener).unwrap();
+
+        server.shutdown(Duration::ZERO, accepted).unwrap();
+        let (_, fin) = in_flight(&mut server);
+        client.receive(Duration::ZERO, &fin[0]);
+        client.close(Duration::ZERO, socket).unwrap();
+        settle(&mut client, &mut server);
+        assert_eq!(server.get_state(accepted), Ok(TcpState::TimeWait));
+
+        /*
+         * 前のコネクションより古いシーケンス番号で、タイムスタンプもない SYN は受け付けない。
+         */
+        let now = Duration::from_secs(1);
+        let socket = client.socket();
+        client.bind(socket, CLIENT, 5000).unwrap();
+        client.connect(now, socket, SERVER, 80).unwrap();
+        let syn = client.poll_transmit(now).unwrap();
+        let syn = TcpPacket::decode(&syn).unwrap();
+        let fin = TcpPacket::decode(&fin[0]).unwrap();
+        let old_sequence = fin.get_tcp_header().get_acknowledgment_number() - 1;
+        server.receive(
+            now,
+            &rewrite(&syn, |tcp_header| {
+                tcp_header.set_sequence_number(old_sequence);
+                tcp_header.set_options(&[]).unwrap();
+            }),
+        );
+        assert!(in_flight(&mut server).1.is_empty());
+        assert_eq!(server.get_state(accepted), Ok(TcpState::TimeWait));
+
+        /*
+         * 新しい SYN なら TIME-WAIT を閉じて、前の SND.NXT より先の ISS で受け付ける。
+         */
+        server.receive(now, &syn.encode().unwrap());
+        assert_eq!(server.get_state(accepted), Ok(TcpState::Closed));
+        let (_, syn_ack) = in_flight(&mut server);
+        let syn_ack = TcpPacket::decode(&syn_ack[0]).unwrap();
+        assert!(syn_ack.get_tcp_header().get_control_bits().get_syn());
+        assert_eq!(
+            syn_ack.get_tcp_header().get_sequence_number(),
+            fin.get_tcp_header()
+                .get_sequence_number()
+                .wrapping_add(1 + 65535 + 2)
+        );
+        client.receive(now, &syn_ack.encode().unwrap());
+        settle_at(&mut client, &mut server, now);
+        assert_eq!(client.get_state(socket), Ok(TcpState::Established));
+        assert!(server.accept(listener).is_ok());
+
+        /*
+         * TIME-WAIT の数が上限に達していたら、TIME-WAIT を飛ばして閉じる。
+         */
+        let mut config = TcpConfig::default();
+        config.set_maximum_time_wait_connections(1);
+        let mut client = TcpStack::with_config(&[CLIENT], config);
+        let sockets = [client.socket(), client.socket()];
+        for socket in sockets {
+            client.connect(now, socket, SERVER, 80).unwrap();
+            settle_at(&mut client, &mut server, now);
+            let accepted = server.accept(listener).unwrap();
+            client.shutdown(now, socket).unwrap();
+            server.close(now, accepted).unwrap();
+            settle_at(&mut client, &mut server, now);
+        }
+        assert_eq!(client.get_state(sockets[0]), Ok(TcpState::TimeWait));
+        assert_eq!(client.get_state(sockets[1]), Ok(TcpState::Closed));
+    }
+
+    /*
+     * 第三者が CLIENT:5000 になりすまして SERVER:80 に送るセグメント。
+     */
+    fn forged(
+        sequence: u32,
+        acknowledgment: u32,
+        control_bits: ControlBits,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut tcp_header = TcpHeader::new(5000, 80);
+        tcp_header.set_sequence_number(sequence);
+        tcp_header.set_acknowledgment_number(acknowledgment);
+        tcp_header.set_control_bits(control_bits);
+        tcp_header.set_window(u16::MAX);
+        datagram(CLIENT, SERVER, tcp_header, payload)
+    }
+
+    fn control_bits(ack: bool, rst: bool, syn: bool) -> ControlBits {
+        let mut control_bits = ControlBits::default();
+        control_bits.set_ack(ack);
+        control_bits.set_rst(rst);
+        control_bits.set_syn(syn);
+        control_bits
+    }
+
+    /*
+     * タイムスタンプを使わない（PAWS で弾かれない）コネクションを作り、クライアントから 5 バイト送る。
+     * サーバーの RCV.NXT と SND.NXT を返す。
+     */
+    fn connection_without_timestamps(
+        config: TcpConfig,
+    ) -> (TcpStack, SocketHandle, TcpStack, u32, u32) {
+        let mut config = config;
+        config.set_timestamps(false);
+        let mut server = TcpStack::with_config(&[SERVER], config);
+        let listener = server.socket();
+        server.bind(listener, SERVER, 80).unwrap();
+        server.listen(listener, 16).unwrap();
+        let mut client = TcpStack::with_config(&[CLIENT], config);
+        let socket = client.socket();
+        client.bind(socket, CLIENT, 5000).unwrap();
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        settle(&mut client, &mut server);
+        let accepted = server.accept(listener).unwrap();
+
+        client.send(Duration::ZERO, socket, b"hello").unwrap();
+        let (_, data) = in_flight(&mut client);
+        server.receive(Duration::ZERO, &data[0]);
+        in_flight(&mut server);
+        let mut buffer = [0u8; 16];
+        assert_eq!(server.recv(Duration::ZERO, accepted, &mut buffer), Ok(5));
+        in_flight(&mut server);
+
+        let data = TcpPacket::decode(&data[0]).unwrap();
+        let tcp_header = data.get_tcp_header();
+        (
+            server,
+            accepted,
+            client,
+            tcp_header.get_sequence_number() + 5,
+            tcp_header.get_acknowledgment_number(),
+        )
+    }
+
+    #[test]
+    fn test_blind_reset_and_syn_attacks() {
+        let (mut server, accepted, _client, receive_next, send_next) =
+            connection_without_timestamps(TcpConfig::default());
+        let challenged = |server: &mut TcpStack| {
+            let (_, datagrams) = in_flight(server);
+            assert_eq!(datagrams.len(), 1);
+            let ack = TcpPacket::decode(&datagrams[0]).unwrap();
+            let tcp_header = ack.get_tcp_header();
+            assert_eq!(
+                tcp_header.get_control_bits(),
+                control_bits(true, false, false)
+            );
+            assert_eq!(tcp_header.get_sequence_number(), send_next);
+            assert_eq!(tcp_header.get_acknowledgment_number(), receive_next);
+        };
+
+        /*
+         * ウィンドウ外の RST は黙って捨てる。ウィンドウ内でも RCV.NXT からずれていれば challenge ACK.
+         */
+        let reset = control_bits(false, true, false);
+        server.receive(
+            Duration::ZERO,
+            &forged(receive_next + 1_000_000, 0, reset, &[]),
+        );
+        assert!(in_flight(&mut server).1.is_empty());
+        server.receive(Duration::ZERO, &forged(receive_next + 1000, 0, reset, &[]));
+        challenged(&mut server);
+        assert_eq!(server.get_state(accepted), Ok(TcpState::Established));
+
+        /*
+         * SYN はシーケンス番号に関わらず challenge ACK.
+         */
+        let syn = control_bits(false, false, true);
+        for sequence in [receive_next, receive_next + 1000, receive_next - 1000] {
+            server.receive(Duration::ZERO, &forged(sequence, 0, syn, &[]));
+            challenged(&mut server);
+            assert_eq!(server.get_state(accepted), Ok(TcpState::Established));
+        }
+
+        /*
+         * RCV.NXT ちょうどの RST で閉じる。
+         */
+        server.receive(Duration::ZERO, &forged(receive_next, 0, reset, &[]));
+        assert!(in_flight(&mut server).1.is_empty());
+        assert_eq!(server.get_state(accepted), Ok(TcpState::Closed));
+        assert_eq!(
+            server.take_error(accepted),
+            Ok(Some(SocketError::ConnectionReset))
+        );
+    }
+
+    #[test]
+    fn test_blind_data_injection() {
+        let (mut server, accepted, _client, receive_next, send_next) =
+            connection_without_timestamps(TcpConfig::default());
+        let ack = control_bits(true, false, false);
+        let mut buffer = [0u8; 16];
+
+        let challenged = |server: &mut TcpStack, acknowledgment: u32| {
+            server.receive(
+                Duration::ZERO,
+                &forged(receive_next, acknowledgment, ack, b"evil"),
+            );
+            let (_, datagrams) = in_flight(server);
+            assert_eq!(datagrams.len(), 1);
+            assert_eq!(acknowledgment_of(&datagrams[0]), receive_next);
+            assert_eq!(
+                server.recv(Duration::ZERO, accepted, &mut [0u8; 16]),
+                Err(SocketError::WouldBlock)
+            );
+        };
+
+        /*
+         * SND.NXT (= SND.MAX) より先の ACK は、データごと捨てて challenge ACK.
+         */
+        challenged(&mut server, send_next.wrapping_add(1));
+
+        /*
+         * SND.UNA - MAX.SND.WND より古い ACK も同じ。MAX.SND.WND はクライアントの受信バッファの 262144.
+         */
+        let maximum_send_window = 262144;
+        challenged(&mut server, send_next.wrapping_sub(maximum_send_window + 1));
+
+        /*
+         * MAX.SND.WND の範囲内なら、古い ACK でもデータは受け取る。
+         */
+        server.receive(
+            Duration::ZERO,
+            &forged(
+                receive_next,
+                send_next.wrapping_sub(maximum_send_window),
+                ack,
+                b"late",
+            ),
+        );
+        assert_eq!(server.recv(Duration::ZERO, accepted, &mut buffer), Ok(4));
+        assert_eq!(&buffer[..4], b"late");
+    }
+
+    #[test]
+    fn test_challenge_ack_rate_limit() {
+        let mut config = TcpConfig::default();
+        config.set_challenge_ack_limit(4);
+        let (mut server, accepted, _client, receive_next, _) =
+            connection_without_timestamps(config);
+        let reset = control_bits(false, true, false);
+
+        /*
+         * 1 秒あたり 2 から 5 個まで。次の 1 秒になれば、また送る。
+         */
+        for second in 0..3 {
+            let now = Duration::from_secs(second);
+            for offset in 1..=10 {
+                server.receive(now, &forged(receive_next + offset, 0, reset, &[]));
+            }
+            let (_, datagrams) = in_flight(&mut server);
+            assert!((2..=5).contains(&datagrams.len()));
+        }
+        assert_eq!(server.get_state(accepted), Ok(TcpState::Established));
+    }
+
+    #[test]
+    fn test_challenge_ack_limit_is_global() {
+        let mut config = TcpConfig::default();
+        config.set_challenge_ack_limit(4);
+        let (mut server, first, mut client, first_receive_next, _) =
+            connection_without_timestamps(config);
+
+        /*
+         * 同じクライアントの別のポートから、もう 1 つコネクションを作る。
+         */
+        let socket = client.socket();
+        client.bind(socket, CLIENT, 5001).unwrap();
+        client.connect(Duration::ZERO, socket, SERVER, 80).unwrap();
+        let (_, syn) = in_flight(&mut client);
+        server.receive(Duration::ZERO, &syn[0]);
+        settle(&mut client, &mut server);
+        let second_receive_next = TcpPacket::decode(&syn[0])
+            .unwrap()
+            .get_tcp_header()
+            .get_sequence_number()
+            + 1;
+
+        let reset_to_second = |sequence: u32| {
+            let mut tcp_header = TcpHeader::new(5001, 80);
+            tcp_header.set_sequence_number(sequence);
+            tcp_header.set_control_bits(control_bits(false, true, false));
+            datagram(CLIENT, SERVER, tcp_header, &[])
+        };
+        let reset = control_bits(false, true, false);
+
+        /*
+         * 1 つ目のコネクションで 1 秒分の上限を使い切ると、2 つ目のコネクションにも challenge ACK は送らない。
+         */
+        let now = Duration::ZERO;
+        for offset in 1..=10 {
+            server.receive(now, &forged(first_receive_next + offset, 0, reset, &[]));
+        }
+        assert!((2..=5).contains(&in_flight(&mut server).1.len()));
+        for offset in 1..=10 {
+            server.receive(now, &reset_to_second(second_receive_next + offset));
+        }
+        assert!(in_flight(&mut server).1.is_empty());
+
+        /*
+         * 次の 1 秒になれば、2 つ目のコネクションにも送る。
+         */
+        let now = Duration::from_secs(1);
+        server.receive(now, &reset_to_second(second_receive_next + 1));
+        let (_, datagrams) = in_flight(&mut server);
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(acknowledgment_of(&datagrams[0]), second_receive_next);
+        assert_eq!(server.get_state(first), Ok(TcpState::Established));
+    }
+}
@@ -0,0 +1,86 @@
+use crate::tcp_stack::demux::KeyedHasher;
+use core::hash::BuildHasher;
+use core::time::Duration;
+
+/*
+ * RFC 5961 10 の challenge ACK の上限。スタック全体で、1 秒あたりおよそ`limit`個まで送る。
+ *
+ * 上限がぴったり決まっていると、攻撃者は自分のコネクションに返ってくる challenge ACK を数えて、
+ * 他のコネクションに送った偽の RST や SYN が上限を消費したか（シーケンス番号が当たったか）を知ることができる
+ * (CVE-2016-5696)。Linux と同じく、1 秒ごとの上限を`limit`/2 から`limit`*3/2 の間でばらつかせる。
+ *
+ * See: https://www.rfc-editor.org/rfc/rfc5961.html#section-10
+ */
+pub(crate) struct ChallengeAckLimiter {
+    hasher: KeyedHasher,
+    limit: u32,
+
+    /*
+     * 数えている 1 秒と、その間にまだ送れる数。
+     */
+    second: Option<u64>,
+    remaining: u32,
+}
+
+impl ChallengeAckLimiter {
+    pub(crate) fn new(hasher: KeyedHasher, limit: u32) -> Self {
+        Self {
+            hasher,
+            limit,
+            second: None,
+            remaining: 0,
+        }
+    }
+
+    /*
+     * challenge ACK を 1 つ送ってよいか。
+     */
+    pub(crate) fn allow(&mut self, now: Duration) -> bool {
+        let second = now.as_secs();
+        if self.second != Some(second) {
+            let jitter =
+                self.hasher.hash_one(("challenge_ack", second)) % u64::from(self.limit.max(1));
+            self.second = Some(second);
+            self.remaining = self.limit.div_ceil(2) + jitter as u32;
+        }
+        if self.remaining == 0 {
+            return false;
+        }
+        self.remaining -= 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn count_allowed(limiter: &mut ChallengeAckLimiter, now: Duration) -> u32 {
+        (0..1000).filter(|_| limiter.allow(now)).count() as u32
+    }
+
+    #[test]
+    fn test_limit_per_second() {
+        let mut limiter = ChallengeAckLimiter::new(KeyedHasher::new([7; 16]), 100);
+        let mut counts = Vec::new();
+        for second in 0..10 {
+            let now = Duration::from_secs(second);
+            let count = count_allowed(&mut limiter, now);
+            assert!((50..150).contains(&count));
+            assert_eq!(
+                count_allowed(&mut limiter, now + Duration::from_millis(999)),
+                0
+            );
+            counts.push(count);
+        }
+
+        /*
+         * 上限は秒ごとに変わる。
+         */
+        assert!(counts.iter().any(|count| *count != counts[0]));
+
+        let mut disabled = ChallengeAckLimiter::new(KeyedHasher::new([7; 16]), 0);
+        assert_eq!(count_allowed(&mut disabled, Duration::ZERO), 0);
+    }
+}
@@ -0,0 +1,1733 @@
+use crate::tcp_stack::path_mtu::PathMtu;
+use crate::tcp_stack::retransmission::RetransmissionTimer;
+use crate::tcp_stack::syn_cookie::SynCookieOptions;
+use crate::tcp_stack::{
+    segment_ip_header, Endpoint, SocketError, SocketOption, SocketOptions, TcpConfig,
+    HEADERS_LENGTH, TIMER_RESOLUTION,
+};
+use crate::transmission_control_protocol::tcp_option::TcpOption;
+use crate::transmission_control_protocol::tcp_packet::TcpPacket;
+use crate::transmission_control_protocol::{ControlBits, TcpHeader};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+/*
+ * See: https://www.rfc-editor.org/rfc/rfc9293.html#section-3.3.2
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TcpState {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+impl TcpState {
+    /*
+     * SYN を送受信し終えて、シーケンス番号が同期している状態。
+     */
+    pub fn is_synchronized(&self) -> bool {
+        !matches!(
+            self,
+            TcpState::Closed | TcpState::Listen | TcpState::SynSent | TcpState::SynReceived
+        )
+    }
+}
+
+/*
+ * MSS option がない時の送信 MSS. RFC 9293 3.7.1.
+ */
+pub(crate) const DEFAULT_SEND_MSS: u16 = 536;
+
+const MAXIMUM_WINDOW_SHIFT: u8 = 14;
+
+/*
+ * 受信バッファ全体を広告できる最小のシフト。
+ */
+pub(crate) fn window_shift_for(receive_buffer_size: usize) -> u8 {
+    let mut shift = 0;
+    while shift < MAXIMUM_WINDOW_SHIFT && receive_buffer_size >> shift > usize::from(u16::MAX) {
+        shift += 1;
+    }
+    shift
+}
+
+/*
+ * NOP, NOP, Timestamps の 12 バイト。タイムスタンプを使う時は、その分 MSS より小さく送る (RFC 6691)。
+ */
+const TIMESTAMPS_OPTION_LENGTH: u16 = 12;
+
+/*
+ * 続けてこの回数タイムアウトしたら、経路の MTU が小さくなったのかもしれないと疑う。
+ */
+const BLACK_HOLE_RETRANSMISSIONS: u32 = 2;
+
+/*
+ * cork 中でも、MSS に満たないデータをこれより長くは待たせない（Linux と同じ）。
+ */
+const CORK_TIMEOUT: Duration = Duration::from_millis(200);
+
+/*
+ * quick ACK モードに入ったら、この数のセグメントまで遅延させずに ACK する（Linux の TCP_MAX_QUICKACKS）。
+ */
+const QUICK_ACKS: u32 = 16;
+
+/*
+ * RFC 7323 5.5: これより長く更新されていない TS.Recent は古すぎて、PAWS に使えない。
+ */
+const PAWS_IDLE_LIMIT: Duration = Duration::from_secs(24 * 24 * 60 * 60);
+
+/*
+ * タイムスタンプの時計 (TSval)。1 ミリ秒ごとに 1 増える。`offset`はコネクションごとのずれ。
+ */
+pub(crate) fn timestamp_clock(now: Duration, offset: u32) -> u32 {
+    (now.as_millis() as u32).wrapping_add(offset)
+}
+
+/*
+ * Timestamps オプションの (TSval, TSecr).
+ */
+pub(crate) fn timestamps_of(tcp_header: &TcpHeader) -> Option<(u32, u32)> {
+    tcp_header
+        .get_options()
+        .iter()
+        .find_map(|option| match option {
+            TcpOption::Timestamps { tsval, tsecr } => Some((*tsval, *tsecr)),
+            _ => None,
+        })
+}
+
+/*
+ * User Timeout オプションにする。15 ビットの秒に収まらなければ分で表す。
+ */
+fn user_timeout_option(timeout: Duration) -> TcpOption {
+    let seconds = timeout.as_secs();
+    if seconds <= 0x7fff {
+        TcpOption::UserTimeout {
+            minutes: false,
+            timeout: seconds as u16,
+        }
+    } else {
+        TcpOption::UserTimeout {
+            minutes: true,
+            timeout: (seconds / 60).min(0x7fff) as u16,
+        }
+    }
+}
+
+fn user_timeout_of(tcp_header: &TcpHeader) -> Option<Duration> {
+    tcp_header
+        .get_options()
+        .iter()
+        .find_map(|option| match option {
+            TcpOption::UserTimeout { minutes, timeout } => {
+                let unit = if *minutes { 60 } else { 1 };
+                Some(Duration::from_secs(u64::from(*timeout) * unit))
+            }
+            _ => None,
+        })
+}
+
+/*
+ * シーケンス番号の比較。2^31 以内の差なら正しく比較できる（RFC 1982 のシリアル番号算術）。
+ */
+pub(crate) fn sequence_lt(lhs: u32, rhs: u32) -> bool {
+    (lhs.wrapping_sub(rhs) as i32) < 0
+}
+
+pub(crate) fn sequence_le(lhs: u32, rhs: u32) -> bool {
+    (lhs.wrapping_sub(rhs) as i32) <= 0
+}
+
+/*
+ * TCB (Transmission Control Block). 1 つのコネクションの状態。
+ *
+ * 変数名は RFC 9293 3.3.1 の SND.UNA, SND.NXT などに対応させている。
+ *
+ * `send_buffer`の先頭は、まだ ACK されていない最初のデータ（SYN は含まない）。
+ *
+ * 再送は go-back-N で、タイムアウトしたら SND.NXT を SND.UNA まで戻して送り直す。
+ */
+pub(crate) struct Connection {
+    local: Endpoint,
+    remote: Endpoint,
+    state: TcpState,
+
+    initial_send_sequence: u32,
+    initial_receive_sequence: u32,
+
+    send_unacknowledged: u32,
+    send_next: u32,
+
+    /*
+     * これまでに送った最大のシーケンス番号の次。再送で SND.NXT を戻しても、ここまでの ACK は受け付ける。
+     */
+    send_maximum: u32,
+    send_window: u32,
+
+    /*
+     * 相手がこれまでに広告した最大のウィンドウ (RFC 5961 の MAX.SND.WND)。
+     */
+    maximum_send_window: u32,
+    send_window_update_sequence: u32,
+    send_window_update_acknowledgment: u32,
+    send_mss: u16,
+
+    /*
+     * ウィンドウスケール (RFC 7323)。`send_window_shift`は受け取ったウィンドウに、
+     * `receive_window_shift`は広告するウィンドウにかける。両方が SYN で Window Scale を
+     * 送った時だけ 0 以外になる。SYN のウィンドウにはかけない。
+     */
+    send_window_shift: u8,
+    receive_window_shift: u8,
+
+    /*
+     * SYN で申し出るシフト。相手が応じなければ`None`にする。
+     */
+    offered_window_shift: Option<u8>,
+
+    receive_next: u32,
+
+    /*
+     * タイムスタンプ (RFC 7323)。SYN で申し出て、相手も送ってきた時だけ使い続ける。
+     * `timestamp_recent`は TS.Recent, `timestamp_recent_age`はそれを更新した時刻、
+     * `last_acknowledgment_sent`は Last.ACK.sent. `timestamp_offset`は TSval の時計に足すずれ。
+     */
+    timestamps: bool,
+    timestamp_offset: u32,
+    timestamp_recent: u32,
+    timestamp_recent_age: Duration,
+    last_acknowledgment_sent: u32,
+
+    send_buffer: VecDeque<u8>,
+    send_buffer_size: usize,
+    receive_buffer: VecDeque<u8>,
+    receive_buffer_size: usize,
+    advertised_mss: u16,
+
+    /*
+     * close / shutdown が呼ばれたら、FIN のシーケンス番号（送信バッファの最後の次）が決まる。
+     */
+    fin_sequence: Option<u32>,
+    fin_received: bool,
+
+    ack_pending: bool,
+
+    /*
+     * RFC 5961 の challenge ACK を送りたい。送るかどうかは、スタック全体の上限を見て`TcpStack`が決める。
+     */
+    challenge_ack_pending: bool,
+
+    /*
+     * 最後に広告したウィンドウの右端 (RCV.NXT + RCV.WND)。広告したウィンドウは縮めない。
+     */
+    receive_window_edge: u32,
+
+    /*
+     * RST を受信した、もしくは送ることになった時のエラー。
+     */
+    error: Option<SocketError>,
+    reset_pending: bool,
+
+    /*
+     * 受け入れられない ACK に返す <SEQ=SEG.ACK><CTL=RST> のシーケンス番号。こちらの状態は変えない。
+     */
+    reset_reply: Option<u32>,
+
+    retransmission: RetransmissionTimer,
+
+    /*
+     * タイムアウトの後は、最初の未 ACK のセグメントだけを送り直し、ACK が来るまで残りの再送を待つ。
+     */
+    retransmission_held: bool,
+    maximum_retransmissions: u32,
+    maximum_syn_retransmissions: u32,
+
+    path_mtu: PathMtu,
+
+    /*
+     * Nagle のアルゴリズム (RFC 896) は Minshall の変形（Linux と同じ）で、`small_segment_end`は
+     * 最後に送った MSS 未満のセグメントの終わり。それが ACK されるまで、次の小さいセグメントを送らない。
+     * cork 中は MSS に満たないデータを`corked_since`から`CORK_TIMEOUT`まで待たせ、
+     * 過ぎたら`cork_expired`にして送る。
+     */
+    nodelay: bool,
+    cork: bool,
+    small_segment_end: u32,
+    corked_since: Option<Duration>,
+    cork_expired: bool,
+
+    /*
+     * 遅延 ACK (RFC 1122 4.2.3.2)。`delayed_ack`は ACK を送る期限、`unacknowledged_segments`は
+     * 最後に ACK してから受け取ったセグメントの数。`quick_acks`が残っている間は遅延させない。
+     */
+    delayed_ack_timeout: Duration,
+    delayed_ack: Option<Duration>,
+    unacknowledged_segments: u32,
+    quick_acks: u32,
+    quick_ack: bool,
+    last_received: Duration,
+
+    /*
+     * persist タイマー (RFC 9293 3.8.6.1)。相手のウィンドウが 0 の間、`persist`の時刻に probe を送る。
+     * `persist_backoff`は続けて送った probe の数で、間隔を倍にしていく。
+     * `unanswered_probes`は ACK が返ってこなかった probe の数。
+     */
+    persist: Option<Duration>,
+    persist_backoff: u32,
+    probe_pending: bool,
+    unanswered_probes: u32,
+
+    /*
+     * keepalive (RFC 1122 4.2.3.6)。`last_segment_received`から`keep_alive_idle`経ったら
+     * probe を送り始める。`keep_alive_probes`は応答のないまま送った数。
+     */
+    keep_alive: bool,
+    keep_alive_idle: Duration,
+    keep_alive_interval: Duration,
+    keep_alive_count: u32,
+    keep_alive_probes: u32,
+    last_segment_received: Duration,
+
+    /*
+     * User Timeout (RFC 5482)。`user_timeout`は TCP_USER_TIMEOUT, `advertised_user_timeout`は ADV_UTO,
+     * `remote_user_timeout`は相手から受け取った REMOTE_UTO. `user_timeout_pending`なら、
+     * 次のデータのないセグメントでオプションを送る。
+     * `waiting_since`は、送ったもの（データ、probe）への応答を待ち始めた時刻。
+     */
+    user_timeout: Duration,
+    advertised_user_timeout: Duration,
+    remote_user_timeout: Option<Duration>,
+    user_timeout_limits: (Duration, Duration),
+    user_timeout_pending: bool,
+    waiting_since: Option<Duration>,
+
+    /*
+     * TIME-WAIT で待つ時間 (2MSL) と、CLOSED にする時刻。
+     */
+    time_wait_timeout: Duration,
+    time_wait: Option<Duration>,
+}
+
+impl Connection {
+    /*
+     * 能動オープン。SYN-SENT から始める。
+     */
+    pub(crate) fn connect(
+        local: Endpoint,
+        remote: Endpoint,
+        initial_send_sequence: u32,
+        timestamp_offset: u32,
+        config: &TcpConfig,
+        options: &SocketOptions,
+    ) -> Self {
+        let receive_buffer_size = options.receive_buffer_size;
+        Self {
+            local,
+            remote,
+            state: TcpState::SynSent,
+            initial_send_sequence,
+            initial_receive_sequence: 0,
+            send_unacknowledged: initial_send_sequence,
+            send_next: initial_send_sequence,
+            send_maximum: initial_send_sequence,
+            send_window: 0,
+            maximum_send_window: 0,
+            send_window_update_sequence: 0,
+            send_window_update_acknowledgment: 0,
+            send_mss: DEFAULT_SEND_MSS,
+            send_window_shift: 0,
+            receive_window_shift: 0,
+            offered_window_shift: config
+                .get_window_scaling()
+                .then(|| window_shift_for(receive_buffer_size)),
+            receive_next: 0,
+            timestamps: config.get_timestamps(),
+            timestamp_offset,
+            timestamp_recent: 0,
+            timestamp_recent_age: Duration::ZERO,
+            last_acknowledgment_sent: 0,
+            send_buffer: VecDeque::new(),
+            send_buffer_size: options.send_buffer_size,
+            receive_buffer: VecDeque::new(),
+            receive_buffer_size,
+            advertised_mss: config.get_advertised_mss(),
+            fin_sequence: None,
+            fin_received: false,
+            ack_pending: false,
+            challenge_ack_pending: false,
+            receive_window_edge: 0,
+            error: None,
+            reset_pending: false,
+            reset_reply: None,
+            retransmission: RetransmissionTimer::new(
+                config.get_initial_retransmission_timeout(),
+                config.get_minimum_retransmission_timeout(),
+                config.get_maximum_retransmission_timeout(),
+                TIMER_RESOLUTION,
+            ),
+            retransmission_held: false,
+            maximum_retransmissions: config.get_maximum_retransmissions(),
+            maximum_syn_retransmissions: config.get_maximum_syn_retransmissions(),
+            path_mtu: PathMtu::new(config.get_mtu(), config.get_mtu_probing()),
+            nodelay: options.nodelay,
+            cork: options.cork,
+            small_segment_end: initial_send_sequence,
+            corked_since: None,
+            cork_expired: false,
+            delayed_ack_timeout: config.get_delayed_ack_timeout(),
+            delayed_ack: None,
+            unacknowledged_segments: 0,
+            quick_acks: 0,
+            quick_ack: options.quick_ack,
+            last_received: Duration::ZERO,
+            persist: None,
+            persist_backoff: 0,
+            probe_pending: false,
+            unanswered_probes: 0,
+            keep_alive: options.keep_alive,
+            keep_alive_idle: options.keep_alive_idle,
+            keep_alive_interval: options.keep_alive_interval,
+            keep_alive_count: options.keep_alive_count,
+            keep_alive_probes: 0,
+            last_segment_received: Duration::ZERO,
+            user_timeout: options.user_timeout,
+            advertised_user_timeout: options.advertised_user_timeout,
+            remote_user_timeout: None,
+            user_timeout_limits: config.get_user_timeout_limits(),
+            user_timeout_pending: false,
+            waiting_since: None,
+            time_wait_timeout: config.get_maximum_segment_lifetime() * 2,
+            time_wait: None,
+        }
+    }
+
+    /*
+     * 受動オープン。LISTEN で SYN を受け取ったところから、SYN-RECEIVED で始める。
+     */
+    pub(crate) fn accept(
+        now: Duration,
+        syn: &TcpPacket,
+        initial_send_sequence: u32,
+        timestamp_offset: u32,
+        config: &TcpConfig,
+        options: &SocketOptions,
+    ) -> Self {
+        let tcp_header = syn.get_tcp_header();
+        let mut connection = Self::connect(
+            Endpoint::new(
+                syn.get_destination_address(),
+                tcp_header.get_destination_port(),
+            ),
+            Endpoint::new(syn.get_source_address(), tcp_header.get_source_port()),
+            initial_send_sequence,
+            timestamp_offset,
+            config,
+            options,
+        );
+        connection.state = TcpState::SynReceived;
+        connection.receive_syn(now, syn);
+        connection
+    }
+
+    /*
+     * SYN cookie を検証した ACK から作る。SYN-ACK は送ったことにして、SYN-RECEIVED で始める。
+     * ACK そのものは`on_segment`に渡して処理させる。
+     *
+     * TODO: SACK に対応したら`cookie_options.sack_permitted`も使う。
+     */
+    pub(crate) fn accept_cookie(
+        now: Duration,
+        ack: &TcpPacket,
+        send_mss: u16,
+        cookie_options: SynCookieOptions,
+        timestamp_offset: u32,
+        config: &TcpConfig,
+        options: &SocketOptions,
+    ) -> Self {
+        let tcp_header = ack.get_tcp_header();
+        let initial_send_sequence = tcp_header.get_acknowledgment_number().wrapping_sub(1);
+        let initial_receive_sequence = tcp_header.get_sequence_number().wrapping_sub(1);
+        let mut connection = Self::connect(
+            Endpoint::new(
+                ack.get_destination_address(),
+                tcp_header.get_destination_port(),
+            ),
+            Endpoint::new(ack.get_source_address(), tcp_header.get_source_port()),
+            initial_send_sequence,
+            timestamp_offset,
+            config,
+            options,
+        );
+        connection.state = TcpState::SynReceived;
+        connection.initial_receive_sequence = initial_receive_sequence;
+        connection.receive_next = tcp_header.get_sequence_number();
+        connection.receive_window_edge = connection
+            .receive_next
+            .wrapping_add(options.receive_buffer_size.min(usize::from(u16::MAX)) as u32);
+        connection.send_mss = send_mss.min(connection.advertised_mss);
+        connection.send_next = tcp_header.get_acknowledgment_number();
+        connection.send_maximum = connection.send_next;
+        connection.last_acknowledgment_sent = connection.receive_next;
+        connection.negotiate_window_scale(cookie_options.window_scale);
+        connection.negotiate_timestamps(now, timestamps_of(tcp_header).map(|(tsval, _)| tsval));
+        connection.quick_acks = QUICK_ACKS;
+        connection.last_received = now;
+        connection.last_segment_received = now;
+        connection
+    }
+
+    pub(crate) fn get_local(&self) -> Endpoint {
+        self.local
+    }
+
+    pub(crate) fn get_remote(&self) -> Endpoint {
+        self.remote
+    }
+
+    pub(crate) fn get_state(&self) -> TcpState {
+        self.state
+    }
+
+    pub(crate) fn take_error(&mut self) -> Option<SocketError> {
+        self.error.take()
+    }
+
+    pub(crate) fn get_retransmission_timeout(&self) -> Duration {
+        self.retransmission.get_timeout()
+    }
+
+    pub(crate) fn get_smoothed_rtt(&self) -> Option<Duration> {
+        self.retransmission.get_smoothed_rtt()
+    }
+
+    pub(crate) fn get_path_mtu(&self) -> u16 {
+        self.path_mtu.get_mtu()
+    }
+
+    /*
+     * 接続した後に変えられたソケットオプション。
+     */
+    pub(crate) fn set_option(&mut self, option: SocketOption) {
+        match option {
+            SocketOption::NoDelay(nodelay) => self.nodelay = nodelay,
+            SocketOption::Cork(cork) => {
+                self.cork = cork;
+                if !cork {
+                    self.corked_since = None;
+                    self.cork_expired = false;
+                }
+            }
+
+            /*
+             * 遅延させている ACK があれば、すぐに送る。
+             */
+            SocketOption::QuickAck(quick_ack) => {
+                self.quick_ack = quick_ack;
+                if quick_ack && self.delayed_ack.take().is_some() {
+                    self.ack_pending = true;
+                }
+            }
+            SocketOption::KeepAlive(keep_alive) => self.keep_alive = keep_alive,
+            SocketOption::KeepAliveIdle(idle) => self.keep_alive_idle = idle,
+            SocketOption::KeepAliveInterval(interval) => self.keep_alive_interval = interval,
+            SocketOption::KeepAliveCount(count) => self.keep_alive_count = count,
+            SocketOption::UserTimeout(timeout) => self.user_timeout = timeout,
+            SocketOption::AdvertisedUserTimeout(timeout) => {
+                self.advertised_user_timeout = timeout;
+                if !timeout.is_zero() && self.state.is_synchronized() {
+                    self.user_timeout_pending = true;
+                    self.ack_pending = true;
+                }
+            }
+            SocketOption::ReuseAddress(_)
+            | SocketOption::ReusePort(_)
+            | SocketOption::SendBufferSize(_)
+            | SocketOption::ReceiveBufferSize(_) => {}
+        }
+    }
+
+    /*
+     * RFC 5482 3.1: ADV_UTO を設定していれば、相手の User Timeout オプションも使って
+     * USER_TIMEOUT = min(U_LIMIT, max(ADV_UTO, REMOTE_UTO, L_LIMIT)) にする。
+     * TCP_USER_TIMEOUT を設定していれば、そちらを優先する。どちらもなければ再送の回数で決める。
+     */
+    pub(crate) fn get_user_timeout(&self) -> Option<Duration> {
+        if !self.user_timeout.is_zero() {
+            return Some(self.user_timeout);
+        }
+        if self.advertised_user_timeout.is_zero() {
+            return None;
+        }
+        let remote = self.remote_user_timeout?;
+        let (minimum, maximum) = self.user_timeout_limits;
+        Some(
+            self.advertised_user_timeout
+                .max(remote)
+                .max(minimum)
+                .min(maximum),
+        )
+    }
+
+    fn user_timeout_deadline(&self) -> Option<Duration> {
+        Some(self.waiting_since? + self.get_user_timeout()?)
+    }
+
+    /*
+     * 送るものも ACK を待つものもない間は、keepalive のタイマーをかける。
+     */
+    fn keep_alive_deadline(&self) -> Option<Duration> {
+        let idle = self.keep_alive
+            && matches!(
+                self.state,
+                TcpState::Established | TcpState::CloseWait | TcpState::FinWait2
+            )
+            && self.send_unacknowledged == self.send_maximum
+            && self.send_buffer.is_empty();
+        idle.then(|| {
+            self.last_segment_received
+                + self.keep_alive_idle
+                + self
+                    .keep_alive_interval
+                    .saturating_mul(self.keep_alive_probes)
+        })
+    }
+
+    /*
+     * 次にタイマーが切れる時刻。再送、遅延 ACK, cork, persist, keepalive, User Timeout, TIME-WAIT のうち一番早いもの。
+     */
+    pub(crate) fn get_deadline(&self) -> Option<Duration> {
+        if self.state == TcpState::Closed {
+            return None;
+        }
+        [
+            self.retransmission.get_deadline(),
+            self.delayed_ack,
+            self.corked_since.map(|since| since + CORK_TIMEOUT),
+            self.persist,
+            self.keep_alive_deadline(),
+            self.user_timeout_deadline(),
+            self.time_wait,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    fn receive_syn(&mut self, now: Duration, syn: &TcpPacket) {
+        let tcp_header = syn.get_tcp_header();
+
+        /*
+         * 同時オープンでは SYN で広告したウィンドウを、相手のシーケンス番号から数え直す。
+         */
+        let advertised = self.receive_window_edge.wrapping_sub(self.receive_next);
+        self.initial_receive_sequence = tcp_header.get_sequence_number();
+        self.receive_next = self.initial_receive_sequence.wrapping_add(1);
+        self.receive_window_edge = self.receive_next.wrapping_add(advertised);
+        self.last_acknowledgment_sent = self.receive_next;
+        self.send_mss = DEFAULT_SEND_MSS;
+
+        /*
+         * 相手はスロースタートから始めるので、最初は遅延させずに ACK する。
+         */
+        self.quick_acks = QUICK_ACKS;
+        self.last_received = now;
+        self.last_segment_received = now;
+        self.remote_user_timeout = user_timeout_of(tcp_header);
+        let mut peer_window_shift = None;
+        let mut peer_timestamp = None;
+        for option in tcp_header.get_options() {
+            match option {
+                TcpOption::MaximumSegmentSize(mss) => self.send_mss = mss.min(self.advertised_mss),
+                TcpOption::WindowScale(shift) => peer_window_shift = Some(shift),
+                TcpOption::Timestamps { tsval, .. } => peer_timestamp = Some(tsval),
+                _ => {}
+            }
+        }
+        self.negotiate_window_scale(peer_window_shift);
+        self.negotiate_timestamps(now, peer_timestamp);
+        self.update_send_window(tcp_header);
+    }
+
+    /*
+     * RFC 7323 2.3: 14 より大きいシフトは 14 として扱う。
+     */
+    fn negotiate_window_scale(&mut self, peer_window_shift: Option<u8>) {
+        match (self.offered_window_shift, peer_window_shift) {
+            (Some(offered), Some(peer)) => {
+                self.send_window_shift = peer.min(MAXIMUM_WINDOW_SHIFT);
+                self.receive_window_shift = offered;
+            }
+            _ => {
+                self.offered_window_shift = None;
+                self.send_window_shift = 0;
+                self.receive_window_shift = 0;
+            }
+        }
+    }
+
+    /*
+     * RFC 7323 3.2: 相手の SYN にも Timestamps があれば使い、TS.Recent をその TSval にする。
+     */
+    fn negotiate_timestamps(&mut self, now: Duration, peer_timestamp: Option<u32>) {
+        match (self.timestamps, peer_timestamp) {
+            (true, Some(tsval)) => self.update_timestamp_recent(now, tsval),
+            _ => self.timestamps = false,
+        }
+    }
+
+    fn update_timestamp_recent(&mut self, now: Duration, tsval: u32) {
+        self.timestamp_recent = tsval;
+        self.timestamp_recent_age = now;
+    }
+
+    /*
+     * RFC 7323 5.3 R1 (PAWS): TSval が TS.Recent より古ければ、前の周回の古いセグメント。
+     * 24 日以上 TS.Recent が更新されていなければ、相手の時計が回り込んでいるかもしれないので比べない。
+     */
+    fn is_timestamp_stale(&self, now: Duration, tsval: u32) -> bool {
+        now.saturating_sub(self.timestamp_recent_age) <= PAWS_IDLE_LIMIT
+            && sequence_lt(tsval, self.timestamp_recent)
+    }
+
+    fn options_length(&self) -> u16 {
+        if self.timestamps {
+            TIMESTAMPS_OPTION_LENGTH
+        } else {
+            0
+        }
+    }
+
+    /*
+     * 1 セグメントに入れられるデータの大きさ。相手の MSS と経路の MTU の小さい方に収める。
+     */
+    fn segment_size(&self) -> usize {
+        let mss = self.send_mss.min(self.path_mtu.get_mtu() - HEADERS_LENGTH);
+        usize::from(mss.saturating_sub(self.options_length()).max(1))
+    }
+
+    /*
+     * ICMP Fragmentation Needed を受け取った。`sequence`は大きすぎたセグメントのシーケンス番号。
+     */
+    pub(crate) fn on_packet_too_big(
+        &mut self,
+        sequence: u32,
+        next_hop_mtu: u16,
+        datagram_length: u16,
+    ) {
+        /*
+         * RFC 5927: 送ってまだ ACK されていないものでなければ、偽物かもしれないので無視する。
+         */
+        if !(sequence_le(self.send_unacknowledged, sequence)
+            && sequence_lt(sequence, self.send_maximum))
+        {
+            return;
+        }
+
+        /*
+         * RFC 1191 6.4: 捨てられたセグメントは、小さくしてすぐに送り直す。RTO は変えない。
+         */
+        if self
+            .path_mtu
+            .on_packet_too_big(next_hop_mtu, datagram_length)
+            && self.state.is_synchronized()
+        {
+            self.send_next = self.send_unacknowledged;
+            self.retransmission_held = false;
+        }
+    }
+
+    fn update_send_window(&mut self, tcp_header: &TcpHeader) {
+        let shift = if tcp_header.get_control_bits().get_syn() {
+            0
+        } else {
+            self.send_window_shift
+        };
+        self.send_window = u32::from(tcp_header.get_window()) << shift;
+        self.maximum_send_window = self.maximum_send_window.max(self.send_window);
+        self.send_window_update_sequence = tcp_header.get_sequence_number();
+        self.send_window_update_acknowledgment = tcp_header.get_acknowledgment_number();
+    }
+
+    /*
+     * SYN がまだ ACK されていなければ 1. send_buffer の位置の計算に使う。
+     */
+    fn unacknowledged_syn(&self) -> u32 {
+        u32::from(self.send_unacknowledged == self.initial_send_sequence)
+    }
+
+    /*
+     * 送信済みで ACK されていないデータのバイト数（SYN, FIN は含まない）。
+     */
+    fn bytes_in_flight(&self) -> usize {
+        let sequence_space = self.send_next.wrapping_sub(self.send_unacknowledged);
+        let offset = sequence_space.saturating_sub(self.unacknowledged_syn()) as usize;
+        offset.min(self.send_buffer.len())
+    }
+
+    fn is_fin_sent(&self) -> bool {
+        self.fin_sequence
+            .is_some_and(|fin_sequence| sequence_lt(fin_sequence, self.send_next))
+    }
+
+    fn is_fin_acknowledged(&self) -> bool {
+        self.fin_sequence
+            .is_some_and(|fin_sequence| sequence_lt(fin_sequence, self.send_unacknowledged))
+    }
+
+    /*
+     * 広告するウィンドウ。スケールした後の値で、シフトで割り切れない分は切り捨てる。
+     *
+     * RFC 9293 3.8.6.2.2 (受信側の SWS 回避): 空きが前に広告した右端から
+     * min(受信バッファの半分, MSS) 以上増えるまで、右端を動かさない。
+     */
+    fn receive_window(&self) -> u32 {
+        let maximum = u32::from(u16::MAX) << self.receive_window_shift;
+        let free = self
+            .receive_buffer_size
+            .saturating_sub(self.receive_buffer.len())
+            .min(maximum as usize) as u32;
+        let free = free >> self.receive_window_shift << self.receive_window_shift;
+
+        let advertised = self.advertised_window();
+        if free >= advertised.saturating_add(self.window_update_threshold()) {
+            free
+        } else {
+            advertised.min(free)
+        }
+    }
+
+    /*
+     * 前に広告したウィンドウのうち、まだ残っている分。
+     */
+    fn advertised_window(&self) -> u32 {
+        if sequence_lt(self.receive_next, self.receive_window_edge) {
+            self.receive_window_edge.wrapping_sub(self.receive_next)
+        } else {
+            0
+        }
+    }
+
+    fn window_update_threshold(&self) -> u32 {
+        (self.receive_buffer_size / 2).min(usize::from(self.send_mss)) as u32
+    }
+
+    pub(crate) fn send(&mut self, data: &[u8]) -> Result<usize, SocketError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        if self.fin_sequence.is_some() {
+            return Err(SocketError::BrokenPipe);
+        }
+        match self.state {
+            TcpState::Established | TcpState::CloseWait => {}
+            TcpState::SynSent | TcpState::SynReceived => return Err(SocketError::WouldBlock),
+            _ => return Err(SocketError::NotConnected),
+        }
+
+        let space = self.send_buffer_size.saturating_sub(self.send_buffer.len());
+        if space == 0 {
+            return Err(SocketError::WouldBlock);
+        }
+        let length = space.min(data.len());
+        self.send_buffer.extend(&data[..length]);
+        Ok(length)
+    }
+
+    /*
+     * 相手が FIN を送ってきて、読むデータがもうなければ`Ok(0)`.
+     */
+    pub(crate) fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, SocketError> {
+        if !self.receive_buffer.is_empty() {
+            let length = buffer.len().min(self.receive_buffer.len());
+            for (dst, src) in buffer.iter_mut().zip(self.receive_buffer.drain(..length)) {
+                *dst = src;
+            }
+
+            /*
+             * 広告していたウィンドウが小さく、相手が待っているかもしれなければ、開いたことを知らせる。
+             */
+            let advertised = self.advertised_window();
+            if advertised < self.window_update_threshold()
+                && self.receive_window() > advertised
+                && self.state.is_synchronized()
+            {
+                self.ack_pending = true;
+            }
+            return Ok(length);
+        }
+        if self.fin_received {
+            return Ok(0);
+        }
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        match self.state {
+            TcpState::Closed => Err(SocketError::NotConnected),
+            _ => Err(SocketError::WouldBlock),
+        }
+    }
+
+    pub(crate) fn has_unread_data(&self) -> bool {
+        !self.receive_buffer.is_empty()
+    }
+
+    /*
+     * 送信側を閉じる。データを送り終えたら FIN を送る。
+     */
+    pub(crate) fn shutdown(&mut self) -> Result<(), SocketError> {
+        match self.state {
+            TcpState::SynReceived | TcpState::Established | TcpState::CloseWait => {
+                if self.fin_sequence.is_none() {
+                    self.fin_sequence = Some(
+                        self.send_unacknowledged
+                            .wrapping_add(self.unacknowledged_syn())
+                            .wrapping_add(self.send_buffer.len() as u32),
+                    );
+                }
+                Ok(())
+            }
+            TcpState::SynSent | TcpState::Closed => {
+                self.state = TcpState::Closed;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /*
+     * RST を送って即座に閉じる。
+     */
+    pub(crate) fn abort(&mut self) {
+        if matches!(
+            self.state,
+            TcpState::SynReceived
+                | TcpState::Established
+                | TcpState::FinWait1
+                | TcpState::FinWait2
+                | TcpState::CloseWait
+        ) {
+            self.reset_pending = true;
+        }
+        self.state = TcpState::Closed;
+    }
+
+    /*
+     * RFC 9293 3.10.7.3 (SYN-SENT) と 3.10.7.4 (その他の状態) のセグメント到着時の処理。
+     */
+    pub(crate) fn on_segment(&mut self, now: Duration, packet: &TcpPacket) {
+        match self.state {
+            TcpState::Closed | TcpState::Listen => {}
+            TcpState::SynSent => self.on_segment_in_syn_sent(now, packet),
+            _ => self.on_segment_in_synchronized(now, packet),
+        }
+    }
+
+    fn on_segment_in_syn_sent(&mut self, now: Duration, packet: &TcpPacket) {
+        let tcp_header = packet.get_tcp_header();
+        let control_bits = tcp_header.get_control_bits();
+        let acknowledgment = tcp_header.get_acknowledgment_number();
+
+        if control_bits.get_ack()
+            && (sequence_le(acknowledgment, self.initial_send_sequence)
+                || sequence_lt(self.send_maximum, acknowledgment))
+        {
+            /*
+             * 前のコネクションの残りなど。相手が half-open なら RST で終わらせる。RST には RST を返さない。
+             */
+            if !control_bits.get_rst() {
+                self.reset_reply = Some(acknowledgment);
+            }
+            return;
+        }
+
+        if control_bits.get_rst() {
+            if control_bits.get_ack() {
+                self.error = Some(SocketError::ConnectionRefused);
+                self.state = TcpState::Closed;
+            }
+            return;
+        }
+
+        if !control_bits.get_syn() {
+            return;
+        }
+
+        self.receive_syn(now, packet);
+        self.ack_pending = true;
+        if control_bits.get_ack() {
+            self.send_unacknowledged = acknowledgment;
+            self.waiting_since = None;
+            self.sample_rtt(now, tcp_header);
+            self.retransmission
+                .on_acknowledgment(now, acknowledgment, false);
+            self.state = TcpState::Established;
+        } else {
+            /*
+             * 同時オープン。SYN を送り直すために send_next を戻す。
+             */
+            self.send_next = self.initial_send_sequence;
+            self.state = TcpState::SynReceived;
+        }
+    }
+
+    /*
+     * RFC 9293 3.10.7.4 の "first check sequence number".
+     */
+    fn is_acceptable(&self, tcp_header: &TcpHeader, length: u32) -> bool {
+        let sequence = tcp_header.get_sequence_number();
+        let window = self.receive_window();
+        let in_window = |sequence: u32| {
+            sequence_le(self.receive_next, sequence)
+                && sequence_lt(sequence, self.receive_next.wrapping_add(window))
+        };
+
+        match (length, window) {
+            (0, 0) => sequence == self.receive_next,
+            (0, _) => in_window(sequence),
+            (_, 0) => false,
+            (_, _) => in_window(sequence) || in_window(sequence.wrapping_add(length - 1)),
+        }
+    }
+
+    fn on_segment_in_synchronized(&mut self, now: Duration, packet: &TcpPacket) {
+        let tcp_header = packet.get_tcp_header();
+        let control_bits = tcp_header.get_control_bits();
+        let segment_length = packet.get_payload().len() as u32
+            + u32::from(control_bits.get_syn())
+            + u32::from(control_bits.get_fin());
+
+        let timestamp = timestamps_of(tcp_header);
+        if self.timestamps && !control_bits.get_rst() {
+            /*
+             * RFC 7323 3.2: タイムスタンプを使っているのに付いていなければ、黙って捨てる。
+             */
+            let Some((tsval, _)) = timestamp else {
+                return;
+            };
+            if self.is_timestamp_stale(now, tsval) {
+                self.ack_pending = true;
+                return;
+            }
+        }
+
+        /*
+         * RFC 5961 4.2: 同期した状態の SYN は、シーケンス番号に関わらず challenge ACK を返して捨てる。
+         * 相手が本当に再起動したのなら、challenge ACK への RST でこちらも閉じる。
+         */
+        if control_bits.get_syn() && !control_bits.get_rst() && self.state.is_synchronized() {
+            self.challenge_ack_pending = true;
+            return;
+        }
+
+        if !self.is_acceptable(tcp_header, segment_length) {
+            if !control_bits.get_rst() {
+                self.ack_pending = true;
+
+                /*
+                 * TIME-WAIT に届くのは、相手が再送した FIN だけのはず。ACK し直して 2MSL のタイマーをかけ直す。
+                 */
+                if self.state == TcpState::TimeWait && control_bits.get_fin() {
+                    self.time_wait = Some(now + self.time_wait_timeout);
+                }
+            }
+            return;
+        }
+
+        /*
+         * RFC 5961 3.2: RCV.NXT ちょうどの RST だけで閉じる。ウィンドウ内でもずれていれば、
+         * challenge ACK を返す。相手が本当に RST を送ったのなら、正しいシーケンス番号で送り直してくる。
+         */
+        if control_bits.get_rst() {
+            if tcp_header.get_sequence_number() == self.receive_next {
+                self.on_reset();
+            } else {
+                self.challenge_ack_pending = true;
+            }
+            return;
+        }
+
+        self.last_segment_received = now;
+        self.keep_alive_probes = 0;
+        if let Some(timeout) = user_timeout_of(tcp_header) {
+            self.remote_user_timeout = Some(timeout);
+        }
+
+        /*
+         * RFC 7323 5.3 R3: 最後に送った ACK までを含むセグメントの TSval を覚えておき、次の ACK で返す。
+         */
+        if let (true, Some((tsval, _))) = (self.timestamps, timestamp) {
+            if sequence_le(
+                tcp_header.get_sequence_number(),
+                self.last_acknowledgment_sent,
+            ) {
+                self.update_timestamp_recent(now, tsval);
+            }
+        }
+
+        /*
+         * SYN-RECEIVED でウィンドウ内の SYN はエラー。RFC 793 に従ってコネクションをリセットする。
+         */
+        if control_bits.get_syn() {
+            self.error = Some(SocketError::ConnectionReset);
+            self.abort();
+            return;
+        }
+
+        if !control_bits.get_ack() {
+            return;
+        }
+        if !self.on_acknowledgment(now, tcp_header) {
+            return;
+        }
+
+        self.on_payload(now, packet);
+        if control_bits.get_fin() {
+            self.on_fin(now, tcp_header, packet.get_payload().len() as u32);
+        }
+    }
+
+    pub(crate) fn take_challenge_ack(&mut self) -> bool {
+        core::mem::take(&mut self.challenge_ack_pending)
+    }
+
+    pub(crate) fn send_challenge_ack(&mut self) {
+        self.ack_pending = true;
+    }
+
+    fn on_reset(&mut self) {
+        self.error = Some(match self.state {
+            TcpState::SynReceived => SocketError::ConnectionRefused,
+
+            /*
+             * RFC 1337: TIME-WAIT を RST で終わらせると、古いセグメントが新しいコネクションに紛れ込む
+             * (TIME-WAIT assassination)。RST は無視して 2MSL 待ち続ける。
+             */
+            TcpState::TimeWait => return,
+            TcpState::Closing | TcpState::LastAck => {
+                self.state = TcpState::Closed;
+                return;
+            }
+            _ => SocketError::ConnectionReset,
+        });
+        self.state = TcpState::Closed;
+    }
+
+    /*
+     * ACK の処理。セグメントの処理を続けるなら true.
+     */
+    fn on_acknowledgment(&mut self, now: Duration, tcp_header: &TcpHeader) -> bool {
+        let acknowledgment = tcp_header.get_acknowledgment_number();
+        self.unanswered_probes = 0;
+
+        if self.state == TcpState::SynReceived {
+            if sequence_lt(self.send_unacknowledged, acknowledgment)
+                && sequence_le(acknowledgment, self.send_maximum)
+            {
+                self.state = TcpState::Established;
+                self.update_send_window(tcp_header);
+            } else {
+                self.reset_reply = Some(acknowledgment);
+                return false;
+            }
+        }
+
+        /*
+         * RFC 5961 5.2: まだ送っていないものへの ACK や、SND.UNA - MAX.SND.WND より古い ACK は、
+         * 盲目的に注入されたものかもしれない。challenge ACK を返して捨てる。
+         */
+        if sequence_lt(self.send_maximum, acknowledgment)
+            || sequence_lt(
+                acknowledgment,
+                self.send_unacknowledged
+                    .wrapping_sub(self.maximum_send_window),
+            )
+        {
+            self.challenge_ack_pending = true;
+            return false;
+        }
+
+        if sequence_lt(self.send_unacknowledged, acknowledgment) {
+            let mut acknowledged = acknowledgment.wrapping_sub(self.send_unacknowledged);
+            acknowledged -= self.unacknowledged_syn().min(acknowledged);
+            let data = (acknowledged as usize).min(self.send_buffer.len());
+            self.send_buffer.drain(..data);
+            self.send_unacknowledged = acknowledgment;
+
+            /*
+             * 再送のために戻した SND.NXT より先まで届いていた。
+             */
+            if sequence_lt(self.send_next, acknowledgment) {
+                self.send_next = acknowledgment;
+            }
+            self.retransmission_held = false;
+            self.waiting_since = Some(now);
+            self.path_mtu.on_acknowledgment(acknowledgment);
+            self.sample_rtt(now, tcp_header);
+            self.retransmission.on_acknowledgment(
+                now,
+                acknowledgment,
+                self.send_maximum != acknowledgment,
+            );
+        }
+
+        if self.send_unacknowledged == self.send_maximum {
+            self.waiting_since = None;
+        }
+
+        /*
+         * 古いセグメントでウィンドウを巻き戻さないように、SND.WL1 / SND.WL2 と比べる。
+         */
+        let sequence = tcp_header.get_sequence_number();
+        if sequence_lt(self.send_window_update_sequence, sequence)
+            || (self.send_window_update_sequence == sequence
+                && sequence_le(self.send_window_update_acknowledgment, acknowledgment))
+        {
+            self.update_send_window(tcp_header);
+        }
+
+        let fin_acknowledged = self.is_fin_acknowledged();
+        match self.state {
+            TcpState::FinWait1 if fin_acknowledged => self.state = TcpState::FinWait2,
+            TcpState::Closing if fin_acknowledged => self.enter_time_wait(now),
+            TcpState::LastAck if fin_acknowledged => {
+                self.state = TcpState::Closed;
+                return false;
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /*
+     * RFC 7323 4 (RTTM): 新しいデータを ACK したセグメントの TSecr は、こちらが送った TSval なので、
+     * そこからの経過時間が RTT になる。
+     */
+    fn sample_rtt(&mut self, now: Duration, tcp_header: &TcpHeader) {
+        if !self.timestamps {
+            return;
+        }
+        if let Some((_, tsecr)) = timestamps_of(tcp_header) {
+            let elapsed = timestamp_clock(now, self.timestamp_offset).wrapping_sub(tsecr);
+            if (elapsed as i32) >= 0 {
+                self.retransmission
+                    .on_rtt_sample(Duration::from_millis(u64::from(elapsed)));
+            }
+        }
+    }
+
+    fn on_payload(&mut self, now: Duration, packet: &TcpPacket) {
+        let payload = packet.get_payload();
+        if payload.is_empty()
+            || !matches!(
+                self.state,
+                TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+            )
+        {
+            return;
+        }
+
+        /*
+         * 既に受け取った部分は捨てる。順番が飛んでいるものは、今は再送を待つ。
+         * 失われたセグメントがあるので、quick ACK モードに入って相手の再送を急がせる。
+         */
+        let sequence = packet.get_tcp_header().get_sequence_number();
+        if sequence_lt(self.receive_next, sequence) {
+            self.quick_acks = QUICK_ACKS;
+            self.ack_pending = true;
+            return;
+        }
+        let duplicate = self.receive_next.wrapping_sub(sequence) as usize;
+        let new_data = &payload[duplicate.min(payload.len())..];
+        let length = new_data.len().min(self.receive_window() as usize);
+
+        self.receive_buffer.extend(&new_data[..length]);
+        self.receive_next = self.receive_next.wrapping_add(length as u32);
+        if length == 0 || length < new_data.len() {
+            self.ack_pending = true;
+        } else {
+            self.acknowledge_later(now);
+        }
+    }
+
+    /*
+     * RFC 5681 4.2: 2 セグメントごとに ACK し、1 つだけなら`delayed_ack_timeout`まで待つ。
+     * 待っている間にこちらからデータを送れば、ACK はそれに載る。
+     */
+    fn acknowledge_later(&mut self, now: Duration) {
+        /*
+         * しばらく受信がなければ、相手はスロースタートからやり直す (RFC 5681 4.1)。
+         */
+        if now.saturating_sub(self.last_received) > self.retransmission.get_timeout() {
+            self.quick_acks = QUICK_ACKS;
+        }
+        self.last_received = now;
+        self.unacknowledged_segments += 1;
+
+        if self.quick_ack
+            || self.quick_acks > 0
+            || self.unacknowledged_segments >= 2
+            || self.delayed_ack_timeout.is_zero()
+        {
+            self.quick_acks = self.quick_acks.saturating_sub(1);
+            self.ack_pending = true;
+        } else if self.delayed_ack.is_none() {
+            self.delayed_ack = Some(now + self.delayed_ack_timeout);
+        }
+    }
+
+    fn on_fin(&mut self, now: Duration, tcp_header: &TcpHeader, payload_length: u32) {
+        let fin_sequence = tcp_header
+            .get_sequence_number()
+            .wrapping_add(payload_length);
+        if fin_sequence != self.receive_next {
+            return;
+        }
+
+        self.receive_next = self.receive_next.wrapping_add(1);
+        self.fin_received = true;
+        self.ack_pending = true;
+        match self.state {
+            TcpState::SynReceived | TcpState::Established => self.state = TcpState::CloseWait,
+            TcpState::FinWait1 => self.state = TcpState::Closing,
+            TcpState::FinWait2 => self.enter_time_wait(now),
+            _ => {}
+        }
+    }
+
+    fn enter_time_wait(&mut self, now: Duration) {
+        self.state = TcpState::TimeWait;
+        self.time_wait = Some(now + self.time_wait_timeout);
+    }
+
+    /*
+     * TIME-WAIT を待たずに閉じる。
+     */
+    pub(crate) fn close_time_wait(&mut self) {
+        if self.state == TcpState::TimeWait {
+            self.state = TcpState::Closed;
+            self.time_wait = None;
+        }
+    }
+
+    /*
+     * TIME-WAIT に同じ 4 タプルの SYN が来た。Linux と同じく、シーケンス番号が RCV.NXT より先か、
+     * TSval が TS.Recent より新しければ、前のコネクションの古いセグメントとは区別できるので、
+     * 新しいコネクションを受け付けてよい。その ISS（前の SND.NXT より十分先）を返す。
+     */
+    pub(crate) fn get_recycled_sequence(&self, now: Duration, syn: &TcpHeader) -> Option<u32> {
+        if self.state != TcpState::TimeWait {
+            return None;
+        }
+        let timestamp = timestamps_of(syn).filter(|_| self.timestamps);
+        if timestamp.is_some_and(|(tsval, _)| self.is_timestamp_stale(now, tsval)) {
+            return None;
+        }
+        let newer = sequence_lt(self.receive_next, syn.get_sequence_number())
+            || timestamp.is_some_and(|(tsval, _)| sequence_lt(self.timestamp_recent, tsval));
+        newer.then(|| self.send_next.wrapping_add(u32::from(u16::MAX) + 2))
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Duration) {
+        if self.state == TcpState::Closed {
+            return;
+        }
+        if self.time_wait.is_some_and(|deadline| deadline <= now) {
+            self.time_wait = None;
+            self.state = TcpState::Closed;
+            return;
+        }
+        if self
+            .user_timeout_deadline()
+            .is_some_and(|deadline| deadline <= now)
+        {
+            self.error = Some(SocketError::TimedOut);
+            self.state = TcpState::Closed;
+            return;
+        }
+        if self.delayed_ack.is_some_and(|deadline| deadline <= now) {
+            self.delayed_ack = None;
+            self.ack_pending = true;
+        }
+        if self
+            .corked_since
+            .is_some_and(|since| since + CORK_TIMEOUT <= now)
+        {
+            self.corked_since = None;
+            self.cork_expired = true;
+        }
+        if self.persist.is_some_and(|deadline| deadline <= now) {
+            self.on_persist_timeout();
+        }
+        if self
+            .keep_alive_deadline()
+            .is_some_and(|deadline| deadline <= now)
+        {
+            self.on_keep_alive_timeout();
+        }
+        if self
+            .retransmission
+            .get_deadline()
+            .is_some_and(|deadline| deadline <= now)
+        {
+            self.on_retransmission_timeout(now);
+        }
+    }
+
+    /*
+     * RFC 1122 4.2.2.17: 相手が probe に ACK を返し続ける限り、ウィンドウが 0 のままでも閉じない。
+     * 全く返ってこなければ、再送と同じ回数（User Timeout があればその時間）で諦める。
+     */
+    fn on_persist_timeout(&mut self) {
+        self.persist = None;
+        if self.get_user_timeout().is_none()
+            && self.unanswered_probes >= self.maximum_retransmissions
+        {
+            self.error = Some(SocketError::TimedOut);
+            self.state = TcpState::Closed;
+            return;
+        }
+        self.unanswered_probes += 1;
+        self.persist_backoff += 1;
+        self.probe_pending = true;
+    }
+
+    /*
+     * 応答のないまま`keep_alive_count`回 probe を送ったら、Linux と同じく RST を送って閉じる。
+     */
+    fn on_keep_alive_timeout(&mut self) {
+        if self.get_user_timeout().is_none() && self.keep_alive_probes >= self.keep_alive_count {
+            self.error = Some(SocketError::TimedOut);
+            self.abort();
+            return;
+        }
+        self.keep_alive_probes += 1;
+        self.probe_pending = true;
+    }
+
+    /*
+     * RFC 9293 3.8.6.1: 送るデータがあるのに相手のウィンドウが 0 で、ACK を待っているものもなければ、
+     * ウィンドウの更新が失われても止まらないように persist タイマーをかける。
+     * 最初の probe は RTO の後に送り、その後は間隔を倍にしていく。
+     */
+    fn update_persist_timer(&mut self, now: Duration) {
+        let stalled = self.send_window == 0
+            && self.send_unacknowledged == self.send_maximum
+            && !self.send_buffer.is_empty()
+            && matches!(self.state, TcpState::Established | TcpState::CloseWait);
+        if !stalled {
+            self.persist = None;
+            self.persist_backoff = 0;
+        } else if self.persist.is_none() {
+            self.persist = Some(
+                now + self
+                    .retransmission
+                    .get_backed_off_timeout(self.persist_backoff),
+            );
+        }
+    }
+
+    /*
+     * RFC 6298 5.4 - 5.6: 最初の未 ACK のセグメントを送り直し、RTO を倍にする。
+     * 再送の回数が上限を超えたら諦めて閉じる。
+     */
+    fn on_retransmission_timeout(&mut self, now: Duration) {
+        if self.send_maximum == self.send_unacknowledged {
+            self.retransmission.stop();
+            return;
+        }
+
+        /*
+         * RFC 4821: probe が届かなかっただけなら、輻輳ではないので RTO は倍にしない。
+         */
+        if self.path_mtu.on_timeout() {
+            self.retransmission.restart(now);
+            self.send_next = self.send_unacknowledged;
+            self.retransmission_held = false;
+            return;
+        }
+
+        let limit = match self.state {
+            TcpState::SynSent | TcpState::SynReceived => self.maximum_syn_retransmissions,
+            _ => self.maximum_retransmissions,
+        };
+        if self.get_user_timeout().is_none() && self.retransmission.get_retransmissions() >= limit {
+            self.error = Some(SocketError::TimedOut);
+            self.state = TcpState::Closed;
+            self.retransmission.stop();
+            return;
+        }
+
+        self.retransmission.on_timeout(now);
+        self.send_next = self.send_unacknowledged;
+        self.retransmission_held = false;
+        if self.state.is_synchronized()
+            && self.retransmission.get_retransmissions() >= BLACK_HOLE_RETRANSMISSIONS
+        {
+            self.path_mtu.on_black_hole();
+        }
+    }
+
+    /*
+     * 送るべきセグメントがあれば 1 つ作る。
+     */
+    pub(crate) fn poll_segment(&mut self, now: Duration) -> Option<TcpPacket> {
+        if self.reset_pending {
+            self.reset_pending = false;
+            let mut control_bits = ControlBits::default();
+            control_bits.set_rst(true);
+            control_bits.set_ack(true);
+            return Some(self.segment(now, self.send_next, control_bits, &[], Vec::new()));
+        }
+        if let Some(sequence) = self.reset_reply.take() {
+            let mut control_bits = ControlBits::default();
+            control_bits.set_rst(true);
+            return Some(self.segment(now, sequence, control_bits, &[], Vec::new()));
+        }
+
+        match self.state {
+            TcpState::SynSent | TcpState::SynReceived
+                if self.send_next == self.initial_send_sequence =>
+            {
+                let segment = self.syn_segment(now);
+                self.on_sent(now, self.initial_send_sequence);
+                return Some(segment);
+            }
+            TcpState::Closed | TcpState::Listen | TcpState::SynSent => return None,
+            _ => {}
+        }
+
+        let sequence = self.send_next;
+        if let Some(segment) = self.data_segment(now) {
+            self.on_sent(now, sequence);
+            return Some(segment);
+        }
+        self.update_persist_timer(now);
+
+        /*
+         * ウィンドウと keepalive の probe. Linux と同じく、SND.UNA - 1 の空のセグメントで、相手に ACK を返させる。
+         */
+        if self.probe_pending {
+            self.probe_pending = false;
+            self.waiting_since.get_or_insert(now);
+            let mut control_bits = ControlBits::default();
+            control_bits.set_ack(true);
+            let sequence = self.send_unacknowledged.wrapping_sub(1);
+            return Some(self.segment(now, sequence, control_bits, &[], Vec::new()));
+        }
+
+        if self.ack_pending {
+            let mut control_bits = ControlBits::default();
+            control_bits.set_ack(true);
+            return Some(self.segment(now, self.send_next, control_bits, &[], Vec::new()));
+        }
+        None
+    }
+
+    /*
+     * `sequence`から SND.NXT までを送った。再送タイマーをかけ、SND.MAX を進める。
+     */
+    fn on_sent(&mut self, now: Duration, sequence: u32) {
+        if sequence == self.send_next {
+            return;
+        }
+        let retransmission = sequence_lt(sequence, self.send_maximum);
+        if retransmission {
+            self.retransmission_held = true;
+        }
+        self.waiting_since.get_or_insert(now);
+        if sequence_lt(self.send_maximum, self.send_next) {
+            self.send_maximum = self.send_next;
+        }
+        self.retransmission
+            .on_send(now, self.send_next, retransmission);
+    }
+
+    fn syn_segment(&mut self, now: Duration) -> TcpPacket {
+        let mut control_bits = ControlBits::default();
+        control_bits.set_syn(true);
+        control_bits.set_ack(self.state == TcpState::SynReceived);
+
+        let mut options = Vec::from([TcpOption::MaximumSegmentSize(self.advertised_mss)]);
+        if self.timestamps {
+            options.extend(self.timestamps_option(now, control_bits));
+        }
+        if let Some(shift) = self.offered_window_shift {
+            options.extend([TcpOption::NoOperation, TcpOption::WindowScale(shift)]);
+        }
+        self.user_timeout_pending = !self.advertised_user_timeout.is_zero();
+        self.send_next = self.initial_send_sequence.wrapping_add(1);
+        self.segment(now, self.initial_send_sequence, control_bits, &[], options)
+    }
+
+    /*
+     * ACK を送る時は TS.Recent を返す。能動オープンの SYN では 0.
+     */
+    fn timestamps_option(&self, now: Duration, control_bits: ControlBits) -> [TcpOption; 3] {
+        let tsecr = if control_bits.get_ack() {
+            self.timestamp_recent
+        } else {
+            0
+        };
+        [
+            TcpOption::NoOperation,
+            TcpOption::NoOperation,
+            TcpOption::Timestamps {
+                tsval: timestamp_clock(now, self.timestamp_offset),
+                tsecr,
+            },
+        ]
+    }
+
+    /*
+     * ウィンドウの範囲でまだ送っていないデータを、MSS ずつ送る。送り終えていれば FIN も。
+     */
+    fn data_segment(&mut self, now: Duration) -> Option<TcpPacket> {
+        if !matches!(
+            self.state,
+            TcpState::Established
+                | TcpState::CloseWait
+                | TcpState::FinWait1
+                | TcpState::Closing
+                | TcpState::LastAck
+        ) || self.is_fin_sent()
+        {
+            return None;
+        }
+        if self.retransmission_held && sequence_lt(self.send_next, self.send_maximum) {
+            return None;
+        }
+
+        let sent = self.bytes_in_flight();
+        let unsent = self.send_buffer.len() - sent;
+        let usable_window = self
+            .send_window
+            .saturating_sub(self.send_next.wrapping_sub(self.send_unacknowledged))
+            as usize;
+        let mut length = unsent.min(usable_window).min(self.segment_size());
+
+        /*
+         * RFC 4821: 送るデータが十分あれば、大きいセグメントを 1 つ送って経路の MTU を探る。
+         */
+        if self.state == TcpState::Established && self.send_next == self.send_maximum {
+            let maximum = self.send_mss.saturating_add(HEADERS_LENGTH);
+            if let Some(size) = self.path_mtu.get_probe_size(now, maximum) {
+                let probe_length = usize::from(size - HEADERS_LENGTH - self.options_length());
+                if probe_length <= unsent.min(usable_window) {
+                    length = probe_length;
+                    self.path_mtu
+                        .on_probe_sent(self.send_next.wrapping_add(length as u32), size);
+                }
+            }
+        }
+        let all_sent = length == unsent;
+        let fin = self.fin_sequence.is_some() && all_sent;
+        if length == 0 && !fin {
+            return None;
+        }
+
+        /*
+         * 送るデータが MSS に満たなければ、cork 中や、前の小さいセグメントが ACK されていない間は待つ。
+         * 再送と FIN は待たせない。
+         */
+        let small = length < self.segment_size();
+        if small && all_sent && !fin && !sequence_lt(self.send_next, self.send_maximum) {
+            if self.cork && !self.cork_expired {
+                self.corked_since.get_or_insert(now);
+                return None;
+            }
+            if !self.nodelay
+                && !self.cork
+                && sequence_lt(self.send_unacknowledged, self.small_segment_end)
+                && sequence_le(self.small_segment_end, self.send_next)
+            {
+                return None;
+            }
+        }
+
+        let payload: Vec<u8> = self
+            .send_buffer
+            .range(sent..sent + length)
+            .copied()
+            .collect();
+        let mut control_bits = ControlBits::default();
+        control_bits.set_ack(true);
+        control_bits.set_psh(length > 0 && all_sent);
+        control_bits.set_fin(fin);
+
+        let sequence = self.send_next;
+        self.send_next = self
+            .send_next
+            .wrapping_add(length as u32)
+            .wrapping_add(u32::from(fin));
+        if small {
+            self.small_segment_end = self.send_next;
+            self.corked_since = None;
+            self.cork_expired = false;
+        }
+        if fin {
+            self.state = match self.state {
+                TcpState::Established => TcpState::FinWait1,
+                TcpState::CloseWait => TcpState::LastAck,
+                state => state,
+            };
+        }
+        Some(self.segment(now, sequence, control_bits, &payload, Vec::new()))
+    }
+
+    /*
+     * タイムスタンプを使っていれば、SYN と RST 以外には Timestamps を付ける。
+     * User Timeout オプションは、MSS を超えないようにデータのないセグメントにだけ付ける。
+     */
+    fn segment(
+        &mut self,
+        now: Duration,
+        sequence: u32,
+        control_bits: ControlBits,
+        payload: &[u8],
+        mut options: Vec<TcpOption>,
+    ) -> TcpPacket {
+        let mut tcp_header = TcpHeader::new(self.local.port, self.remote.port);
+        tcp_header.set_sequence_number(sequence);
+        if control_bits.get_ack() {
+            tcp_header.set_acknowledgment_number(self.receive_next);
+            self.last_acknowledgment_sent = self.receive_next;
+            self.ack_pending = false;
+            self.delayed_ack = None;
+            self.unacknowledged_segments = 0;
+        }
+        if self.timestamps && !control_bits.get_syn() && !control_bits.get_rst() {
+            options.extend(self.timestamps_option(now, control_bits));
+        }
+        if self.user_timeout_pending && payload.is_empty() && !control_bits.get_rst() {
+            self.user_timeout_pending = false;
+            options.push(user_timeout_option(self.advertised_user_timeout));
+        }
+        tcp_header.set_control_bits(control_bits);
+
+        let window = self.receive_window();
+        let shift = if control_bits.get_syn() {
+            0
+        } else {
+            self.receive_window_shift
+        };
+        let field = (window >> shift).min(u32::from(u16::MAX));
+        tcp_header.set_window(field as u16);
+        self.receive_window_edge = self.receive_next.wrapping_add(field << shift);
+        tcp_header.set_options(&options).unwrap();
+
+        TcpPacket::new(
+            segment_ip_header(self.local.address, self.remote.address),
+            tcp_header,
+            payload.to_vec(),
+        )
+    }
+}
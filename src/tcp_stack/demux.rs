@@ -0,0 +1,363 @@
+use crate::internet_protocol::Ipv4Address;
+use crate::tcp_stack::{segment_ip_header, Endpoint, SocketHandle, UNSPECIFIED_ADDRESS};
+use crate::transmission_control_protocol::tcp_packet::TcpPacket;
+use crate::transmission_control_protocol::{ControlBits, TcpHeader};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::hash::{BuildHasher, Hasher};
+use hashbrown::HashMap;
+use siphasher::sip::SipHasher13;
+use siphasher::sip128::{self, Hasher128};
+
+/*
+ * 鍵付きの SipHash-1-3.
+ *
+ * 4 タプルは送信元が自由に選べるので、鍵のないハッシュだと同じバケットに集まるパケットを
+ * 作られて、探索が線形になってしまう。
+ */
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct KeyedHasher {
+    key: [u8; 16],
+}
+
+impl KeyedHasher {
+    pub(crate) fn new(key: [u8; 16]) -> Self {
+        Self { key }
+    }
+
+    /*
+     * `label`の用途に使う副鍵を作る。ある用途の出力から鍵を推測されても、他の用途の値は
+     * 予測できないように、用途ごとに別の鍵を使うこと。
+     */
+    pub(crate) fn derive(&self, label: &str) -> Self {
+        let mut hasher = sip128::SipHasher13::new_with_key(&self.key);
+        hasher.write(label.as_bytes());
+        Self::new(u128::from(hasher.finish128()).to_le_bytes())
+    }
+}
+
+impl BuildHasher for KeyedHasher {
+    type Hasher = SipHasher13;
+
+    fn build_hasher(&self) -> SipHasher13 {
+        SipHasher13::new_with_key(&self.key)
+    }
+}
+
+/*
+ * 受信したセグメントの行き先。
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum Destination {
+    Connection(SocketHandle),
+    Listener(SocketHandle),
+    None,
+}
+
+/*
+ * (ローカルアドレス, ローカルポート, リモートアドレス, リモートポート) からコネクションを、
+ * 見つからなければ (ローカルアドレス, ローカルポート) から listen ソケットを引く表。
+ *
+ * listen ソケットは、アドレスが一致するものを INADDR_ANY のものより優先する。
+ */
+pub(crate) struct ConnectionTable {
+    hasher: KeyedHasher,
+    connections: HashMap<(Endpoint, Endpoint), SocketHandle, KeyedHasher>,
+    listeners: HashMap<Endpoint, ListenerGroup, KeyedHasher>,
+}
+
+/*
+ * 同じアドレスとポートで listen しているソケット。SO_REUSEPORT の時だけ複数になる。
+ */
+struct ListenerGroup {
+    handles: Vec<SocketHandle>,
+    reuse_port: bool,
+}
+
+impl ConnectionTable {
+    pub(crate) fn new(hasher: KeyedHasher) -> Self {
+        Self {
+            hasher,
+            connections: HashMap::with_hasher(hasher),
+            listeners: HashMap::with_hasher(hasher),
+        }
+    }
+
+    /*
+     * 既に同じ 4 タプルのコネクションがあれば false.
+     */
+    pub(crate) fn insert_connection(
+        &mut self,
+        local: Endpoint,
+        remote: Endpoint,
+        handle: SocketHandle,
+    ) -> bool {
+        match self.connections.entry((local, remote)) {
+            hashbrown::hash_map::Entry::Occupied(_) => false,
+            hashbrown::hash_map::Entry::Vacant(entry) => {
+                entry.insert(handle);
+                true
+            }
+        }
+    }
+
+    /*
+     * `handle`が登録しているものだけを消す。同じ 4 タプルで作り直されたコネクションは残す。
+     */
+    pub(crate) fn remove_connection(
+        &mut self,
+        local: Endpoint,
+        remote: Endpoint,
+        handle: SocketHandle,
+    ) {
+        if self.connections.get(&(local, remote)) == Some(&handle) {
+            self.connections.remove(&(local, remote));
+        }
+    }
+
+    pub(crate) fn get_connection(&self, local: Endpoint, remote: Endpoint) -> Option<SocketHandle> {
+        self.connections.get(&(local, remote)).copied()
+    }
+
+    /*
+     * 既に listen しているソケットがあれば、どちらも SO_REUSEPORT の時だけ追加できる。
+     */
+    pub(crate) fn insert_listener(
+        &mut self,
+        local: Endpoint,
+        handle: SocketHandle,
+        reuse_port: bool,
+    ) -> bool {
+        match self.listeners.entry(local) {
+            hashbrown::hash_map::Entry::Occupied(mut entry) => {
+                let group = entry.get_mut();
+                if !(reuse_port && group.reuse_port) {
+                    return false;
+                }
+                group.handles.push(handle);
+                true
+            }
+            hashbrown::hash_map::Entry::Vacant(entry) => {
+                entry.insert(ListenerGroup {
+                    handles: vec![handle],
+                    reuse_port,
+                });
+                true
+            }
+        }
+    }
+
+    pub(crate) fn remove_listener(&mut self, local: Endpoint, handle: SocketHandle) {
+        if let Some(group) = self.listeners.get_mut(&local) {
+            group.handles.retain(|listener| *listener != handle);
+            if group.handles.is_empty() {
+                self.listeners.remove(&local);
+            }
+        }
+    }
+
+    /*
+     * SO_REUSEPORT で複数あれば、同じ相手がいつも同じソケットに行くように、相手のハッシュで選ぶ。
+     */
+    pub(crate) fn get_listener(&self, local: Endpoint, remote: Endpoint) -> Option<SocketHandle> {
+        let group = self.listeners.get(&local).or_else(|| {
+            self.listeners
+                .get(&Endpoint::new(UNSPECIFIED_ADDRESS, local.port))
+        })?;
+        let index = self.hasher.hash_one(remote) % group.handles.len() as u64;
+        Some(group.handles[index as usize])
+    }
+
+    pub(crate) fn lookup(&self, local: Endpoint, remote: Endpoint) -> Destination {
+        if let Some(handle) = self.get_connection(local, remote) {
+            return Destination::Connection(handle);
+        }
+        match self.get_listener(local, remote) {
+            Some(handle) => Destination::Listener(handle),
+            None => Destination::None,
+        }
+    }
+}
+
+/*
+ * どのコネクションにも当てはまらないセグメントへの RST. RFC 9293 3.10.7.1.
+ *
+ * - RST には RST を返さない。
+ * - ACK があれば <SEQ=SEG.ACK><CTL=RST>.
+ * - なければ <SEQ=0><ACK=SEG.SEQ+SEG.LEN><CTL=RST,ACK>.
+ *
+ * NOTE: ブロードキャストやマルチキャストから来たように見えるものにも返さない（RFC 1122 4.2.3.10）。
+ */
+pub(crate) fn reset_for(packet: &TcpPacket) -> Option<TcpPacket> {
+    let tcp_header = packet.get_tcp_header();
+    let control_bits = tcp_header.get_control_bits();
+    if control_bits.get_rst() || !is_unicast(packet.get_source_address()) {
+        return None;
+    }
+
+    let mut reset = TcpHeader::new(
+        tcp_header.get_destination_port(),
+        tcp_header.get_source_port(),
+    );
+    let mut reset_bits = ControlBits::default();
+    reset_bits.set_rst(true);
+    if control_bits.get_ack() {
+        reset.set_sequence_number(tcp_header.get_acknowledgment_number());
+    } else {
+        let length = packet.get_payload().len() as u32
+            + u32::from(control_bits.get_syn())
+            + u32::from(control_bits.get_fin());
+        reset.set_sequence_number(0);
+        reset.set_acknowledgment_number(tcp_header.get_sequence_number().wrapping_add(length));
+        reset_bits.set_ack(true);
+    }
+    reset.set_control_bits(reset_bits);
+    reset.set_window(0);
+
+    Some(TcpPacket::new(
+        segment_ip_header(
+            packet.get_destination_address(),
+            packet.get_source_address(),
+        ),
+        reset,
+        Vec::new(),
+    ))
+}
+
+fn is_unicast(address: Ipv4Address) -> bool {
+    let [first, ..] = address;
+    address != [255, 255, 255, 255]
+        && address != UNSPECIFIED_ADDRESS
+        && !(224..=239).contains(&first)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL: Endpoint = Endpoint {
+        address: [10, 0, 0, 1],
+        port: 80,
+    };
+    const REMOTE: Endpoint = Endpoint {
+        address: [10, 0, 0, 2],
+        port: 50000,
+    };
+
+    #[test]
+    fn test_lookup_prefers_connection_then_exact_listener() {
+        let mut table = ConnectionTable::new(KeyedHasher::new([7; 16]));
+        assert_eq!(table.lookup(LOCAL, REMOTE), Destination::None);
+
+        let wildcard = Endpoint::new(UNSPECIFIED_ADDRESS, 80);
+        assert!(table.insert_listener(wildcard, SocketHandle(0), false));
+        assert!(!table.insert_listener(wildcard, SocketHandle(9), false));
+        assert_eq!(
+            table.lookup(LOCAL, REMOTE),
+            Destination::Listener(SocketHandle(0))
+        );
+
+        assert!(table.insert_listener(LOCAL, SocketHandle(1), false));
+        assert_eq!(
+            table.lookup(LOCAL, REMOTE),
+            Destination::Listener(SocketHandle(1))
+        );
+
+        assert!(table.insert_connection(LOCAL, REMOTE, SocketHandle(2)));
+        assert!(!table.insert_connection(LOCAL, REMOTE, SocketHandle(3)));
+        assert_eq!(
+            table.lookup(LOCAL, REMOTE),
+            Destination::Connection(SocketHandle(2))
+        );
+
+        /*
+         * 別のハンドルでは消えない。
+         */
+        table.remove_connection(LOCAL, REMOTE, SocketHandle(3));
+        assert_eq!(table.get_connection(LOCAL, REMOTE), Some(SocketHandle(2)));
+        table.remove_connection(LOCAL, REMOTE, SocketHandle(2));
+        table.remove_listener(LOCAL, SocketHandle(1));
+        assert_eq!(
+            table.lookup(LOCAL, REMOTE),
+            Destination::Listener(SocketHandle(0))
+        );
+    }
+
+    #[test]
+    fn test_reuse_port_listeners() {
+        let mut table = ConnectionTable::new(KeyedHasher::new([7; 16]));
+        assert!(table.insert_listener(LOCAL, SocketHandle(0), true));
+        assert!(!table.insert_listener(LOCAL, SocketHandle(1), false));
+        assert!(table.insert_listener(LOCAL, SocketHandle(2), true));
+
+        /*
+         * 同じ相手は同じソケットに。相手が変われば両方に振り分けられる。
+         */
+        let mut chosen = Vec::new();
+        for port in 50000..50064 {
+            let remote = Endpoint::new(REMOTE.address, port);
+            let handle = table.get_listener(LOCAL, remote).unwrap();
+            assert_eq!(table.get_listener(LOCAL, remote), Some(handle));
+            chosen.push(handle);
+        }
+        assert!(chosen.contains(&SocketHandle(0)));
+        assert!(chosen.contains(&SocketHandle(2)));
+
+        table.remove_listener(LOCAL, SocketHandle(0));
+        assert_eq!(table.get_listener(LOCAL, REMOTE), Some(SocketHandle(2)));
+        table.remove_listener(LOCAL, SocketHandle(2));
+        assert_eq!(table.get_listener(LOCAL, REMOTE), None);
+    }
+
+    #[test]
+    fn test_hash_depends_on_key() {
+        let a = KeyedHasher::new([1; 16]);
+        let b = KeyedHasher::new([2; 16]);
+        assert_eq!(a.hash_one((LOCAL, REMOTE)), a.hash_one((LOCAL, REMOTE)));
+        assert_ne!(a.hash_one((LOCAL, REMOTE)), b.hash_one((LOCAL, REMOTE)));
+
+        let isn = a.derive("initial sequence");
+        assert_eq!(isn.key, a.derive("initial sequence").key);
+        assert_ne!(isn.key, a.key);
+        assert_ne!(isn.key, a.derive("syn cookie").key);
+        assert_ne!(isn.key, b.derive("initial sequence").key);
+    }
+
+    fn segment(control_bits: ControlBits, payload: &[u8]) -> TcpPacket {
+        let mut tcp_header = TcpHeader::new(REMOTE.port, LOCAL.port);
+        tcp_header.set_sequence_number(1000);
+        tcp_header.set_acknowledgment_number(5000);
+        tcp_header.set_control_bits(control_bits);
+        TcpPacket::new(
+            segment_ip_header(REMOTE.address, LOCAL.address),
+            tcp_header,
+            payload.to_vec(),
+        )
+    }
+
+    #[test]
+    fn test_reset_generation() {
+        let mut syn = ControlBits::default();
+        syn.set_syn(true);
+        let reset = reset_for(&segment(syn, b"abc")).unwrap();
+        let tcp_header = reset.get_tcp_header();
+        assert_eq!(reset.get_destination_address(), REMOTE.address);
+        assert_eq!(tcp_header.get_destination_port(), REMOTE.port);
+        assert_eq!(tcp_header.get_sequence_number(), 0);
+        assert_eq!(tcp_header.get_acknowledgment_number(), 1004);
+        assert!(tcp_header.get_control_bits().get_rst());
+        assert!(tcp_header.get_control_bits().get_ack());
+
+        let mut ack = ControlBits::default();
+        ack.set_ack(true);
+        let reset = reset_for(&segment(ack, b"abc")).unwrap();
+        let tcp_header = reset.get_tcp_header();
+        assert_eq!(tcp_header.get_sequence_number(), 5000);
+        assert!(tcp_header.get_control_bits().get_rst());
+        assert!(!tcp_header.get_control_bits().get_ack());
+
+        let mut rst = ControlBits::default();
+        rst.set_rst(true);
+        assert!(reset_for(&segment(rst, &[])).is_none());
+    }
+}
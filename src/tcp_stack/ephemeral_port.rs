@@ -0,0 +1,114 @@
+use crate::internet_protocol::Ipv4Address;
+use crate::tcp_stack::demux::KeyedHasher;
+use crate::tcp_stack::Endpoint;
+use core::hash::BuildHasher;
+
+/*
+ * RFC 6056 3.3.3 の Algorithm 3 (Simple Hash-Based Port Selection Algorithm).
+ *
+ * 宛先ごとに鍵付きハッシュで決まるオフセットから、共通のカウンターで順に探す。
+ * 同じ宛先へは前回の次のポートから使うので TIME-WAIT とぶつかりにくく、
+ * 別の宛先で使われたポートからは次のポートを推測できない。
+ *
+ * See: https://www.rfc-editor.org/rfc/rfc6056.html#section-3.3.3
+ */
+pub(crate) struct EphemeralPortAllocator {
+    hasher: KeyedHasher,
+    next: u32,
+}
+
+impl EphemeralPortAllocator {
+    pub(crate) fn new(hasher: KeyedHasher) -> Self {
+        Self {
+            hasher,
+            next: hasher.hash_one("next_ephemeral") as u32,
+        }
+    }
+
+    /*
+     * `range`（両端を含む）から`is_suitable`を満たすポートを選ぶ。全て使われていれば`None`.
+     *
+     * bind(port 0) のように宛先がまだ決まっていなければ、`remote`は`None`.
+     */
+    pub(crate) fn allocate(
+        &mut self,
+        range: (u16, u16),
+        local_address: Ipv4Address,
+        remote: Option<Endpoint>,
+        mut is_suitable: impl FnMut(u16) -> bool,
+    ) -> Option<u16> {
+        let (minimum, maximum) = range;
+        let count = u64::from(maximum.checked_sub(minimum)?) + 1;
+        let offset = self.hasher.hash_one((local_address, remote));
+
+        for _ in 0..count {
+            let port = u64::from(minimum) + (u64::from(self.next) + offset % count) % count;
+            self.next = self.next.wrapping_add(1);
+            if is_suitable(port as u16) {
+                return Some(port as u16);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    const LOCAL: Ipv4Address = [10, 0, 0, 1];
+
+    fn remote(port: u16) -> Option<Endpoint> {
+        Some(Endpoint::new([10, 0, 0, 2], port))
+    }
+
+    #[test]
+    fn test_sequential_per_destination_and_keyed() {
+        let range = (1024, 65535);
+        let mut allocator = EphemeralPortAllocator::new(KeyedHasher::new([1; 16]));
+        let first = allocator
+            .allocate(range, LOCAL, remote(80), |_| true)
+            .unwrap();
+        let second = allocator
+            .allocate(range, LOCAL, remote(80), |_| true)
+            .unwrap();
+        assert_eq!(second, if first == 65535 { 1024 } else { first + 1 });
+
+        /*
+         * 別の宛先、別の鍵ではオフセットが変わる。
+         */
+        let other = allocator
+            .allocate(range, LOCAL, remote(443), |_| true)
+            .unwrap();
+        assert_ne!(other, second.wrapping_add(1));
+        let mut another = EphemeralPortAllocator::new(KeyedHasher::new([2; 16]));
+        assert_ne!(
+            another.allocate(range, LOCAL, remote(80), |_| true),
+            Some(first)
+        );
+    }
+
+    #[test]
+    fn test_skips_unsuitable_ports_and_stays_in_range() {
+        let mut allocator = EphemeralPortAllocator::new(KeyedHasher::new([3; 16]));
+        let mut used = Vec::new();
+        for _ in 0..8 {
+            let port = allocator
+                .allocate((5000, 5007), LOCAL, remote(80), |port| {
+                    !used.contains(&port)
+                })
+                .unwrap();
+            assert!((5000..=5007).contains(&port));
+            used.push(port);
+        }
+        let exhausted = allocator.allocate((5000, 5007), LOCAL, remote(80), |port| {
+            !used.contains(&port)
+        });
+        assert_eq!(exhausted, None);
+        assert_eq!(
+            allocator.allocate((6000, 5999), LOCAL, None, |_| true),
+            None
+        );
+    }
+}
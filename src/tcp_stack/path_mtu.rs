@@ -0,0 +1,269 @@
+use crate::tcp_stack::connection::sequence_le;
+use core::time::Duration;
+
+/*
+ * 経路の MTU の推定。
+ *
+ * - RFC 1191 (Path MTU Discovery): 全てのセグメントに DF を立てて送り、途中のルーターが返す
+ *   ICMP Fragmentation Needed の Next-Hop MTU まで下げる。
+ * - RFC 4821 (Packetization Layer Path MTU Discovery): ICMP が届かない経路（ブラックホール）では、
+ *   再送が続いたら MTU を下げ、大きいセグメントを 1 つずつ送って（probe）ACK が返るかで探り直す。
+ *
+ * `mtu`は今使っている大きさで、RFC 4821 の search_low（届くと分かっている最大）も兼ねる。
+ * `search_high`は届かないと分かっている大きさの手前。
+ *
+ * See: https://www.rfc-editor.org/rfc/rfc1191.html
+ * See: https://www.rfc-editor.org/rfc/rfc4821.html
+ */
+pub(crate) struct PathMtu {
+    interface_mtu: u16,
+    probing: bool,
+    mtu: u16,
+    search_high: u16,
+
+    /*
+     * 送った probe の最後のシーケンス番号の次と、その大きさ。
+     */
+    probe: Option<(u32, u16)>,
+
+    /*
+     * 探し終えた後、`search_high`を戻して探し直す時刻。
+     */
+    next_search: Option<Duration>,
+}
+
+/*
+ * 偽の ICMP で小さくされすぎないように、これより下げない（Linux の min_pmtu と同じ）。
+ */
+pub(crate) const MINIMUM_PATH_MTU: u16 = 552;
+
+/*
+ * RFC 4821 7.2: ブラックホールを見つけたら、まずこの大きさまで下げる。
+ */
+const BASE_PATH_MTU: u16 = 1024;
+
+/*
+ * `search_high`と`mtu`の差がこれより小さくなったら探すのをやめる（Linux の tcp_probe_threshold）。
+ */
+const SEARCH_THRESHOLD: u16 = 8;
+
+/*
+ * RFC 4821: 経路が変わって大きくなっているかもしれないので、時々探し直す。
+ */
+const SEARCH_INTERVAL: Duration = Duration::from_secs(600);
+
+/*
+ * RFC 1191 7: Next-Hop MTU を返さない古いルーターには、よく使われる MTU から推測する。
+ */
+const PLATEAUS: [u16; 10] = [32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68];
+
+impl PathMtu {
+    pub(crate) fn new(interface_mtu: u16, probing: bool) -> Self {
+        Self {
+            interface_mtu,
+            probing,
+            mtu: interface_mtu,
+            search_high: interface_mtu,
+            probe: None,
+            next_search: None,
+        }
+    }
+
+    pub(crate) fn get_mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    /*
+     * ICMP Fragmentation Needed を受け取った。`datagram_length`は ICMP に入っていた、送った datagram の長さ。
+     * 送り直すべきなら（MTU を下げた、もしくは probe が届かなかった）true.
+     */
+    pub(crate) fn on_packet_too_big(&mut self, next_hop_mtu: u16, datagram_length: u16) -> bool {
+        let next_hop_mtu = match next_hop_mtu {
+            0 => PLATEAUS
+                .iter()
+                .copied()
+                .find(|plateau| *plateau < datagram_length)
+                .unwrap_or(0),
+            next_hop_mtu => next_hop_mtu,
+        }
+        .max(MINIMUM_PATH_MTU);
+
+        self.search_high = self.search_high.min(next_hop_mtu);
+        let probe_lost = self.probe.is_some_and(|(_, size)| next_hop_mtu < size);
+        if probe_lost {
+            self.probe = None;
+        }
+        if next_hop_mtu < self.mtu {
+            self.mtu = next_hop_mtu;
+            self.probe = None;
+            return true;
+        }
+        probe_lost
+    }
+
+    /*
+     * 再送が続いている。ICMP が届かずに大きいセグメントが捨てられているのかもしれないので、MTU を下げる。
+     * 下げたら true.
+     */
+    pub(crate) fn on_black_hole(&mut self) -> bool {
+        if !self.probing {
+            return false;
+        }
+        let lowered = if self.mtu > BASE_PATH_MTU {
+            BASE_PATH_MTU
+        } else {
+            (self.mtu / 2).max(MINIMUM_PATH_MTU)
+        };
+        if lowered >= self.mtu {
+            return false;
+        }
+        self.search_high = self.mtu - 1;
+        self.mtu = lowered;
+        self.probe = None;
+        self.next_search = None;
+        true
+    }
+
+    /*
+     * 今 probe を送るなら、その大きさ。`maximum`は相手の MSS から決まる上限。
+     */
+    pub(crate) fn get_probe_size(&mut self, now: Duration, maximum: u16) -> Option<u16> {
+        if !self.probing || self.probe.is_some() {
+            return None;
+        }
+        let converged = |search_high: u16, mtu: u16| {
+            search_high.min(maximum).saturating_sub(mtu) < SEARCH_THRESHOLD
+        };
+        if converged(self.search_high, self.mtu) {
+            match self.next_search {
+                Some(next_search) if next_search <= now => {
+                    self.next_search = None;
+                    self.search_high = self.interface_mtu;
+                }
+                Some(_) => return None,
+                None => {
+                    self.next_search = Some(now + SEARCH_INTERVAL);
+                    return None;
+                }
+            }
+            if converged(self.search_high, self.mtu) {
+                return None;
+            }
+        }
+        Some((self.mtu + self.search_high.min(maximum)) / 2)
+    }
+
+    pub(crate) fn on_probe_sent(&mut self, end: u32, size: u16) {
+        self.probe = Some((end, size));
+    }
+
+    /*
+     * probe が ACK されたら、その大きさまで使える。
+     */
+    pub(crate) fn on_acknowledgment(&mut self, acknowledgment: u32) {
+        if let Some((end, size)) = self.probe {
+            if sequence_le(end, acknowledgment) {
+                self.mtu = size;
+                self.probe = None;
+            }
+        }
+    }
+
+    /*
+     * 再送タイマーが切れた。probe を送っていたなら、それが届かなかったとみなして true.
+     */
+    pub(crate) fn on_timeout(&mut self) -> bool {
+        let Some((_, size)) = self.probe.take() else {
+            return false;
+        };
+        self.search_high = size - 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_too_big() {
+        let mut path_mtu = PathMtu::new(1500, false);
+        assert!(path_mtu.on_packet_too_big(1400, 1500));
+        assert_eq!(path_mtu.get_mtu(), 1400);
+
+        /*
+         * ICMP では大きくしない。小さすぎる値は切り上げる。
+         */
+        assert!(!path_mtu.on_packet_too_big(1450, 1400));
+        assert_eq!(path_mtu.get_mtu(), 1400);
+        assert!(path_mtu.on_packet_too_big(100, 1400));
+        assert_eq!(path_mtu.get_mtu(), MINIMUM_PATH_MTU);
+
+        /*
+         * Next-Hop MTU が 0 なら、送った大きさより小さい plateau.
+         */
+        let mut path_mtu = PathMtu::new(1500, false);
+        assert!(path_mtu.on_packet_too_big(0, 1500));
+        assert_eq!(path_mtu.get_mtu(), 1492);
+        assert!(path_mtu.on_packet_too_big(0, 1492));
+        assert_eq!(path_mtu.get_mtu(), 1006);
+    }
+
+    #[test]
+    fn test_black_hole_and_probing() {
+        let mut path_mtu = PathMtu::new(1500, true);
+        assert_eq!(path_mtu.get_probe_size(Duration::ZERO, 1500), None);
+        assert!(path_mtu.on_black_hole());
+        assert_eq!(path_mtu.get_mtu(), BASE_PATH_MTU);
+
+        /*
+         * 1024 から 1499 の間を二分探索する。経路の MTU が 1300 なら、そこに近づく。
+         */
+        let mut now = Duration::ZERO;
+        let mut end = 0;
+        while let Some(size) = path_mtu.get_probe_size(now, 1500) {
+            assert!(path_mtu.get_mtu() < size && size < 1500);
+            end += u32::from(size);
+            path_mtu.on_probe_sent(end, size);
+            if size <= 1300 {
+                path_mtu.on_acknowledgment(end);
+            } else {
+                assert!(path_mtu.on_timeout());
+            }
+            now += Duration::from_secs(1);
+        }
+        assert!((1300 - SEARCH_THRESHOLD..=1300).contains(&path_mtu.get_mtu()));
+        assert!(!path_mtu.on_timeout());
+
+        /*
+         * しばらくしたら、インターフェースの MTU まで探し直す。
+         */
+        let second = Duration::from_secs(1);
+        assert_eq!(
+            path_mtu.get_probe_size(now + SEARCH_INTERVAL - second, 1500),
+            None
+        );
+        let size = path_mtu.get_probe_size(now + SEARCH_INTERVAL, 1500);
+        assert_eq!(size, Some((path_mtu.get_mtu() + 1500) / 2));
+
+        /*
+         * 相手の MSS より大きくは探らない。
+         */
+        let mut path_mtu = PathMtu::new(1500, true);
+        path_mtu.on_black_hole();
+        assert_eq!(path_mtu.get_probe_size(Duration::ZERO, 1028), None);
+    }
+
+    #[test]
+    fn test_black_hole_without_probing() {
+        let mut path_mtu = PathMtu::new(1500, false);
+        assert!(!path_mtu.on_black_hole());
+        assert_eq!(path_mtu.get_mtu(), 1500);
+
+        let mut path_mtu = PathMtu::new(1500, true);
+        assert!(path_mtu.on_black_hole());
+        assert!(path_mtu.on_black_hole());
+        assert_eq!(path_mtu.get_mtu(), MINIMUM_PATH_MTU);
+        assert!(!path_mtu.on_black_hole());
+    }
+}
@@ -0,0 +1,237 @@
+use crate::tcp_stack::connection::sequence_le;
+use core::time::Duration;
+
+/*
+ * RFC 6298 の再送タイマー (RTO) と RTT の推定。
+ *
+ * See: https://www.rfc-editor.org/rfc/rfc6298.html
+ */
+pub(crate) struct RetransmissionTimer {
+    minimum_timeout: Duration,
+    maximum_timeout: Duration,
+
+    /*
+     * 時計の分解能 (G).
+     */
+    granularity: Duration,
+
+    timeout: Duration,
+    smoothed_rtt: Option<Duration>,
+    rtt_variance: Duration,
+    deadline: Option<Duration>,
+
+    /*
+     * ACK が進まないまま続けてタイムアウトした回数。
+     */
+    retransmissions: u32,
+
+    /*
+     * RTT を測っているセグメントの最後のシーケンス番号と、送った時刻。
+     * 再送したセグメントでは測らない（Karn のアルゴリズム）。
+     */
+    measuring: Option<(u32, Duration)>,
+}
+
+impl RetransmissionTimer {
+    pub(crate) fn new(
+        initial_timeout: Duration,
+        minimum_timeout: Duration,
+        maximum_timeout: Duration,
+        granularity: Duration,
+    ) -> Self {
+        Self {
+            minimum_timeout,
+            maximum_timeout,
+            granularity,
+            timeout: initial_timeout,
+            smoothed_rtt: None,
+            rtt_variance: Duration::ZERO,
+            deadline: None,
+            retransmissions: 0,
+            measuring: None,
+        }
+    }
+
+    pub(crate) fn get_deadline(&self) -> Option<Duration> {
+        self.deadline
+    }
+
+    pub(crate) fn get_timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub(crate) fn get_smoothed_rtt(&self) -> Option<Duration> {
+        self.smoothed_rtt
+    }
+
+    pub(crate) fn get_retransmissions(&self) -> u32 {
+        self.retransmissions
+    }
+
+    /*
+     * RTO を`count`回倍にしたもの。上限は再送と同じ。persist タイマーに使う。
+     */
+    pub(crate) fn get_backed_off_timeout(&self, count: u32) -> Duration {
+        self.timeout
+            .saturating_mul(1 << count.min(31))
+            .min(self.maximum_timeout)
+    }
+
+    /*
+     * シーケンス番号を消費するセグメント（SYN, FIN, データ）を送った。`end`はその次のシーケンス番号。
+     */
+    pub(crate) fn on_send(&mut self, now: Duration, end: u32, retransmission: bool) {
+        if self.deadline.is_none() {
+            self.deadline = Some(now + self.timeout);
+        }
+        if !retransmission && self.measuring.is_none() {
+            self.measuring = Some((end, now));
+        }
+    }
+
+    /*
+     * 新しいデータが ACK された。`outstanding`は、まだ ACK されていないものが残っているか。
+     */
+    pub(crate) fn on_acknowledgment(
+        &mut self,
+        now: Duration,
+        acknowledgment: u32,
+        outstanding: bool,
+    ) {
+        if let Some((end, sent_at)) = self.measuring {
+            if sequence_le(end, acknowledgment) {
+                self.measuring = None;
+                self.update_rtt(now.saturating_sub(sent_at));
+            }
+        }
+        self.retransmissions = 0;
+        self.deadline = outstanding.then(|| now + self.timeout);
+    }
+
+    /*
+     * タイムスタンプ (RFC 7323 4) で測った RTT. どのセグメントへの ACK かが分かるので、
+     * 再送したセグメントでも使える。測っている途中のものは捨てる。
+     */
+    pub(crate) fn on_rtt_sample(&mut self, rtt: Duration) {
+        self.measuring = None;
+        self.update_rtt(rtt);
+    }
+
+    /*
+     * RFC 6298 5.5, 5.6: RTO を倍にしてタイマーをかけ直す。
+     */
+    pub(crate) fn on_timeout(&mut self, now: Duration) {
+        self.timeout = (self.timeout * 2).min(self.maximum_timeout);
+        self.retransmissions += 1;
+        self.measuring = None;
+        self.deadline = Some(now + self.timeout);
+    }
+
+    /*
+     * RTO は変えずにタイマーをかけ直す。輻輳ではない理由で送り直す時に使う。
+     */
+    pub(crate) fn restart(&mut self, now: Duration) {
+        self.measuring = None;
+        self.deadline = Some(now + self.timeout);
+    }
+
+    pub(crate) fn stop(&mut self) {
+        self.deadline = None;
+        self.measuring = None;
+    }
+
+    /*
+     * RFC 6298 2.2, 2.3.
+     */
+    fn update_rtt(&mut self, rtt: Duration) {
+        let smoothed_rtt = match self.smoothed_rtt {
+            None => {
+                self.rtt_variance = rtt / 2;
+                rtt
+            }
+            Some(smoothed_rtt) => {
+                let error = smoothed_rtt.abs_diff(rtt);
+                self.rtt_variance = self.rtt_variance * 3 / 4 + error / 4;
+                smoothed_rtt * 7 / 8 + rtt / 8
+            }
+        };
+        self.smoothed_rtt = Some(smoothed_rtt);
+        self.timeout = (smoothed_rtt + self.granularity.max(self.rtt_variance * 4))
+            .clamp(self.minimum_timeout, self.maximum_timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timer() -> RetransmissionTimer {
+        RetransmissionTimer::new(
+            Duration::from_secs(1),
+            Duration::from_millis(200),
+            Duration::from_secs(60),
+            Duration::from_millis(1),
+        )
+    }
+
+    #[test]
+    fn test_rtt_estimation() {
+        let mut timer = timer();
+        timer.on_send(Duration::ZERO, 100, false);
+        assert_eq!(timer.get_deadline(), Some(Duration::from_secs(1)));
+
+        timer.on_acknowledgment(Duration::from_millis(100), 100, false);
+        assert_eq!(timer.get_smoothed_rtt(), Some(Duration::from_millis(100)));
+        assert_eq!(timer.get_timeout(), Duration::from_millis(300));
+        assert_eq!(timer.get_deadline(), None);
+
+        timer.on_send(Duration::from_secs(1), 200, false);
+        timer.on_acknowledgment(Duration::from_millis(1180), 200, false);
+        assert_eq!(timer.get_smoothed_rtt(), Some(Duration::from_millis(110)));
+        assert_eq!(timer.get_timeout(), Duration::from_millis(340));
+
+        /*
+         * 下限で切り詰める。
+         */
+        for i in 0..20 {
+            let now = Duration::from_secs(2 + i);
+            timer.on_send(now, 300 + i as u32, false);
+            timer.on_acknowledgment(now + Duration::from_millis(1), 300 + i as u32, false);
+        }
+        assert_eq!(timer.get_timeout(), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_backoff_and_karn() {
+        let mut timer = timer();
+        timer.on_send(Duration::ZERO, 1, false);
+        for expected in [2, 4, 8, 16, 32, 60, 60] {
+            let now = timer.get_deadline().unwrap();
+            timer.on_timeout(now);
+            timer.on_send(now, 1, true);
+            assert_eq!(timer.get_timeout(), Duration::from_secs(expected));
+        }
+        assert_eq!(timer.get_retransmissions(), 7);
+
+        /*
+         * 再送したセグメントの ACK では RTT を測らない。
+         */
+        timer.on_acknowledgment(Duration::from_secs(200), 1, true);
+        assert_eq!(timer.get_smoothed_rtt(), None);
+        assert_eq!(timer.get_retransmissions(), 0);
+        assert_eq!(timer.get_deadline(), Some(Duration::from_secs(260)));
+    }
+
+    #[test]
+    fn test_rtt_sample_after_retransmission() {
+        let mut timer = timer();
+        timer.on_send(Duration::ZERO, 1, false);
+        timer.on_timeout(Duration::from_secs(1));
+        timer.on_send(Duration::from_secs(1), 1, true);
+
+        timer.on_rtt_sample(Duration::from_millis(100));
+        timer.on_acknowledgment(Duration::from_millis(1100), 1, false);
+        assert_eq!(timer.get_smoothed_rtt(), Some(Duration::from_millis(100)));
+        assert_eq!(timer.get_timeout(), Duration::from_millis(300));
+    }
+}
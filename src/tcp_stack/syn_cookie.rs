@@ -0,0 +1,321 @@
+use crate::tcp_stack::connection::timestamps_of;
+use crate::tcp_stack::demux::KeyedHasher;
+use crate::tcp_stack::{segment_ip_header, Endpoint};
+use crate::transmission_control_protocol::tcp_option::TcpOption;
+use crate::transmission_control_protocol::tcp_packet::TcpPacket;
+use crate::transmission_control_protocol::{ControlBits, TcpHeader};
+use alloc::vec::Vec;
+use core::hash::BuildHasher;
+use core::time::Duration;
+
+/*
+ * SYN cookie.
+ *
+ * SYN キューがあふれた時は状態を持たずに SYN-ACK を返し、必要なものを ISS に詰めておく。
+ * 戻ってきた ACK の SEG.ACK - 1 を検証して、コネクションを作り直す。
+ *
+ *  31      27 26  24 23                       0
+ * +----------+------+--------------------------+
+ * | 時刻 t   | MSS  |          MAC             |
+ * +----------+------+--------------------------+
+ *
+ * - t: 64 秒ごとに増えるカウンターの下位 5 ビット。
+ * - MSS: `MSS_TABLE`の添字。
+ * - MAC: 鍵付きハッシュ (4 タプル, 相手の ISN, t, MSS) の下位 24 ビット。
+ *
+ * See: https://www.rfc-editor.org/rfc/rfc4987.html#section-3.6
+ */
+pub(crate) struct SynCookies {
+    hasher: KeyedHasher,
+}
+
+/*
+ * よく使われる MSS. 相手の MSS 以下で一番大きいものを使う。
+ */
+const MSS_TABLE: [u16; 8] = [536, 1024, 1200, 1300, 1360, 1400, 1440, 1460];
+
+const COUNTER_PERIOD: u64 = 64;
+
+/*
+ * この周期数前までの cookie を受け付ける。
+ */
+const MAXIMUM_AGE: u32 = 2;
+
+const MAC_MASK: u32 = 0x00ff_ffff;
+
+impl SynCookies {
+    pub(crate) fn new(hasher: KeyedHasher) -> Self {
+        Self { hasher }
+    }
+
+    /*
+     * SYN-ACK の ISS にする cookie. `mss`は相手が SYN で広告した MSS.
+     */
+    pub(crate) fn generate(
+        &self,
+        now: Duration,
+        local: Endpoint,
+        remote: Endpoint,
+        remote_initial_sequence: u32,
+        mss: u16,
+    ) -> u32 {
+        let index = MSS_TABLE
+            .iter()
+            .rposition(|candidate| *candidate <= mss)
+            .unwrap_or(0);
+        let counter = counter(now);
+        let mac = self.mac(local, remote, remote_initial_sequence, counter, index);
+        (counter % 32) << 27 | (index as u32) << 24 | mac
+    }
+
+    /*
+     * cookie が正しく、古すぎなければ、相手の MSS（`MSS_TABLE`に丸めたもの）。
+     */
+    pub(crate) fn validate(
+        &self,
+        now: Duration,
+        local: Endpoint,
+        remote: Endpoint,
+        remote_initial_sequence: u32,
+        cookie: u32,
+    ) -> Option<u16> {
+        let index = (cookie >> 24 & 0b111) as usize;
+        let current = counter(now);
+        (0..MAXIMUM_AGE)
+            .filter_map(|age| current.checked_sub(age))
+            .filter(|counter| counter % 32 == cookie >> 27)
+            .any(|counter| {
+                self.mac(local, remote, remote_initial_sequence, counter, index)
+                    == cookie & MAC_MASK
+            })
+            .then_some(MSS_TABLE[index])
+    }
+
+    fn mac(
+        &self,
+        local: Endpoint,
+        remote: Endpoint,
+        remote_initial_sequence: u32,
+        counter: u32,
+        index: usize,
+    ) -> u32 {
+        self.hasher.hash_one((
+            "syn_cookie",
+            local,
+            remote,
+            remote_initial_sequence,
+            counter,
+            index,
+        )) as u32
+            & MAC_MASK
+    }
+}
+
+fn counter(now: Duration) -> u32 {
+    (now.as_secs() / COUNTER_PERIOD) as u32
+}
+
+/*
+ * ISS に入りきらない SYN のオプション。タイムスタンプが使える時は、TSval の下位 5 ビットに入れて、
+ * ACK の TSecr で返してもらう（Linux と同じ方法）。
+ *
+ *  4      1 0
+ * +--------+---+
+ * | wscale |SACK|  wscale が 0xf ならウィンドウスケールなし。
+ * +--------+---+
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub(crate) struct SynCookieOptions {
+    pub(crate) window_scale: Option<u8>,
+    pub(crate) sack_permitted: bool,
+}
+
+const TIMESTAMP_OPTION_BITS: u32 = 5;
+const NO_WINDOW_SCALE: u32 = 0xf;
+
+impl SynCookieOptions {
+    /*
+     * SYN のオプションのうち、cookie に残すもの。SYN に Timestamps がなければ残せないので`None`.
+     *
+     * TODO: SACK に対応したら、SACK Permitted も取り出す。
+     */
+    pub(crate) fn from_syn(syn: &TcpPacket) -> Option<Self> {
+        let tcp_header = syn.get_tcp_header();
+        timestamps_of(tcp_header)?;
+        Some(Self {
+            window_scale: tcp_header
+                .get_options()
+                .iter()
+                .find_map(|option| match option {
+                    TcpOption::WindowScale(shift) => Some(*shift),
+                    _ => None,
+                }),
+            sack_permitted: false,
+        })
+    }
+
+    /*
+     * 下位ビットを置き換えると`tsval`より大きくなることがある。コネクションを作った後の TSval が
+     * それより小さいと PAWS で捨てられてしまうので、その時は 1 つ前のブロックに戻す。
+     */
+    pub(crate) fn encode_timestamp(&self, tsval: u32) -> u32 {
+        let window_scale = self
+            .window_scale
+            .map_or(NO_WINDOW_SCALE, |shift| u32::from(shift.min(14)));
+        let bits = window_scale << 1 | u32::from(self.sack_permitted);
+        let mut block = tsval & !((1 << TIMESTAMP_OPTION_BITS) - 1);
+        if block | bits > tsval {
+            block = block.wrapping_sub(1 << TIMESTAMP_OPTION_BITS);
+        }
+        block | bits
+    }
+
+    pub(crate) fn decode_timestamp(tsecr: u32) -> Self {
+        let window_scale = tsecr >> 1 & 0xf;
+        Self {
+            window_scale: (window_scale != NO_WINDOW_SCALE).then_some(window_scale as u8),
+            sack_permitted: tsecr & 1 != 0,
+        }
+    }
+}
+
+/*
+ * cookie を ISS にした SYN-ACK. `mss`と`window_shift`はこちらが広告するもの。
+ * `options`があれば、タイムスタンプを付けてそこに入れる。`clock`は TSval の時計で、cookie の ACK から
+ * 作るコネクションと同じずれを足したもの。
+ */
+pub(crate) fn syn_ack_for(
+    syn: &TcpPacket,
+    cookie: u32,
+    mss: u16,
+    window: u16,
+    window_shift: Option<u8>,
+    options: Option<SynCookieOptions>,
+    clock: u32,
+) -> TcpPacket {
+    let syn_header = syn.get_tcp_header();
+    let mut tcp_header = TcpHeader::new(
+        syn_header.get_destination_port(),
+        syn_header.get_source_port(),
+    );
+    tcp_header.set_sequence_number(cookie);
+    tcp_header.set_acknowledgment_number(syn_header.get_sequence_number().wrapping_add(1));
+    let mut control_bits = ControlBits::default();
+    control_bits.set_syn(true);
+    control_bits.set_ack(true);
+    tcp_header.set_control_bits(control_bits);
+    tcp_header.set_window(window);
+
+    let mut tcp_options = Vec::from([TcpOption::MaximumSegmentSize(mss)]);
+    if let (Some(options), Some((tsecr, _))) = (options, timestamps_of(syn_header)) {
+        let tsval = options.encode_timestamp(clock);
+        tcp_options.extend([
+            TcpOption::NoOperation,
+            TcpOption::NoOperation,
+            TcpOption::Timestamps { tsval, tsecr },
+        ]);
+        if let (Some(shift), Some(_)) = (window_shift, options.window_scale) {
+            tcp_options.extend([TcpOption::NoOperation, TcpOption::WindowScale(shift)]);
+        }
+        if options.sack_permitted {
+            tcp_options.push(TcpOption::SackPermitted);
+        }
+    }
+    tcp_header.set_options(&tcp_options).unwrap();
+
+    TcpPacket::new(
+        segment_ip_header(syn.get_destination_address(), syn.get_source_address()),
+        tcp_header,
+        Vec::new(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL: Endpoint = Endpoint {
+        address: [10, 0, 0, 1],
+        port: 80,
+    };
+    const REMOTE: Endpoint = Endpoint {
+        address: [10, 0, 0, 2],
+        port: 50000,
+    };
+
+    #[test]
+    fn test_cookie_round_trip_and_expiry() {
+        let cookies = SynCookies::new(KeyedHasher::new([9; 16]));
+        let now = Duration::from_secs(1000);
+        let cookie = cookies.generate(now, LOCAL, REMOTE, 12345, 1400);
+        assert_eq!(
+            cookies.validate(now, LOCAL, REMOTE, 12345, cookie),
+            Some(1400)
+        );
+        assert_eq!(
+            cookies.validate(now + Duration::from_secs(64), LOCAL, REMOTE, 12345, cookie),
+            Some(1400)
+        );
+        assert_eq!(
+            cookies.validate(now + Duration::from_secs(192), LOCAL, REMOTE, 12345, cookie),
+            None
+        );
+
+        /*
+         * 4 タプル、相手の ISN、cookie 自体のどれが違っても通らない。
+         */
+        let other = Endpoint::new(REMOTE.address, REMOTE.port + 1);
+        assert_eq!(cookies.validate(now, LOCAL, other, 12345, cookie), None);
+        assert_eq!(cookies.validate(now, LOCAL, REMOTE, 12346, cookie), None);
+        assert_eq!(
+            cookies.validate(now, LOCAL, REMOTE, 12345, cookie ^ 1 << 24),
+            None
+        );
+        assert_eq!(
+            cookies.validate(now, LOCAL, REMOTE, 12345, cookie ^ 1),
+            None
+        );
+    }
+
+    #[test]
+    fn test_mss_rounds_down() {
+        let cookies = SynCookies::new(KeyedHasher::new([9; 16]));
+        for (requested, expected) in [(9000, 1460), (1459, 1440), (1000, 536), (100, 536)] {
+            let cookie = cookies.generate(Duration::ZERO, LOCAL, REMOTE, 0, requested);
+            assert_eq!(
+                cookies.validate(Duration::ZERO, LOCAL, REMOTE, 0, cookie),
+                Some(expected)
+            );
+        }
+    }
+
+    #[test]
+    fn test_options_in_timestamp() {
+        for options in [
+            SynCookieOptions::default(),
+            SynCookieOptions {
+                window_scale: Some(7),
+                sack_permitted: true,
+            },
+            SynCookieOptions {
+                window_scale: Some(0),
+                sack_permitted: false,
+            },
+        ] {
+            let tsval = options.encode_timestamp(0xdead_beef);
+            assert!(tsval <= 0xdead_beef && 0xdead_beef - tsval < 0x20);
+            assert_eq!(SynCookieOptions::decode_timestamp(tsval), options);
+        }
+
+        /*
+         * 置き換えると大きくなる時は、1 つ前のブロックに入れる。0 からは巻き戻る。
+         */
+        let options = SynCookieOptions {
+            window_scale: Some(7),
+            sack_permitted: false,
+        };
+        assert_eq!(options.encode_timestamp(0x1000_0000), 0x0fff_ffee);
+        assert_eq!(options.encode_timestamp(0x1000_001e), 0x1000_000e);
+        assert_eq!(options.encode_timestamp(0), 0xffff_ffee);
+    }
+}
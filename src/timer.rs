@@ -0,0 +1,577 @@
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::time::Duration;
+
+/*
+ * 単調増加する時計。起点からの経過時間を返す。
+ *
+ * `NetworkStack`には時刻を引数で渡すので、スタックを駆動する側がこれを使って時刻を得る。
+ */
+pub trait Clock {
+    fn now(&self) -> Duration;
+}
+
+/*
+ * 手動で進める時計。テストやシミュレーション用。
+ */
+#[derive(Debug, Default)]
+pub struct VirtualClock {
+    now: Cell<Duration>,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+
+    /*
+     * NOTE: 時刻を戻すことはできない。
+     */
+    pub fn set(&self, now: Duration) {
+        assert!(
+            now >= self.now.get(),
+            "VirtualClock cannot go backwards: {:?} -> {:?}",
+            self.now.get(),
+            now
+        );
+        self.now.set(now);
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration {
+        self.now.get()
+    }
+}
+
+/*
+ * 作成した時点を起点にした、OS の単調増加時計。
+ */
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy)]
+pub struct MonotonicClock {
+    started_at: std::time::Instant,
+}
+
+#[cfg(feature = "std")]
+impl MonotonicClock {
+    pub fn new() -> Self {
+        Self {
+            started_at: std::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for MonotonicClock {
+    fn now(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+}
+
+/*
+ * 1 段あたり 64 スロットで 4 段。分解能 1 ms なら約 4.6 時間先までをそのまま置ける。
+ * それより先のタイマーは最上段の一番遠いスロットに置き、そこに来た時に置き直す。
+ */
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 4;
+const MAX_SPAN: u64 = 1 << (SLOT_BITS as usize * LEVELS);
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct TimerId {
+    index: usize,
+
+    /*
+     * 再利用されたエントリーを古い ID で消さないように。
+     */
+    generation: u64,
+}
+
+/*
+ * 階層型タイマーホイール (Varghese & Lauck)。
+ *
+ * 追加と取り消しは O(1)。時刻を進める時の処理は、段数 × スロット数と期限切れになった数に比例する。
+ * 期限は分解能の単位に切り上げるので、期限より早く切れることはない。
+ *
+ * エントリーは Vec に置いて、スロットごとに添字の双方向リストでつなぐ。
+ */
+pub struct TimerWheel<T> {
+    resolution: Duration,
+
+    /*
+     * ここまでの tick は処理済み。
+     */
+    current_tick: u64,
+
+    entries: Vec<Entry<T>>,
+    free: Vec<usize>,
+    slots: [[Option<usize>; SLOTS]; LEVELS],
+
+    /*
+     * 空でないスロットのビットマップ。次に切れる時刻を探すのに使う。
+     */
+    occupied: [u64; LEVELS],
+
+    /*
+     * 追加した時点で既に期限が過ぎていたもの。次の`advance`で切れる。
+     */
+    due: Option<usize>,
+    len: usize,
+}
+
+struct Entry<T> {
+    value: Option<T>,
+    deadline: Duration,
+    tick: u64,
+    generation: u64,
+    list: List,
+    previous: Option<usize>,
+    next: Option<usize>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum List {
+    Unlinked,
+    Due,
+    Slot { level: usize, slot: usize },
+}
+
+impl<T> TimerWheel<T> {
+    /*
+     * 時刻 0 から始める。
+     */
+    pub fn new(resolution: Duration) -> Self {
+        assert!(!resolution.is_zero(), "Timer resolution must not be zero.");
+        Self {
+            resolution,
+            current_tick: 0,
+            entries: Vec::new(),
+            free: Vec::new(),
+            slots: [[None; SLOTS]; LEVELS],
+            occupied: [0; LEVELS],
+            due: None,
+            len: 0,
+        }
+    }
+
+    pub fn get_resolution(&self) -> Duration {
+        self.resolution
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn insert(&mut self, deadline: Duration, value: T) -> TimerId {
+        let tick = self.to_tick_ceil(deadline);
+        let index = match self.free.pop() {
+            Some(index) => {
+                let entry = &mut self.entries[index];
+                entry.value = Some(value);
+                entry.deadline = deadline;
+                entry.tick = tick;
+                entry.generation += 1;
+                index
+            }
+            None => {
+                self.entries.push(Entry {
+                    value: Some(value),
+                    deadline,
+                    tick,
+                    generation: 0,
+                    list: List::Unlinked,
+                    previous: None,
+                    next: None,
+                });
+                self.entries.len() - 1
+            }
+        };
+        self.place(index);
+        self.len += 1;
+        TimerId {
+            index,
+            generation: self.entries[index].generation,
+        }
+    }
+
+    /*
+     * まだ切れていなければ取り消して値を返す。
+     */
+    pub fn cancel(&mut self, id: TimerId) -> Option<T> {
+        self.get_entry(id)?;
+        self.unlink(id.index);
+        self.release(id.index)
+    }
+
+    pub fn get_deadline(&self, id: TimerId) -> Option<Duration> {
+        self.get_entry(id).map(|entry| entry.deadline)
+    }
+
+    /*
+     * 次に`advance`を呼ぶべき時刻。
+     *
+     * NOTE: 上の段のタイマーは、下の段へ置き直す時刻を返すので、実際の期限より早いことがある。
+     *       その時刻に`advance`しても何も切れないが、次の時刻はより正確になる。
+     */
+    pub fn next_wakeup(&self) -> Option<Duration> {
+        if self.due.is_some() {
+            return Some(self.to_time(self.current_tick));
+        }
+
+        let mut wakeup: Option<u64> = None;
+        for level in 0..LEVELS {
+            let occupied = self.occupied[level];
+            if occupied == 0 {
+                continue;
+            }
+            let shift = SLOT_BITS * level as u32;
+            let next_block = (self.current_tick >> shift) + 1;
+            let rotated = occupied.rotate_right((next_block % SLOTS as u64) as u32);
+            let tick = (next_block + u64::from(rotated.trailing_zeros())) << shift;
+            wakeup = Some(wakeup.map_or(tick, |wakeup| wakeup.min(tick)));
+        }
+        wakeup.map(|tick| self.to_time(tick))
+    }
+
+    /*
+     * `now`までに期限が来たタイマーを取り除き、期限の早い順に返す。
+     */
+    pub fn advance(&mut self, now: Duration) -> Vec<T> {
+        let target = self.to_tick_floor(now);
+        let mut collected = Vec::new();
+        self.detach_list(List::Due, &mut collected);
+
+        if target > self.current_tick {
+            for level in 0..LEVELS {
+                let shift = SLOT_BITS * level as u32;
+                let from = self.current_tick >> shift;
+                let to = target >> shift;
+                if from == to {
+                    break;
+                }
+                for block in from + 1..=to.min(from + SLOTS as u64) {
+                    let slot = (block % SLOTS as u64) as usize;
+                    self.detach_list(List::Slot { level, slot }, &mut collected);
+                }
+            }
+            self.current_tick = target;
+        }
+
+        let mut expired = Vec::new();
+        for index in collected {
+            if self.entries[index].tick <= self.current_tick {
+                expired.push(index);
+            } else {
+                self.place(index);
+            }
+        }
+        expired.sort_by_key(|index| (self.entries[*index].deadline, *index));
+        expired
+            .into_iter()
+            .filter_map(|index| self.release(index))
+            .collect()
+    }
+
+    fn get_entry(&self, id: TimerId) -> Option<&Entry<T>> {
+        self.entries
+            .get(id.index)
+            .filter(|entry| entry.generation == id.generation && entry.value.is_some())
+    }
+
+    fn release(&mut self, index: usize) -> Option<T> {
+        let value = self.entries[index].value.take();
+        if value.is_some() {
+            self.free.push(index);
+            self.len -= 1;
+        }
+        value
+    }
+
+    fn to_tick_ceil(&self, time: Duration) -> u64 {
+        time.as_nanos().div_ceil(self.resolution.as_nanos()) as u64
+    }
+
+    fn to_tick_floor(&self, time: Duration) -> u64 {
+        (time.as_nanos() / self.resolution.as_nanos()) as u64
+    }
+
+    fn to_time(&self, tick: u64) -> Duration {
+        let nanos = self.resolution.as_nanos() * u128::from(tick);
+        Duration::new(
+            (nanos / 1_000_000_000) as u64,
+            (nanos % 1_000_000_000) as u32,
+        )
+    }
+
+    /*
+     * 現在の tick からの距離で段を決めて、スロットのリストの先頭につなぐ。
+     */
+    fn place(&mut self, index: usize) {
+        let tick = self.entries[index].tick;
+        if tick <= self.current_tick {
+            self.push(index, List::Due);
+            return;
+        }
+
+        let slot_tick = tick.min(self.current_tick + MAX_SPAN - 1);
+        let delta = slot_tick - self.current_tick;
+        let level = ((u64::BITS - delta.leading_zeros() - 1) / SLOT_BITS) as usize;
+        let shift = SLOT_BITS * level as u32;
+        let slot = ((slot_tick >> shift) % SLOTS as u64) as usize;
+        self.push(index, List::Slot { level, slot });
+    }
+
+    fn head_mut(&mut self, list: List) -> &mut Option<usize> {
+        match list {
+            List::Due => &mut self.due,
+            List::Slot { level, slot } => &mut self.slots[level][slot],
+            List::Unlinked => unreachable!(),
+        }
+    }
+
+    fn push(&mut self, index: usize, list: List) {
+        let head = *self.head_mut(list);
+        if let Some(head) = head {
+            self.entries[head].previous = Some(index);
+        }
+        let entry = &mut self.entries[index];
+        entry.list = list;
+        entry.previous = None;
+        entry.next = head;
+        *self.head_mut(list) = Some(index);
+        if let List::Slot { level, slot } = list {
+            self.occupied[level] |= 1 << slot;
+        }
+    }
+
+    fn unlink(&mut self, index: usize) {
+        let Entry {
+            list,
+            previous,
+            next,
+            ..
+        } = self.entries[index];
+        if list == List::Unlinked {
+            return;
+        }
+
+        match previous {
+            Some(previous) => self.entries[previous].next = next,
+            None => *self.head_mut(list) = next,
+        }
+        if let Some(next) = next {
+            self.entries[next].previous = previous;
+        }
+        if let List::Slot { level, slot } = list {
+            if self.slots[level][slot].is_none() {
+                self.occupied[level] &= !(1 << slot);
+            }
+        }
+
+        let entry = &mut self.entries[index];
+        entry.list = List::Unlinked;
+        entry.previous = None;
+        entry.next = None;
+    }
+
+    fn detach_list(&mut self, list: List, collected: &mut Vec<usize>) {
+        let mut cursor = self.head_mut(list).take();
+        if let List::Slot { level, slot } = list {
+            self.occupied[level] &= !(1 << slot);
+        }
+        while let Some(index) = cursor {
+            let entry = &mut self.entries[index];
+            cursor = entry.next;
+            entry.list = List::Unlinked;
+            entry.previous = None;
+            entry.next = None;
+            collected.push(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const MILLISECOND: Duration = Duration::from_millis(1);
+
+    /*
+     * `next_wakeup`に従って`until`まで進め、切れた時刻と値を記録する。
+     */
+    fn run_until(
+        wheel: &mut TimerWheel<u32>,
+        clock: &VirtualClock,
+        until: Duration,
+    ) -> Vec<(Duration, u32)> {
+        let mut fired = Vec::new();
+        while let Some(wakeup) = wheel.next_wakeup() {
+            if wakeup > until {
+                break;
+            }
+            clock.set(wakeup.max(clock.now()));
+            for value in wheel.advance(clock.now()) {
+                fired.push((clock.now(), value));
+            }
+        }
+        clock.set(until.max(clock.now()));
+        fired
+    }
+
+    #[test]
+    fn test_fires_at_deadline_in_order() {
+        let clock = VirtualClock::new();
+        let mut wheel = TimerWheel::new(MILLISECOND);
+        let deadlines = [
+            Duration::from_millis(1),
+            Duration::from_micros(1500),
+            Duration::from_millis(63),
+            Duration::from_millis(64),
+            Duration::from_millis(1000),
+            Duration::from_secs(3600),
+            Duration::from_secs(24 * 3600),
+        ];
+        for (value, deadline) in deadlines.iter().enumerate().rev() {
+            wheel.insert(*deadline, value as u32);
+        }
+        assert_eq!(wheel.len(), deadlines.len());
+
+        let fired = run_until(&mut wheel, &clock, Duration::from_secs(2 * 24 * 3600));
+        let expected: Vec<(Duration, u32)> = vec![
+            (Duration::from_millis(1), 0),
+            (Duration::from_millis(2), 1),
+            (Duration::from_millis(63), 2),
+            (Duration::from_millis(64), 3),
+            (Duration::from_millis(1000), 4),
+            (Duration::from_secs(3600), 5),
+            (Duration::from_secs(24 * 3600), 6),
+        ];
+        assert_eq!(fired, expected);
+        assert!(wheel.is_empty());
+        assert_eq!(wheel.next_wakeup(), None);
+    }
+
+    #[test]
+    fn test_cancel_and_reuse() {
+        let mut wheel = TimerWheel::new(MILLISECOND);
+        let first = wheel.insert(Duration::from_millis(10), 1);
+        let second = wheel.insert(Duration::from_millis(10), 2);
+        let third = wheel.insert(Duration::from_millis(5000), 3);
+
+        assert_eq!(wheel.get_deadline(second), Some(Duration::from_millis(10)));
+        assert_eq!(wheel.cancel(second), Some(2));
+        assert_eq!(wheel.cancel(second), None);
+        assert_eq!(wheel.get_deadline(second), None);
+
+        /*
+         * 取り消したエントリーが再利用されても、古い ID では触れない。
+         */
+        let fourth = wheel.insert(Duration::from_millis(20), 4);
+        assert_eq!(fourth.index, second.index);
+        assert_eq!(wheel.cancel(second), None);
+
+        assert_eq!(wheel.cancel(third), Some(3));
+        assert_eq!(wheel.advance(Duration::from_secs(10)), vec![1, 4]);
+        assert_eq!(wheel.cancel(first), None);
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn test_past_deadline_and_large_jump() {
+        let mut wheel = TimerWheel::new(MILLISECOND);
+        assert_eq!(wheel.advance(Duration::from_millis(100)), Vec::<u32>::new());
+
+        wheel.insert(Duration::from_millis(50), 1);
+        assert_eq!(wheel.next_wakeup(), Some(Duration::from_millis(100)));
+        wheel.insert(Duration::from_millis(100), 2);
+        wheel.insert(Duration::from_millis(100) + Duration::from_micros(1), 3);
+        wheel.insert(Duration::from_secs(7200), 4);
+        wheel.insert(Duration::from_secs(100_000), 5);
+
+        assert_eq!(wheel.advance(Duration::from_millis(100)), vec![1, 2]);
+        assert_eq!(wheel.next_wakeup(), Some(Duration::from_millis(101)));
+
+        /*
+         * 一度に大きく進めても、期限の来たものだけが切れる。
+         */
+        assert_eq!(wheel.advance(Duration::from_secs(7199)), vec![3]);
+        assert_eq!(wheel.advance(Duration::from_secs(7200)), vec![4]);
+        assert_eq!(
+            wheel.advance(Duration::from_secs(99_999)),
+            Vec::<u32>::new()
+        );
+        assert_eq!(wheel.advance(Duration::from_secs(100_000)), vec![5]);
+    }
+
+    #[test]
+    fn test_next_wakeup_is_never_late() {
+        let clock = VirtualClock::new();
+        let mut wheel = TimerWheel::new(MILLISECOND);
+
+        /*
+         * 決まった疑似乱数列で、追加と取り消しを混ぜる。
+         */
+        let mut state = 0x1234_5678_u64;
+        let mut random = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let mut ids = Vec::new();
+        let mut deadlines = Vec::new();
+        for value in 0..2000u32 {
+            let deadline = Duration::from_millis(random() % 20_000_000);
+            ids.push(wheel.insert(deadline, value));
+            deadlines.push(deadline);
+        }
+        let mut cancelled = 0;
+        for id in ids.iter().step_by(3) {
+            assert!(wheel.cancel(*id).is_some());
+            cancelled += 1;
+        }
+
+        let fired = run_until(&mut wheel, &clock, Duration::from_secs(20_001));
+        assert_eq!(fired.len(), 2000 - cancelled);
+        for (time, value) in &fired {
+            let deadline = deadlines[*value as usize];
+            assert!(value % 3 != 0);
+            assert!(*time >= deadline && *time < deadline + MILLISECOND);
+        }
+        assert!(fired.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+    }
+
+    #[test]
+    fn test_virtual_clock() {
+        let clock = VirtualClock::new();
+        assert_eq!(clock.now(), Duration::ZERO);
+        clock.advance(Duration::from_millis(1500));
+        clock.set(Duration::from_secs(2));
+        assert_eq!(clock.now(), Duration::from_secs(2));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_virtual_clock_cannot_go_backwards() {
+        let clock = VirtualClock::new();
+        clock.advance(Duration::from_secs(1));
+        clock.set(Duration::ZERO);
+    }
+}
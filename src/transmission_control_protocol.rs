@@ -1,8 +1,15 @@
-use crate::internet_protocol::Ipv4Header;
+use crate::checksum::calculate_internet_checksum_of;
+use crate::decode_options::{DecodeOptions, DecodeWarning};
+use crate::internet_protocol::Ipv4Address;
+use crate::layer::Layer;
+use alloc::vec;
+use alloc::vec::Vec;
 use byteorder::{BigEndian, ByteOrder};
-use std::error::Error;
-use std::fmt;
+use core::fmt;
+use tcp_option::{TcpOption, TcpOptionEncodeError};
+use tcp_pseudo_header::TcpPseudoHeader;
 
+pub mod tcp_option;
 pub mod tcp_packet;
 pub mod tcp_pseudo_header;
 
@@ -12,22 +19,110 @@ pub const TCP_PROTOCOL_NUMBER: u8 = 6;
  * See: https://www.rfc-editor.org/rfc/rfc9293.html
  */
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum TcpHeaderDecodeError {
     InputTooShort,
-    InvalidFieldValue(String),
+
+    /*
+     * Data Offset が 5 未満、もしくはバッファ長を超えている。
+     */
+    BadDataOffset { data_offset: u8, buffer_len: usize },
+
+    /*
+     * Reserved (4bits) が 0 ではない。
+     */
+    ReservedBitsSet { reserved: u8 },
+
+    /*
+     * option がヘッダーの終わりを超えている。offset はヘッダー先頭からの位置。
+     */
+    TruncatedOption { kind: u8, offset: usize },
+
+    /*
+     * option の length フィールドが kind に対して不正。
+     */
+    BadOptionLength { kind: u8, length: u8, offset: usize },
+
+    /*
+     * 擬似ヘッダーを含めたチェックサムが一致しない。
+     */
+    ChecksumMismatch { expected: u16, found: u16 },
+}
+
+impl TcpHeaderDecodeError {
+    /*
+     * 問題のあったフィールド名。
+     */
+    pub fn field(&self) -> Option<&'static str> {
+        match self {
+            TcpHeaderDecodeError::InputTooShort => None,
+            TcpHeaderDecodeError::BadDataOffset { .. } => Some("data_offset"),
+            TcpHeaderDecodeError::ReservedBitsSet { .. } => Some("reserved"),
+            TcpHeaderDecodeError::TruncatedOption { .. }
+            | TcpHeaderDecodeError::BadOptionLength { .. } => Some("options"),
+            TcpHeaderDecodeError::ChecksumMismatch { .. } => Some("checksum"),
+        }
+    }
+
+    /*
+     * 問題のあったフィールドの、TCP ヘッダー先頭からの byte offset.
+     */
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            TcpHeaderDecodeError::InputTooShort => None,
+            TcpHeaderDecodeError::BadDataOffset { .. } => Some(12),
+            TcpHeaderDecodeError::ReservedBitsSet { .. } => Some(12),
+            TcpHeaderDecodeError::TruncatedOption { offset, .. }
+            | TcpHeaderDecodeError::BadOptionLength { offset, .. } => Some(*offset),
+            TcpHeaderDecodeError::ChecksumMismatch { .. } => Some(16),
+        }
+    }
 }
 
 impl fmt::Display for TcpHeaderDecodeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             TcpHeaderDecodeError::InputTooShort => write!(f, "Input too short."),
-            TcpHeaderDecodeError::InvalidFieldValue(_todo) => write!(f, "Invalid field value."),
+            TcpHeaderDecodeError::BadDataOffset {
+                data_offset,
+                buffer_len,
+            } => write!(
+                f,
+                "Bad data offset at byte 12: data_offset={} (header length {} bytes, minimum 20) but buffer length is {}.",
+                data_offset,
+                usize::from(*data_offset) * 4,
+                buffer_len
+            ),
+            TcpHeaderDecodeError::ReservedBitsSet { reserved } => write!(
+                f,
+                "Reserved bits set at byte 12: expected 0, found {:#06b}.",
+                reserved
+            ),
+            TcpHeaderDecodeError::TruncatedOption { kind, offset } => write!(
+                f,
+                "Option kind={} at byte {} runs past the end of the header.",
+                kind, offset
+            ),
+            TcpHeaderDecodeError::BadOptionLength {
+                kind,
+                length,
+                offset,
+            } => write!(
+                f,
+                "Option kind={} at byte {} has an invalid length {}.",
+                kind, offset, length
+            ),
+            TcpHeaderDecodeError::ChecksumMismatch { expected, found } => write!(
+                f,
+                "Checksum mismatch at byte 16: expected {:#06x}, found {:#06x}.",
+                expected, found
+            ),
         }
     }
 }
 
-impl Error for TcpHeaderDecodeError {}
+#[cfg(feature = "std")]
+impl std::error::Error for TcpHeaderDecodeError {}
 
 #[derive(Debug, Clone)]
 pub struct TcpHeader {
@@ -87,7 +182,7 @@ pub struct TcpHeader {
     options: Vec<u8>,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
 pub struct ControlBits {
     cwr: bool,
     ece: bool,
@@ -99,16 +194,127 @@ pub struct ControlBits {
     fin: bool,
 }
 
+pub const TCP_HEADER_MIN_LEN: usize = 20;
+
 impl TcpHeader {
-    fn get_data_offset(&self) -> u8 {
+    /*
+     * Options なし、フラグなしのヘッダーを作る。
+     */
+    pub fn new(source_port: u16, destination_port: u16) -> Self {
+        Self {
+            source_port,
+            destination_port,
+            sequence_number: 0,
+            acknowledgment_number: 0,
+            data_offset: (TCP_HEADER_MIN_LEN / 4) as u8,
+            reserved: 0,
+            control_bits: ControlBits::default(),
+            window: 0,
+            checksum: 0,
+            urgent_pointer: 0,
+            options: Vec::new(),
+        }
+    }
+
+    pub fn get_source_port(&self) -> u16 {
+        self.source_port
+    }
+
+    pub fn set_source_port(&mut self, source_port: u16) {
+        self.source_port = source_port;
+    }
+
+    pub fn get_destination_port(&self) -> u16 {
+        self.destination_port
+    }
+
+    pub fn set_destination_port(&mut self, destination_port: u16) {
+        self.destination_port = destination_port;
+    }
+
+    pub fn get_sequence_number(&self) -> u32 {
+        self.sequence_number
+    }
+
+    pub fn set_sequence_number(&mut self, sequence_number: u32) {
+        self.sequence_number = sequence_number;
+    }
+
+    pub fn get_acknowledgment_number(&self) -> u32 {
+        self.acknowledgment_number
+    }
+
+    pub fn set_acknowledgment_number(&mut self, acknowledgment_number: u32) {
+        self.acknowledgment_number = acknowledgment_number;
+    }
+
+    pub fn get_data_offset(&self) -> u8 {
         self.data_offset & 0b0000_1111
     }
 
     /*
-     * NOTE: `0`であるべきって仕様.
+     * Data Offset から求めたヘッダーの byte 長。
+     */
+    pub fn get_header_length(&self) -> usize {
+        usize::from(self.get_data_offset()) * 4
+    }
+
+    pub fn get_control_bits(&self) -> ControlBits {
+        self.control_bits
+    }
+
+    pub fn set_control_bits(&mut self, control_bits: ControlBits) {
+        self.control_bits = control_bits;
+    }
+
+    pub fn get_window(&self) -> u16 {
+        self.window
+    }
+
+    pub fn set_window(&mut self, window: u16) {
+        self.window = window;
+    }
+
+    pub fn get_checksum(&self) -> u16 {
+        self.checksum
+    }
+
+    /*
+     * NOTE: 通常は`TcpPacket::encode`が計算して埋めるので、直接呼ぶ必要はない。
+     */
+    pub fn set_checksum(&mut self, checksum: u16) {
+        self.checksum = checksum;
+    }
+
+    pub fn get_urgent_pointer(&self) -> u16 {
+        self.urgent_pointer
+    }
+
+    pub fn set_urgent_pointer(&mut self, urgent_pointer: u16) {
+        self.urgent_pointer = urgent_pointer;
+    }
+
+    /*
+     * NOTE: `0`であるべきって仕様. decode 時に検証している。
      */
     fn get_reserved(&self) -> u8 {
-        0
+        self.reserved & 0b0000_1111
+    }
+
+    /*
+     * options を解釈したもの。decode 時に検証済みなので、壊れていることはない想定。
+     */
+    pub fn get_options(&self) -> Vec<TcpOption> {
+        TcpOption::decode_all(&self.options, 20).unwrap_or_default()
+    }
+
+    /*
+     * Data Offset も合わせて更新する。option 領域に収まらなければ、ヘッダーは変えずにエラーを返す。
+     */
+    pub fn set_options(&mut self, options: &[TcpOption]) -> Result<(), TcpOptionEncodeError> {
+        self.options = TcpOption::encode_all(options)?;
+        self.data_offset = ((TCP_HEADER_MIN_LEN + self.options.len()) / 4) as u8;
+        Ok(())
     }
 
     pub fn encode(&self) -> Vec<u8> {
@@ -137,8 +343,41 @@ impl TcpHeader {
         buffer
     }
 
+    /*
+     * NOTE: チェックサムの検証には擬似ヘッダー（IP アドレス）が必要なので、ここでは検証しない。
+     *       受信したセグメントは `decode_with_options` を使うこと。
+     */
     pub fn decode(buffer: &[u8]) -> Result<Self, TcpHeaderDecodeError> {
+        Self::decode_fields(buffer, &DecodeOptions::strict()).map(|(header, _)| header)
+    }
+
+    /*
+     * `segment` は TCP ヘッダーから payload の終わりまで。
+     */
+    pub fn decode_with_options(
+        segment: &[u8],
+        source_address: Ipv4Address,
+        destination_address: Ipv4Address,
+        options: &DecodeOptions,
+    ) -> Result<(Self, Vec<DecodeWarning>), TcpHeaderDecodeError> {
+        let (header, mut warnings) = Self::decode_fields(segment, options)?;
+
+        if options.verifies_checksums() {
+            match Self::validate_checksum(segment, source_address, destination_address) {
+                Err(error) if options.is_lenient() => warnings.push(DecodeWarning::Tcp(error)),
+                result => result?,
+            }
+        }
+
+        Ok((header, warnings))
+    }
+
+    fn decode_fields(
+        buffer: &[u8],
+        options: &DecodeOptions,
+    ) -> Result<(Self, Vec<DecodeWarning>), TcpHeaderDecodeError> {
         Self::validate_buffer_length(buffer)?;
+        let mut warnings = Vec::new();
 
         let source_port = BigEndian::read_u16(&buffer[0..2]);
         let destination_port = BigEndian::read_u16(&buffer[2..4]);
@@ -149,7 +388,10 @@ impl TcpHeader {
         Self::validate_data_offset(data_offset, buffer)?;
 
         let reserved = buffer[12] & 0b0000_1111;
-        Self::validate_reserved(reserved)?;
+        match Self::validate_reserved(reserved) {
+            Err(error) if options.is_lenient() => warnings.push(DecodeWarning::Tcp(error)),
+            result => result?,
+        }
 
         let control_bits = ControlBits::decode(buffer[13]).unwrap();
 
@@ -157,12 +399,11 @@ impl TcpHeader {
         let checksum = BigEndian::read_u16(&buffer[16..18]);
         let urgent_pointer = BigEndian::read_u16(&buffer[18..20]);
 
-        /*
-         * TODO: 一旦後回し。
-         */
-        let options = vec![];
+        let header_length = usize::from(data_offset) * 4;
+        let options = buffer[20..header_length].to_vec();
+        TcpOption::decode_all(&options, 20)?;
 
-        Ok(Self {
+        let header = Self {
             source_port,
             destination_port,
             sequence_number,
@@ -174,7 +415,9 @@ impl TcpHeader {
             checksum,
             urgent_pointer,
             options,
-        })
+        };
+
+        Ok((header, warnings))
     }
 
     fn validate_buffer_length(buffer: &[u8]) -> Result<(), TcpHeaderDecodeError> {
@@ -185,32 +428,47 @@ impl TcpHeader {
         }
     }
 
-    /*
-     * TODO: options を考慮に入れてバリデーションをする。
-     */
     fn validate_data_offset(data_offset: u8, buffer: &[u8]) -> Result<(), TcpHeaderDecodeError> {
-        if data_offset < 5 {
-            Err(TcpHeaderDecodeError::InvalidFieldValue(format!(
-                "Data Offset field must be equal or greater than 5. data_offset={}",
-                data_offset
-            )))
-        } else if buffer.len() < data_offset as usize * 4 {
-            Err(TcpHeaderDecodeError::InvalidFieldValue(format!(
-                "Expected buffer length to be at least {} but was {}",
-                data_offset as usize * 4,
-                buffer.len()
-            )))
+        if data_offset < 5 || buffer.len() < data_offset as usize * 4 {
+            Err(TcpHeaderDecodeError::BadDataOffset {
+                data_offset,
+                buffer_len: buffer.len(),
+            })
         } else {
             Ok(())
         }
     }
 
+    fn validate_checksum(
+        segment: &[u8],
+        source_address: Ipv4Address,
+        destination_address: Ipv4Address,
+    ) -> Result<(), TcpHeaderDecodeError> {
+        let pseudo_header =
+            TcpPseudoHeader::from_addresses(source_address, destination_address, segment.len())
+                .encode();
+
+        /*
+         * 正しいセグメントであれば、チェックサムを含めた全体の和の 1 の補数は 0 になる。
+         */
+        if calculate_internet_checksum_of(&[&pseudo_header, segment]) == 0 {
+            return Ok(());
+        }
+
+        let found = BigEndian::read_u16(&segment[16..18]);
+        let expected = calculate_internet_checksum_of(&[
+            &pseudo_header,
+            &segment[..16],
+            &[0, 0],
+            &segment[18..],
+        ]);
+
+        Err(TcpHeaderDecodeError::ChecksumMismatch { expected, found })
+    }
+
     fn validate_reserved(reserved: u8) -> Result<(), TcpHeaderDecodeError> {
         if reserved != 0 {
-            Err(TcpHeaderDecodeError::InvalidFieldValue(format!(
-                "Reserved field must be zero. reserved={}",
-                reserved
-            )))
+            Err(TcpHeaderDecodeError::ReservedBitsSet { reserved })
         } else {
             Ok(())
         }
@@ -218,7 +476,71 @@ impl TcpHeader {
 }
 
 impl ControlBits {
-    fn encode(&self) -> u8 {
+    pub fn get_cwr(&self) -> bool {
+        self.cwr
+    }
+
+    pub fn set_cwr(&mut self, cwr: bool) {
+        self.cwr = cwr;
+    }
+
+    pub fn get_ece(&self) -> bool {
+        self.ece
+    }
+
+    pub fn set_ece(&mut self, ece: bool) {
+        self.ece = ece;
+    }
+
+    pub fn get_urg(&self) -> bool {
+        self.urg
+    }
+
+    pub fn set_urg(&mut self, urg: bool) {
+        self.urg = urg;
+    }
+
+    pub fn get_ack(&self) -> bool {
+        self.ack
+    }
+
+    pub fn set_ack(&mut self, ack: bool) {
+        self.ack = ack;
+    }
+
+    pub fn get_psh(&self) -> bool {
+        self.psh
+    }
+
+    pub fn set_psh(&mut self, psh: bool) {
+        self.psh = psh;
+    }
+
+    pub fn get_rst(&self) -> bool {
+        self.rst
+    }
+
+    pub fn set_rst(&mut self, rst: bool) {
+        self.rst = rst;
+    }
+
+    pub fn get_syn(&self) -> bool {
+        self.syn
+    }
+
+    pub fn set_syn(&mut self, syn: bool) {
+        self.syn = syn;
+    }
+
+    pub fn get_fin(&self) -> bool {
+        self.fin
+    }
+
+    pub fn set_fin(&mut self, fin: bool) {
+        self.fin = fin;
+    }
+
+    pub fn encode(&self) -> u8 {
         (u8::from(self.cwr) << 7)
             | (u8::from(self.ece) << 6)
             | (u8::from(self.urg) << 5)
@@ -229,7 +551,7 @@ impl ControlBits {
             | u8::from(self.fin)
     }
 
-    fn decode(byte: u8) -> Result<Self, TcpHeaderDecodeError> {
+    pub fn decode(byte: u8) -> Result<Self, TcpHeaderDecodeError> {
         let cwr = ((byte & 0b1000_0000) >> 7) == 1;
         let ece = ((byte & 0b0100_0000) >> 6) == 1;
         let urg = ((byte & 0b0010_0000) >> 5) == 1;
@@ -252,6 +574,29 @@ impl ControlBits {
     }
 }
 
+impl Layer for TcpHeader {
+    type Error = TcpHeaderDecodeError;
+
+    fn decode(buffer: &[u8]) -> Result<Self, Self::Error> {
+        TcpHeader::decode(buffer)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        TcpHeader::encode(self)
+    }
+
+    fn header_len(&self) -> usize {
+        self.get_header_length()
+    }
+
+    /*
+     * TCP の上はアプリケーションのデータなので、次の層はない。
+     */
+    fn next_protocol(&self) -> Option<u8> {
+        None
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -348,4 +693,250 @@ mod tests {
         assert!(result.is_err());
         assert!(matches!(result, Err(TcpHeaderDecodeError::InputTooShort)));
     }
+
+    fn sample_header_bytes(options: &[u8]) -> Vec<u8> {
+        let control_bits = ControlBits {
+            cwr: false,
+            ece: false,
+            urg: false,
+            ack: true,
+            psh: false,
+            rst: false,
+            syn: true,
+            fin: false,
+        };
+
+        let tcp_header = TcpHeader {
+            source_port: 5432,
+            destination_port: 3306,
+            sequence_number: 1,
+            acknowledgment_number: 2,
+            data_offset: (5 + options.len() / 4) as u8,
+            reserved: 0,
+            control_bits,
+            window: 1000,
+            checksum: 0,
+            urgent_pointer: 0,
+            options: options.to_vec(),
+        };
+        tcp_header.encode()
+    }
+
+    #[test]
+    fn test_decode_options() {
+        let options = TcpOption::encode_all(&[
+            TcpOption::MaximumSegmentSize(1460),
+            TcpOption::SackPermitted,
+            TcpOption::Timestamps {
+                tsval: 100,
+                tsecr: 0,
+            },
+            TcpOption::NoOperation,
+            TcpOption::WindowScale(7),
+            TcpOption::UserTimeout {
+                minutes: true,
+                timeout: 30,
+            },
+        ])
+        .unwrap();
+        let buffer = sample_header_bytes(&options);
+
+        let tcp_header = TcpHeader::decode(&buffer).unwrap();
+        assert_eq!(
+            tcp_header.get_options(),
+            vec![
+                TcpOption::MaximumSegmentSize(1460),
+                TcpOption::SackPermitted,
+                TcpOption::Timestamps {
+                    tsval: 100,
+                    tsecr: 0
+                },
+                TcpOption::NoOperation,
+                TcpOption::WindowScale(7),
+                TcpOption::UserTimeout {
+                    minutes: true,
+                    timeout: 30,
+                },
+            ]
+        );
+        assert_eq!(tcp_header.encode(), buffer);
+    }
+
+    #[test]
+    fn test_encode_options_padding_and_limits() {
+        /*
+         * 32bits 境界までは 0 (End of Option List) で埋める。
+         */
+        assert_eq!(
+            TcpOption::encode_all(&[TcpOption::WindowScale(7)]),
+            Ok(vec![3, 3, 7, 0])
+        );
+
+        let blocks: Vec<(u32, u32)> = (0..32).map(|i| (i * 10, i * 10 + 5)).collect();
+        assert_eq!(
+            TcpOption::encode_all(&[TcpOption::Sack(blocks[..4].to_vec())])
+                .map(|bytes| bytes.len()),
+            Ok(36)
+        );
+        for count in [5, 32] {
+            assert_eq!(
+                TcpOption::encode_all(&[TcpOption::Sack(blocks[..count].to_vec())]),
+                Err(TcpOptionEncodeError::TooManySackBlocks { count })
+            );
+        }
+        assert_eq!(
+            TcpOption::encode_all(&[TcpOption::Unknown {
+                kind: 254,
+                data: vec![0; 300],
+            }]),
+            Err(TcpOptionEncodeError::OptionTooLong {
+                kind: 254,
+                length: 302
+            })
+        );
+
+        /*
+         * 収まらなければ、ヘッダーは変えない。
+         */
+        let mut tcp_header = TcpHeader::new(5432, 3306);
+        tcp_header
+            .set_options(&[TcpOption::MaximumSegmentSize(1460)])
+            .unwrap();
+        assert_eq!(
+            tcp_header.set_options(&[
+                TcpOption::Sack(blocks[..4].to_vec()),
+                TcpOption::Timestamps { tsval: 1, tsecr: 2 },
+            ]),
+            Err(TcpOptionEncodeError::OptionsTooLong { length: 44 })
+        );
+        assert_eq!(
+            tcp_header.get_options(),
+            vec![TcpOption::MaximumSegmentSize(1460)]
+        );
+        assert_eq!(tcp_header.encode().len(), 24);
+    }
+
+    #[test]
+    fn test_decode_bad_data_offset() {
+        let mut buffer = sample_header_bytes(&[]);
+        buffer[12] = 4 << 4;
+        let error = TcpHeader::decode(&buffer).unwrap_err();
+        assert_eq!(
+            error,
+            TcpHeaderDecodeError::BadDataOffset {
+                data_offset: 4,
+                buffer_len: 20
+            }
+        );
+        assert_eq!(error.offset(), Some(12));
+
+        buffer[12] = 6 << 4;
+        assert_eq!(
+            TcpHeader::decode(&buffer).unwrap_err(),
+            TcpHeaderDecodeError::BadDataOffset {
+                data_offset: 6,
+                buffer_len: 20
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_reserved_bits_set() {
+        let mut buffer = sample_header_bytes(&[]);
+        buffer[12] |= 0b0000_0010;
+        assert_eq!(
+            TcpHeader::decode(&buffer).unwrap_err(),
+            TcpHeaderDecodeError::ReservedBitsSet {
+                reserved: 0b0000_0010
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_truncated_option() {
+        /*
+         * NOP, NOP, NOP の後に、長さ 10 の Timestamps が 1byte しか入っていない。
+         */
+        let buffer = sample_header_bytes(&[1, 1, 1, 8]);
+        let error = TcpHeader::decode(&buffer).unwrap_err();
+        assert_eq!(
+            error,
+            TcpHeaderDecodeError::TruncatedOption {
+                kind: 8,
+                offset: 23
+            }
+        );
+        assert_eq!(error.field(), Some("options"));
+
+        let buffer = sample_header_bytes(&[2, 4, 5, 180, 8, 10, 0, 0]);
+        assert_eq!(
+            TcpHeader::decode(&buffer).unwrap_err(),
+            TcpHeaderDecodeError::TruncatedOption {
+                kind: 8,
+                offset: 24
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_bad_option_length() {
+        let buffer = sample_header_bytes(&[2, 3, 5, 180]);
+        assert_eq!(
+            TcpHeader::decode(&buffer).unwrap_err(),
+            TcpHeaderDecodeError::BadOptionLength {
+                kind: 2,
+                length: 3,
+                offset: 20
+            }
+        );
+    }
+
+    fn sample_segment(source: Ipv4Address, destination: Ipv4Address) -> Vec<u8> {
+        let mut segment = sample_header_bytes(&[]);
+        segment.extend_from_slice(b"hello");
+
+        let pseudo_header =
+            TcpPseudoHeader::from_addresses(source, destination, segment.len()).encode();
+        let checksum = calculate_internet_checksum_of(&[&pseudo_header, &segment]);
+        segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+        segment
+    }
+
+    #[test]
+    fn test_decode_with_options_checksum() {
+        let source = [10, 0, 0, 1];
+        let destination = [10, 0, 0, 2];
+        let segment = sample_segment(source, destination);
+
+        let (_, warnings) =
+            TcpHeader::decode_with_options(&segment, source, destination, &DecodeOptions::strict())
+                .unwrap();
+        assert!(warnings.is_empty());
+
+        /*
+         * 宛先アドレスが違えば擬似ヘッダーが変わるので、チェックサムは一致しない。
+         */
+        let other = [10, 0, 0, 3];
+        let result =
+            TcpHeader::decode_with_options(&segment, source, other, &DecodeOptions::strict());
+        let error = result.unwrap_err();
+        assert!(matches!(
+            error,
+            TcpHeaderDecodeError::ChecksumMismatch { .. }
+        ));
+
+        let (_, warnings) =
+            TcpHeader::decode_with_options(&segment, source, other, &DecodeOptions::lenient())
+                .unwrap();
+        assert_eq!(warnings, vec![DecodeWarning::Tcp(error)]);
+
+        let (_, warnings) = TcpHeader::decode_with_options(
+            &segment,
+            source,
+            other,
+            &DecodeOptions::checksum_offloaded(),
+        )
+        .unwrap();
+        assert!(warnings.is_empty());
+    }
 }
@@ -0,0 +1,310 @@
+use crate::transmission_control_protocol::TcpHeaderDecodeError;
+use alloc::vec::Vec;
+use byteorder::{BigEndian, ByteOrder};
+use core::fmt;
+
+/*
+ * See: https://www.iana.org/assignments/tcp-parameters/tcp-parameters.xhtml
+ */
+pub const END_OF_OPTION_LIST_KIND: u8 = 0;
+pub const NO_OPERATION_KIND: u8 = 1;
+pub const MAXIMUM_SEGMENT_SIZE_KIND: u8 = 2;
+pub const WINDOW_SCALE_KIND: u8 = 3;
+pub const SACK_PERMITTED_KIND: u8 = 4;
+pub const SACK_KIND: u8 = 5;
+pub const TIMESTAMPS_KIND: u8 = 8;
+pub const USER_TIMEOUT_KIND: u8 = 28;
+
+/*
+ * option 領域の最大長（Data Offset の最大 15 ワードからヘッダーの 20bytes を引いたもの）。
+ */
+pub const MAX_OPTIONS_LEN: usize = 40;
+
+/*
+ * 1 つの SACK option に入るブロックの最大数。2 + 8 * 4 = 34bytes で、option 領域に収まるのは 4 つまで。
+ */
+pub const MAX_SACK_BLOCKS: usize = 4;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TcpOptionEncodeError {
+    /*
+     * SACK のブロックが`MAX_SACK_BLOCKS`を超えている。
+     */
+    TooManySackBlocks { count: usize },
+
+    /*
+     * option の長さが length フィールド (1byte) に収まらない。
+     */
+    OptionTooLong { kind: u8, length: usize },
+
+    /*
+     * option 全体が`MAX_OPTIONS_LEN`を超えている。
+     */
+    OptionsTooLong { length: usize },
+}
+
+impl fmt::Display for TcpOptionEncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpOptionEncodeError::TooManySackBlocks { count } => write!(
+                f,
+                "Too many SACK blocks: {} (maximum {}).",
+                count, MAX_SACK_BLOCKS
+            ),
+            TcpOptionEncodeError::OptionTooLong { kind, length } => write!(
+                f,
+                "Option kind={} is {} bytes long, which does not fit in the length field.",
+                kind, length
+            ),
+            TcpOptionEncodeError::OptionsTooLong { length } => write!(
+                f,
+                "Options are {} bytes long (maximum {}).",
+                length, MAX_OPTIONS_LEN
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for TcpOptionEncodeError {}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum TcpOption {
+    EndOfOptionList,
+    NoOperation,
+    MaximumSegmentSize(u16),
+    WindowScale(u8),
+    SackPermitted,
+
+    /*
+     * (left edge, right edge) の組。
+     */
+    Sack(Vec<(u32, u32)>),
+
+    Timestamps { tsval: u32, tsecr: u32 },
+
+    /*
+     * RFC 5482 の User Timeout. `minutes`が G ビットで、true なら`timeout`は分、false なら秒（15 ビット）。
+     */
+    UserTimeout { minutes: bool, timeout: u16 },
+
+    /*
+     * 解釈できない option は中身をそのまま保持する。
+     */
+    Unknown { kind: u8, data: Vec<u8> },
+}
+
+impl TcpOption {
+    pub fn get_kind(&self) -> u8 {
+        match self {
+            TcpOption::EndOfOptionList => END_OF_OPTION_LIST_KIND,
+            TcpOption::NoOperation => NO_OPERATION_KIND,
+            TcpOption::MaximumSegmentSize(_) => MAXIMUM_SEGMENT_SIZE_KIND,
+            TcpOption::WindowScale(_) => WINDOW_SCALE_KIND,
+            TcpOption::SackPermitted => SACK_PERMITTED_KIND,
+            TcpOption::Sack(_) => SACK_KIND,
+            TcpOption::Timestamps { .. } => TIMESTAMPS_KIND,
+            TcpOption::UserTimeout { .. } => USER_TIMEOUT_KIND,
+            TcpOption::Unknown { kind, .. } => *kind,
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, TcpOptionEncodeError> {
+        let mut buffer = Vec::new();
+
+        match self {
+            TcpOption::EndOfOptionList => buffer.push(END_OF_OPTION_LIST_KIND),
+            TcpOption::NoOperation => buffer.push(NO_OPERATION_KIND),
+            TcpOption::MaximumSegmentSize(mss) => {
+                buffer.extend_from_slice(&[MAXIMUM_SEGMENT_SIZE_KIND, 4]);
+                buffer.extend_from_slice(&mss.to_be_bytes());
+            }
+            TcpOption::WindowScale(shift) => {
+                buffer.extend_from_slice(&[WINDOW_SCALE_KIND, 3, *shift]);
+            }
+            TcpOption::SackPermitted => buffer.extend_from_slice(&[SACK_PERMITTED_KIND, 2]),
+            TcpOption::Sack(blocks) => {
+                if blocks.len() > MAX_SACK_BLOCKS {
+                    return Err(TcpOptionEncodeError::TooManySackBlocks {
+                        count: blocks.len(),
+                    });
+                }
+                buffer.extend_from_slice(&[SACK_KIND, (2 + blocks.len() * 8) as u8]);
+                for (left, right) in blocks {
+                    buffer.extend_from_slice(&left.to_be_bytes());
+                    buffer.extend_from_slice(&right.to_be_bytes());
+                }
+            }
+            TcpOption::Timestamps { tsval, tsecr } => {
+                buffer.extend_from_slice(&[TIMESTAMPS_KIND, 10]);
+                buffer.extend_from_slice(&tsval.to_be_bytes());
+                buffer.extend_from_slice(&tsecr.to_be_bytes());
+            }
+            TcpOption::UserTimeout { minutes, timeout } => {
+                buffer.extend_from_slice(&[USER_TIMEOUT_KIND, 4]);
+                let value = u16::from(*minutes) << 15 | timeout & 0x7fff;
+                buffer.extend_from_slice(&value.to_be_bytes());
+            }
+            TcpOption::Unknown { kind, data } => {
+                let length = 2 + data.len();
+                let length =
+                    u8::try_from(length).map_err(|_| TcpOptionEncodeError::OptionTooLong {
+                        kind: *kind,
+                        length,
+                    })?;
+                buffer.extend_from_slice(&[*kind, length]);
+                buffer.extend_from_slice(data);
+            }
+        }
+
+        Ok(buffer)
+    }
+
+    /*
+     * option 列を 32bits 境界まで 0 (End of Option List) で埋めてエンコードする。
+     * RFC 9293 3.1: ヘッダーの最後の option の後ろのパディングは 0.
+     */
+    pub fn encode_all(options: &[TcpOption]) -> Result<Vec<u8>, TcpOptionEncodeError> {
+        let mut buffer = Vec::new();
+        for option in options {
+            buffer.extend(option.encode()?);
+        }
+        while !buffer.len().is_multiple_of(4) {
+            buffer.push(END_OF_OPTION_LIST_KIND);
+        }
+        if buffer.len() > MAX_OPTIONS_LEN {
+            return Err(TcpOptionEncodeError::OptionsTooLong {
+                length: buffer.len(),
+            });
+        }
+        Ok(buffer)
+    }
+
+    /*
+     * NOTE: `base_offset` は TCP ヘッダー先頭から見た options の開始位置（通常は 20）。
+     *       エラーの offset をヘッダー先頭基準で報告するために使う。
+     */
+    pub fn decode_all(
+        buffer: &[u8],
+        base_offset: usize,
+    ) -> Result<Vec<Self>, TcpHeaderDecodeError> {
+        let mut options = Vec::new();
+        let mut i = 0;
+
+        while i < buffer.len() {
+            let kind = buffer[i];
+            let offset = base_offset + i;
+
+            match kind {
+                END_OF_OPTION_LIST_KIND => {
+                    /*
+                     * EOL 以降はパディングなので読まない。
+                     */
+                    options.push(TcpOption::EndOfOptionList);
+                    break;
+                }
+                NO_OPERATION_KIND => {
+                    options.push(TcpOption::NoOperation);
+                    i += 1;
+                    continue;
+                }
+                _ => {}
+            }
+
+            if i + 1 >= buffer.len() {
+                return Err(TcpHeaderDecodeError::TruncatedOption { kind, offset });
+            }
+            let length = buffer[i + 1];
+            if length < 2 {
+                return Err(TcpHeaderDecodeError::BadOptionLength {
+                    kind,
+                    length,
+                    offset,
+                });
+            }
+            if i + usize::from(length) > buffer.len() {
+                return Err(TcpHeaderDecodeError::TruncatedOption { kind, offset });
+            }
+
+            let data = &buffer[i + 2..i + usize::from(length)];
+            let option = Self::decode_one(kind, length, data, offset)?;
+            options.push(option);
+
+            i += usize::from(length);
+        }
+
+        Ok(options)
+    }
+
+    fn decode_one(
+        kind: u8,
+        length: u8,
+        data: &[u8],
+        offset: usize,
+    ) -> Result<Self, TcpHeaderDecodeError> {
+        let bad_length = TcpHeaderDecodeError::BadOptionLength {
+            kind,
+            length,
+            offset,
+        };
+
+        match kind {
+            MAXIMUM_SEGMENT_SIZE_KIND => {
+                if length != 4 {
+                    return Err(bad_length);
+                }
+                Ok(TcpOption::MaximumSegmentSize(BigEndian::read_u16(data)))
+            }
+            WINDOW_SCALE_KIND => {
+                if length != 3 {
+                    return Err(bad_length);
+                }
+                Ok(TcpOption::WindowScale(data[0]))
+            }
+            SACK_PERMITTED_KIND => {
+                if length != 2 {
+                    return Err(bad_length);
+                }
+                Ok(TcpOption::SackPermitted)
+            }
+            SACK_KIND => {
+                if data.is_empty() || !data.len().is_multiple_of(8) {
+                    return Err(bad_length);
+                }
+                let blocks = data
+                    .chunks(8)
+                    .map(|block| {
+                        (
+                            BigEndian::read_u32(&block[0..4]),
+                            BigEndian::read_u32(&block[4..8]),
+                        )
+                    })
+                    .collect();
+                Ok(TcpOption::Sack(blocks))
+            }
+            TIMESTAMPS_KIND => {
+                if length != 10 {
+                    return Err(bad_length);
+                }
+                Ok(TcpOption::Timestamps {
+                    tsval: BigEndian::read_u32(&data[0..4]),
+                    tsecr: BigEndian::read_u32(&data[4..8]),
+                })
+            }
+            USER_TIMEOUT_KIND => {
+                if length != 4 {
+                    return Err(bad_length);
+                }
+                let value = BigEndian::read_u16(data);
+                Ok(TcpOption::UserTimeout {
+                    minutes: value & 0x8000 != 0,
+                    timeout: value & 0x7fff,
+                })
+            }
+            _ => Ok(TcpOption::Unknown {
+                kind,
+                data: data.to_vec(),
+            }),
+        }
+    }
+}
@@ -1,7 +1,80 @@
-use byteorder::{BigEndian, ByteOrder};
+use alloc::vec::Vec;
+use core::fmt;
 
-use crate::internet_protocol::{Ipv4Address, Ipv4Header};
-use crate::transmission_control_protocol::{tcp_pseudo_header::TcpPseudoHeader, TcpHeader};
+use crate::checksum::calculate_internet_checksum_of;
+use crate::decode_options::{DecodeOptions, DecodeWarning};
+use crate::internet_protocol::{Ipv4Address, Ipv4Header, Ipv4HeaderDecodeError};
+use crate::transmission_control_protocol::{
+    tcp_pseudo_header::TcpPseudoHeader, TcpHeader, TcpHeaderDecodeError, TCP_PROTOCOL_NUMBER,
+};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TcpPacketDecodeError {
+    Ipv4(Ipv4HeaderDecodeError),
+    Tcp(TcpHeaderDecodeError),
+
+    /*
+     * IP ヘッダーの protocol が TCP ではない。
+     */
+    NotTcp(u8),
+
+    /*
+     * フラグメントされた datagram. 再構築してから decode すること。
+     */
+    Fragmented,
+}
+
+impl From<Ipv4HeaderDecodeError> for TcpPacketDecodeError {
+    fn from(error: Ipv4HeaderDecodeError) -> Self {
+        TcpPacketDecodeError::Ipv4(error)
+    }
+}
+
+impl From<TcpHeaderDecodeError> for TcpPacketDecodeError {
+    fn from(error: TcpHeaderDecodeError) -> Self {
+        TcpPacketDecodeError::Tcp(error)
+    }
+}
+
+impl fmt::Display for TcpPacketDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpPacketDecodeError::Ipv4(error) => write!(f, "IPv4: {}", error),
+            TcpPacketDecodeError::Tcp(error) => write!(f, "TCP: {}", error),
+            TcpPacketDecodeError::NotTcp(protocol) => {
+                write!(f, "Not a TCP datagram. protocol={}", protocol)
+            }
+            TcpPacketDecodeError::Fragmented => write!(f, "Fragmented datagram."),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for TcpPacketDecodeError {}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TcpPacketEncodeError {
+    /*
+     * IP ヘッダー、TCP ヘッダー、payload の合計が total_length (2bytes) に収まらない。
+     */
+    DatagramTooLong { length: usize },
+}
+
+impl fmt::Display for TcpPacketEncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpPacketEncodeError::DatagramTooLong { length } => write!(
+                f,
+                "Datagram is {} bytes long (maximum {}).",
+                length,
+                u16::MAX
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for TcpPacketEncodeError {}
 
 #[derive(Debug, Clone)]
 pub struct TcpPacket {
@@ -11,6 +84,26 @@ pub struct TcpPacket {
 }
 
 impl TcpPacket {
+    pub fn new(ip_v4_header: Ipv4Header, tcp_header: TcpHeader, payload: Vec<u8>) -> Self {
+        Self {
+            ip_v4_header,
+            tcp_header,
+            payload,
+        }
+    }
+
+    pub fn get_ipv4_header(&self) -> &Ipv4Header {
+        &self.ip_v4_header
+    }
+
+    pub fn get_tcp_header(&self) -> &TcpHeader {
+        &self.tcp_header
+    }
+
+    pub fn get_payload(&self) -> &[u8] {
+        &self.payload
+    }
+
     pub fn get_source_address(&self) -> Ipv4Address {
         self.ip_v4_header.get_source_address()
     }
@@ -28,55 +121,221 @@ impl TcpPacket {
     }
 
     pub fn calculate_checksum(&self) -> u16 {
-        let tcp_pseudo_header = TcpPseudoHeader::new(&self);
+        let tcp_pseudo_header = TcpPseudoHeader::new(self);
         let tcp_pseudo_header_bytes = tcp_pseudo_header.encode();
-        let tcp_header_bytes = self.tcp_header.encode();
+
+        /*
+         * 注意：計算する前に、Checksum はゼロにしておく必要がある。
+         */
+        let mut tcp_header = self.tcp_header.clone();
+        tcp_header.set_checksum(0);
+        let tcp_header_bytes = tcp_header.encode();
 
         /*
          * TCP擬似ヘッダー、TCPヘッダー、Payload の順番で結合して計算する.
          */
-        let mut buffer = tcp_pseudo_header_bytes;
+        calculate_internet_checksum_of(&[
+            &tcp_pseudo_header_bytes,
+            &tcp_header_bytes,
+            &self.payload,
+        ])
+    }
+
+    /*
+     * IP ヘッダーの total_length と header checksum, TCP の checksum を埋めてエンコードする。
+     * total_length に収まらない大きさなら、切り詰めずにエラーを返す。
+     */
+    pub fn encode(&self) -> Result<Vec<u8>, TcpPacketEncodeError> {
+        let mut tcp_header = self.tcp_header.clone();
+        tcp_header.set_checksum(self.calculate_checksum());
+        let tcp_header_bytes = tcp_header.encode();
+
+        let total_length =
+            self.ip_v4_header.get_header_length() + tcp_header_bytes.len() + self.payload.len();
+        let total_length =
+            u16::try_from(total_length).map_err(|_| TcpPacketEncodeError::DatagramTooLong {
+                length: total_length,
+            })?;
+        let mut ip_v4_header = self.ip_v4_header.clone();
+        ip_v4_header.set_total_length(total_length);
+
+        let mut buffer = ip_v4_header.encode();
         buffer.extend_from_slice(&tcp_header_bytes);
         buffer.extend_from_slice(&self.payload);
+        Ok(buffer)
+    }
+
+    /*
+     * IPv4 datagram のバイト列から decode する。チェックサムも検証する。
+     */
+    pub fn decode(buffer: &[u8]) -> Result<Self, TcpPacketDecodeError> {
+        Self::decode_with_options(buffer, &DecodeOptions::default()).map(|(packet, _)| packet)
+    }
+
+    pub fn decode_with_options(
+        buffer: &[u8],
+        options: &DecodeOptions,
+    ) -> Result<(Self, Vec<DecodeWarning>), TcpPacketDecodeError> {
+        let (ip_v4_header, mut warnings) = Ipv4Header::decode_with_options(buffer, options)?;
+
+        if ip_v4_header.get_protocol() != TCP_PROTOCOL_NUMBER {
+            return Err(TcpPacketDecodeError::NotTcp(ip_v4_header.get_protocol()));
+        }
+        if ip_v4_header.is_fragment() {
+            return Err(TcpPacketDecodeError::Fragmented);
+        }
+        ip_v4_header.validate_total_length(buffer.len())?;
 
         /*
-         * チェックサムの計算は 16bits 単位で計算をする。
-         * もし buffer.len() が奇数（＝8bitだけ余ってる）の場合は、0u8 を追加して長さを合わせる。
-         *
-         * payload のところでずれる可能性があるのかな。ヘッダーは（有効なものなら）32bits単位のヘッダーとして
-         * 仕様が決められているから。payload はその限りじゃないと思うし。
+         * IHL の後ろから total_length までが TCP セグメント。
          */
-        if buffer.len() % 2 == 0 {
-            buffer.push(0);
+        let segment = ip_v4_header.get_payload(buffer);
+        let (tcp_header, tcp_warnings) = TcpHeader::decode_with_options(
+            segment,
+            ip_v4_header.get_source_address(),
+            ip_v4_header.get_destination_address(),
+            options,
+        )?;
+        warnings.extend(tcp_warnings);
+
+        let payload = segment[tcp_header.get_header_length()..].to_vec();
+
+        Ok((
+            Self {
+                ip_v4_header,
+                tcp_header,
+                payload,
+            },
+            warnings,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transmission_control_protocol::tcp_option::TcpOption;
+    use crate::transmission_control_protocol::ControlBits;
+    use alloc::vec;
+
+    fn sample_packet(payload: &[u8]) -> TcpPacket {
+        let ip_v4_header = Ipv4Header::new([10, 0, 0, 1], [10, 0, 0, 2], TCP_PROTOCOL_NUMBER);
+
+        let mut control_bits = ControlBits::default();
+        control_bits.set_syn(true);
+        let mut tcp_header = TcpHeader::new(5432, 3306);
+        tcp_header.set_sequence_number(1);
+        tcp_header.set_control_bits(control_bits);
+        tcp_header.set_window(1000);
+        tcp_header
+            .set_options(&[TcpOption::MaximumSegmentSize(1460)])
+            .unwrap();
+
+        TcpPacket::new(ip_v4_header, tcp_header, payload.to_vec())
+    }
+
+    /*
+     * 16bits ごとの 1 の補数和。奇数長なら、最後のバイトの後ろを 0 で埋める。
+     */
+    fn sum_of_words(bytes: &[u8]) -> u16 {
+        let mut sum = 0u32;
+        for word in bytes.chunks(2) {
+            let last = word.get(1).copied().unwrap_or(0);
+            sum += u32::from(u16::from_be_bytes([word[0], last]));
+        }
+        while sum > 0xFFFF {
+            sum = (sum & 0xFFFF) + (sum >> 16);
         }
+        sum as u16
+    }
 
+    #[test]
+    fn test_encode_decode_round_trip() {
         /*
-         * 16bits word に変換する。
-         *
-         * Big endian で変換。
+         * 奇数長の payload でチェックサムのパディングも確認する。
          */
-        let mut words = Vec::new();
-        for i in (0..buffer.len()).step_by(2) {
-            let word = BigEndian::read_u16(&buffer[i..(i + 2)]);
-            words.push(word);
-        }
+        let bytes = sample_packet(b"hello").encode().unwrap();
+        assert_eq!(bytes.len(), 20 + 24 + 5);
+        assert_eq!(u16::from_be_bytes([bytes[2], bytes[3]]), 49);
+
+        let packet = TcpPacket::decode(&bytes).unwrap();
+        assert_eq!(packet.get_payload(), b"hello");
+        assert_eq!(packet.get_tcp_header().get_source_port(), 5432);
+        assert!(packet.get_tcp_header().get_control_bits().get_syn());
+        assert_eq!(
+            packet.get_tcp_header().get_options(),
+            vec![TcpOption::MaximumSegmentSize(1460)]
+        );
+        assert_eq!(
+            packet.get_tcp_header().get_checksum(),
+            packet.calculate_checksum()
+        );
+        assert_eq!(packet.encode().unwrap(), bytes);
 
         /*
-         * 全てのワードを足し算する。
-         * オーバーフローを考慮して、32bits で計算結果を保持する。
+         * 疑似ヘッダーとセグメント全体の和は 0xFFFF になる。
          */
-        let mut sum = 0u32;
-        for &word in &words {
-            sum = sum.wrapping_add(u32::from(word));
-        }
+        let mut summed = TcpPseudoHeader::new(&packet).encode();
+        summed.extend_from_slice(&bytes[20..]);
+        assert_eq!(sum_of_words(&summed), 0xFFFF);
+    }
 
+    #[test]
+    fn test_encode_too_long() {
         /*
-         * オーバーフローした分を end-around carry して加え戻す
+         * ヘッダー 44bytes と合わせて、ちょうど 65535bytes までは encode できる。
          */
-        while sum > 0xFFFF {
-            sum = (sum & 0xFFFF) + (sum >> 16);
-        }
+        let bytes = sample_packet(&vec![0; 65535 - 44]).encode().unwrap();
+        assert_eq!(u16::from_be_bytes([bytes[2], bytes[3]]), u16::MAX);
+
+        assert_eq!(
+            sample_packet(&vec![0; 65536 - 44]).encode().unwrap_err(),
+            TcpPacketEncodeError::DatagramTooLong { length: 65536 }
+        );
+    }
+
+    #[test]
+    fn test_decode_ignores_trailing_padding() {
+        let mut bytes = sample_packet(b"").encode().unwrap();
+        bytes.extend_from_slice(&[0; 6]);
+
+        let packet = TcpPacket::decode(&bytes).unwrap();
+        assert!(packet.get_payload().is_empty());
+    }
+
+    #[test]
+    fn test_decode_bad_tcp_checksum() {
+        let mut bytes = sample_packet(b"hello").encode().unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+
+        assert!(matches!(
+            TcpPacket::decode(&bytes),
+            Err(TcpPacketDecodeError::Tcp(
+                TcpHeaderDecodeError::ChecksumMismatch { .. }
+            ))
+        ));
+
+        let (packet, warnings) =
+            TcpPacket::decode_with_options(&bytes, &DecodeOptions::lenient()).unwrap();
+        assert_eq!(packet.get_payload(), b"helln");
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_decode_bad_total_length() {
+        let mut packet = sample_packet(b"hello");
+        let bytes = packet.encode().unwrap();
+        packet.ip_v4_header.set_total_length(100);
+        let mut truncated = packet.ip_v4_header.encode();
+        truncated.extend_from_slice(&bytes[20..]);
 
-        !(sum as u16)
+        assert_eq!(
+            TcpPacket::decode(&truncated).unwrap_err(),
+            TcpPacketDecodeError::Ipv4(Ipv4HeaderDecodeError::BadTotalLength {
+                total_length: 100,
+                buffer_len: 49
+            })
+        );
     }
 }
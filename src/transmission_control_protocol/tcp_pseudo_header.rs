@@ -1,5 +1,7 @@
 use crate::internet_protocol::Ipv4Address;
 use crate::transmission_control_protocol::{tcp_packet::TcpPacket, TCP_PROTOCOL_NUMBER};
+use alloc::vec;
+use alloc::vec::Vec;
 
 /*
  * NOTE: IPv4 を念頭に実装する。IPv6 の場合は違う。
@@ -36,19 +38,28 @@ impl TcpPseudoHeader {
     pub fn new(tcp_packet: &TcpPacket) -> Self {
         let source_address = tcp_packet.get_source_address();
         let destination_address = tcp_packet.get_destination_address();
-        let zero = 0u8;
-        let ptcl = TCP_PROTOCOL_NUMBER;
 
         // TODO: これでいいんだっけ。勢いで書いてて眠い。
-        let tcp_length = (tcp_packet.calculate_tcp_header_length()
-            + tcp_packet.calculate_payload_length()) as u16;
+        let tcp_length =
+            tcp_packet.calculate_tcp_header_length() + tcp_packet.calculate_payload_length();
 
+        Self::from_addresses(source_address, destination_address, tcp_length)
+    }
+
+    /*
+     * `tcp_length` は TCP ヘッダーと payload を合わせた byte 長。
+     */
+    pub fn from_addresses(
+        source_address: Ipv4Address,
+        destination_address: Ipv4Address,
+        tcp_length: usize,
+    ) -> Self {
         Self {
             source_address,
             destination_address,
-            zero,
-            ptcl,
-            tcp_length,
+            zero: 0u8,
+            ptcl: TCP_PROTOCOL_NUMBER,
+            tcp_length: tcp_length as u16,
         }
     }
 
@@ -57,8 +68,8 @@ impl TcpPseudoHeader {
 
         buffer[0..4].copy_from_slice(&self.source_address);
         buffer[4..8].copy_from_slice(&self.destination_address);
-        buffer[9] = self.zero;
-        buffer[10] = self.ptcl;
+        buffer[8] = self.zero;
+        buffer[9] = self.ptcl;
         buffer[10..12].copy_from_slice(&self.tcp_length.to_be_bytes());
 
         buffer
@@ -0,0 +1,124 @@
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::time::Duration;
+
+/*
+ * Linux の TUN デバイス。IPv4 datagram をそのまま読み書きできる。
+ *
+ * NOTE: 作成には CAP_NET_ADMIN が必要。アドレスの設定はしないので、例えば
+ *       `ip addr add 10.0.0.1/24 dev tun0 && ip link set tun0 up` を別途実行すること。
+ */
+pub struct TunDevice {
+    file: File,
+    name: String,
+}
+
+const TUN_CLONE_DEVICE: &str = "/dev/net/tun";
+
+/*
+ * `linux/if_tun.h`の値。
+ */
+const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+const IFF_TUN: libc::c_short = 0x0001;
+const IFF_NO_PI: libc::c_short = 0x1000;
+
+/*
+ * `struct ifreq`のうち、TUNSETIFF で使う部分。共用体の残りはパディングで埋める。
+ */
+#[repr(C)]
+struct InterfaceRequest {
+    name: [libc::c_char; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    padding: [u8; 22],
+}
+
+impl TunDevice {
+    /*
+     * `name`が空ならカーネルに名前を決めさせる（tun0, tun1, ...）。
+     */
+    pub fn open(name: &str) -> io::Result<Self> {
+        if name.len() >= libc::IFNAMSIZ || name.contains('\0') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid interface name: {:?}", name),
+            ));
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(TUN_CLONE_DEVICE)?;
+
+        let mut request = InterfaceRequest {
+            name: [0; libc::IFNAMSIZ],
+            flags: IFF_TUN | IFF_NO_PI,
+            padding: [0; 22],
+        };
+        for (dst, src) in request.name.iter_mut().zip(name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+
+        /*
+         * SAFETY: request は TUNSETIFF が読み書きする`struct ifreq`と同じ大きさで、呼び出し中は有効。
+         */
+        let result = unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, &mut request) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        /*
+         * SAFETY: カーネルは NUL 終端した名前を書き戻す。
+         */
+        let name = unsafe { CStr::from_ptr(request.name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+
+        Ok(Self { file, name })
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+        self.file.write_all(datagram)
+    }
+
+    /*
+     * datagram を 1 つ読む。`timeout`までに届かなければ`Ok(None)`. `None`なら届くまで待つ。
+     */
+    pub fn receive(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<Option<usize>> {
+        let mut poll_fd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = match timeout {
+            Some(timeout) => timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int,
+            None => -1,
+        };
+
+        /*
+         * SAFETY: poll_fd は呼び出し中は有効で、要素数は 1.
+         */
+        let ready = unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) };
+        match ready {
+            0 => Ok(None),
+            n if n < 0 => {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    Ok(None)
+                } else {
+                    Err(error)
+                }
+            }
+            _ => self.file.read(buffer).map(Some),
+        }
+    }
+}